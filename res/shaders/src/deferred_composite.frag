@@ -0,0 +1,14 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D lit;
+
+layout(location = 0) in vec2 coord;
+
+layout(location = 0) out vec4 out_col;
+
+void main() {
+    vec3 col = texture(lit, coord).rgb;
+
+    // Reinhard tonemapping, lighting can go above 1 with several lights
+    out_col = vec4(col / (col + 1.0), 1.0);
+}
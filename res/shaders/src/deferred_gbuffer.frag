@@ -0,0 +1,24 @@
+#version 450
+
+#ifdef ALBEDO_TEXTURE
+layout(set = 0, binding = 0) uniform sampler2D albedo_texture;
+#endif
+
+layout(location = 0) in vec3 f_normal;
+layout(location = 1) in vec2 f_tex_coord;
+
+// Albedo alpha is left cleared to 0 wherever nothing was drawn
+layout(location = 0) out vec4 out_albedo;
+layout(location = 1) out vec4 out_normal;
+layout(location = 2) out float out_depth;
+
+void main() {
+#ifdef ALBEDO_TEXTURE
+    out_albedo = vec4(texture(albedo_texture, f_tex_coord).rgb, 1.0);
+#else
+    out_albedo = vec4(1.0);
+#endif
+
+    out_normal = vec4(normalize(f_normal), 0.0);
+    out_depth = gl_FragCoord.z;
+}
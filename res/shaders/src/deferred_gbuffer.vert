@@ -0,0 +1,21 @@
+#version 450
+
+layout(push_constant) uniform push_constants {
+    mat4 view_proj;
+    mat4 model;
+} mats;
+
+layout(location = 0) in vec3 pos;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec2 tex_coord;
+
+layout(location = 0) out vec3 f_normal;
+layout(location = 1) out vec2 f_tex_coord;
+
+void main() {
+    gl_Position = mats.view_proj * mats.model * vec4(pos, 1.0);
+
+    // Assumes the model matrix scales uniformly
+    f_normal = mat3(mats.model) * normal;
+    f_tex_coord = tex_coord;
+}
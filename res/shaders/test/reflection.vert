@@ -0,0 +1,20 @@
+#version 450
+
+// What reflection.vert.spv declares, used by the shader reflection tests
+
+layout(location = 0) in vec3 pos;
+layout(location = 1) in uvec2 ids;
+
+layout(set = 0, binding = 0) uniform Camera {
+    mat4 view_proj;
+} camera;
+
+layout(set = 0, binding = 1) uniform sampler2D tex;
+
+layout(push_constant) uniform Push {
+    vec4 col;
+    float scale;
+} push;
+
+void main() {
+}
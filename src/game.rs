@@ -1,6 +1,7 @@
 use std::f32::consts::PI;
 
-use crate::{math::{vec::{Vec2, Vec3, Vec4}, mat::Mat4}, renderer::{vertex_buffer::{VertexAttribute, VertexAttributes, NoVertices}, mesh::{FromObjTri, self}, graphics_pass::{GraphicsPassDrawInfo, GraphicsPassBuilder}, buffer::BufferBuilder, image::{ImageBuilder, Image}, descriptors::{CreationReference, BindingReference}, compute_pass::{ComputePassDispatchInfo, ComputePassBuilder}, layer::{LayerExecution, PassDependency}, shader::ShaderType, renderer_data::ResourceReference}, space::meshes::Torus};
+use crate::{math::{vec::{Vec2, Vec3, Vec4}, mat::Mat4, quat::Quat}, renderer::{vertex_buffer::{VertexAttribute, VertexAttributes, NoVertices}, graphics_pass::{GraphicsPassDrawInfo, GraphicsPassBuilder}, buffer::BufferBuilder, image::{ImageBuilder, Image}, descriptors::{CreationReference, BindingReference}, compute_pass::{ComputePassDispatchInfo, ComputePassBuilder}, layer::LayerExecution, error::RendererError}, space::meshes::{SpaceVert, Torus}};
+use crate::scene::{NodeId, Scene, camera::Camera, transform::Transform};
 
 use crate::renderer::Renderer;
 use crate::util::frametime::Frametime;
@@ -10,23 +11,6 @@ use ash::vk;
 use raw_window_handle::{RawDisplayHandle, RawWindowHandle};
 use winit::event::{VirtualKeyCode, ElementState};
 
-#[repr(C, align(16))]
-pub struct RaytracerTri {
-    pub verts: [Vec4; 3],
-    pub normal: Vec4,
-    pub col: Vec4,
-}
-
-impl FromObjTri for RaytracerTri {
-    fn from_obj_tri(tri: mesh::Tri) -> RaytracerTri {
-        RaytracerTri {
-            verts: tri.verts.clone(),
-            normal: tri.normal,
-            col: tri.normal,
-        }
-    }
-}
-
 #[repr(C)]
 pub struct MapPushConstant {
     pub pos: Vec2,
@@ -67,35 +51,36 @@ pub struct Game {
 
     sens: f32,
 
-    pos: Vec3,
-    vel: Vec3,
-    rot: Vec3,
+    rot: Vec3, // Mouse look angles, the camera node's rotation is built from them
 
     uv_pos: Vec2,
 
     map_push_constant: MapPushConstant,
-    mesh_push_constant: MeshPushConstant,
 
-    space_mesh: Torus,
+    scene: Scene,
+    camera: NodeId,
 }
 
 impl Game {
-    pub unsafe fn new(window: RawWindowHandle, display: RawDisplayHandle, r: Vec2) -> Game {
+    pub unsafe fn new(window: RawWindowHandle, display: RawDisplayHandle, r: Vec2) -> Result<Game, RendererError> {
         let map_push_constant = MapPushConstant {
             pos: Vec2::zero(),
             height_by_width: 0.5,
             angle: 0.0,
         };
 
-        let mesh_push_constant = MeshPushConstant {
-            view_proj: Mat4::identity(),
-            model: Mat4::identity(),
-        };
-
         let space_mesh = Torus::new(10.0 * map_push_constant.height_by_width, 10.0, 15);
+
+        let mut scene = Scene::new();
+
+        let camera = scene.add_node("camera", None, Transform::from_pos(Vec3::new(0.0, 0.0, -3.0)))?;
+        scene.get_mut(camera)?.camera = Some(Camera::perspective(PI / 2.0, 0.0005, 100.0));
+        scene.active_camera = Some(camera);
+
+        let space = scene.add_node("space", None, Transform::identity())?;
         
         let mut game = Game {
-            renderer: Renderer::new(window, display),
+            renderer: Renderer::new(window, display, r.x as u32, r.y as u32)?,
             keys: HashMap::new(),
             screen_res: r,
 
@@ -105,18 +90,19 @@ impl Game {
 
             sens: 0.001,
 
-            pos: Vec3::new(0.0, 0.0, -3.0),
-            vel: Vec3::new(0.0, 0.0, 0.0),
             rot: Vec3::new(0.0, 0.0, 0.0),
 
             uv_pos: Vec2::zero(),
 
             map_push_constant,
-            mesh_push_constant,
 
-            space_mesh,
+            scene,
+            camera,
         };
 
+        let space_mesh_handle = game.renderer.add_mesh("space", &space_mesh.verts, Some(&space_mesh.indices))?;
+        game.scene.get_mut(space)?.mesh = Some(space_mesh_handle);
+
         let map_image_builder = ImageBuilder::new()
             .width(1024)
             .height((1024 as f32 * game.map_push_constant.height_by_width) as u32)
@@ -124,7 +110,7 @@ impl Game {
             .format(vk::Format::B8G8R8A8_UNORM)
             .layout(vk::ImageLayout::GENERAL);
 
-        game.renderer.add_images("map", map_image_builder);
+        game.renderer.add_images("map", map_image_builder)?;
 
         let map_pass_creation_refs = vec![CreationReference::Image("map".to_string())];
         let mesh_pass_creation_refs = vec![CreationReference::Sampler("map".to_string())];
@@ -132,73 +118,72 @@ impl Game {
 
         let map_pass_builder = ComputePassBuilder::new()
             .compute_shader("map.comp")
-            .dispatch_info(ComputePassDispatchInfo::for_image("map", &game.renderer.data))
+            .dispatch_info(ComputePassDispatchInfo::for_image("map", &game.renderer.data)?)
             .push_constant::<MapPushConstant>()
-            .descriptors(map_pass_creation_refs, &game.renderer.data);
+            .descriptors(map_pass_creation_refs, &game.renderer.data)?
+            .writes(CreationReference::Image("map".to_string()));
 
-        let mesh_pass_builder = GraphicsPassBuilder::new()
+        let mesh_pass_builder = GraphicsPassBuilder::<SpaceVert>::new()
             .vertex_shader("mesh.vert")
             .fragment_shader("mesh.frag")
-            .draw_info(GraphicsPassDrawInfo::simple_indexed(game.space_mesh.verts.len(), game.space_mesh.indices.len()))
             .targets(&game.renderer.swapchain.images)
-            .verts(&game.space_mesh.verts)
-            .vertex_indices(&game.space_mesh.indices)
+            .mesh_draws()
             .vertex_push_constant::<MeshPushConstant>()
-            .fragment_descriptors(mesh_pass_creation_refs, &game.renderer.data)
+            .fragment_descriptors(mesh_pass_creation_refs, &game.renderer.data)?
             .clear_col(Vec4::new(0.82, 0.8, 0.9, 1.0))
-            .with_depth_buffer();
+            .with_depth_buffer()
+            .msaa(4);
 
         let ui_pass_builder = GraphicsPassBuilder::<NoVertices>::new()
             .vertex_shader("draw_to_screen.vert")
             .fragment_shader("draw_to_screen.frag")
             .draw_info(GraphicsPassDrawInfo::simple_vertex(6))
             .targets(&game.renderer.swapchain.images)
-            .fragment_descriptors(ui_pass_creation_refs, &game.renderer.data)
-            .extent(vk::Extent2D { width: 500, height: (500 as f32 * game.map_push_constant.height_by_width) as u32})
-            .offset(vk::Offset2D { x: 780, y: 0})
+            .fragment_descriptors(ui_pass_creation_refs, &game.renderer.data)?
+            .extent(game.minimap_rect().extent)
+            .offset(game.minimap_rect().offset)
             .clear_col(Vec4::new(0.0, 0.5, 0.9, 1.0));
 
-        let pass_dependancy = PassDependency {
-            resource: ResourceReference::Image(game.renderer.data.get_image_refs("map")),
-
-            src_access: vk::AccessFlags::SHADER_WRITE,
-            src_stage: vk::PipelineStageFlags::COMPUTE_SHADER,
-            src_shader: ShaderType::Compute,
-            
-            dst_access: vk::AccessFlags::SHADER_READ,
-            dst_stage: vk::PipelineStageFlags::FRAGMENT_SHADER,
-            dst_shader: ShaderType::Fragment,
-        };
-
-        game.renderer.add_layer("final_layer", true, LayerExecution::Main);
+        game.renderer.add_layer("final_layer", true, LayerExecution::Main)?;
 
-        game.renderer.add_compute_pass("final_layer", "map_draw", map_pass_builder);
-        game.renderer.add_graphics_pass("final_layer", "mesh_draw", mesh_pass_builder);
-        game.renderer.add_graphics_pass("final_layer", "ui_draw", ui_pass_builder);
+        game.renderer.add_compute_pass("final_layer", "map_draw", map_pass_builder)?;
+        game.renderer.add_graphics_pass("final_layer", "mesh_draw", mesh_pass_builder)?;
+        game.renderer.add_graphics_pass("final_layer", "ui_draw", ui_pass_builder)?;
 
-        game.renderer.add_pass_dependency("final_layer", "map_draw", "mesh_draw", Some(pass_dependancy));
-        game.renderer.add_pass_dependency("final_layer", "mesh_draw", "ui_draw", None);
+        game.renderer.add_pass_dependency("final_layer", "map_draw", "mesh_draw", None)?;
+        game.renderer.add_pass_dependency("final_layer", "mesh_draw", "ui_draw", None)?;
 
-        game.renderer.get_layer_mut("final_layer").set_root_path("ui_draw");
+        game.renderer.get_layer_mut("final_layer")?.set_root_path("ui_draw");
 
-        game
+        Ok(game)
     }
 
-    pub unsafe fn main_loop(&mut self) {
+    pub unsafe fn main_loop(&mut self) -> Result<(), RendererError> {
+        for reload in self.renderer.reload_shaders() {
+            if let Err(e) = reload.result {
+                println!("Error: Failed to reload {}/{}: {}", reload.layer, reload.pass, e);
+            }
+        }
+
         let delta = self.frametime.get_delta();
 
         self.frametime.refresh();
 
-        self.update(delta);
+        self.update(delta)?;
         self.frametime.set("Game");
 
-        self.draw();
+        self.draw()?;
         self.frametime.set("Draw");
 
-        //println!("{}", self.frametime);
+        // Only kept while profiling, the GPU timings of the frame come back frames_in_flight frames later
+        self.renderer.add_frametime(&self.frametime);
+
+        Ok(())
     }
 
-    pub fn update(&mut self, delta: f32) {
+    pub fn update(&mut self, delta: f32) -> Result<(), RendererError> {
+        let mut vel = Vec3::new(0.0, 0.0, 0.0);
+
         self.rot.x -= self.mouse_delta.y * self.sens;
         self.rot.y += self.mouse_delta.x * self.sens;
 
@@ -206,23 +191,23 @@ impl Game {
         let uv_vel = uv_speed * delta;
 
         if self.key_down(VirtualKeyCode::W) {
-            self.vel.z = -0.2;
+            vel.z = -0.2;
         }
         if self.key_down(VirtualKeyCode::S) {
-            self.vel.z = 0.2;
+            vel.z = 0.2;
         }
         if self.key_down(VirtualKeyCode::A) {
-            self.vel.x = -0.2;
+            vel.x = -0.2;
         }
         if self.key_down(VirtualKeyCode::D) {
-            self.vel.x = 0.2;
+            vel.x = 0.2;
         }
 
         if self.key_down(VirtualKeyCode::Space) {
-            self.vel.y = 0.2;
+            vel.y = 0.2;
         }
         if self.key_down(VirtualKeyCode::LShift) {
-            self.vel.y = -0.2;
+            vel.y = -0.2;
         }
 
         if self.key_down(VirtualKeyCode::I) {
@@ -246,30 +231,63 @@ impl Game {
         // self.uv_pos.x %= 1.0;
         // self.uv_pos.y %= 1.0;
 
-        self.pos.x += self.vel.x * self.rot.y.cos() - self.vel.z * self.rot.y.sin();
-        self.pos.z -= self.vel.x * self.rot.y.sin() + self.vel.z * self.rot.y.cos();
-        self.pos.y += self.vel.y;
+        let camera = self.scene.transform_mut(self.camera)?;
 
-        let mut view_dir = Vec3::new(0.0, 0.0, 1.0);
+        camera.pos.x += vel.x * self.rot.y.cos() - vel.z * self.rot.y.sin();
+        camera.pos.z -= vel.x * self.rot.y.sin() + vel.z * self.rot.y.cos();
+        camera.pos.y += vel.y;
 
-        view_dir.x = self.rot.x.cos() * self.rot.y.sin();
-        view_dir.y = self.rot.x.sin();
-        view_dir.z = self.rot.x.cos() * self.rot.y.cos();
+        // Pitching by -rot.x then yawing by rot.y turns +z to where the mouse is looking
+        camera.rot = Quat::from_a(self.rot.y, Vec3::new(0.0, 1.0, 0.0)) * Quat::from_a(-self.rot.x, Vec3::new(1.0, 0.0, 0.0));
         
         self.map_push_constant.pos = self.uv_pos;
-        self.mesh_push_constant.view_proj = (Mat4::view(view_dir, self.pos) * Mat4::perspective(16.0 / 9.0, PI / 2.0, 0.0005, 100.0)).transpose();
         
-        self.vel = Vec3::new(0.0, 0.0, 0.0);
         self.mouse_delta = Vec2::new(0.0, 0.0);
+
+        Ok(())
+    }
+
+    pub unsafe fn draw(&mut self) -> Result<(), RendererError> {
+        if !self.renderer.pre_draw()? {
+            return Ok(());
+        }
+
+        let extent = self.renderer.device.surface_extent;
+        if extent.width as f32 != self.screen_res.x || extent.height as f32 != self.screen_res.y {
+            self.screen_res = Vec2::new(extent.width as f32, extent.height as f32);
+
+            let minimap_rect = self.minimap_rect();
+            self.renderer.get_layer_mut("final_layer")?.set_graphics_pass_rect("ui_draw", Some(minimap_rect.extent), Some(minimap_rect.offset))?;
+        }
+
+        self.renderer.get_layer_mut("final_layer")?.fill_compute_push_constant("map_draw", &self.map_push_constant)?;
+
+        let extract = self.scene.extract(self.screen_res.x / self.screen_res.y);
+        let draws = extract.draw_items(|draw, camera| MeshPushConstant {
+            view_proj: camera.map_or(Mat4::identity(), |c| c.view_proj).transpose(),
+            model: draw.model.transpose(),
+        });
+
+        self.renderer.set_draws("final_layer", "mesh_draw", draws)?;
+
+        self.renderer.draw()
+    }
+
+    pub fn resize(&mut self, r: Vec2) {
+        self.renderer.resize(r.x as u32, r.y as u32);
     }
 
-    pub unsafe fn draw(&mut self) {
-        self.renderer.pre_draw();
+    // The minimap sits in the top right corner and keeps the same proportion of the window width
+    fn minimap_rect(&self) -> vk::Rect2D {
+        const MINIMAP_WIDTH_FRACTION: f32 = 500.0 / 1280.0;
 
-        self.renderer.get_layer_mut("final_layer").fill_compute_push_constant("map_draw", &self.map_push_constant);
-        self.renderer.get_layer_mut("final_layer").fill_vertex_push_constant("mesh_draw", &self.mesh_push_constant);
+        let width = (self.screen_res.x * MINIMAP_WIDTH_FRACTION) as u32;
+        let height = (width as f32 * self.map_push_constant.height_by_width) as u32;
 
-        self.renderer.draw();
+        vk::Rect2D {
+            extent: vk::Extent2D { width, height },
+            offset: vk::Offset2D { x: self.screen_res.x as i32 - width as i32, y: 0 },
+        }
     }
 
     pub fn update_key(&mut self, vk: VirtualKeyCode, s: ElementState) {
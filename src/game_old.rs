@@ -1,7 +1,8 @@
 use std::f32::consts::PI;
 
-use crate::{math::{vec::{Vec2, Vec3, Vec4}, mat::Mat4}, renderer::{vertex_buffer::{VertexAttribute, VertexAttributes, NoVertices}, mesh::{FromObjTri, self}, graphics_pass::{GraphicsPassDrawInfo, GraphicsPassBuilder}, buffer::BufferBuilder, image::ImageBuilder, descriptors::{CreationReference, BindingReference}, compute_pass::{ComputePassDispatchInfo, ComputePassBuilder}, layer::{LayerExecution, PassDependency}, shader::ShaderType}};
+use crate::{math::{vec::{Vec2, Vec3, Vec4}, mat::Mat4}, renderer::{vertex_buffer::{VertexAttribute, VertexAttributes, NoVertices}, mesh, graphics_pass::{GraphicsPassDrawInfo, GraphicsPassBuilder}, buffer::BufferBuilder, image::ImageBuilder, descriptors::{CreationReference, BindingReference}, compute_pass::{ComputePassDispatchInfo, ComputePassBuilder}, layer::{LayerExecution, PassDependency}, shader::ShaderType}};
 
+use crate::raytracer::{RaytracerPushConstant, RaytracerTri};
 use crate::renderer::Renderer;
 use crate::util::frametime::Frametime;
 
@@ -10,31 +11,6 @@ use ash::vk;
 use raw_window_handle::{RawDisplayHandle, RawWindowHandle};
 use winit::event::{VirtualKeyCode, ElementState};
 
-#[repr(C)]
-pub struct RaytracerPushConstant {
-    pub view: Mat4,
-    pub pos: Vec3,
-    pub downscale: u32,
-    pub tri_count: u32,
-}
-
-#[repr(C, align(16))]
-pub struct RaytracerTri {
-    pub verts: [Vec4; 3],
-    pub normal: Vec4,
-    pub col: Vec4,
-}
-
-impl FromObjTri for RaytracerTri {
-    fn from_obj_tri(tri: mesh::Tri) -> RaytracerTri {
-        RaytracerTri {
-            verts: tri.verts.clone(),
-            normal: tri.normal,
-            col: tri.normal,
-        }
-    }
-}
-
 #[repr(C)]
 pub struct MeshPushConstant {
     pub view_proj: Mat4,
@@ -85,7 +61,7 @@ impl Game {
 
         let mut tris = Vec::<RaytracerTri>::with_capacity(MAX_TRIS);
 
-        mesh::parse_obj_as_tris::<RaytracerTri>(&mut tris, "res/meshes/asdf.obj");
+        mesh::parse_obj_as_tris::<RaytracerTri>(&mut tris, "res/meshes/asdf.obj").expect("Error: Failed to load mesh");
 
         let raytracer_push_constant = RaytracerPushConstant {
             view: Mat4::identity(),
@@ -153,7 +129,7 @@ impl Game {
             .fragment_descriptors(quad_pass_creation_refs, &game.renderer.data);
 
         let mut mesh_tris = Vec::<RaytracerTri>::with_capacity(2048);
-        mesh::parse_obj_as_tris(&mut mesh_tris, "res/meshes/torus.obj");
+        mesh::parse_obj_as_tris(&mut mesh_tris, "res/meshes/torus.obj").expect("Error: Failed to load mesh");
 
         let mut mesh_verts = Vec::<MeshVertex>::with_capacity(2048);
         for tri in mesh_tris {
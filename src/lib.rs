@@ -1,5 +1,7 @@
 pub mod math;
 pub mod game;
 pub mod renderer;
+pub mod scene;
 pub mod util;
-pub mod space;
\ No newline at end of file
+pub mod space;
+pub mod raytracer;
\ No newline at end of file
@@ -1,7 +1,7 @@
 use std::sync::mpsc;
 use std::thread;
 
-use engine::{math::vec::Vec2, game};
+use engine::{math::vec::Vec2, game, renderer::error::RendererError};
 use engine::util::window;
 use raw_window_handle::{HasRawWindowHandle, HasRawDisplayHandle, RawWindowHandle, RawDisplayHandle};
 use winit::event::{Event, VirtualKeyCode, WindowEvent, DeviceEvent, ElementState};
@@ -16,6 +16,10 @@ pub struct MouseMessage {
     delta: Vec2,
 }
 
+pub struct ResizeMessage {
+    res: Vec2,
+}
+
 pub struct RawWindowDataWrapper {
     window_handle: RawWindowHandle,
     display_handle: RawDisplayHandle,
@@ -31,15 +35,17 @@ fn main() {
 
         let (key_t, key_r) = mpsc::channel::<KeyboardMessage>();
         let (mouse_t, mouse_r) = mpsc::channel::<MouseMessage>();
+        let (resize_t, resize_r) = mpsc::channel::<ResizeMessage>();
 
         let raw_window_data = RawWindowDataWrapper {
             window_handle: window.window.raw_window_handle(),
             display_handle: window.window.raw_display_handle(),
         };
 
-        let game_handle = thread::spawn(move || {
+        // The game thread only finishes if something fails, the error is reported from the event loop
+        let game_handle = thread::spawn(move || -> Result<(), RendererError> {
             let raw_window_data_copy = raw_window_data;
-            let mut game = game::Game::new(raw_window_data_copy.window_handle, raw_window_data_copy.display_handle, Vec2::new(window.res.0 as f32, window.res.1 as f32));
+            let mut game = game::Game::new(raw_window_data_copy.window_handle, raw_window_data_copy.display_handle, Vec2::new(window.res.0 as f32, window.res.1 as f32))?;
 
             let mut game_should_close = false;
             let mut renders = 0;
@@ -52,6 +58,12 @@ fn main() {
                     _ => {},
                 }
 
+                let mut resize_msg = resize_r.try_recv();
+                while resize_msg.is_ok() {
+                    game.resize(resize_msg.unwrap().res);
+                    resize_msg = resize_r.try_recv();
+                }
+
                 let mut mouse_msg = mouse_r.try_recv();
                 while mouse_msg.is_ok() {
                     game.mouse_delta = game.mouse_delta + mouse_msg.unwrap().delta;
@@ -59,15 +71,28 @@ fn main() {
                 }
 
                 //if renders < 1 {
-                    game.main_loop();
+                    game.main_loop()?;
                 //    renders += 1;
                 //}
             }
+
+            Ok(())
         });
 
+        let mut game_handle = Some(game_handle);
+
         event_loop.run(move |event, _, control_flow| {
             *control_flow = ControlFlow::Poll;
 
+            if game_handle.as_ref().map_or(false, |h| h.is_finished()) {
+                if let Some(Ok(Err(e))) = game_handle.take().map(|h| h.join()) {
+                    println!("Error: {}", e);
+                }
+
+                *control_flow = ControlFlow::ExitWithCode(1);
+                return;
+            }
+
             match event {
                 Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => {
                     *control_flow = ControlFlow::Exit;
@@ -90,6 +115,11 @@ fn main() {
                         mouse_t.send(MouseMessage { delta: Vec2::new(delta.0 as f32, delta.1 as f32) }).unwrap();
                     }
                 }
+                Event::WindowEvent { event: WindowEvent::Resized(size), .. } => {
+                    window.res = (size.width, size.height);
+
+                    resize_t.send(ResizeMessage { res: Vec2::new(size.width as f32, size.height as f32) }).unwrap();
+                },
                 Event::WindowEvent { event: WindowEvent::Focused(f), .. } => {
                     window.focused = f;
                 },
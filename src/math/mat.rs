@@ -112,6 +112,15 @@ impl Mat4 {
         }
     }
 
+    pub fn scale(scale: Vec3) -> Mat4 {
+        Mat4 {
+            x: Vec4::new(scale.x, 0.0, 0.0, 0.0),
+            y: Vec4::new(0.0, scale.y, 0.0, 0.0),
+            z: Vec4::new(0.0, 0.0, scale.z, 0.0),
+            w: Vec4::new(0.0, 0.0, 0.0, 1.0),
+        }
+    }
+
     pub fn transpose(&self) -> Mat4 {
         Mat4 {
             x: Vec4::new(self.x.x, self.y.x, self.z.x, self.w.x),
@@ -121,6 +130,54 @@ impl Mat4 {
         }
     }
 
+    // Gauss-Jordan elimination with partial pivoting, None if the matrix is singular
+    pub fn inverse(&self) -> Option<Mat4> {
+        let mut m = self.to_rows();
+        let mut inv = Mat4::identity().to_rows();
+
+        for col in 0..4 {
+            let pivot = (col..4).max_by(|&a, &b| m[a][col].abs().total_cmp(&m[b][col].abs()))?;
+
+            if m[pivot][col].abs() < f32::EPSILON {
+                return None;
+            }
+
+            m.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let scale = 1.0 / m[col][col];
+            for i in 0..4 {
+                m[col][i] *= scale;
+                inv[col][i] *= scale;
+            }
+
+            for row in (0..4).filter(|&row| row != col) {
+                let factor = m[row][col];
+
+                for i in 0..4 {
+                    m[row][i] -= factor * m[col][i];
+                    inv[row][i] -= factor * inv[col][i];
+                }
+            }
+        }
+
+        Some(Mat4 {
+            x: Vec4::new(inv[0][0], inv[0][1], inv[0][2], inv[0][3]),
+            y: Vec4::new(inv[1][0], inv[1][1], inv[1][2], inv[1][3]),
+            z: Vec4::new(inv[2][0], inv[2][1], inv[2][2], inv[2][3]),
+            w: Vec4::new(inv[3][0], inv[3][1], inv[3][2], inv[3][3]),
+        })
+    }
+
+    fn to_rows(self) -> [[f32; 4]; 4] {
+        [
+            [self.x.x, self.x.y, self.x.z, self.x.w],
+            [self.y.x, self.y.y, self.y.z, self.y.w],
+            [self.z.x, self.z.y, self.z.z, self.z.w],
+            [self.w.x, self.w.y, self.w.z, self.w.w],
+        ]
+    }
+
     pub fn view(dir: Vec3, pos: Vec3) -> Mat4 {
         const UP: Vec3 = Vec3{ x: 0.0, y: -1.0, z: 0.0 };
 
@@ -11,6 +11,15 @@ pub struct Quat {
 }
 
 impl Quat {
+    pub fn identity() -> Quat {
+        Quat {
+            r: 1.0,
+            i: 0.0,
+            j: 0.0,
+            k: 0.0,
+        }
+    }
+
     pub fn from_a(r: f32, a: Vec3) -> Quat {
         let s = (r / 2.0).sin();
         Quat {
@@ -52,10 +61,34 @@ impl ops::Mul<Quat> for Quat {
 
     fn mul(self, rhs: Quat) -> Quat {
         Quat {
-            r: self.r * rhs.r - self.i * rhs.i - self.j * rhs.j - self.k * rhs.j,
+            r: self.r * rhs.r - self.i * rhs.i - self.j * rhs.j - self.k * rhs.k,
             i: self.r * rhs.i + self.i * rhs.r - self.j * rhs.k + self.k * rhs.j,
             j: self.r * rhs.j + self.i * rhs.k + self.j * rhs.r - self.k * rhs.i,
             k: self.r * rhs.k - self.i * rhs.j + self.j * rhs.i + self.k * rhs.r,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mul_uses_both_k_components() {
+        let k = Quat { r: 0.0, i: 0.0, j: 0.0, k: 1.0 };
+        let kk = k * k;
+        assert_eq!((kk.r, kk.i, kk.j, kk.k), (-1.0, 0.0, 0.0, 0.0));
+
+        let q = Quat { r: 1.0, i: 2.0, j: 3.0, k: 4.0 } * Quat { r: 5.0, i: 6.0, j: 7.0, k: 8.0 };
+        assert_eq!(q.r, 5.0 - 12.0 - 21.0 - 32.0);
+    }
+
+    #[test]
+    fn rotations_about_z_compose() {
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        let q = Quat::from_a(0.5, z) * Quat::from_a(0.25, z);
+        let expected = Quat::from_a(0.75, z);
+
+        assert!((q.r - expected.r).abs() < 1e-6 && (q.k - expected.k).abs() < 1e-6);
+    }
+}
@@ -75,7 +75,7 @@ impl Vec3 {
     }
 
     pub fn dot(v1: Vec3, v2: Vec3) -> f32 {
-        v1.x * v2.x + v1.y * v2.y + v1.z * v1.z
+        v1.x * v2.x + v1.y * v2.y + v1.z * v2.z
     }
 
     pub fn cross(v1: Vec3, v2: Vec3) -> Vec3 {
@@ -246,4 +246,15 @@ impl std::fmt::Display for Vec4 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "x: {}, y: {}, z: {}, w: {}", self.x, self.y, self.z, self.w)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dot_uses_both_z_components() {
+        assert_eq!(Vec3::dot(Vec3::new(1.0, 2.0, 3.0), Vec3::new(4.0, 5.0, 6.0)), 32.0);
+        assert_eq!(Vec3::dot(Vec3::new(0.0, 0.0, 2.0), Vec3::new(0.0, 0.0, 0.0)), 0.0);
+    }
+}
@@ -0,0 +1,212 @@
+pub mod bvh;
+
+use std::thread;
+
+use crate::math::{vec::{Vec3, Vec4}, mat::Mat4};
+use crate::renderer::mesh::{self, FromObjTri};
+
+// Mirrors the constants and layouts in raytracer.comp so the same buffers can be handed to either
+pub const WIDTH: i32 = 1280;
+pub const HEIGHT: i32 = 720;
+pub const ASPECT_RATIO: f32 = 16.0 / 9.0;
+
+const MAX_DISTANCE: f32 = 1000000000.0;
+
+const SKY_COLOUR: Vec4 = Vec4 { x: 0.8, y: 0.8, z: 1.0, w: 1.0 };
+const MISS_COLOUR: Vec3 = Vec3 { x: 1.0, y: 0.0, z: 1.0 };
+
+#[repr(C)]
+pub struct RaytracerPushConstant {
+    pub view: Mat4,
+    pub pos: Vec3,
+    pub downscale: u32,
+    pub tri_count: u32,
+}
+
+// Matches the std140 layout of tri, vec3s are padded out to 16 bytes
+#[derive(Copy, Clone)]
+#[repr(C, align(16))]
+pub struct RaytracerTri {
+    pub verts: [Vec4; 3],
+    pub normal: Vec4,
+    pub col: Vec4,
+}
+
+#[derive(Copy, Clone)]
+pub struct Ray {
+    pub pos: Vec3,
+    pub dir: Vec3,
+}
+
+impl FromObjTri for RaytracerTri {
+    fn from_obj_tri(tri: mesh::Tri) -> RaytracerTri {
+        RaytracerTri {
+            verts: tri.verts,
+            normal: tri.normal,
+            col: tri.normal,
+        }
+    }
+}
+
+impl RaytracerPushConstant {
+    // The size of the image raytracer.comp writes to for this downscale
+    pub fn image_size(&self) -> (u32, u32) {
+        ((WIDTH / self.downscale as i32) as u32, (HEIGHT / self.downscale as i32) as u32)
+    }
+}
+
+impl Ray {
+    // Same as main() in raytracer.comp, integer divisions included
+    pub fn for_pixel(push_constant: &RaytracerPushConstant, x: u32, y: u32) -> Ray {
+        let half_width = (WIDTH / push_constant.downscale as i32) / 2;
+        let half_height = (HEIGHT / push_constant.downscale as i32) / 2;
+
+        let x = x as i32 - half_width;
+        let y = half_height - y as i32;
+
+        let x_norm = (x as f32 * ASPECT_RATIO) / half_width as f32;
+        let y_norm = y as f32 / half_height as f32;
+
+        // GLSL reads the matrix column major, so it sees the transpose of the rows stored in Mat4
+        let dir = push_constant.view.transpose() * Vec4::new(x_norm, y_norm, 1.0, 0.0).normalize();
+
+        Ray {
+            pos: push_constant.pos,
+            dir: dir.to_vec3(),
+        }
+    }
+}
+
+// Möller–Trumbore, returns the distance along the ray if it hits the triangle
+pub fn collision(r: &Ray, t: &RaytracerTri) -> Option<f32> {
+    let v0 = t.verts[0].to_vec3();
+
+    let a = t.verts[1].to_vec3() - v0;
+    let b = t.verts[2].to_vec3() - v0;
+
+    let axis_t = r.pos - v0;
+
+    let cross_dir_b = Vec3::cross(r.dir, b);
+    let cross_t_a = Vec3::cross(axis_t, a);
+
+    let det = Vec3::dot(cross_dir_b, a);
+
+    let d = Vec3::dot(cross_t_a, b) / det;
+    let u = Vec3::dot(cross_dir_b, axis_t) / det;
+    let v = Vec3::dot(cross_t_a, r.dir) / det;
+
+    // Written so NaNs from a zero determinant count as a miss, the same as the comparisons in the shader
+    if u + v <= 1.0 && (0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v) && d >= 0.0 {
+        Some(d)
+    } else {
+        None
+    }
+}
+
+// Returns the closest hit as (distance, triangle index), however far away it is
+pub fn closest_hit(r: &Ray, tris: &[RaytracerTri]) -> Option<(f32, usize)> {
+    let mut closest: Option<(f32, usize)> = None;
+
+    for (i, t) in tris.iter().enumerate() {
+        if let Some(d) = collision(r, t) {
+            if closest.is_none_or(|c| d < c.0) {
+                closest = Some((d, i));
+            }
+        }
+    }
+
+    closest
+}
+
+// Any hit at all counts as a collision, but only hits closer than MAX_DISTANCE colour the pixel
+pub fn trace(r: &Ray, tris: &[RaytracerTri]) -> Vec4 {
+    match closest_hit(r, tris) {
+        Some((d, i)) if d < MAX_DISTANCE => {
+            let normal = tris[i].normal;
+            Vec4::new(normal.x / 2.0 + 0.5, normal.y / 2.0 + 0.5, normal.z / 2.0 + 0.5, 1.0)
+        },
+        Some(_) => Vec4::new(MISS_COLOUR.x, MISS_COLOUR.y, MISS_COLOUR.z, 1.0),
+        None => SKY_COLOUR,
+    }
+}
+
+// Renders the whole image raytracer.comp would write, as tightly packed RGBA8 rows
+pub fn render(tris: &[RaytracerTri], push_constant: &RaytracerPushConstant, thread_count: usize) -> Vec<u8> {
+    let (width, height) = push_constant.image_size();
+    let tris = &tris[..(push_constant.tri_count as usize).min(tris.len())];
+
+    let mut pixels = vec![0u8; (width * height * 4) as usize];
+
+    let thread_count = thread_count.max(1);
+    let rows_per_thread = (height as usize).div_ceil(thread_count);
+
+    if rows_per_thread == 0 {
+        return pixels;
+    }
+
+    thread::scope(|s| {
+        for (chunk_index, chunk) in pixels.chunks_mut(rows_per_thread * width as usize * 4).enumerate() {
+            s.spawn(move || {
+                let first_row = chunk_index * rows_per_thread;
+
+                for (i, pixel) in chunk.chunks_exact_mut(4).enumerate() {
+                    let x = (i % width as usize) as u32;
+                    let y = (first_row + i / width as usize) as u32;
+
+                    let col = trace(&Ray::for_pixel(push_constant, x, y), tris);
+                    pixel.copy_from_slice(&to_rgba8(col));
+                }
+            });
+        }
+    });
+
+    pixels
+}
+
+// Uses every core the OS reports
+pub fn render_parallel(tris: &[RaytracerTri], push_constant: &RaytracerPushConstant) -> Vec<u8> {
+    let thread_count = thread::available_parallelism().map_or(1, |n| n.get());
+    render(tris, push_constant, thread_count)
+}
+
+// The conversion imageStore does for an rgba8 image
+pub fn to_rgba8(col: Vec4) -> [u8; 4] {
+    let convert = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    [convert(col.x), convert(col.y), convert(col.z), convert(col.w)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Facing the ray at distance z along the z axis
+    fn tri(z: f32) -> RaytracerTri {
+        RaytracerTri::from_obj_tri(mesh::Tri::new(Vec3::new(-1.0, -1.0, z), Vec3::new(1.0, -1.0, z), Vec3::new(0.0, 1.0, z)))
+    }
+
+    fn ray(pos: Vec3, dir: Vec3) -> Ray {
+        Ray { pos, dir }
+    }
+
+    #[test]
+    fn collision_hits_and_misses() {
+        let forward = Vec3::new(0.0, 0.0, 1.0);
+
+        assert_eq!(collision(&ray(Vec3::zero(), forward), &tri(5.0)), Some(5.0));
+        assert_eq!(collision(&ray(Vec3::new(3.0, 0.0, 0.0), forward), &tri(5.0)), None);
+    }
+
+    #[test]
+    fn collision_ignores_parallel_rays_and_tris_behind() {
+        assert_eq!(collision(&ray(Vec3::new(0.0, 0.0, 5.0), Vec3::new(1.0, 0.0, 0.0)), &tri(5.0)), None);
+        assert_eq!(collision(&ray(Vec3::new(0.0, 0.0, 10.0), Vec3::new(0.0, 0.0, 1.0)), &tri(5.0)), None);
+    }
+
+    #[test]
+    fn closest_hit_picks_the_nearest_tri() {
+        let tris = [tri(5.0), tri(3.0), tri(-2.0)];
+
+        assert_eq!(closest_hit(&ray(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0)), &tris), Some((3.0, 1)));
+        assert_eq!(closest_hit(&ray(Vec3::new(0.0, 5.0, 0.0), Vec3::new(0.0, 0.0, 1.0)), &tris), None);
+    }
+}
@@ -0,0 +1,409 @@
+use crate::math::vec::{Vec3, Vec4};
+use crate::raytracer::{collision, Ray, RaytracerTri, SKY_COLOUR};
+use crate::renderer::mesh;
+
+const BIN_COUNT: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+
+// Traversal never holds more than MAX_DEPTH + 1 nodes on its stack, raytracer_bvh.comp only has room for STACK_SIZE = 64
+// Nodes at this depth stay leaves however many tris they hold
+pub const MAX_DEPTH: usize = 62;
+
+// Relative costs used by the surface area heuristic
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+
+// Anything with three vertices can be put in a BVH
+pub trait BvhTri {
+    fn verts(&self) -> [Vec3; 3];
+}
+
+#[derive(Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+// Matches BvhNode in raytracer_bvh.comp, the uints pack into the padding after each vec3 in both std140 and std430
+// Interior nodes have count 0 and their children at left_or_first and left_or_first + 1, leaves hold count tris starting at left_or_first
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct BvhNode {
+    pub min: Vec3,
+    pub left_or_first: u32,
+    pub max: Vec3,
+    pub count: u32,
+}
+
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    pub tri_indices: Vec<u32>, // Original index of each tri, in the order the leaves reference them
+}
+
+struct BuildTri {
+    bounds: Aabb,
+    centroid: Vec3,
+}
+
+#[derive(Copy, Clone)]
+struct Bin {
+    bounds: Aabb,
+    count: usize,
+}
+
+impl BvhTri for RaytracerTri {
+    fn verts(&self) -> [Vec3; 3] {
+        [self.verts[0].to_vec3(), self.verts[1].to_vec3(), self.verts[2].to_vec3()]
+    }
+}
+
+impl BvhTri for mesh::Tri {
+    fn verts(&self) -> [Vec3; 3] {
+        [self.verts[0].to_vec3(), self.verts[1].to_vec3(), self.verts[2].to_vec3()]
+    }
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3::new(f32::MAX, f32::MAX, f32::MAX),
+            max: Vec3::new(f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+
+    pub fn grow(&mut self, p: Vec3) {
+        self.min = Vec3::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z));
+        self.max = Vec3::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z));
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        let mut bounds = *self;
+        bounds.grow(other.min);
+        bounds.grow(other.max);
+        bounds
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+
+        let e = self.max - self.min;
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+
+    // Slab test, returns the distance to the box if the ray enters it before max_dst
+    pub fn intersect(&self, r: &Ray, inv_dir: Vec3, max_dst: f32) -> Option<f32> {
+        let tx1 = (self.min.x - r.pos.x) * inv_dir.x;
+        let tx2 = (self.max.x - r.pos.x) * inv_dir.x;
+        let ty1 = (self.min.y - r.pos.y) * inv_dir.y;
+        let ty2 = (self.max.y - r.pos.y) * inv_dir.y;
+        let tz1 = (self.min.z - r.pos.z) * inv_dir.z;
+        let tz2 = (self.max.z - r.pos.z) * inv_dir.z;
+
+        let t_min = tx1.min(tx2).max(ty1.min(ty2)).max(tz1.min(tz2));
+        let t_max = tx1.max(tx2).min(ty1.max(ty2)).min(tz1.max(tz2));
+
+        if t_max >= t_min.max(0.0) && t_min < max_dst {
+            Some(t_min)
+        } else {
+            None
+        }
+    }
+
+    fn axis(v: Vec3, axis: usize) -> f32 {
+        match axis {
+            0 => v.x,
+            1 => v.y,
+            _ => v.z,
+        }
+    }
+}
+
+impl BvhNode {
+    pub fn bounds(&self) -> Aabb {
+        Aabb { min: self.min, max: self.max }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+impl Bvh {
+    // Binned SAH build, the root is always nodes[0]
+    pub fn build<T: BvhTri>(tris: &[T]) -> Bvh {
+        let build_tris: Vec<BuildTri> = tris.iter().map(|t| {
+            let mut bounds = Aabb::empty();
+            let verts = t.verts();
+
+            for v in verts {
+                bounds.grow(v);
+            }
+
+            BuildTri {
+                bounds,
+                centroid: (verts[0] + verts[1] + verts[2]) * (1.0 / 3.0),
+            }
+        }).collect();
+
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity((2 * tris.len()).max(1)),
+            tri_indices: (0..tris.len() as u32).collect(),
+        };
+
+        bvh.nodes.push(BvhNode { min: Vec3::zero(), left_or_first: 0, max: Vec3::zero(), count: tris.len() as u32 });
+
+        // An empty BVH is just an empty root leaf, traversal has to check the tri count before reading it
+        if tris.is_empty() {
+            return bvh;
+        }
+
+        bvh.subdivide(0, 0, &build_tris);
+
+        bvh
+    }
+
+    fn subdivide(&mut self, node_index: usize, depth: usize, build_tris: &[BuildTri]) {
+        let first = self.nodes[node_index].left_or_first as usize;
+        let count = self.nodes[node_index].count as usize;
+
+        let mut bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for i in &self.tri_indices[first..first + count] {
+            bounds = bounds.union(&build_tris[*i as usize].bounds);
+            centroid_bounds.grow(build_tris[*i as usize].centroid);
+        }
+
+        self.nodes[node_index].min = bounds.min;
+        self.nodes[node_index].max = bounds.max;
+
+        if count <= 1 || depth >= MAX_DEPTH {
+            return;
+        }
+
+        let split = self.find_split(first, count, bounds.surface_area(), &centroid_bounds, build_tris);
+        let leaf_cost = INTERSECTION_COST * count as f32;
+
+        let (axis, position, cost) = match split {
+            Some(split) => split,
+            None => return,
+        };
+
+        // Splitting has to beat intersecting everything in one leaf, unless the leaf would be too big
+        if cost >= leaf_cost && count <= MAX_LEAF_SIZE {
+            return;
+        }
+
+        let indices = &mut self.tri_indices[first..first + count];
+        let mut left_count = 0;
+        for i in 0..indices.len() {
+            if Aabb::axis(build_tris[indices[i] as usize].centroid, axis) < position {
+                indices.swap(i, left_count);
+                left_count += 1;
+            }
+        }
+
+        if left_count == 0 || left_count == count {
+            return;
+        }
+
+        let left_index = self.nodes.len();
+        self.nodes.push(BvhNode { min: Vec3::zero(), left_or_first: first as u32, max: Vec3::zero(), count: left_count as u32 });
+        self.nodes.push(BvhNode { min: Vec3::zero(), left_or_first: (first + left_count) as u32, max: Vec3::zero(), count: (count - left_count) as u32 });
+
+        self.nodes[node_index].left_or_first = left_index as u32;
+        self.nodes[node_index].count = 0;
+
+        self.subdivide(left_index, depth + 1, build_tris);
+        self.subdivide(left_index + 1, depth + 1, build_tris);
+    }
+
+    // Returns the axis, centroid position and SAH cost of the cheapest split between bins
+    fn find_split(&self, first: usize, count: usize, parent_area: f32, centroid_bounds: &Aabb, build_tris: &[BuildTri]) -> Option<(usize, f32, f32)> {
+        let mut best: Option<(usize, f32, f32)> = None;
+
+        for axis in 0..3 {
+            let min = Aabb::axis(centroid_bounds.min, axis);
+            let max = Aabb::axis(centroid_bounds.max, axis);
+
+            if max <= min {
+                continue;
+            }
+
+            let mut bins = [Bin { bounds: Aabb::empty(), count: 0 }; BIN_COUNT];
+            let scale = BIN_COUNT as f32 / (max - min);
+
+            for i in &self.tri_indices[first..first + count] {
+                let tri = &build_tris[*i as usize];
+                let bin = (((Aabb::axis(tri.centroid, axis) - min) * scale) as usize).min(BIN_COUNT - 1);
+
+                bins[bin].count += 1;
+                bins[bin].bounds = bins[bin].bounds.union(&tri.bounds);
+            }
+
+            // Sweep from both ends so each split's cost is known without rescanning the bins
+            let mut left_areas = [0.0; BIN_COUNT - 1];
+            let mut left_counts = [0; BIN_COUNT - 1];
+            let mut right_areas = [0.0; BIN_COUNT - 1];
+            let mut right_counts = [0; BIN_COUNT - 1];
+
+            let mut left_bounds = Aabb::empty();
+            let mut right_bounds = Aabb::empty();
+            let mut left_count = 0;
+            let mut right_count = 0;
+
+            for i in 0..BIN_COUNT - 1 {
+                left_count += bins[i].count;
+                left_bounds = left_bounds.union(&bins[i].bounds);
+                left_counts[i] = left_count;
+                left_areas[i] = left_bounds.surface_area();
+
+                right_count += bins[BIN_COUNT - 1 - i].count;
+                right_bounds = right_bounds.union(&bins[BIN_COUNT - 1 - i].bounds);
+                right_counts[BIN_COUNT - 2 - i] = right_count;
+                right_areas[BIN_COUNT - 2 - i] = right_bounds.surface_area();
+            }
+
+            for i in 0..BIN_COUNT - 1 {
+                if left_counts[i] == 0 || right_counts[i] == 0 {
+                    continue;
+                }
+
+                let cost = TRAVERSAL_COST + INTERSECTION_COST * (left_areas[i] * left_counts[i] as f32 + right_areas[i] * right_counts[i] as f32) / parent_area.max(f32::EPSILON);
+
+                if best.is_none_or(|b| cost < b.2) {
+                    best = Some((axis, min + (i + 1) as f32 / scale, cost));
+                }
+            }
+        }
+
+        best
+    }
+
+    // Size in bytes of the node array, for a storage buffer built with BufferBuilder and filled from nodes
+    pub fn nodes_size(&self) -> usize {
+        std::mem::size_of::<BvhNode>() * self.nodes.len()
+    }
+
+    // Puts per tri data in the order the leaves expect, this is what should be uploaded alongside the nodes
+    pub fn reorder<T: Clone>(&self, items: &[T]) -> Vec<T> {
+        self.tri_indices.iter().map(|i| items[*i as usize].clone()).collect()
+    }
+
+    pub fn original_index(&self, i: usize) -> usize {
+        self.tri_indices[i] as usize
+    }
+
+    // Same traversal as raytracer_bvh.comp, tris must already be reordered
+    // Returns the closest hit as (distance, index into the reordered tris)
+    pub fn closest_hit(&self, r: &Ray, tris: &[RaytracerTri]) -> Option<(f32, usize)> {
+        if self.tri_indices.is_empty() {
+            return None;
+        }
+
+        let inv_dir = Vec3::new(1.0 / r.dir.x, 1.0 / r.dir.y, 1.0 / r.dir.z);
+
+        let mut closest: Option<(f32, usize)> = None;
+        let mut stack = vec![0usize];
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            let max_dst = closest.map_or(f32::MAX, |c| c.0);
+
+            if node.bounds().intersect(r, inv_dir, max_dst).is_none() {
+                continue;
+            }
+
+            if node.is_leaf() {
+                let first = node.left_or_first as usize;
+
+                for (i, tri) in tris[first..first + node.count as usize].iter().enumerate() {
+                    if let Some(d) = collision(r, tri) {
+                        if d < closest.map_or(f32::MAX, |c| c.0) {
+                            closest = Some((d, first + i));
+                        }
+                    }
+                }
+            } else {
+                stack.push(node.left_or_first as usize);
+                stack.push(node.left_or_first as usize + 1);
+            }
+        }
+
+        closest
+    }
+
+    // For picking, returns the distance and the index of the tri in the order it was passed to build
+    pub fn pick(&self, r: &Ray, tris: &[RaytracerTri]) -> Option<(f32, usize)> {
+        self.closest_hit(r, tris).map(|(d, i)| (d, self.original_index(i)))
+    }
+
+    // Colours a ray the same way as raytracer::trace
+    pub fn trace(&self, r: &Ray, tris: &[RaytracerTri]) -> Vec4 {
+        match self.closest_hit(r, tris) {
+            Some((_, i)) => {
+                let normal = tris[i].normal;
+                Vec4::new(normal.x / 2.0 + 0.5, normal.y / 2.0 + 0.5, normal.z / 2.0 + 0.5, 1.0)
+            },
+            None => SKY_COLOUR,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raytracer;
+    use crate::renderer::mesh::FromObjTri;
+
+    // Small tris scattered through a box, from a fixed seed so failures can be reproduced
+    fn scene(count: usize) -> Vec<RaytracerTri> {
+        let mut seed = 12345u32;
+        let mut next = move || {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) as f32 / (1 << 24) as f32 * 10.0 - 5.0
+        };
+
+        (0..count).map(|_| {
+            let centre = Vec3::new(next(), next(), next());
+            let offset = |a: f32, b: f32, c: f32| centre + Vec3::new(a, b, c) * 0.2;
+
+            RaytracerTri::from_obj_tri(mesh::Tri::new(offset(next(), next(), next()), offset(next(), next(), next()), offset(next(), next(), next())))
+        }).collect()
+    }
+
+    #[test]
+    fn traversal_matches_brute_force() {
+        let tris = scene(200);
+        let bvh = Bvh::build(&tris);
+        let reordered = bvh.reorder(&tris);
+
+        let mut hits = 0;
+
+        for x in -20..=20 {
+            for y in -20..=20 {
+                let r = Ray {
+                    pos: Vec3::new(0.0, 0.0, -20.0),
+                    dir: Vec3::new(x as f32 * 0.02, y as f32 * 0.02, 1.0).normalize(),
+                };
+
+                let expected = raytracer::closest_hit(&r, &tris);
+                let picked = bvh.pick(&r, &reordered);
+
+                assert_eq!(expected.map(|h| h.1), picked.map(|h| h.1), "Ray through ({}, {})", x, y);
+                if let (Some(expected), Some(picked)) = (expected, picked) {
+                    assert_eq!(expected.0, picked.0);
+                    hits += 1;
+                }
+            }
+        }
+
+        // Otherwise the test passes without checking anything
+        assert!(hits > 0);
+    }
+}
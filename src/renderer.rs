@@ -1,4 +1,6 @@
 pub mod core;
+pub mod error;
+pub mod allocator;
 pub mod device;
 pub mod swapchain;
 pub mod buffer;
@@ -7,6 +9,9 @@ pub mod sampler;
 pub mod vertex_buffer;
 pub mod descriptors;
 pub mod shader;
+pub mod shader_compiler;
+pub mod shader_reflection;
+pub mod shader_watcher;
 pub mod framebuffer;
 pub mod commands;
 pub mod compute_pipeline;
@@ -17,14 +22,24 @@ pub mod fence;
 pub mod semaphore;
 pub mod frame;
 pub mod mesh;
+pub mod gltf_import;
+pub mod texture;
 pub mod push_constant;
 pub mod renderer_data;
 pub mod layer;
+pub mod resource_access;
+pub mod deletion_queue;
+pub mod deferred;
+pub mod profiler;
+pub mod upload;
+
+use std::ffi::c_void;
+use std::time::Duration;
 
 use ash::vk;
 use raw_window_handle::{RawWindowHandle, RawDisplayHandle};
 
-use crate::{renderer::{vertex_buffer::VertexAttributes, buffer::Buffer, image::Image, layer::{LayerDependencyInfo, LayerSubmitInfo, PassDependency}}, util::graph::Graph};
+use crate::{renderer::{vertex_buffer::VertexAttributes, buffer::Buffer, image::Image, layer::{LayerDependencyInfo, LayerSubmitInfo, PassDependency}, renderer_data::ResourceReference, resource_access::QueueTransfer, error::RendererError}, util::{frametime::Frametime, graph::{Graph, GraphError}}};
 
 pub struct Renderer {
     pub core: core::Core,
@@ -32,6 +47,11 @@ pub struct Renderer {
     pub swapchain: swapchain::Swapchain,
 
     pub data: renderer_data::RendererData,
+    pub deletion_queue: deletion_queue::DeletionQueue,
+    pub staging: upload::StagingRing,
+    pub uploader: upload::Uploader,
+    pub shader_watcher: shader_watcher::ShaderWatcher,
+    pub profiler: Option<profiler::Profiler>,
  
     pub layers: Vec<layer::Layer>,
     pub layer_graph: Graph<usize, LayerDependencyInfo>,
@@ -41,188 +61,851 @@ pub struct Renderer {
     pub frames_in_flight: usize,
     pub current_frame: usize,
     pub present_index: usize,
+    pub frame_number: u64, // Counts up from 1 for each frame drawn, the value layers signal their timeline semaphores with
+
+    pub headless: bool,
+
+    pub window_extent: vk::Extent2D,
+    pub swapchain_out_of_date: bool,
 }
 
 impl Renderer {
-    pub unsafe fn new(window: RawWindowHandle, display: RawDisplayHandle) -> Renderer {
-        const FRAMES_IN_FLIGHT: u32 = 2;
+    const FRAMES_IN_FLIGHT: u32 = 2;
+    const STAGING_SIZE: u64 = 16 * 1024 * 1024; // Per frame in flight
 
+    pub unsafe fn new(window: RawWindowHandle, display: RawDisplayHandle, width: u32, height: u32) -> Result<Renderer, RendererError> {
         let debug = false;
 
-        let core = core::Core::new(debug, display);
-        let device = device::Device::new(&core, window, display);
-        let swapchain = swapchain::Swapchain::new(&core, &device);
+        let core = core::Core::new(debug, Some(display))?;
+        let device = device::Device::new(&core, window, display, vk::Extent2D { width, height })?;
+        let swapchain = swapchain::Swapchain::new(&core, &device)?;
+
+        Renderer::from_parts(core, device, swapchain, false)
+    }
+
+    /// Renders into offscreen images in place of a swapchain, the result of each frame is retrieved with read_back
+    ///
+    /// # Safety
+    /// Needs a Vulkan loader.
+    pub unsafe fn new_headless(width: u32, height: u32) -> Result<Renderer, RendererError> {
+        let debug = false;
+
+        let core = core::Core::new(debug, None)?;
+        let device = device::Device::new_headless(&core, vk::Extent2D { width, height })?;
+        let swapchain = swapchain::Swapchain::new_headless(&core, &device, Renderer::FRAMES_IN_FLIGHT)?;
+
+        Renderer::from_parts(core, device, swapchain, true)
+    }
+
+    unsafe fn from_parts(core: core::Core, device: device::Device, swapchain: swapchain::Swapchain, headless: bool) -> Result<Renderer, RendererError> {
+        let window_extent = device.surface_extent;
 
         let layers = Vec::<layer::Layer>::new();
         let layer_graph = Graph::new();
 
-        let data = renderer_data::RendererData::new(FRAMES_IN_FLIGHT as usize);
+        let data = renderer_data::RendererData::new(Renderer::FRAMES_IN_FLIGHT as usize);
+        let deletion_queue = deletion_queue::DeletionQueue::new(Renderer::FRAMES_IN_FLIGHT as usize);
+        let staging = upload::StagingRing::new(&core, &device, Renderer::FRAMES_IN_FLIGHT as usize, Renderer::STAGING_SIZE)?;
+        let uploader = upload::Uploader::new(&device)?;
+        let shader_watcher = shader_watcher::ShaderWatcher::new(Duration::from_millis(250));
 
         let mut frames = Vec::<frame::Frame>::new();
-        for _ in 0..FRAMES_IN_FLIGHT {
-            frames.push(frame::Frame::new(&device));
+        for _ in 0..Renderer::FRAMES_IN_FLIGHT {
+            frames.push(frame::Frame::new(&device)?);
         }
 
-        Renderer {
+        Ok(Renderer {
             core,
             device,
             swapchain,
 
             data,
+            deletion_queue,
+            staging,
+            uploader,
+            shader_watcher,
+            profiler: None,
 
             layers,
             layer_graph,
 
             frames,
 
-            frames_in_flight: FRAMES_IN_FLIGHT as usize,
+            frames_in_flight: Renderer::FRAMES_IN_FLIGHT as usize,
             current_frame: 0,
             present_index: 0,
-        }
+            frame_number: 0,
+
+            headless,
+
+            window_extent,
+            swapchain_out_of_date: false,
+        })
     }
 
-    pub unsafe fn pre_draw(&mut self) {
+    // Returns false when there is nothing to draw to this frame, such as while the window is minimised
+    pub unsafe fn pre_draw(&mut self) -> Result<bool, RendererError> {
+        if self.swapchain_out_of_date && !self.recreate_swapchain()? {
+            return Ok(false);
+        }
+
         self.current_frame = (self.current_frame + 1) % self.frames_in_flight;
 
         let active_frame = self.frames[self.current_frame];
         
-        self.device.device.wait_for_fences(&[active_frame.in_flight_fence.fence], true, u64::MAX).unwrap();
-        self.device.device.reset_fences(&[active_frame.in_flight_fence.fence]).unwrap();
-        
-        self.present_index = self.swapchain.swapchain_init.acquire_next_image(self.swapchain.swapchain, u64::MAX, active_frame.image_available_semaphore.semaphore, vk::Fence::null()).unwrap().0 as usize;
+        self.device.device.wait_for_fences(&[active_frame.in_flight_fence.fence], true, u64::MAX)?;
+        self.wait_for_layers(active_frame.frame_number)?;
+        self.collect_profile()?;
+
+        self.deletion_queue.advance(&self.device);
+        self.staging.reset(self.current_frame);
+        self.uploader.collect(&self.device)?;
+
+        if self.headless {
+            self.present_index = self.current_frame;
+            self.frame_number += 1;
+
+            return Ok(true);
+        }
+
+        match self.swapchain.swapchain_init.acquire_next_image(self.swapchain.swapchain, u64::MAX, active_frame.image_available_semaphore.semaphore, vk::Fence::null()) {
+            Ok((present_index, suboptimal)) => {
+                self.present_index = present_index as usize;
+                self.swapchain_out_of_date |= suboptimal;
+            },
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                self.swapchain_out_of_date = true;
+
+                return Ok(false);
+            },
+            Err(e) => return Err(e.into()),
+        }
+
+        self.frame_number += 1;
+
+        Ok(true)
     }
 
-    pub unsafe fn draw(&mut self) {
-        let active_frame = self.frames[self.current_frame];
+    pub unsafe fn draw(&mut self) -> Result<(), RendererError> {
+        let frame_number = self.frame_number;
 
+        let (layer_submit_infos, present_wait_semaphores, order) = match self.record_frame() {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                self.abandon_frame()?;
+                return Err(e);
+            },
+        };
+
+        for layer_submit_info in &layer_submit_infos {
+            let mut timeline_submit_i = vk::TimelineSemaphoreSubmitInfo::builder()
+                .wait_semaphore_values(&layer_submit_info.wait_values)
+                .signal_semaphore_values(&layer_submit_info.signal_values);
+
+            let submit_i = vk::SubmitInfo::builder()
+                .push_next(&mut timeline_submit_i)
+                .wait_semaphores(&layer_submit_info.wait_semaphores)
+                .signal_semaphores(&layer_submit_info.signal_semaphores)
+                .wait_dst_stage_mask(&layer_submit_info.wait_stages)
+                .command_buffers(&layer_submit_info.command_buffers)
+                .build();
+
+            // Only reset right before the submit that signals it, so an error earlier in the frame can't leave it unsignaled
+            if layer_submit_info.fence != vk::Fence::null() {
+                self.device.device.reset_fences(&[layer_submit_info.fence])?;
+            }
+
+            self.device.device.queue_submit(layer_submit_info.queue, &[submit_i], layer_submit_info.fence)?;
+        }
+
+        for layer_ref in order {
+            self.layers[layer_ref].timeline_value = frame_number;
+        }
+        self.frames[self.current_frame].frame_number = frame_number;
+
+        if self.headless {
+            return Ok(());
+        }
+
+        let swapchains = [self.swapchain.swapchain];
         let present_indices = [self.present_index as u32];
 
-        for layer in &self.layers {
-            layer.record_one(&self.device, &self.data, self.current_frame, self.present_index);
+        let present_i = vk::PresentInfoKHR::builder()
+            .wait_semaphores(&present_wait_semaphores)
+            .swapchains(&swapchains)
+            .image_indices(&present_indices);
+
+        match self.swapchain.swapchain_init.queue_present(self.device.queue_present.0, &present_i) {
+            Ok(suboptimal) => self.swapchain_out_of_date |= suboptimal,
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => self.swapchain_out_of_date = true,
+            Err(e) => return Err(e.into()),
         }
 
+        Ok(())
+    }
+
+    // Records every layer the final layer depends on, returns their submissions, what presenting waits on and the layers in submission order
+    unsafe fn record_frame(&mut self) -> Result<(Vec<LayerSubmitInfo>, Vec<vk::Semaphore>, Vec<usize>), RendererError> {
+        let active_frame = self.frames[self.current_frame];
+        let frame_number = self.frame_number;
+
         let mut present_wait_semaphores = Vec::<vk::Semaphore>::new();
 
         let mut layer_submit_infos = Vec::<LayerSubmitInfo>::with_capacity(self.layer_graph.node_count());
 
-        let mut nodes = self.layer_graph.breadth_first_backwards("final_layer");
-        nodes.reverse();
+        // Anything queued for the transfer queue goes with this frame's submissions
+        self.uploader.flush(&self.device)?;
+
+        let uploaded = self.submit_uploads(frame_number)?;
+
+        let nodes = self.layer_graph.topological_sort_to("final_layer")?;
+        let order: Vec<usize> = nodes.iter().map(|n| n.data).collect();
 
         let mut present_info_set = false;
         for node in nodes {
             let mut wait_semaphores = Vec::<vk::Semaphore>::new();
+            let mut wait_values = Vec::<u64>::new();
             let mut wait_stages = Vec::<vk::PipelineStageFlags>::new();
-            let mut signal_semaphores = Vec::<vk::Semaphore>::new();
 
-            let dependencies = self.layer_graph.get_prev_edges(&node.name);
+            let dependencies = self.layer_graph.get_prev_edges(&node.name)?;
 
-            for dependency in dependencies {
-                wait_semaphores.push(self.get_layer(&self.layer_graph.get_src_node(dependency).name).semaphore.semaphore);
+            for dependency in &dependencies {
+                wait_semaphores.push(self.get_layer(&self.layer_graph.get_src_node(dependency).name)?.semaphore.semaphore);
+                wait_values.push(frame_number);
                 wait_stages.push(dependency.info.stage);
             }
 
-            signal_semaphores.push(self.get_layer(&node.name).semaphore.semaphore);
+            let initial_stage = dependencies.iter().fold(vk::PipelineStageFlags::empty(), |stage, e| stage | e.info.stage);
+            let (acquires, releases, previous_frame_waits) = self.get_queue_transfers(&order, node.data);
+
+            // Resources handed over at the end of the last frame were released by a submission nothing else in this frame waits on
+            for semaphore in previous_frame_waits {
+                wait_semaphores.push(semaphore);
+                wait_values.push(frame_number - 1);
+                wait_stages.push(vk::PipelineStageFlags::ALL_COMMANDS);
+            }
+
+            let layer = &mut self.layers[node.data];
+
+            // The main queue orders layers after the copies by itself
+            if uploaded && self.device.get_queue(layer.exec).0 != self.device.queue_main.0 {
+                wait_semaphores.push(self.staging.semaphore.semaphore);
+                wait_values.push(frame_number);
+                wait_stages.push(vk::PipelineStageFlags::ALL_COMMANDS);
+            }
+
+            layer.record_one(&self.device, &self.data, self.current_frame, self.present_index, frame_number, initial_stage, &acquires, &releases)?;
+
+            let mut signal_semaphores = vec![layer.semaphore.semaphore];
+            let mut signal_values = vec![frame_number];
 
             let mut fence = vk::Fence::null();
 
-            let layer = self.get_layer(&node.name);
             if layer.present {
-                assert!(!present_info_set, "Error: Multiple layers marked as present");
+                if present_info_set {
+                    return Err(RendererError::InvalidPassConfig("Only one layer can be marked as present"));
+                }
                 present_info_set = true;
 
-                wait_semaphores.push(active_frame.image_available_semaphore.semaphore);
-                wait_stages.push(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT);
                 fence = active_frame.in_flight_fence.fence;
-                present_wait_semaphores = signal_semaphores.clone();
+
+                // Nothing is acquired or presented without a swapchain, so there is nothing else to wait on or signal
+                if !self.headless {
+                    wait_semaphores.push(active_frame.image_available_semaphore.semaphore);
+                    wait_values.push(0);
+                    wait_stages.push(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT);
+
+                    // Presenting can't wait on timeline semaphores
+                    signal_semaphores.push(active_frame.render_finished_semaphore.semaphore);
+                    signal_values.push(0);
+                    present_wait_semaphores.push(active_frame.render_finished_semaphore.semaphore);
+                }
             }
 
             let command_buffers = vec![layer.commands.buffers[self.current_frame]];
             let queue = self.device.get_queue(layer.exec).0;
 
-            let mut layer_submit_info = LayerSubmitInfo {
+            layer_submit_infos.push(LayerSubmitInfo {
                 wait_semaphores,
+                wait_values,
                 wait_stages,
                 signal_semaphores,
+                signal_values,
                 command_buffers,
                 queue,
                 fence,
-                submit_i: vk::SubmitInfo::builder().build(),
-            };
+            });
+        };
 
-            layer_submit_info.submit_i = vk::SubmitInfo::builder()
-                .wait_semaphores(&layer_submit_info.wait_semaphores)
-                .signal_semaphores(&layer_submit_info.signal_semaphores)
-                .wait_dst_stage_mask(&layer_submit_info.wait_stages)
-                .command_buffers(&layer_submit_info.command_buffers)
-                .build();
+        // The frame's fence is only signalled by the present layer's submission
+        if !present_info_set {
+            return Err(RendererError::InvalidPassConfig("A layer the final layer depends on has to be marked as present"));
+        }
+
+        Ok((layer_submit_infos, present_wait_semaphores, order))
+    }
+
+    // Nothing was submitted for the frame, an empty submit signals the fence and waits on the acquired image's semaphore so the frame slot can be reused
+    unsafe fn abandon_frame(&mut self) -> Result<(), RendererError> {
+        let active_frame = self.frames[self.current_frame];
+
+        let mut wait_semaphores = Vec::<vk::Semaphore>::new();
+        let mut wait_stages = Vec::<vk::PipelineStageFlags>::new();
 
-            layer_submit_infos.push(layer_submit_info);
+        if !self.headless {
+            wait_semaphores.push(active_frame.image_available_semaphore.semaphore);
+            wait_stages.push(vk::PipelineStageFlags::ALL_COMMANDS);
+
+            // The acquired image is never presented, recreating the swapchain hands it back
+            self.swapchain_out_of_date = true;
+        }
+
+        let submit_i = vk::SubmitInfo::builder()
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&wait_stages)
+            .build();
+
+        self.device.device.reset_fences(&[active_frame.in_flight_fence.fence])?;
+        self.device.device.queue_submit(self.device.queue_main.0, &[submit_i], active_frame.in_flight_fence.fence)?;
+
+        Ok(())
+    }
+
+    // Returns whether anything was copied this frame
+    unsafe fn submit_uploads(&self, frame_number: u64) -> Result<bool, RendererError> {
+        let command_buffer = match self.staging.record(&self.device, self.current_frame)? {
+            Some(command_buffer) => command_buffer,
+            None => return Ok(false),
         };
-        
-        for layer_submit_info in layer_submit_infos {
-            self.device.device.queue_submit(layer_submit_info.queue, &[layer_submit_info.submit_i], layer_submit_info.fence).unwrap();
+
+        let command_buffers = [command_buffer];
+        let signal_semaphores = [self.staging.semaphore.semaphore];
+        let signal_values = [frame_number];
+
+        let mut timeline_submit_i = vk::TimelineSemaphoreSubmitInfo::builder()
+            .signal_semaphore_values(&signal_values);
+
+        let submit_i = vk::SubmitInfo::builder()
+            .push_next(&mut timeline_submit_i)
+            .command_buffers(&command_buffers)
+            .signal_semaphores(&signal_semaphores)
+            .build();
+
+        self.device.device.queue_submit(self.device.queue_main.0, &[submit_i], vk::Fence::null())?;
+
+        Ok(true)
+    }
+
+    // Called when the window changes size, the swapchain is recreated before the next frame
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.window_extent = vk::Extent2D { width, height };
+        self.swapchain_out_of_date = true;
+    }
+
+    /// Recreates the swapchain along with the framebuffers and depth images of every graphics pass drawing to it
+    ///
+    /// # Safety
+    /// Waits for the device to be idle, so it can't be called while command buffers are being recorded.
+    pub unsafe fn recreate_swapchain(&mut self) -> Result<bool, RendererError> {
+        self.device.device.device_wait_idle()?;
+
+        let old_images: Vec<image::Image> = self.swapchain.images.iter().map(image::Image::share).collect();
+
+        if self.headless {
+            self.device.surface_extent = self.window_extent;
+
+            let old_swapchain = std::mem::replace(&mut self.swapchain, swapchain::Swapchain::new_headless(&self.core, &self.device, Renderer::FRAMES_IN_FLIGHT)?);
+            old_swapchain.destroy(&self.device);
+        } else {
+            self.device.refresh_surface(self.window_extent)?;
+
+            if self.device.surface_extent.width == 0 || self.device.surface_extent.height == 0 {
+                return Ok(false);
+            }
+
+            self.swapchain.recreate(&self.core, &self.device)?;
         }
 
-        let swapchains = [self.swapchain.swapchain];
+        for layer in &mut self.layers {
+            for pass in &mut layer.graphics_passes {
+                if pass.renders_to(&old_images) {
+                    pass.recreate_targets(&self.core, &self.device, &self.swapchain.images)?;
+                }
+            }
+        }
 
-        let present_i = vk::PresentInfoKHR::builder()
-            .wait_semaphores(&present_wait_semaphores)
-            .swapchains(&swapchains)
-            .image_indices(&present_indices);
+        self.swapchain_out_of_date = false;
+
+        Ok(true)
+    }
 
-        self.swapchain.swapchain_init.queue_present(self.device.queue_present.0, &present_i).unwrap();
+    pub unsafe fn add_buffers(&mut self, name: &str, builder: buffer::BufferBuilder) -> Result<(), RendererError> {
+        self.data.add_buffers(&self.core, &self.device, name, builder)
     }
 
-    pub unsafe fn add_buffers(&mut self, name: &str, builder: buffer::BufferBuilder) {
-        self.data.add_buffers(&self.core, &self.device, name, builder);
+    pub unsafe fn add_images(&mut self, name: &str, builder: image::ImageBuilder) -> Result<(), RendererError> {
+        self.data.add_images(&self.core, &self.device, name, builder)
     }
 
-    pub unsafe fn add_images(&mut self, name: &str, builder: image::ImageBuilder) {
-        self.data.add_images(&self.core, &self.device, name, builder);
+    /// # Safety
+    /// Submits the upload and waits for it, so it can't be called while a frame is being recorded.
+    pub unsafe fn add_texture(&mut self, name: &str, builder: texture::TextureBuilder) -> Result<(), RendererError> {
+        self.data.add_texture(&self.core, &self.device, name, builder)
     }
 
-    pub fn get_buffers(&self, name: &str) -> &Vec<Buffer> {
+    // Destroyed once every frame in flight has finished with them, passes still using them have to be removed or rebuilt
+    pub fn remove_buffers(&mut self, name: &str) -> Result<(), RendererError> {
+        self.data.remove_buffers(name, &mut self.deletion_queue)
+    }
+
+    pub fn remove_images(&mut self, name: &str) -> Result<(), RendererError> {
+        self.data.remove_images(name, &mut self.deletion_queue)
+    }
+
+    /// Meshes are drawn by passes built with mesh_draws, through the draw items set on them
+    ///
+    /// # Safety
+    /// Must not be called while a frame is being recorded.
+    pub unsafe fn add_mesh<T: VertexAttributes>(&mut self, name: &str, verts: &[T], indices: Option<&[u32]>) -> Result<renderer_data::MeshHandle, RendererError> {
+        self.data.add_mesh(&self.core, &self.device, name, verts, indices)
+    }
+
+    pub fn get_mesh(&self, name: &str) -> Result<renderer_data::MeshHandle, RendererError> {
+        self.data.get_mesh(name)
+    }
+
+    pub fn remove_mesh(&mut self, name: &str) -> Result<(), RendererError> {
+        self.data.remove_mesh(name, &mut self.deletion_queue)
+    }
+
+    pub fn memory_stats(&self) -> allocator::AllocatorStats {
+        self.device.memory_stats()
+    }
+
+    pub fn get_buffers(&self, name: &str) -> Result<&Vec<Buffer>, RendererError> {
         self.data.get_buffers(name)
     }
 
-    pub fn get_images(&self, name: &str) -> &Vec<Image> {
+    pub fn get_images(&self, name: &str) -> Result<&Vec<Image>, RendererError> {
         self.data.get_images(name)
     }
 
-    pub unsafe fn add_layer(&mut self, name: &str, present: bool, exec: layer::LayerExecution) {
-        self.layers.push(layer::Layer::new(&self.core, &self.device, self.frames_in_flight, present, exec));
-        self.layer_graph.add_node(name, self.layers.len() - 1);
+    pub unsafe fn add_layer(&mut self, name: &str, present: bool, exec: layer::LayerExecution) -> Result<(), RendererError> {
+        let mut layer = layer::Layer::new(&self.core, &self.device, self.frames_in_flight, present, exec)?;
+
+        if let Some(profiler) = &self.profiler {
+            if let Err(e) = layer.enable_queries(&self.device, profiler.max_passes, profiler.pipeline_statistics) {
+                layer.destroy(&self.device);
+                return Err(e);
+            }
+        }
+
+        if let Err(e) = self.layer_graph.add_node(name, self.layers.len()) {
+            layer.destroy(&self.device);
+            return Err(e.into());
+        }
+        self.layers.push(layer);
+
+        Ok(())
     }
 
-    pub unsafe fn add_layer_dependency(&mut self, src: &str, dst: &str, stage: vk::PipelineStageFlags) {
-        self.layer_graph.add_edge(src, dst, LayerDependencyInfo { stage });
+    pub unsafe fn add_layer_dependency(&mut self, src: &str, dst: &str, stage: vk::PipelineStageFlags) -> Result<(), RendererError> {
+        Ok(self.layer_graph.add_edge(src, dst, LayerDependencyInfo { stage })?)
     }
 
-    pub unsafe fn add_compute_pass(&mut self, layer_name: &str, pass_name: &str, builder: compute_pass::ComputePassBuilder) {
-        let pass = builder.build(&self.core, &self.device);
-        self.get_layer_mut(layer_name).add_compute_pass(pass_name, pass);
+    // The layer is looked up first so a bad name fails before any Vulkan objects are created for the pass
+    pub unsafe fn add_compute_pass(&mut self, layer_name: &str, pass_name: &str, builder: compute_pass::ComputePassBuilder) -> Result<(), RendererError> {
+        self.get_layer(layer_name)?;
+
+        let mut pass = builder.build(&self.core, &self.device)?;
+        if let Err(e) = pass.infer_accesses(&self.data) {
+            pass.destroy(&self.device);
+            return Err(e);
+        }
+
+        if self.get_layer(layer_name)?.pass_graph.contains_node(pass_name) {
+            pass.destroy(&self.device);
+            return Err(GraphError::DuplicateNode(pass_name.to_string()).into());
+        }
+
+        self.shader_watcher.watch(&pass.pipeline.dependencies);
+        self.get_layer_mut(layer_name)?.add_compute_pass(pass_name, pass)
     }
 
-    pub unsafe fn add_graphics_pass<T: VertexAttributes>(&mut self, layer_name: &str, pass_name: &str, builder: graphics_pass::GraphicsPassBuilder<T>) {
-        let pass = builder.build(&self.core, &self.device);
-        self.get_layer_mut(layer_name).add_graphics_pass(pass_name, pass);
+    pub unsafe fn add_graphics_pass<T: VertexAttributes>(&mut self, layer_name: &str, pass_name: &str, builder: graphics_pass::GraphicsPassBuilder<T>) -> Result<(), RendererError> {
+        self.get_layer(layer_name)?;
+
+        let mut pass = builder.build(&self.core, &self.device)?;
+        if let Err(e) = pass.infer_accesses(&self.data) {
+            pass.destroy(&self.device);
+            return Err(e);
+        }
+
+        if self.get_layer(layer_name)?.pass_graph.contains_node(pass_name) {
+            pass.destroy(&self.device);
+            return Err(GraphError::DuplicateNode(pass_name.to_string()).into());
+        }
+
+        self.shader_watcher.watch(&pass.pipeline.dependencies);
+        self.get_layer_mut(layer_name)?.add_graphics_pass(pass_name, pass)
+    }
+
+    pub fn remove_pass(&mut self, layer_name: &str, pass_name: &str) -> Result<(), RendererError> {
+        let pass = self.get_layer_mut(layer_name)?.remove_pass(pass_name)?;
+        self.deletion_queue.retire(pass);
+
+        Ok(())
+    }
+
+    pub fn add_pass_dependency(&mut self, layer_name: &str, src_name: &str, dst_name: &str, dep: Option<PassDependency>) -> Result<(), RendererError> {
+        self.get_layer_mut(layer_name)?.add_pass_dependency(src_name, dst_name, dep)
+    }
+
+    /// Recompiles the shaders of passes whose sources changed on disk, a pass whose shaders fail to compile keeps drawing with its old pipeline
+    ///
+    /// # Safety
+    /// Must be called between frames, replaced pipelines are only destroyed once no frame in flight uses them.
+    pub unsafe fn reload_shaders(&mut self) -> Vec<shader_watcher::ShaderReload> {
+        let changed = self.shader_watcher.poll();
+
+        let mut reloads = Vec::<shader_watcher::ShaderReload>::new();
+
+        if changed.is_empty() {
+            return reloads;
+        }
+
+        for node in self.layer_graph.nodes() {
+            let layer = &mut self.layers[node.data];
+
+            for (pass, result) in layer.reload_shaders(&self.device, &changed, &mut self.deletion_queue) {
+                reloads.push(shader_watcher::ShaderReload { layer: node.name.clone(), pass, result });
+            }
+
+            // A shader that compiled may now include files it didn't before
+            self.shader_watcher.watch(&layer.shader_dependencies());
+        }
+
+        reloads
+    }
+
+    // Ownership of a resource is passed along the layers using it in submission order, and from the last back to the first for the next time it's used.
+    // Layers taking a resource from the previous frame are also given the semaphores they need to wait on for it
+    fn get_queue_transfers(&self, order: &[usize], layer_ref: usize) -> (Vec<QueueTransfer>, Vec<QueueTransfer>, Vec<vk::Semaphore>) {
+        let queue_family = self.device.get_queue(self.layers[layer_ref].exec).1;
+        let accessed: Vec<Vec<ResourceReference>> = order.iter().map(|&i| self.layers[i].get_accessed_resources()).collect();
+
+        let mut acquires = Vec::<QueueTransfer>::new();
+        let mut releases = Vec::<QueueTransfer>::new();
+        let mut waits = Vec::<vk::Semaphore>::new();
+
+        let position = match order.iter().position(|&i| i == layer_ref) {
+            Some(position) => position,
+            None => return (acquires, releases, waits),
+        };
+
+        for resource in &accessed[position] {
+            let users: Vec<usize> = order.iter().zip(&accessed).filter(|(_, resources)| resources.contains(resource)).map(|(&i, _)| i).collect();
+            let user = users.iter().position(|&i| i == layer_ref).unwrap_or(0);
+
+            let prev = match user {
+                0 => self.previously_used(*resource).then(|| users[users.len() - 1]),
+                _ => Some(users[user - 1]),
+            };
+            let next = users.get(user + 1).copied().unwrap_or(users[0]);
+
+            if let Some(prev) = prev {
+                let prev_queue_family = self.device.get_queue(self.layers[prev].exec).1;
+
+                if prev_queue_family != queue_family {
+                    acquires.push(QueueTransfer { resource: *resource, src_queue_family: prev_queue_family, dst_queue_family: queue_family });
+
+                    if user == 0 && !waits.contains(&self.layers[prev].semaphore.semaphore) {
+                        waits.push(self.layers[prev].semaphore.semaphore);
+                    }
+                }
+            }
+
+            let next_queue_family = self.device.get_queue(self.layers[next].exec).1;
+
+            if next_queue_family != queue_family {
+                releases.push(QueueTransfer { resource: *resource, src_queue_family: queue_family, dst_queue_family: next_queue_family });
+            }
+        }
+
+        (acquires, releases, waits)
+    }
+
+    // Textures are one image shared by every frame in flight, everything else has its own copy per frame
+    fn previously_used(&self, resource: ResourceReference) -> bool {
+        let shared = match resource {
+            ResourceReference::Image(i) => self.data.images[i].windows(2).any(|w| w[0].image == w[1].image),
+            ResourceReference::Buffer(_) => false,
+        };
+
+        match shared {
+            true => self.frame_number > 1,
+            false => self.frame_number > self.frames_in_flight as u64,
+        }
+    }
+
+    // The frame's fence only covers the present layer, layers that don't lead into it are waited on through their timelines
+    unsafe fn wait_for_layers(&self, frame_number: u64) -> Result<(), RendererError> {
+        if self.layers.is_empty() {
+            return Ok(());
+        }
+
+        let semaphores: Vec<vk::Semaphore> = self.layers.iter().map(|l| l.semaphore.semaphore).collect();
+        let values: Vec<u64> = self.layers.iter().map(|l| l.timeline_value.min(frame_number)).collect();
+
+        let wait_i = vk::SemaphoreWaitInfo::builder()
+            .semaphores(&semaphores)
+            .values(&values);
+
+        self.device.device.wait_semaphores(&wait_i, u64::MAX)?;
+
+        Ok(())
+    }
+
+    /// Times every pass on the GPU from the next frame on, results are read back once each frame has finished
+    ///
+    /// # Safety
+    /// Waits for the device to be idle before creating the query pools.
+    pub unsafe fn enable_profiling(&mut self, builder: profiler::ProfilerBuilder) -> Result<(), RendererError> {
+        let profiler = builder.build(&self.device)?;
+
+        self.device.device.device_wait_idle()?;
+
+        for layer in &mut self.layers {
+            layer.enable_queries(&self.device, profiler.max_passes, profiler.pipeline_statistics)?;
+        }
+
+        self.profiler = Some(profiler);
+
+        Ok(())
+    }
+
+    /// # Safety
+    /// Waits for the device to be idle before destroying the query pools.
+    pub unsafe fn disable_profiling(&mut self) -> Result<(), RendererError> {
+        self.device.device.device_wait_idle()?;
+
+        for layer in &mut self.layers {
+            layer.disable_queries(&self.device);
+        }
+
+        self.profiler = None;
+
+        Ok(())
+    }
+
+    // CPU timings are kept until the GPU results of the frame being drawn come back, so they end up in the same profile
+    pub fn add_frametime(&mut self, frametime: &Frametime) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.add_frametime(self.frame_number, frametime);
+        }
+    }
+
+    pub fn latest_profile(&self) -> Option<&profiler::FrameProfile> {
+        self.profiler.as_ref().and_then(|p| p.latest())
     }
 
-    pub fn add_pass_dependency(&mut self, layer_name: &str, src_name: &str, dst_name: &str, dep: Option<PassDependency>) {
-        self.get_layer_mut(layer_name).add_pass_dependency(src_name, dst_name, dep);
+    // Reads the queries of the frame about to be reused, which every layer has finished with by now
+    unsafe fn collect_profile(&mut self) -> Result<(), RendererError> {
+        let profiler = match &mut self.profiler {
+            Some(profiler) => profiler,
+            None => return Ok(()),
+        };
+
+        let mut frame_number = None;
+        let mut passes = Vec::<profiler::PassTiming>::new();
+
+        for node in self.layer_graph.nodes() {
+            if let Some(queries) = &self.layers[node.data].queries {
+                if let Some((n, mut timings)) = queries.read(&self.device, self.current_frame, &node.name, profiler.timestamp_period)? {
+                    frame_number = Some(n);
+                    passes.append(&mut timings);
+                }
+            }
+        }
+
+        if let Some(frame_number) = frame_number {
+            profiler.add_frame(frame_number, passes);
+        }
+
+        Ok(())
+    }
+
+    pub fn get_layer(&self, name: &str) -> Result<&layer::Layer, RendererError> {
+        let layer_ref = self.layer_graph.find_node(name)?.data;
+        Ok(&self.layers[layer_ref])
+    }
+
+    pub fn get_layer_mut(&mut self, name: &str) -> Result<&mut layer::Layer, RendererError> {
+        let layer_ref = self.layer_graph.find_node(name)?.data;
+        Ok(&mut self.layers[layer_ref])
+    }
+
+    /// Waits for the current frame to finish and returns the contents of the image it rendered to
+    /// Fails with FrameInProgress between pre_draw and draw, as the frame hasn't been submitted yet
+    ///
+    /// # Safety
+    /// Only valid for a headless renderer after at least one frame has been drawn.
+    pub unsafe fn read_back(&self) -> Result<Vec<u8>, RendererError> {
+        self.wait_for_current_frame()?;
+
+        self.swapchain.images[self.present_index].read_back(&self.core, &self.device)
+    }
+
+    /// Waits for every layer of the current frame to finish, on whichever queue it ran, and returns the contents of the frame's image
+    /// Fails with FrameInProgress between pre_draw and draw, as the frame hasn't been submitted yet
+    ///
+    /// # Safety
+    /// The images must not be written by anything outside the renderer's layers while they're read back.
+    pub unsafe fn read_back_images(&self, name: &str) -> Result<Vec<u8>, RendererError> {
+        self.wait_for_current_frame()?;
+
+        self.data.get_images(name)?[self.current_frame].read_back(&self.core, &self.device)
+    }
+
+    // Async layers signal their timeline semaphores instead of the frame's fence
+    unsafe fn wait_for_current_frame(&self) -> Result<(), RendererError> {
+        let active_frame = self.frames[self.current_frame];
+
+        // pre_draw has moved on to a frame that draw hasn't submitted, waiting for it would never return
+        if active_frame.frame_number != self.frame_number {
+            return Err(RendererError::FrameInProgress);
+        }
+
+        self.device.device.wait_for_fences(&[active_frame.in_flight_fence.fence], true, u64::MAX)?;
+        self.wait_for_layers(active_frame.frame_number)
+    }
+
+    pub unsafe fn fill_buffer<T>(&mut self, name: &str, data: &[T]) -> Result<(), RendererError> {
+        let buffer = &self.data.get_buffers(name)?[self.current_frame];
+        let size = (data.len() * std::mem::size_of::<T>()) as u64;
+
+        if size > buffer.size {
+            return Err(RendererError::BufferOverflow { name: name.to_string(), size: buffer.size, required: size });
+        }
+
+        buffer.fill(&self.device, data);
+
+        Ok(())
+    }
+
+    /// Writes part of the current frame's buffer, device local buffers are written through the staging ring before this frame's layers run
+    ///
+    /// # Safety
+    /// `T` must be plain data matching the buffer's layout in the shaders that read it.
+    pub unsafe fn update_buffer<T>(&mut self, name: &str, offset: usize, data: &[T]) -> Result<(), RendererError> {
+        let buffer = &self.data.get_buffers(name)?[self.current_frame];
+        let size = (data.len() * std::mem::size_of::<T>()) as u64;
+
+        if offset as u64 + size > buffer.size {
+            return Err(RendererError::BufferOverflow { name: name.to_string(), size: buffer.size, required: offset as u64 + size });
+        }
+
+        if size == 0 {
+            return Ok(());
+        }
+
+        if buffer.host_visible {
+            buffer.fill_at(offset, data.as_ptr() as *const c_void, size as usize);
+        } else {
+            self.staging.stage(self.current_frame, buffer, offset as u64, data.as_ptr() as *const c_void, size)?;
+        }
+
+        Ok(())
     }
 
-    pub fn get_layer(&self, name: &str) -> &layer::Layer {
-        let layer_ref = self.layer_graph.get_node(name).data;
-        &self.layers[layer_ref]
+    /// For filling buffers no frame in flight is reading yet, such as ones just added, without waiting on the device. Every frame's copy is written
+    ///
+    /// # Safety
+    /// `T` must be plain data matching the buffer's layout in the shaders that read it.
+    pub unsafe fn upload_buffer<T>(&mut self, name: &str, offset: usize, data: &[T]) -> Result<upload::UploadId, RendererError> {
+        let size = (data.len() * std::mem::size_of::<T>()) as u64;
+        let mut id = 0;
+
+        for buffer in self.data.get_buffers(name)? {
+            if offset as u64 + size > buffer.size {
+                return Err(RendererError::BufferOverflow { name: name.to_string(), size: buffer.size, required: offset as u64 + size });
+            }
+
+            // Host visible buffers are written straight away, so are already resident
+            if buffer.host_visible {
+                buffer.fill_at(offset, data.as_ptr() as *const c_void, size as usize);
+            } else if size > 0 {
+                id = self.uploader.upload_buffer(&self.core, &self.device, buffer, offset as u64, data.as_ptr() as *const c_void, size)?;
+            }
+        }
+
+        Ok(id)
+    }
+
+    /// The texture is added straight away but has to be resident before a pass samples it
+    ///
+    /// # Safety
+    /// The texture mustn't be sampled before its upload is resident.
+    pub unsafe fn add_texture_async(&mut self, name: &str, builder: texture::TextureBuilder) -> Result<upload::UploadId, RendererError> {
+        let data = builder.load()?;
+        let (image, id) = self.uploader.upload_texture(&self.core, &self.device, &data, builder.mipmaps)?;
+
+        self.data.insert_texture(name, image);
+
+        Ok(id)
     }
 
-    pub fn get_layer_mut(&mut self, name: &str) -> &mut layer::Layer {
-        let layer_ref = self.layer_graph.get_node(name).data;
-        &mut self.layers[layer_ref]
+    /// Uploads are flushed with each frame anyway, this is for submitting them sooner
+    ///
+    /// # Safety
+    /// Must not be called while a frame is being recorded.
+    pub unsafe fn flush_uploads(&mut self) -> Result<Option<upload::UploadId>, RendererError> {
+        self.uploader.flush(&self.device)
     }
 
-    pub unsafe fn fill_buffer<T>(&mut self, name: &str, data: &Vec<T>) {
-        self.data.get_buffers(name)[self.current_frame].fill(&self.device, &data);
+    /// # Safety
+    /// The id must come from this renderer's uploader.
+    pub unsafe fn is_resident(&self, id: upload::UploadId) -> Result<bool, RendererError> {
+        self.uploader.is_resident(&self.device, id)
     }
-}
\ No newline at end of file
+
+    pub fn set_dynamic_offset(&mut self, layer_name: &str, pass_name: &str, stage: vk::ShaderStageFlags, binding: u32, offset: u32) -> Result<(), RendererError> {
+        let layer_ref = self.layer_graph.find_node(layer_name)?.data;
+
+        self.layers[layer_ref].set_dynamic_offset(&self.device, pass_name, stage, binding, offset)
+    }
+
+    // Takes effect from the next frame recorded, frames already in flight keep the draws they were recorded with
+    pub fn set_draws(&mut self, layer_name: &str, pass_name: &str, items: Vec<graphics_pass::DrawItem>) -> Result<(), RendererError> {
+        let layer_ref = self.layer_graph.find_node(layer_name)?.data;
+
+        self.layers[layer_ref].set_draws(&self.device, &self.data, pass_name, items)
+    }
+}
+
+// Everything is destroyed in the reverse order it was created, once the device has finished with it
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.device.device_wait_idle().ok();
+
+            self.deletion_queue.flush(&self.device);
+            self.staging.destroy(&self.device);
+            self.uploader.destroy(&self.device);
+
+            for layer in &self.layers {
+                layer.destroy(&self.device);
+            }
+
+            self.data.destroy(&self.device);
+
+            for frame in &self.frames {
+                frame.destroy(&self.device);
+            }
+
+            self.swapchain.destroy(&self.device);
+            self.device.destroy();
+            self.core.destroy();
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::ffi::c_void;
+
+use ash::vk;
+
+use crate::renderer::core::Core;
+use crate::renderer::error::RendererError;
+
+// Requests bigger than half a block get a block of their own
+const BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Allocation {
+    pub memory: vk::DeviceMemory,
+    pub offset: u64,
+    pub size: u64,
+    pub memory_type: u32,
+    pub p_mapped: Option<*mut c_void>, // Already offset to the start of the allocation
+    block: usize,
+    generation: u32, // Of the block slot, so a stale allocation can't free a newer block that reused it
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+pub struct MemoryTypeStats {
+    pub block_count: usize,
+    pub allocation_count: usize,
+    pub reserved_bytes: u64, // Allocated from the driver
+    pub used_bytes: u64, // Handed out to resources, including alignment padding
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct AllocatorStats {
+    pub total: MemoryTypeStats,
+    pub memory_types: Vec<MemoryTypeStats>,
+}
+
+struct MemoryBlock {
+    memory: vk::DeviceMemory,
+    size: u64,
+    p_mapped: Option<*mut c_void>,
+    free_ranges: Vec<(u64, u64)>, // Sorted by offset, .0 is offset, .1 is size
+    allocations: Vec<(u64, u64, vk::ImageTiling)>, // Sorted by offset, .2 is LINEAR for buffers
+}
+
+pub struct Allocator {
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    granularity: u64,
+
+    blocks: Vec<Option<MemoryBlock>>, // Freed blocks leave a gap so allocations can keep their block index
+    block_types: Vec<u32>,
+    block_generations: Vec<u32>, // Bumped whenever a gap is reused
+}
+
+impl MemoryBlock {
+    // First fit, returns the aligned offset. Linear and optimal resources are only kept a granularity apart where they're neighbours
+    fn allocate(&mut self, size: u64, alignment: u64, tiling: vk::ImageTiling, granularity: u64) -> Option<u64> {
+        for i in 0..self.free_ranges.len() {
+            let (offset, range_size) = self.free_ranges[i];
+            let neighbour = self.allocations.partition_point(|a| a.0 < offset);
+
+            let mut aligned_offset = align_up(offset, alignment);
+
+            if let Some(&(prev_offset, prev_size, prev_tiling)) = neighbour.checked_sub(1).map(|n| &self.allocations[n]) {
+                if prev_tiling != tiling && same_page(prev_offset + prev_size - 1, aligned_offset, granularity) {
+                    aligned_offset = align_up(aligned_offset, granularity);
+                }
+            }
+
+            let padding = aligned_offset - offset;
+
+            if range_size < padding + size {
+                continue;
+            }
+
+            if let Some(&(next_offset, _, next_tiling)) = self.allocations.get(neighbour) {
+                if next_tiling != tiling && same_page(aligned_offset + size - 1, next_offset, granularity) {
+                    continue;
+                }
+            }
+
+            // The padding before the allocation stays free
+            let mut remaining = Vec::<(u64, u64)>::with_capacity(2);
+            if padding > 0 {
+                remaining.push((offset, padding));
+            }
+            if range_size > padding + size {
+                remaining.push((aligned_offset + size, range_size - padding - size));
+            }
+
+            self.free_ranges.splice(i..i + 1, remaining);
+            self.allocations.insert(neighbour, (aligned_offset, size, tiling));
+
+            return Some(aligned_offset);
+        }
+
+        None
+    }
+
+    // Merges the range back in with its neighbours. The range must be a live allocation, so a double free can't corrupt the free list
+    fn free(&mut self, offset: u64, size: u64) {
+        let allocation = self.allocations.partition_point(|a| a.0 < offset);
+        match self.allocations.get(allocation) {
+            Some(&(o, s, _)) if o == offset && s == size => self.allocations.remove(allocation),
+            _ => panic!("Error: Allocation has already been freed"),
+        };
+
+        let i = self.free_ranges.partition_point(|r| r.0 < offset);
+        self.free_ranges.insert(i, (offset, size));
+
+        if i + 1 < self.free_ranges.len() && self.free_ranges[i].0 + self.free_ranges[i].1 == self.free_ranges[i + 1].0 {
+            self.free_ranges[i].1 += self.free_ranges[i + 1].1;
+            self.free_ranges.remove(i + 1);
+        }
+
+        if i > 0 && self.free_ranges[i - 1].0 + self.free_ranges[i - 1].1 == self.free_ranges[i].0 {
+            self.free_ranges[i - 1].1 += self.free_ranges[i].1;
+            self.free_ranges.remove(i);
+        }
+    }
+
+    fn free_bytes(&self) -> u64 {
+        self.free_ranges.iter().map(|r| r.1).sum()
+    }
+}
+
+impl Allocator {
+    /// # Safety
+    /// `physical_device` must belong to `c`'s instance.
+    pub unsafe fn new(c: &Core, physical_device: vk::PhysicalDevice) -> Allocator {
+        let memory_properties = c.instance.get_physical_device_memory_properties(physical_device);
+        let limits = c.instance.get_physical_device_properties(physical_device).limits;
+
+        Allocator {
+            memory_properties,
+            // Buffers and optimally tiled images share blocks
+            granularity: limits.buffer_image_granularity.max(1),
+
+            blocks: Vec::new(),
+            block_types: Vec::new(),
+            block_generations: Vec::new(),
+        }
+    }
+
+    pub fn get_memory_type(&self, property_flags: vk::MemoryPropertyFlags, memory_requirements: vk::MemoryRequirements) -> Result<u32, RendererError> {
+        let memory_types = &self.memory_properties.memory_types[..self.memory_properties.memory_type_count as usize];
+
+        memory_types.iter().enumerate().position(|(i, m)| {
+            (memory_requirements.memory_type_bits & (1 << i)) != 0 && m.property_flags.contains(property_flags)
+        }).map(|i| i as u32).ok_or(RendererError::NoSuitableMemoryType(property_flags))
+    }
+
+    /// Buffers are allocated with `vk::ImageTiling::LINEAR`
+    ///
+    /// # Safety
+    /// `d` must be created from the physical device the allocator was made for.
+    pub unsafe fn allocate(&mut self, d: &ash::Device, memory_requirements: vk::MemoryRequirements, properties: vk::MemoryPropertyFlags, tiling: vk::ImageTiling) -> Result<Allocation, RendererError> {
+        let memory_type = self.get_memory_type(properties, memory_requirements)?;
+
+        let alignment = memory_requirements.alignment;
+        let size = memory_requirements.size;
+
+        if size <= BLOCK_SIZE / 2 {
+            for (block_index, block) in self.blocks.iter_mut().enumerate() {
+                if self.block_types[block_index] != memory_type {
+                    continue;
+                }
+
+                if let Some(block) = block {
+                    if let Some(offset) = block.allocate(size, alignment, tiling, self.granularity) {
+                        return Ok(Allocation::new(block, block_index, self.block_generations[block_index], memory_type, offset, size));
+                    }
+                }
+            }
+        }
+
+        let block_size = size.max(BLOCK_SIZE);
+        let block_index = self.add_block(d, memory_type, block_size)?;
+        let block = self.blocks[block_index].as_mut().unwrap();
+        let offset = block.allocate(size, alignment, tiling, self.granularity).unwrap();
+
+        Ok(Allocation::new(block, block_index, self.block_generations[block_index], memory_type, offset, size))
+    }
+
+    /// Empty blocks are given back to the driver straight away
+    ///
+    /// # Safety
+    /// The allocation must come from this allocator and nothing bound to it can still be in use.
+    pub unsafe fn free(&mut self, d: &ash::Device, allocation: &Allocation) {
+        let block = match self.blocks[allocation.block].as_mut() {
+            Some(block) if self.block_generations[allocation.block] == allocation.generation => block,
+            _ => panic!("Error: Allocation has already been freed"),
+        };
+        block.free(allocation.offset, allocation.size);
+
+        if block.allocations.is_empty() {
+            d.free_memory(block.memory, None);
+            self.blocks[allocation.block] = None;
+        }
+    }
+
+    /// # Safety
+    /// Every resource bound to memory from this allocator must already be destroyed.
+    pub unsafe fn destroy(&mut self, d: &ash::Device) {
+        for block in self.blocks.iter().flatten() {
+            d.free_memory(block.memory, None);
+        }
+
+        self.blocks.clear();
+        self.block_types.clear();
+        self.block_generations.clear();
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        let mut stats = AllocatorStats {
+            total: MemoryTypeStats::default(),
+            memory_types: vec![MemoryTypeStats::default(); self.memory_properties.memory_type_count as usize],
+        };
+
+        for (block, memory_type) in self.blocks.iter().zip(&self.block_types) {
+            if let Some(block) = block {
+                for type_stats in [&mut stats.memory_types[*memory_type as usize], &mut stats.total] {
+                    type_stats.block_count += 1;
+                    type_stats.allocation_count += block.allocations.len();
+                    type_stats.reserved_bytes += block.size;
+                    type_stats.used_bytes += block.size - block.free_bytes();
+                }
+            }
+        }
+
+        stats
+    }
+
+    unsafe fn add_block(&mut self, d: &ash::Device, memory_type: u32, size: u64) -> Result<usize, RendererError> {
+        let memory_alloc_i = vk::MemoryAllocateInfo::builder()
+            .allocation_size(size)
+            .memory_type_index(memory_type);
+
+        let memory = d.allocate_memory(&memory_alloc_i, None)?;
+
+        // Host visible blocks stay mapped, memory can only be mapped once so sub-allocations share the mapping
+        let host_visible = self.memory_properties.memory_types[memory_type as usize].property_flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE);
+        let p_mapped = match host_visible {
+            true => Some(d.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())?),
+            false => None,
+        };
+
+        let block = MemoryBlock {
+            memory,
+            size,
+            p_mapped,
+            free_ranges: vec![(0, size)],
+            allocations: Vec::new(),
+        };
+
+        match self.blocks.iter().position(|b| b.is_none()) {
+            Some(i) => {
+                self.blocks[i] = Some(block);
+                self.block_types[i] = memory_type;
+                self.block_generations[i] = self.block_generations[i].wrapping_add(1);
+                Ok(i)
+            },
+            None => {
+                self.blocks.push(Some(block));
+                self.block_types.push(memory_type);
+                self.block_generations.push(0);
+                Ok(self.blocks.len() - 1)
+            },
+        }
+    }
+}
+
+impl Allocation {
+    fn new(block: &MemoryBlock, block_index: usize, generation: u32, memory_type: u32, offset: u64, size: u64) -> Allocation {
+        Allocation {
+            memory: block.memory,
+            offset,
+            size,
+            memory_type,
+            p_mapped: block.p_mapped.map(|p| unsafe { (p as *mut u8).add(offset as usize) as *mut c_void }),
+            block: block_index,
+            generation,
+        }
+    }
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+// Whether two byte offsets fall in the same buffer_image_granularity page
+fn same_page(a: u64, b: u64, granularity: u64) -> bool {
+    a / granularity == b / granularity
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(size: u64) -> MemoryBlock {
+        MemoryBlock {
+            memory: vk::DeviceMemory::null(),
+            size,
+            p_mapped: None,
+            free_ranges: vec![(0, size)],
+            allocations: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn granularity_only_between_linear_and_optimal() {
+        let mut block = block(4096);
+
+        assert_eq!(block.allocate(100, 4, vk::ImageTiling::LINEAR, 1024), Some(0));
+        assert_eq!(block.allocate(100, 4, vk::ImageTiling::LINEAR, 1024), Some(100));
+        assert_eq!(block.allocate(100, 4, vk::ImageTiling::OPTIMAL, 1024), Some(1024));
+        assert_eq!(block.allocate(100, 4, vk::ImageTiling::OPTIMAL, 1024), Some(1124));
+
+        // Buffers can fill the gap before the images, another image can only go after them
+        assert_eq!(block.allocate(500, 4, vk::ImageTiling::LINEAR, 1024), Some(200));
+        assert_eq!(block.allocate(100, 4, vk::ImageTiling::OPTIMAL, 1024), Some(1224));
+    }
+
+    #[test]
+    fn free_merges_ranges() {
+        let mut block = block(1024);
+
+        let a = block.allocate(256, 1, vk::ImageTiling::LINEAR, 1).unwrap();
+        let b = block.allocate(256, 1, vk::ImageTiling::LINEAR, 1).unwrap();
+        block.free(a, 256);
+        block.free(b, 256);
+
+        assert_eq!(block.free_ranges, vec![(0, 1024)]);
+        assert!(block.allocations.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "already been freed")]
+    fn double_free_in_live_block_panics() {
+        let mut block = block(1024);
+
+        let a = block.allocate(256, 1, vk::ImageTiling::LINEAR, 1).unwrap();
+        block.allocate(256, 1, vk::ImageTiling::LINEAR, 1).unwrap();
+        block.free(a, 256);
+        block.free(a, 256);
+    }
+}
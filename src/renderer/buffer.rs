@@ -2,8 +2,9 @@ use std::ffi::c_void;
 
 use ash::vk;
 
-use crate::renderer::{core::Core, commands::Commands};
+use crate::renderer::{core::Core, commands::Commands, allocator::Allocation};
 use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
 
 #[derive(Copy, Clone)]
 pub struct BufferBuilder {
@@ -13,13 +14,17 @@ pub struct BufferBuilder {
     properties: Option<vk::MemoryPropertyFlags>,
 }
 
-#[derive(Copy, Clone, Debug)]
+// Not Clone, so only the value that created the handles destroys them. Everything else holds a share
+#[derive(Debug)]
 pub struct Buffer {
     pub buffer: vk::Buffer,
-    pub memory: vk::DeviceMemory,
+    pub allocation: Allocation,
     pub size: u64,
     pub p_dst: Option<*mut c_void>,
     pub host_visible: bool,
+    pub sharing_mode: vk::SharingMode,
+
+    shared: bool,
 }
 
 impl BufferBuilder {
@@ -68,112 +73,213 @@ impl BufferBuilder {
         }
     }
 
-    pub unsafe fn build(&self, c: &Core, d: &Device) -> Buffer {
-        Buffer::new(c, d, None, self.size.expect("Error: BufferBuilder is missing size"), self.usage.expect("Error: BufferBuilder is missing usage"), self.sharing_mode.expect("Error: BufferBuilder is missing sharing_mode"), self.properties.expect("Error: BufferBuilder is missing property"))
+    pub unsafe fn build(&self, c: &Core, d: &Device) -> Result<Buffer, RendererError> {
+        let (size, usage, sharing_mode, properties) = self.fields()?;
+
+        Buffer::new(c, d, None, size, usage, sharing_mode, properties)
     }
 
-    pub unsafe fn build_many(&self, c: &Core, d: &Device, count: usize) -> Vec<Buffer> {
+    pub unsafe fn build_many(&self, c: &Core, d: &Device, count: usize) -> Result<Vec<Buffer>, RendererError> {
+        let (size, usage, sharing_mode, properties) = self.fields()?;
+
         let mut buffers = Vec::<Buffer>::new();
         for _ in 0..count {
-            buffers.push(Buffer::new(c, d, None, self.size.expect("Error: BufferBuilder is missing size"), self.usage.expect("Error: BufferBuilder is missing usage"), self.sharing_mode.expect("Error: BufferBuilder is missing sharing_mode"), self.properties.expect("Error: BufferBuilder is missing property")));
+            match Buffer::new(c, d, None, size, usage, sharing_mode, properties) {
+                Ok(buffer) => buffers.push(buffer),
+                Err(e) => {
+                    for buffer in &buffers {
+                        buffer.destroy(d);
+                    }
+
+                    return Err(e);
+                },
+            }
         }
 
-        buffers
+        Ok(buffers)
     }
 
-    pub unsafe fn build_with_data(&self, c: &Core, d: &Device, data: *const c_void) -> Buffer {
-        Buffer::new(c, d, Some(data), self.size.expect("Error: BufferBuilder is missing size"), self.usage.expect("Error: BufferBuilder is missing usage"), self.sharing_mode.expect("Error: BufferBuilder is missing sharing_mode"), self.properties.expect("Error: BufferBuilder is missing property"))
+    pub unsafe fn build_with_data(&self, c: &Core, d: &Device, data: *const c_void) -> Result<Buffer, RendererError> {
+        let (size, usage, sharing_mode, properties) = self.fields()?;
+
+        Buffer::new(c, d, Some(data), size, usage, sharing_mode, properties)
     }
 
-    pub unsafe fn build_many_with_data(&self, c: &Core, d: &Device, data: Vec<*const c_void>, count: usize) -> Vec<Buffer> {
+    pub unsafe fn build_many_with_data(&self, c: &Core, d: &Device, data: Vec<*const c_void>, count: usize) -> Result<Vec<Buffer>, RendererError> {
+        let (size, usage, sharing_mode, properties) = self.fields()?;
+
         let mut buffers = Vec::<Buffer>::new();
         for i in 0..count {
-            buffers.push(Buffer::new(c, d, Some(data[i]), self.size.expect("Error: BufferBuilder is missing size"), self.usage.expect("Error: BufferBuilder is missing usage"), self.sharing_mode.expect("Error: BufferBuilder is missing sharing_mode"), self.properties.expect("Error: BufferBuilder is missing property")));
+            match Buffer::new(c, d, Some(data[i]), size, usage, sharing_mode, properties) {
+                Ok(buffer) => buffers.push(buffer),
+                Err(e) => {
+                    for buffer in &buffers {
+                        buffer.destroy(d);
+                    }
+
+                    return Err(e);
+                },
+            }
         }
 
-        buffers
+        Ok(buffers)
+    }
+
+    fn fields(&self) -> Result<(usize, vk::BufferUsageFlags, vk::SharingMode, vk::MemoryPropertyFlags), RendererError> {
+        Ok((
+            self.size.ok_or(RendererError::missing("BufferBuilder", "size"))?,
+            self.usage.ok_or(RendererError::missing("BufferBuilder", "usage"))?,
+            self.sharing_mode.ok_or(RendererError::missing("BufferBuilder", "sharing_mode"))?,
+            self.properties.ok_or(RendererError::missing("BufferBuilder", "properties"))?,
+        ))
     }
 }
 impl Buffer {
-    pub unsafe fn new(c: &Core, d: &Device, data: Option<*const c_void>, size: usize, usage: vk::BufferUsageFlags, sm: vk::SharingMode, properties: vk::MemoryPropertyFlags) -> Buffer {
+    pub unsafe fn new(c: &Core, d: &Device, data: Option<*const c_void>, size: usize, usage: vk::BufferUsageFlags, sm: vk::SharingMode, properties: vk::MemoryPropertyFlags) -> Result<Buffer, RendererError> {
         let host_visible = properties & vk::MemoryPropertyFlags::HOST_VISIBLE == vk::MemoryPropertyFlags::HOST_VISIBLE;
         
         let mut usage = usage;
 
         let mut staging_buffer: Option<Buffer> = None;
 
+        // Device local buffers are filled by copying from a staging buffer, either here or later through the renderer's staging ring
         if !host_visible {
-            assert!(data.is_some(), "Buffer is not device local but no data is provided");
+            usage |= vk::BufferUsageFlags::TRANSFER_DST;
+        }
 
+        if !host_visible && data.is_some() {
             staging_buffer = Some(BufferBuilder::new()
                 .size(size)
                 .usage(vk::BufferUsageFlags::TRANSFER_SRC)
                 .sharing_mode(sm)
                 .properties(vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT)
-                .build(c, d));
-
-            usage |= vk::BufferUsageFlags::TRANSFER_DST;
+                .build(c, d)?);
 
-            staging_buffer.unwrap().fill_from_ptr(d, data.unwrap(), size);
+            staging_buffer.as_ref().unwrap().fill_from_ptr(d, data.unwrap(), size);
         }
         
+        let (buffer, allocation) = match Buffer::create(d, size, usage, sm, properties) {
+            Ok(created) => created,
+            Err(e) => {
+                if let Some(staging_buffer) = &staging_buffer {
+                    staging_buffer.destroy(d);
+                }
+
+                return Err(e);
+            },
+        };
+
+        let p_dst = allocation.p_mapped;
+
+        let buffer = Buffer {
+            buffer,
+            allocation,
+            size: size as u64,
+            host_visible,
+            p_dst,
+            sharing_mode: sm,
+            shared: false,
+        };
+
+        if data.is_some() && host_visible {
+            buffer.fill_from_ptr(d, data.unwrap(), size);
+        }
+
+        if let Some(staging_buffer) = staging_buffer {
+            let copied = Buffer::copy(d, &staging_buffer, &buffer, size);
+            staging_buffer.destroy(d);
+
+            if let Err(e) = copied {
+                buffer.destroy(d);
+
+                return Err(e);
+            }
+        }
+
+        Ok(buffer)
+    }
+
+    // The buffer is destroyed again if its memory can't be allocated or bound
+    unsafe fn create(d: &Device, size: usize, usage: vk::BufferUsageFlags, sm: vk::SharingMode, properties: vk::MemoryPropertyFlags) -> Result<(vk::Buffer, Allocation), RendererError> {
         let buffer_ci = vk::BufferCreateInfo::builder()
             .size(size as u64)
             .usage(usage)
             .sharing_mode(sm);
 
-        let buffer = d.device.create_buffer(&buffer_ci, None).unwrap();
+        let buffer = d.device.create_buffer(&buffer_ci, None)?;
 
         let memory_requirements = d.device.get_buffer_memory_requirements(buffer);
-        let memory_type_index = d.get_memory_type(c, properties, memory_requirements);
 
-        let memory_alloc_i = vk::MemoryAllocateInfo::builder()
-            .allocation_size(size as u64)
-            .memory_type_index(memory_type_index as u32);
+        let allocation = match d.allocate(memory_requirements, properties, vk::ImageTiling::LINEAR) {
+            Ok(allocation) => allocation,
+            Err(e) => {
+                d.device.destroy_buffer(buffer, None);
 
-        let memory = d.device.allocate_memory(&memory_alloc_i, None).unwrap();
-        d.device.bind_buffer_memory(buffer, memory, 0).unwrap();
-
-        let p_dst = match host_visible {
-            true => Some(d.device.map_memory(memory, 0, size as u64, vk::MemoryMapFlags::empty()).unwrap()),
-            false => None,
+                return Err(e);
+            },
         };
 
-        let buffer = Buffer {
-            buffer,
-            memory,
-            size: size as u64,
-            host_visible,
-            p_dst,
-        };
+        if let Err(e) = d.device.bind_buffer_memory(buffer, allocation.memory, allocation.offset) {
+            d.device.destroy_buffer(buffer, None);
+            d.free(&allocation);
 
-        if data.is_some() && host_visible {
-            buffer.fill_from_ptr(d, data.unwrap(), size);
+            return Err(e.into());
         }
 
-        if !host_visible {
-            let transfer_commands = Commands::new(d, d.queue_main.1, 1, true);
+        Ok((buffer, allocation))
+    }
 
-            transfer_commands.record_one(d, 0, |b| {
-                let buffer_copy = vk::BufferCopy::builder()
-                    .size(size as u64)
-                    .build();
+    // Copies on the main queue and waits for it
+    unsafe fn copy(d: &Device, src: &Buffer, dst: &Buffer, size: usize) -> Result<(), RendererError> {
+        let transfer_commands = Commands::new(d, d.queue_main.1, 1, true)?;
 
-                d.device.cmd_copy_buffer(b, staging_buffer.unwrap().buffer, buffer.buffer, &[buffer_copy])
-            });
+        let copied = transfer_commands.record_one(d, 0, |b| {
+            let buffer_copy = vk::BufferCopy::builder()
+                .size(size as u64)
+                .build();
 
+            d.device.cmd_copy_buffer(b, src.buffer, dst.buffer, &[buffer_copy])
+        }).and_then(|_| {
             let submit_i = vk::SubmitInfo::builder()
                 .command_buffers(&[transfer_commands.buffers[0]])
                 .build();
 
-            d.device.queue_submit(d.queue_main.0, &[submit_i], vk::Fence::null()).unwrap();
-            d.device.queue_wait_idle(d.queue_main.0).unwrap();
+            d.device.queue_submit(d.queue_main.0, &[submit_i], vk::Fence::null())?;
+            d.device.queue_wait_idle(d.queue_main.0)?;
+
+            Ok(())
+        });
+
+        transfer_commands.destroy(d);
+
+        copied
+    }
+
+    // A copy of the handles for something that uses the buffer without owning it, destroying a share does nothing
+    pub fn share(&self) -> Buffer {
+        Buffer {
+            buffer: self.buffer,
+            allocation: self.allocation,
+            size: self.size,
+            p_dst: self.p_dst,
+            host_visible: self.host_visible,
+            sharing_mode: self.sharing_mode,
+            shared: true,
+        }
+    }
+
+    /// # Safety
+    /// The buffer mustn't be used by the GPU any more. Shares can be destroyed at any time, as they do nothing.
+    pub unsafe fn destroy(&self, d: &Device) {
+        if self.shared {
+            return;
         }
 
-        buffer
+        d.device.destroy_buffer(self.buffer, None);
+        d.free(&self.allocation);
     }
 
-    pub unsafe fn fill<T>(&self, d: &Device, data: &Vec<T>) {
+    pub unsafe fn fill<T>(&self, d: &Device, data: &[T]) {
         assert!(self.host_visible, "Error: Buffer is not host visible");
 
         let size = data.len() * std::mem::size_of::<T>();
@@ -185,4 +291,13 @@ impl Buffer {
         
         std::ptr::copy(p, self.p_dst.unwrap(), s);
     }
+
+    /// # Safety
+    /// The buffer must be host visible, `p` must point to `s` readable bytes and `offset + s` must fit in the buffer.
+    pub unsafe fn fill_at(&self, offset: usize, p: *const c_void, s: usize) {
+        assert!(self.host_visible, "Error: Buffer is not host visible");
+        assert!((offset + s) as u64 <= self.size, "Error: Write goes past the end of the buffer");
+
+        std::ptr::copy(p, (self.p_dst.unwrap() as *mut u8).add(offset) as *mut c_void, s);
+    }
 }
\ No newline at end of file
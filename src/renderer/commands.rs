@@ -1,6 +1,7 @@
 use ash::vk;
 
 use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
 
 pub struct Commands {
     pub pool: vk::CommandPool,
@@ -9,35 +10,45 @@ pub struct Commands {
 }
 
 impl Commands {
-    pub unsafe fn new(d: &Device, q: u32, c: usize, one_time: bool) -> Commands {
+    pub unsafe fn new(d: &Device, q: u32, c: usize, one_time: bool) -> Result<Commands, RendererError> {
         let pool_ci = vk::CommandPoolCreateInfo::builder()
             .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
             .queue_family_index(q);
 
-        let pool = d.device.create_command_pool(&pool_ci, None).unwrap();
+        let pool = d.device.create_command_pool(&pool_ci, None)?;
 
         let buffer_alloc_i = vk::CommandBufferAllocateInfo::builder()
             .command_pool(pool)
             .level(vk::CommandBufferLevel::PRIMARY)
             .command_buffer_count(c as u32);
 
-        let buffers = d.device.allocate_command_buffers(&buffer_alloc_i).unwrap();
+        let buffers = d.device.allocate_command_buffers(&buffer_alloc_i)?;
 
-        Commands {
+        Ok(Commands {
             pool,
             buffers,
             one_time,
-        }
+        })
+    }
+
+    /// Freeing the pool frees every buffer allocated from it
+    ///
+    /// # Safety
+    /// None of the command buffers can still be pending on a queue.
+    pub unsafe fn destroy(&self, d: &Device) {
+        d.device.destroy_command_pool(self.pool, None);
     }
 
-    pub unsafe fn record_all<F: Fn(usize, vk::CommandBuffer)>(&self, d: &Device, r: F) {
+    pub unsafe fn record_all<F: Fn(usize, vk::CommandBuffer)>(&self, d: &Device, r: F) -> Result<(), RendererError> {
         for i in 0..self.buffers.len() {
-            self.record_one(d, i, |b| { r(i, b) });
+            self.record_one(d, i, |b| { r(i, b) })?;
         }
+
+        Ok(())
     }
 
-    pub unsafe fn record_one<F: Fn(vk::CommandBuffer)>(&self, d: &Device, i: usize, r: F) {
-        d.device.reset_command_buffer(self.buffers[i], vk::CommandBufferResetFlags::RELEASE_RESOURCES).unwrap();
+    pub unsafe fn record_one<F: Fn(vk::CommandBuffer)>(&self, d: &Device, i: usize, r: F) -> Result<(), RendererError> {
+        d.device.reset_command_buffer(self.buffers[i], vk::CommandBufferResetFlags::RELEASE_RESOURCES)?;
 
         let mut buffer_bi = vk::CommandBufferBeginInfo::builder();
 
@@ -45,10 +56,12 @@ impl Commands {
             buffer_bi = buffer_bi.flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
         }
 
-        d.device.begin_command_buffer(self.buffers[i], &buffer_bi).unwrap();
+        d.device.begin_command_buffer(self.buffers[i], &buffer_bi)?;
 
         r(self.buffers[i]);
 
-        d.device.end_command_buffer(self.buffers[i]).unwrap();
+        d.device.end_command_buffer(self.buffers[i])?;
+
+        Ok(())
     }
 }
\ No newline at end of file
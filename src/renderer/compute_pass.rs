@@ -5,6 +5,9 @@ use crate::renderer::device::Device;
 use crate::renderer::descriptors::{Descriptors, DescriptorsBuilder};
 use crate::renderer::compute_pipeline::ComputePipeline;
 use crate::renderer::push_constant::{PushConstant, PushConstantBuilder};
+use crate::renderer::resource_access::{AccessDeclaration, AccessIntent, ResourceAccess};
+use crate::renderer::shader::{ShaderSource, ShaderType};
+use crate::renderer::error::RendererError;
 
 pub struct ComputePassDispatchInfo {
     pub x: u32,
@@ -15,8 +18,10 @@ pub struct ComputePassDispatchInfo {
 pub struct ComputePassBuilder<'a> {
     dispatch_info: Option<ComputePassDispatchInfo>,
     cs: Option<&'a str>,
+    defines: Vec<(String, String)>,
     push_constant_builder: Option<PushConstantBuilder>,
     descriptors_builder: Option<DescriptorsBuilder>,
+    access_declarations: Vec<AccessDeclaration>,
 }
 
 pub struct ComputePass {
@@ -24,6 +29,9 @@ pub struct ComputePass {
     pub descriptors: Option<Descriptors>,
     pub pipeline: ComputePipeline,
     pub dispatch_info: ComputePassDispatchInfo,
+
+    pub access_declarations: Vec<AccessDeclaration>,
+    pub accesses: Vec<ResourceAccess>,
 }
 
 impl Pass for ComputePass {}
@@ -33,14 +41,14 @@ impl ComputePassDispatchInfo {
         ComputePassDispatchInfo { x, y, z }
     }
 
-    pub fn for_image(name: &str, data: &RendererData) -> ComputePassDispatchInfo {
-        let image = data.get_images(name)[0];
+    pub fn for_image(name: &str, data: &RendererData) -> Result<ComputePassDispatchInfo, RendererError> {
+        let image = &data.get_images(name)?[0];
 
-        ComputePassDispatchInfo {
+        Ok(ComputePassDispatchInfo {
             x: image.width / 16 + 1,
             y: image.height / 16 + 1,
             z: 1,
-        }
+        })
     }
 }
 
@@ -49,8 +57,10 @@ impl <'a> ComputePassBuilder<'a> {
         ComputePassBuilder {
             dispatch_info: None,
             cs: None,
+            defines: Vec::new(),
             push_constant_builder: None,
             descriptors_builder: None,
+            access_declarations: Vec::new(),
         }
     }
 
@@ -66,6 +76,12 @@ impl <'a> ComputePassBuilder<'a> {
         self
     }
 
+    pub fn define(mut self, name: &str, value: &str) -> ComputePassBuilder<'a> {
+        self.defines.push((name.to_string(), value.to_string()));
+
+        self
+    }
+
     pub fn push_constant<T>(mut self) -> ComputePassBuilder<'a> {
         self.push_constant_builder = Some(PushConstantBuilder::new().stage(vk::ShaderStageFlags::COMPUTE).size(std::mem::size_of::<T>()));
 
@@ -78,54 +94,98 @@ impl <'a> ComputePassBuilder<'a> {
         self
     }
 
-    pub fn descriptors(mut self, create_refs: Vec<CreationReference>, data: &RendererData) -> ComputePassBuilder<'a> {
+    pub fn descriptors(mut self, create_refs: Vec<CreationReference>, data: &RendererData) -> Result<ComputePassBuilder<'a>, RendererError> {
         let mut descriptors_builder = DescriptorsBuilder::new()
             .stage(vk::ShaderStageFlags::COMPUTE)
             .count(data.count);
 
         for create_ref in create_refs {
             match create_ref {
-                CreationReference::Uniform(name) => { descriptors_builder = descriptors_builder.add_uniform_simple(data.get_buffers(&name)); },
-                CreationReference::Storage(name) => { descriptors_builder = descriptors_builder.add_storage_simple(data.get_buffers(&name)); },
-                CreationReference::Image(name) => { descriptors_builder = descriptors_builder.add_image_simple(data.get_images(&name)); },
-                CreationReference::Sampler(name) => { descriptors_builder = descriptors_builder.add_sampler_simple(data.get_images(&name)); },
+                CreationReference::Uniform(name) => { descriptors_builder = descriptors_builder.add_uniform_simple(data.get_buffers(&name)?); },
+                CreationReference::Storage(name) => { descriptors_builder = descriptors_builder.add_storage_simple(data.get_buffers(&name)?); },
+                CreationReference::Image(name) => { descriptors_builder = descriptors_builder.add_image_simple(data.get_images(&name)?); },
+                CreationReference::Sampler(name) => { descriptors_builder = descriptors_builder.add_sampler_simple(data.get_images(&name)?); },
             }
         }
 
         self.descriptors_builder = Some(descriptors_builder);
         
+        Ok(self)
+    }
+
+    pub fn reads(mut self, create_ref: CreationReference) -> ComputePassBuilder<'a> {
+        AccessDeclaration::declare(&mut self.access_declarations, create_ref, AccessIntent::Read);
+
         self
     }
 
-    pub unsafe fn build(self, c: &Core, d: &Device) -> ComputePass {
-        ComputePass::new(c, d, self.descriptors_builder, self.push_constant_builder, self.cs.expect("Error: Compute pass builder has no compute shader"), self.dispatch_info.expect("Error: Compute pass builder has no dispatch info"))
+    pub fn writes(mut self, create_ref: CreationReference) -> ComputePassBuilder<'a> {
+        AccessDeclaration::declare(&mut self.access_declarations, create_ref, AccessIntent::Write);
+
+        self
+    }
+
+    pub unsafe fn build(self, c: &Core, d: &Device) -> Result<ComputePass, RendererError> {
+        let cs = ShaderSource::new(self.cs.ok_or(RendererError::missing("ComputePassBuilder", "compute shader"))?, &self.defines);
+        let dispatch_info = self.dispatch_info.ok_or(RendererError::missing("ComputePassBuilder", "dispatch info"))?;
+
+        let mut pass = ComputePass::new(c, d, self.descriptors_builder, self.push_constant_builder, &cs, dispatch_info)?;
+        pass.access_declarations = self.access_declarations;
+
+        Ok(pass)
     }
 }
 
 impl ComputePass {
-    pub unsafe fn new(c: &Core, d: &Device, descriptors_builder: Option<DescriptorsBuilder>, push_constant_builder: Option<PushConstantBuilder>, cs: &str, dispatch_info: ComputePassDispatchInfo) -> ComputePass {
+    pub unsafe fn new(c: &Core, d: &Device, descriptors_builder: Option<DescriptorsBuilder>, push_constant_builder: Option<PushConstantBuilder>, cs: &ShaderSource, dispatch_info: ComputePassDispatchInfo) -> Result<ComputePass, RendererError> {
         let descriptors = match descriptors_builder {
-            Some(de_b) => Some(de_b.build(c, d)),
-            None => None
-        };
-
-        let descriptor_set_layout = match descriptors.as_ref() {
-            Some(de) => Some(de.set_layout),
+            Some(de_b) => Some(de_b.build(c, d)?),
             None => None
         };
 
         let push_constant = match push_constant_builder {
-            Some(builder) => Some(builder.build()),
+            Some(builder) => Some(builder.build()?),
             None => None
         };
         
-        let pipeline = ComputePipeline::new(c, d, descriptor_set_layout, push_constant.as_ref(), cs);
+        let pipeline = match ComputePipeline::new(c, d, descriptors.as_ref(), push_constant.as_ref(), cs) {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                if let Some(descriptors) = &descriptors {
+                    descriptors.destroy(d);
+                }
+
+                return Err(e);
+            },
+        };
 
-        ComputePass {
+        Ok(ComputePass {
             push_constant,
             descriptors,
             pipeline,
             dispatch_info,
+
+            access_declarations: Vec::new(),
+            accesses: Vec::new(),
+        })
+    }
+
+    /// # Safety
+    /// The pass mustn't be used by a frame still in flight.
+    pub unsafe fn destroy(&self, d: &Device) {
+        self.pipeline.destroy(d);
+
+        if let Some(descriptors) = &self.descriptors {
+            descriptors.destroy(d);
         }
     }
+
+    pub fn infer_accesses(&mut self, data: &RendererData) -> Result<(), RendererError> {
+        self.accesses = match &self.descriptors {
+            Some(descriptors) => ResourceAccess::from_descriptors(descriptors, ShaderType::Compute, data),
+            None => Vec::new(),
+        };
+
+        ResourceAccess::apply_declarations(&mut self.accesses, &self.access_declarations, ShaderType::Compute, data)
+    }
 }
\ No newline at end of file
@@ -1,28 +1,35 @@
 use std::ffi::CString;
+use std::path::PathBuf;
 
 use ash::vk;
 
 use crate::renderer::core::Core;
+use crate::renderer::descriptors::Descriptors;
 use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
 use crate::renderer::push_constant::PushConstant;
-use crate::renderer::shader::Shader;
+use crate::renderer::shader::{Shader, ShaderSource};
+use crate::renderer::shader_reflection::StageInterface;
 
 pub struct ComputePipeline {
     pub pipeline: vk::Pipeline,
     pub pipeline_layout: vk::PipelineLayout,
+
+    pub cs: ShaderSource,
+    pub dependencies: Vec<PathBuf>,
+
+    interface: StageInterface,
 }
 
 impl ComputePipeline {
-    pub unsafe fn new(c: &Core, d: &Device, descriptor_set_layout: Option<vk::DescriptorSetLayout>, push_constant: Option<&PushConstant>, cs: &str) -> ComputePipeline {
-        let comp_shader = Shader::new(d, cs, vk::ShaderStageFlags::COMPUTE);
-
-        let shader_entry_name = CString::new("main").unwrap();
+    pub unsafe fn new(c: &Core, d: &Device, descriptors: Option<&Descriptors>, push_constant: Option<&PushConstant>, cs: &ShaderSource) -> Result<ComputePipeline, RendererError> {
+        let interface = StageInterface {
+            descriptor_set: descriptors.map(|de| (0, de.descriptor_types())),
+            push_constant_size: push_constant.map(|pc| pc.size),
+            vertex_attributes: Vec::new(),
+        };
 
-        let shader_stage_ci = vk::PipelineShaderStageCreateInfo::builder()
-            .module(comp_shader.module)
-            .name(&shader_entry_name)
-            .stage(vk::ShaderStageFlags::COMPUTE)
-            .build();
+        let comp_shader = ComputePipeline::create_shader(d, cs, &interface)?;
 
         let push_constant_ranges = match push_constant {
             Some(pc) => {
@@ -35,9 +42,9 @@ impl ComputePipeline {
             None => vec![]
         };
 
-        let descriptor_set_layouts = match descriptor_set_layout {
-            Some(layout) => {
-                vec![layout]
+        let descriptor_set_layouts = match descriptors {
+            Some(de) => {
+                vec![de.set_layout]
             },
             None => vec![]
         };
@@ -47,18 +54,80 @@ impl ComputePipeline {
             .push_constant_ranges(&push_constant_ranges)
             .build();
 
-        let pipeline_layout = d.device.create_pipeline_layout(&pipeline_layout_ci, None).unwrap();
+        let pipeline_layout = d.device.create_pipeline_layout(&pipeline_layout_ci, None)?;
+
+        let pipeline = ComputePipeline::create_pipeline(d, pipeline_layout, &comp_shader);
+
+        comp_shader.destroy(d);
+
+        let pipeline = match pipeline {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                d.device.destroy_pipeline_layout(pipeline_layout, None);
+                return Err(e);
+            },
+        };
+
+        Ok(ComputePipeline {
+            pipeline,
+            pipeline_layout,
+
+            cs: cs.clone(),
+            dependencies: comp_shader.dependencies,
+
+            interface,
+        })
+    }
+
+    /// Recompiles the shader into a new pipeline with the same layout, the old pipeline is returned so it can be destroyed once no frame uses it
+    ///
+    /// # Safety
+    /// The returned pipeline must be kept alive until no frame in flight uses it.
+    pub unsafe fn reload(&mut self, d: &Device) -> Result<vk::Pipeline, RendererError> {
+        let comp_shader = ComputePipeline::create_shader(d, &self.cs, &self.interface)?;
+
+        let pipeline = ComputePipeline::create_pipeline(d, self.pipeline_layout, &comp_shader);
+
+        comp_shader.destroy(d);
+
+        let old_pipeline = std::mem::replace(&mut self.pipeline, pipeline?);
+        self.dependencies = comp_shader.dependencies;
+
+        Ok(old_pipeline)
+    }
+
+    unsafe fn create_shader(d: &Device, cs: &ShaderSource, interface: &StageInterface) -> Result<Shader, RendererError> {
+        let comp_shader = Shader::new(d, cs, vk::ShaderStageFlags::COMPUTE)?;
+
+        if let Err(e) = comp_shader.check(interface) {
+            comp_shader.destroy(d);
+            return Err(e);
+        }
+
+        Ok(comp_shader)
+    }
+
+    unsafe fn create_pipeline(d: &Device, pipeline_layout: vk::PipelineLayout, comp_shader: &Shader) -> Result<vk::Pipeline, RendererError> {
+        let shader_entry_name = CString::new("main").unwrap();
+
+        let shader_stage_ci = vk::PipelineShaderStageCreateInfo::builder()
+            .module(comp_shader.module)
+            .name(&shader_entry_name)
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .build();
 
         let pipeline_ci = vk::ComputePipelineCreateInfo::builder()
             .stage(shader_stage_ci)
             .layout(pipeline_layout)
             .build();
 
-        let pipeline = d.device.create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_ci], None).unwrap()[0];
+        Ok(d.device.create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_ci], None).map_err(|(_, e)| e)?[0])
+    }
 
-        ComputePipeline {
-            pipeline,
-            pipeline_layout,
-        }
+    /// # Safety
+    /// The pipeline mustn't be used by a frame still in flight.
+    pub unsafe fn destroy(&self, d: &Device) {
+        d.device.destroy_pipeline(self.pipeline, None);
+        d.device.destroy_pipeline_layout(self.pipeline_layout, None);
     }
-}
\ No newline at end of file
+}
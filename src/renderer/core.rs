@@ -1,6 +1,8 @@
 use ash::{vk, extensions::ext::DebugUtils};
 use raw_window_handle::RawDisplayHandle;
 
+use crate::renderer::error::RendererError;
+
 use std::borrow::Cow;
 use std::ffi::{CStr, CString};
 
@@ -45,7 +47,7 @@ pub struct Core {
 }
 
 impl Core {
-    pub unsafe fn new(validation_enabled: bool, display: RawDisplayHandle) -> Core {
+    pub unsafe fn new(validation_enabled: bool, display: Option<RawDisplayHandle>) -> Result<Core, RendererError> {
         //let entry = ash::Entry::new().unwrap();
         let entry = ash::Entry::linked();
 
@@ -54,13 +56,16 @@ impl Core {
         let layer_names = if validation_enabled { vec![CString::new("VK_LAYER_KHRONOS_validation").unwrap()] } else { vec![] } ;
         let layer_names_raw: Vec<*const i8> = layer_names.iter().map(|layer| layer.as_ptr()).collect();
 
-        let extension_names = ash_window::enumerate_required_extensions(display).unwrap();
-        let mut extension_names_raw: Vec<*const i8> = extension_names.iter().map(|extension| *extension).collect();
+        // Headless cores have no display, so no surface extensions are needed
+        let mut extension_names_raw: Vec<*const i8> = match display {
+            Some(display) => ash_window::enumerate_required_extensions(display)?.to_vec(),
+            None => vec![],
+        };
         
         extension_names_raw.push(DebugUtils::name().as_ptr());
 
         let app_i = vk::ApplicationInfo::builder()
-            .api_version(vk::make_version(1, 0, 0))
+            .api_version(vk::API_VERSION_1_2)
             .application_name(&name);
 
         let instance_ci = vk::InstanceCreateInfo::builder()
@@ -68,7 +73,7 @@ impl Core {
             .enabled_layer_names(&layer_names_raw)
             .enabled_extension_names(&extension_names_raw);
 
-        let instance = entry.create_instance(&instance_ci, None).unwrap();
+        let instance = entry.create_instance(&instance_ci, None)?;
 
         let debug_ci = vk::DebugUtilsMessengerCreateInfoEXT::builder()
             .message_severity(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING)
@@ -76,14 +81,21 @@ impl Core {
             .pfn_user_callback(Some(debug_callback_fn));
 
         let debug_utils_init = DebugUtils::new(&entry, &instance);
-        let debug_callback = debug_utils_init.create_debug_utils_messenger(&debug_ci, None).unwrap();
+        let debug_callback = debug_utils_init.create_debug_utils_messenger(&debug_ci, None)?;
 
-        Core {
+        Ok(Core {
             entry,
             instance,
 
             debug_utils_init,
             debug_callback,
-        }
+        })
+    }
+
+    /// # Safety
+    /// Everything created from the instance, including the device, must be destroyed first.
+    pub unsafe fn destroy(&self) {
+        self.debug_utils_init.destroy_debug_utils_messenger(self.debug_callback, None);
+        self.instance.destroy_instance(None);
     }
 }
\ No newline at end of file
@@ -0,0 +1,361 @@
+use ash::vk;
+
+use crate::math::{mat::Mat4, vec::{Vec3, Vec4}};
+use crate::renderer::Renderer;
+use crate::renderer::buffer::BufferBuilder;
+use crate::renderer::compute_pass::{ComputePassBuilder, ComputePassDispatchInfo};
+use crate::renderer::descriptors::CreationReference;
+use crate::renderer::error::RendererError;
+use crate::renderer::graphics_pass::{GraphicsPassBuilder, GraphicsPassDrawInfo};
+use crate::renderer::image::ImageBuilder;
+use crate::renderer::layer::LayerExecution;
+use crate::renderer::vertex_buffer::{NoVertices, VertexAttributes};
+use crate::util::graph::GraphError;
+
+// Names of the resources and passes the deferred renderer adds, so applications can sample the G-buffer or add passes around it
+pub const ALBEDO_IMAGES: &str = "deferred_albedo";
+pub const NORMAL_IMAGES: &str = "deferred_normal";
+pub const DEPTH_IMAGES: &str = "deferred_depth";
+pub const LIT_IMAGES: &str = "deferred_lit";
+pub const LIGHT_BUFFERS: &str = "deferred_lights";
+
+pub const GBUFFER_PASS: &str = "deferred_gbuffer";
+pub const LIGHTING_PASS: &str = "deferred_lighting";
+pub const COMPOSITE_PASS: &str = "deferred_composite";
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum LightType {
+    Point,
+    Directional,
+    Spot,
+}
+
+// Matches the Light struct in deferred_lighting.comp
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Light {
+    pub pos: Vec3,
+    pub light_type: u32,
+    pub dir: Vec3,
+    pub range: f32, // Distance at which point and spot lights fade out completely
+    pub col: Vec3,
+    pub intensity: f32,
+    pub inner_cos: f32,
+    pub outer_cos: f32,
+    _pad: [f32; 2],
+}
+
+#[repr(C)]
+pub struct GeometryPushConstant {
+    pub view_proj: Mat4,
+    pub model: Mat4,
+}
+
+#[repr(C)]
+pub struct LightingPushConstant {
+    pub inverse_view_proj: Mat4,
+    pub camera_pos: Vec4,
+    pub ambient: Vec4,
+    pub background: Vec4,
+    pub light_count: u32,
+}
+
+pub struct DeferredRendererBuilder<'a, T: VertexAttributes> {
+    verts: Option<&'a [T]>,
+    vertex_indices: Option<&'a [u32]>,
+    albedo_texture: Option<&'a str>,
+    extent: Option<vk::Extent2D>,
+    max_lights: usize,
+    ambient: Vec3,
+    background: Vec3,
+}
+
+pub struct DeferredRenderer {
+    pub layer: String,
+    pub max_lights: usize,
+
+    pub lights: Vec<Light>,
+    pub ambient: Vec3,
+    pub background: Vec3,
+
+    geometry_push_constant: GeometryPushConstant,
+    inverse_view_proj: Mat4,
+    camera_pos: Vec3,
+}
+
+impl LightType {
+    fn index(&self) -> u32 {
+        match self {
+            LightType::Point => 0,
+            LightType::Directional => 1,
+            LightType::Spot => 2,
+        }
+    }
+}
+
+impl Light {
+    pub fn point(pos: Vec3, col: Vec3, intensity: f32, range: f32) -> Light {
+        Light { pos, range, ..Light::new(LightType::Point, col, intensity) }
+    }
+
+    pub fn directional(dir: Vec3, col: Vec3, intensity: f32) -> Light {
+        Light { dir, ..Light::new(LightType::Directional, col, intensity) }
+    }
+
+    // Angles are in radians from the centre of the cone, light fades out between the inner and outer angle
+    pub fn spot(pos: Vec3, dir: Vec3, col: Vec3, intensity: f32, range: f32, inner_angle: f32, outer_angle: f32) -> Light {
+        Light { pos, dir, range, inner_cos: inner_angle.cos(), outer_cos: outer_angle.cos(), ..Light::new(LightType::Spot, col, intensity) }
+    }
+
+    // The fields a light type doesn't use are left zeroed
+    fn new(light_type: LightType, col: Vec3, intensity: f32) -> Light {
+        Light {
+            pos: Vec3::zero(),
+            light_type: light_type.index(),
+            dir: Vec3::zero(),
+            range: 0.0,
+            col,
+            intensity,
+            inner_cos: 0.0,
+            outer_cos: 0.0,
+            _pad: [0.0; 2],
+        }
+    }
+}
+
+impl <'a, T: VertexAttributes> Default for DeferredRendererBuilder<'a, T> {
+    fn default() -> DeferredRendererBuilder<'a, T> {
+        DeferredRendererBuilder::new()
+    }
+}
+
+impl <'a, T: VertexAttributes> DeferredRendererBuilder<'a, T> {
+    pub fn new() -> DeferredRendererBuilder<'a, T> {
+        DeferredRendererBuilder {
+            verts: None,
+            vertex_indices: None,
+            albedo_texture: None,
+            extent: None,
+            max_lights: 64,
+            ambient: Vec3::new(0.05, 0.05, 0.05),
+            background: Vec3::zero(),
+        }
+    }
+
+    // Vertices need a position, normal and texture coordinate at locations 0, 1 and 2, like ObjVertex and GltfVertex
+    pub fn verts(mut self, verts: &'a [T]) -> DeferredRendererBuilder<'a, T> {
+        self.verts = Some(verts);
+
+        self
+    }
+
+    pub fn vertex_indices(mut self, vertex_indices: &'a [u32]) -> DeferredRendererBuilder<'a, T> {
+        self.vertex_indices = Some(vertex_indices);
+
+        self
+    }
+
+    // Name of a texture in the renderer data, surfaces are white without one
+    pub fn albedo_texture(mut self, name: &'a str) -> DeferredRendererBuilder<'a, T> {
+        self.albedo_texture = Some(name);
+
+        self
+    }
+
+    // The G-buffer is the size of the surface by default, it isn't resized with the swapchain
+    pub fn extent(mut self, extent: vk::Extent2D) -> DeferredRendererBuilder<'a, T> {
+        self.extent = Some(extent);
+
+        self
+    }
+
+    pub fn max_lights(mut self, max_lights: usize) -> DeferredRendererBuilder<'a, T> {
+        self.max_lights = max_lights;
+
+        self
+    }
+
+    pub fn ambient(mut self, ambient: Vec3) -> DeferredRendererBuilder<'a, T> {
+        self.ambient = ambient;
+
+        self
+    }
+
+    pub fn background(mut self, background: Vec3) -> DeferredRendererBuilder<'a, T> {
+        self.background = background;
+
+        self
+    }
+
+    /// Creates the layer as a present layer and adds the G-buffer, lighting and composite passes to it
+    /// The composite pass has to be the layer's root, so the layer can't be one that already exists
+    /// On failure the passes, images and buffers added so far are removed again, but the layer stays once it has been added
+    ///
+    /// # Safety
+    /// Must not be called while a frame is being recorded.
+    pub unsafe fn build(self, r: &mut Renderer, layer_name: &str) -> Result<DeferredRenderer, RendererError> {
+        let verts = self.verts.ok_or(RendererError::missing("DeferredRendererBuilder", "verts"))?;
+
+        if r.get_layer(layer_name).is_ok() {
+            return Err(RendererError::Graph(GraphError::DuplicateNode(layer_name.to_string())));
+        }
+        let extent = self.extent.unwrap_or(r.device.surface_extent);
+
+        if let Err(e) = self.add_to(r, layer_name, verts, extent) {
+            for pass in [GBUFFER_PASS, LIGHTING_PASS, COMPOSITE_PASS] {
+                r.remove_pass(layer_name, pass).ok();
+            }
+
+            for images in [ALBEDO_IMAGES, NORMAL_IMAGES, DEPTH_IMAGES, LIT_IMAGES] {
+                r.remove_images(images).ok();
+            }
+            r.remove_buffers(LIGHT_BUFFERS).ok();
+
+            return Err(e);
+        }
+
+        Ok(DeferredRenderer {
+            layer: layer_name.to_string(),
+            max_lights: self.max_lights,
+
+            lights: Vec::new(),
+            ambient: self.ambient,
+            background: self.background,
+
+            geometry_push_constant: GeometryPushConstant { view_proj: Mat4::identity(), model: Mat4::identity() },
+            inverse_view_proj: Mat4::identity(),
+            camera_pos: Vec3::zero(),
+        })
+    }
+
+    unsafe fn add_to(&self, r: &mut Renderer, layer_name: &str, verts: &'a [T], extent: vk::Extent2D) -> Result<(), RendererError> {
+        let gbuffer_builder = ImageBuilder::new()
+            .width(extent.width)
+            .height(extent.height)
+            .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+            .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
+        r.add_images(ALBEDO_IMAGES, gbuffer_builder.clone().format(vk::Format::R8G8B8A8_UNORM))?;
+        r.add_images(NORMAL_IMAGES, gbuffer_builder.clone().format(vk::Format::R16G16B16A16_SFLOAT))?;
+        r.add_images(DEPTH_IMAGES, gbuffer_builder.format(vk::Format::R32_SFLOAT))?;
+
+        r.add_images(LIT_IMAGES, ImageBuilder::new()
+            .width(extent.width)
+            .height(extent.height)
+            .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+            .format(vk::Format::R16G16B16A16_SFLOAT)
+            .layout(vk::ImageLayout::GENERAL))?;
+
+        r.add_buffers(LIGHT_BUFFERS, BufferBuilder::new()
+            .size(self.max_lights.max(1) * std::mem::size_of::<Light>())
+            .usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .properties(vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT))?;
+
+        let draw_info = match self.vertex_indices {
+            Some(indices) => GraphicsPassDrawInfo::simple_indexed(verts.len(), indices.len()),
+            None => GraphicsPassDrawInfo::simple_vertex(verts.len()),
+        };
+
+        let mut gbuffer_pass_builder = GraphicsPassBuilder::new()
+            .vertex_shader("deferred_gbuffer.vert")
+            .fragment_shader("deferred_gbuffer.frag")
+            .draw_info(draw_info)
+            .render_targets(vec![ALBEDO_IMAGES, NORMAL_IMAGES, DEPTH_IMAGES], &r.data)?
+            .verts(verts)
+            .vertex_push_constant::<GeometryPushConstant>()
+            .clear_col(Vec4::zero())
+            .with_depth_buffer();
+
+        if let Some(indices) = self.vertex_indices {
+            gbuffer_pass_builder = gbuffer_pass_builder.vertex_indices(indices);
+        }
+
+        if let Some(texture) = self.albedo_texture {
+            gbuffer_pass_builder = gbuffer_pass_builder
+                .define("ALBEDO_TEXTURE", "")
+                .fragment_descriptors(vec![CreationReference::Sampler(texture.to_string())], &r.data)?;
+        }
+
+        let lighting_pass_creation_refs = vec![
+            CreationReference::Sampler(ALBEDO_IMAGES.to_string()),
+            CreationReference::Sampler(NORMAL_IMAGES.to_string()),
+            CreationReference::Sampler(DEPTH_IMAGES.to_string()),
+            CreationReference::Image(LIT_IMAGES.to_string()),
+            CreationReference::Storage(LIGHT_BUFFERS.to_string()),
+        ];
+
+        let lighting_pass_builder = ComputePassBuilder::new()
+            .compute_shader("deferred_lighting.comp")
+            .dispatch_info(ComputePassDispatchInfo::for_image(LIT_IMAGES, &r.data)?)
+            .push_constant::<LightingPushConstant>()
+            .descriptors(lighting_pass_creation_refs, &r.data)?
+            .reads(CreationReference::Storage(LIGHT_BUFFERS.to_string()))
+            .writes(CreationReference::Image(LIT_IMAGES.to_string()));
+
+        let composite_pass_builder = GraphicsPassBuilder::<NoVertices>::new()
+            .vertex_shader("draw_to_screen.vert")
+            .fragment_shader("deferred_composite.frag")
+            .draw_info(GraphicsPassDrawInfo::simple_vertex(6))
+            .targets(&r.swapchain.images)
+            .fragment_descriptors(vec![CreationReference::Sampler(LIT_IMAGES.to_string())], &r.data)?;
+
+        r.add_layer(layer_name, true, LayerExecution::Main)?;
+
+        r.add_graphics_pass(layer_name, GBUFFER_PASS, gbuffer_pass_builder)?;
+        r.add_compute_pass(layer_name, LIGHTING_PASS, lighting_pass_builder)?;
+        r.add_graphics_pass(layer_name, COMPOSITE_PASS, composite_pass_builder)?;
+
+        r.add_pass_dependency(layer_name, GBUFFER_PASS, LIGHTING_PASS, None)?;
+        r.add_pass_dependency(layer_name, LIGHTING_PASS, COMPOSITE_PASS, None)?;
+
+        r.get_layer_mut(layer_name)?.set_root_path(COMPOSITE_PASS);
+
+        Ok(())
+    }
+}
+
+impl DeferredRenderer {
+    // Takes the view projection matrix before it's transposed for the shaders, as built by Mat4::view(..) * Mat4::perspective(..)
+    pub fn set_camera(&mut self, view_proj: Mat4, pos: Vec3) {
+        self.geometry_push_constant.view_proj = view_proj.transpose();
+        self.camera_pos = pos;
+
+        // A singular matrix can't be unprojected, the last good inverse is kept rather than lighting from garbage positions
+        if let Some(inverse) = view_proj.inverse() {
+            self.inverse_view_proj = inverse.transpose();
+        }
+    }
+
+    pub fn set_model(&mut self, model: Mat4) {
+        self.geometry_push_constant.model = model.transpose();
+    }
+
+    // Lights past max_lights are ignored
+    pub fn set_lights(&mut self, lights: Vec<Light>) {
+        self.lights = lights;
+        self.lights.truncate(self.max_lights);
+    }
+
+    /// Writes the lights and push constants for the current frame, so it has to be called between pre_draw and draw
+    ///
+    /// # Safety
+    /// Must be called before the frame is drawn, the light buffers of the frame being recorded are written.
+    pub unsafe fn update(&self, r: &mut Renderer) -> Result<(), RendererError> {
+        r.fill_buffer(LIGHT_BUFFERS, &self.lights)?;
+
+        let lighting_push_constant = LightingPushConstant {
+            inverse_view_proj: self.inverse_view_proj,
+            camera_pos: Vec4::from_vec3(self.camera_pos),
+            ambient: Vec4::from_vec3(self.ambient),
+            background: Vec4::from_vec3(self.background),
+            light_count: self.lights.len() as u32,
+        };
+
+        let layer = r.get_layer_mut(&self.layer)?;
+        layer.fill_vertex_push_constant(GBUFFER_PASS, &self.geometry_push_constant)?;
+        layer.fill_compute_push_constant(LIGHTING_PASS, &lighting_push_constant)?;
+
+        Ok(())
+    }
+}
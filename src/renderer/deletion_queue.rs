@@ -0,0 +1,82 @@
+use ash::vk;
+
+use crate::renderer::device::Device;
+use crate::renderer::buffer::Buffer;
+use crate::renderer::image::Image;
+use crate::renderer::compute_pass::ComputePass;
+use crate::renderer::graphics_pass::GraphicsPass;
+use crate::renderer::vertex_buffer::VertexBuffer;
+
+// Anything that can be removed while frames using it may still be in flight, meshes and passes are boxed as they're far bigger than the rest
+pub enum Retired {
+    Buffer(Buffer),
+    Image(Image),
+    Mesh(Box<VertexBuffer>),
+    ComputePass(Box<ComputePass>),
+    GraphicsPass(Box<GraphicsPass>),
+    Pipeline(vk::Pipeline), // Replaced by a shader reload, its layout stays with the new pipeline
+}
+
+pub struct DeletionQueue {
+    pub frames_in_flight: usize,
+
+    pending: Vec<PendingDeletion>,
+}
+
+type PendingDeletion = (usize, Retired); // .0 is how many more frame fences have to be waited on before .1 can be destroyed
+
+impl Retired {
+    /// # Safety
+    /// The GPU must have finished with the resource.
+    pub unsafe fn destroy(self, d: &Device) {
+        match self {
+            Retired::Buffer(buffer) => buffer.destroy(d),
+            Retired::Image(image) => image.destroy(d),
+            Retired::Mesh(vertex_buffer) => vertex_buffer.destroy(d),
+            Retired::ComputePass(pass) => pass.destroy(d),
+            Retired::GraphicsPass(pass) => pass.destroy(d),
+            Retired::Pipeline(pipeline) => d.device.destroy_pipeline(pipeline, None),
+        }
+    }
+}
+
+impl DeletionQueue {
+    pub fn new(frames_in_flight: usize) -> DeletionQueue {
+        DeletionQueue {
+            frames_in_flight,
+
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn retire(&mut self, resource: Retired) {
+        self.pending.push((self.frames_in_flight, resource));
+    }
+
+    /// Called each time a frame's fence has been waited on, once every frame in flight has finished since a resource was retired nothing can still be using it
+    ///
+    /// # Safety
+    /// The current frame's fence must have been waited on.
+    pub unsafe fn advance(&mut self, d: &Device) {
+        for (frames, _) in &mut self.pending {
+            *frames -= 1;
+        }
+
+        let (finished, pending): (Vec<PendingDeletion>, Vec<PendingDeletion>) = self.pending.drain(..).partition(|(frames, _)| *frames == 0);
+        self.pending = pending;
+
+        for (_, resource) in finished {
+            resource.destroy(d);
+        }
+    }
+
+    /// The device has to be idle before calling this
+    ///
+    /// # Safety
+    /// The device must be idle.
+    pub unsafe fn flush(&mut self, d: &Device) {
+        for (_, resource) in self.pending.drain(..) {
+            resource.destroy(d);
+        }
+    }
+}
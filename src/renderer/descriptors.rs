@@ -7,6 +7,7 @@ use ash::vk;
 
 use crate::renderer::{core::Core, buffer::Buffer, image::Image};
 use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
 use crate::renderer::descriptors::uniform_descriptor::UniformDescriptorBuilder;
 use crate::renderer::descriptors::storage_descriptor::StorageDescriptorBuilder;
 use crate::renderer::descriptors::image_descriptor::ImageDescriptorBuilder;
@@ -28,7 +29,7 @@ pub enum BindingReference {
     Sampler(usize),
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum CreationReference {
     Uniform(String),
     Storage(String),
@@ -67,6 +68,8 @@ pub struct Descriptors {
 
     pub binding_references: Vec<BindingReference>,
     pub desciptor_references: Vec<DescriptorReference>,
+
+    pub dynamic_offsets: Vec<u32>, // One for each dynamic uniform, in binding order
 }
 
 impl DescriptorReference {
@@ -157,22 +160,25 @@ impl  DescriptorsBuilder {
         self.add_sampler_builder(SamplerDescriptorBuilder::new().images(images))
     }
 
-    pub unsafe fn build(self, c: &Core, d: &Device) -> Descriptors {
+    pub unsafe fn build(self, c: &Core, d: &Device) -> Result<Descriptors, RendererError> {
         Descriptors::new(c, d, self)
     }
 }
 
 impl Descriptors {
-    pub unsafe fn new(c: &Core, d: &Device, builder: DescriptorsBuilder) -> Descriptors {
+    pub unsafe fn new(c: &Core, d: &Device, builder: DescriptorsBuilder) -> Result<Descriptors, RendererError> {
+        let stage = builder.stage.ok_or(RendererError::missing("DescriptorsBuilder", "stage"))?;
+        let count = builder.count.ok_or(RendererError::missing("DescriptorsBuilder", "count"))?;
+
         let mut layout_bindings = Vec::<vk::DescriptorSetLayoutBinding>::new();
 
         for descriptor_builder in &builder.uniform_builders {
             layout_bindings.push(
                 vk::DescriptorSetLayoutBinding::builder()
                     .binding(descriptor_builder.0)
-                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                    .descriptor_type(descriptor_builder.1.descriptor_type())
                     .descriptor_count(1)
-                    .stage_flags(builder.stage.expect("Error: descriptors builder has no stage flags"))
+                    .stage_flags(stage)
                     .build()
             )
         }
@@ -183,7 +189,7 @@ impl Descriptors {
                     .binding(descriptor_builder.0)
                     .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
                     .descriptor_count(1)
-                    .stage_flags(builder.stage.expect("Error: descriptors builder has no stage flags"))
+                    .stage_flags(stage)
                     .build()
             )
         }
@@ -194,7 +200,7 @@ impl Descriptors {
                     .binding(descriptor_builder.0)
                     .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
                     .descriptor_count(1)
-                    .stage_flags(builder.stage.expect("Error: descriptors builder has no stage flags"))
+                    .stage_flags(stage)
                     .build()
             )
         }
@@ -205,7 +211,7 @@ impl Descriptors {
                     .binding(descriptor_builder.0)
                     .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
                     .descriptor_count(1)
-                    .stage_flags(builder.stage.expect("Error: descriptors builder has no stage flags"))
+                    .stage_flags(stage)
                     .build()
             )
         }
@@ -213,30 +219,34 @@ impl Descriptors {
         let set_layout_ci = vk::DescriptorSetLayoutCreateInfo::builder()
             .bindings(&layout_bindings);
 
-        let set_layout = d.device.create_descriptor_set_layout(&set_layout_ci, None).unwrap();
+        let set_layout = d.device.create_descriptor_set_layout(&set_layout_ci, None)?;
         let mut set_layouts = Vec::<vk::DescriptorSetLayout>::new();
 
-        for _ in 0..builder.count.expect("Error: descriptors builder has no count") {
+        for _ in 0..count {
             set_layouts.push(set_layout);
         }
 
         let temp_constant: usize = 8;
         let mut pool_sizes = Vec::<vk::DescriptorPoolSize>::new();
 
-        if builder.uniform_builders.len() > 0 {
-            pool_sizes.push(
-                vk::DescriptorPoolSize::builder()
-                    .ty(vk::DescriptorType::UNIFORM_BUFFER)
-                    .descriptor_count((builder.uniform_builders.len() * builder.count.expect("Error: descriptors builder has no count") * temp_constant) as u32)
-                    .build()
-            );
+        for descriptor_type in [vk::DescriptorType::UNIFORM_BUFFER, vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC] {
+            let uniform_count = builder.uniform_builders.iter().filter(|b| b.1.descriptor_type() == descriptor_type).count();
+
+            if uniform_count > 0 {
+                pool_sizes.push(
+                    vk::DescriptorPoolSize::builder()
+                        .ty(descriptor_type)
+                        .descriptor_count((uniform_count * count * temp_constant) as u32)
+                        .build()
+                );
+            }
         }
 
         if builder.storage_builders.len() > 0 {
             pool_sizes.push(
                 vk::DescriptorPoolSize::builder()
                     .ty(vk::DescriptorType::STORAGE_BUFFER)
-                    .descriptor_count((builder.storage_builders.len() * builder.count.expect("Error: descriptors builder has no count") * temp_constant) as u32)
+                    .descriptor_count((builder.storage_builders.len() * count * temp_constant) as u32)
                     .build()
             );
         }
@@ -245,7 +255,7 @@ impl Descriptors {
             pool_sizes.push(
                 vk::DescriptorPoolSize::builder()
                     .ty(vk::DescriptorType::STORAGE_IMAGE)
-                    .descriptor_count((builder.image_builders.len() * builder.count.expect("Error: descriptors builder has no count") * temp_constant) as u32)
+                    .descriptor_count((builder.image_builders.len() * count * temp_constant) as u32)
                     .build()
             );
         }
@@ -254,7 +264,7 @@ impl Descriptors {
             pool_sizes.push(
                 vk::DescriptorPoolSize::builder()
                     .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                    .descriptor_count((builder.sampler_builders.len() * builder.count.expect("Error: descriptors builder has no count") * temp_constant) as u32)
+                    .descriptor_count((builder.sampler_builders.len() * count * temp_constant) as u32)
                     .build()
             );
         }
@@ -263,13 +273,13 @@ impl Descriptors {
             .pool_sizes(&pool_sizes)
             .max_sets(256);
 
-        let pool = d.device.create_descriptor_pool(&pool_ci, None).unwrap();
+        let pool = d.device.create_descriptor_pool(&pool_ci, None)?;
 
         let set_ai = vk::DescriptorSetAllocateInfo::builder()
             .descriptor_pool(pool)
             .set_layouts(&set_layouts);
 
-        let sets = d.device.allocate_descriptor_sets(&set_ai).unwrap();
+        let sets = d.device.allocate_descriptor_sets(&set_ai)?;
 
         let uniforms = Vec::<uniform_descriptor::UniformDescriptor>::new();
         let ssbos = Vec::<storage_descriptor::StorageDescriptor>::new();
@@ -288,28 +298,90 @@ impl Descriptors {
 
             binding_references: builder.binding_references.clone(),
             desciptor_references: builder.desciptor_references.clone(),
+
+            dynamic_offsets: Vec::new(),
         };
 
         for descriptor_builder in &builder.uniform_builders {
-            descriptors.uniforms.push(descriptor_builder.1.build(c, d, descriptor_builder.0, &descriptors.sets));
+            descriptors.uniforms.push(descriptor_builder.1.build(c, d, descriptor_builder.0, &descriptors.sets)?);
         }
 
+        descriptors.dynamic_offsets = vec![0; descriptors.uniforms.iter().filter(|u| u.dynamic_range.is_some()).count()];
+
         for descriptor_builder in &builder.storage_builders {
-            descriptors.ssbos.push(descriptor_builder.1.build(c, d, descriptor_builder.0, &descriptors.sets));
+            descriptors.ssbos.push(descriptor_builder.1.build(c, d, descriptor_builder.0, &descriptors.sets)?);
         }
 
         for descriptor_builder in &builder.image_builders {
-            descriptors.images.push(descriptor_builder.1.build(c, d, descriptor_builder.0, &descriptors.sets));
+            descriptors.images.push(descriptor_builder.1.build(c, d, descriptor_builder.0, &descriptors.sets)?);
         }
 
         for descriptor_builder in &builder.sampler_builders {
-            descriptors.samplers.push(descriptor_builder.1.build(c, d, descriptor_builder.0, &descriptors.sets));
+            descriptors.samplers.push(descriptor_builder.1.build(c, d, descriptor_builder.0, &descriptors.sets)?);
         }
 
-        descriptors
+        Ok(descriptors)
+    }
+
+    /// Sets are freed along with the pool
+    ///
+    /// # Safety
+    /// The sets mustn't be used by a frame still in flight.
+    pub unsafe fn destroy(&self, d: &Device) {
+        for sampler in self.samplers.iter().flat_map(|s| s.samplers.iter()) {
+            sampler.destroy(d);
+        }
+
+        d.device.destroy_descriptor_pool(self.pool, None);
+        d.device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+
+    // Descriptor types in binding order, for checking against what a shader declares. Dynamic uniforms look the same as any other to a shader
+    pub fn descriptor_types(&self) -> Vec<vk::DescriptorType> {
+        self.binding_references.iter().map(|binding_reference| match binding_reference {
+            BindingReference::Uniform(_) => vk::DescriptorType::UNIFORM_BUFFER,
+            BindingReference::Storage(_) => vk::DescriptorType::STORAGE_BUFFER,
+            BindingReference::Image(_) => vk::DescriptorType::STORAGE_IMAGE,
+            BindingReference::Sampler(_) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        }).collect()
     }
 
-    pub unsafe fn bind(&self, d: &Device, b: &vk::CommandBuffer, bp: vk::PipelineBindPoint, pl: &vk::PipelineLayout, i: usize) {
-        d.device.cmd_bind_descriptor_sets(*b, bp, *pl, 0, &[self.sets[i]], &[]);
+    pub unsafe fn bind(&self, d: &Device, b: &vk::CommandBuffer, bp: vk::PipelineBindPoint, pl: &vk::PipelineLayout, set: u32, i: usize) {
+        self.bind_with_offsets(d, b, bp, pl, set, i, &self.dynamic_offsets);
+    }
+
+    /// Lets a single draw use different slices of the dynamic uniforms without changing the offsets the pass binds with
+    ///
+    /// # Safety
+    /// `b` must be recording and `offsets` must have an entry for each dynamic uniform in the set.
+    pub unsafe fn bind_with_offsets(&self, d: &Device, b: &vk::CommandBuffer, bp: vk::PipelineBindPoint, pl: &vk::PipelineLayout, set: u32, i: usize, dynamic_offsets: &[u32]) {
+        d.device.cmd_bind_descriptor_sets(*b, bp, *pl, set, &[self.sets[i]], dynamic_offsets);
+    }
+
+    pub fn set_dynamic_offset(&mut self, d: &Device, binding: u32, offset: u32) -> Result<(), RendererError> {
+        let index = self.dynamic_offset_index(d, binding, offset)?;
+        self.dynamic_offsets[index] = offset;
+
+        Ok(())
+    }
+
+    // Offsets have to be a multiple of the device's minUniformBufferOffsetAlignment, and leave the whole range inside the buffer.
+    // Returns where the offset goes in the dynamic offsets passed when binding
+    pub fn dynamic_offset_index(&self, d: &Device, binding: u32, offset: u32) -> Result<usize, RendererError> {
+        let mut dynamic_uniforms = self.uniforms.iter().filter(|u| u.dynamic_range.is_some()).collect::<Vec<_>>();
+        dynamic_uniforms.sort_by_key(|u| u.binding);
+
+        let index = dynamic_uniforms.iter().position(|u| u.binding == binding).ok_or(RendererError::InvalidPassConfig("Binding isn't a dynamic uniform"))?;
+        let uniform = dynamic_uniforms[index];
+
+        if offset as u64 % d.limits.min_uniform_buffer_offset_alignment != 0 {
+            return Err(RendererError::InvalidPassConfig("Dynamic offset isn't aligned to minUniformBufferOffsetAlignment"));
+        }
+
+        if uniform.data.iter().any(|b| offset as u64 + uniform.dynamic_range.unwrap() > b.size) {
+            return Err(RendererError::InvalidPassConfig("Dynamic offset puts the uniform's range past the end of its buffer"));
+        }
+
+        Ok(index)
     }
 }
\ No newline at end of file
@@ -4,6 +4,7 @@ use crate::renderer::{core::Core, layer::LayerExecution};
 use crate::renderer::device::Device;
 use crate::renderer::image::Image;
 use crate::renderer::commands::Commands;
+use crate::renderer::error::RendererError;
 
 #[derive(Copy, Clone)]
 pub struct ImageData {
@@ -33,13 +34,13 @@ impl  ImageDescriptorBuilder {
         }
     }
 
-    pub unsafe fn build(&self, c: &Core, d: &Device, binding: u32, sets: &Vec<vk::DescriptorSet>) -> ImageDescriptor {
-        ImageDescriptor::new(c, d, binding, self.image_datas.as_ref().expect("Error: Image descriptor builder has no images"), sets)
+    pub unsafe fn build(&self, c: &Core, d: &Device, binding: u32, sets: &Vec<vk::DescriptorSet>) -> Result<ImageDescriptor, RendererError> {
+        ImageDescriptor::new(c, d, binding, self.image_datas.as_ref().ok_or(RendererError::missing("ImageDescriptorBuilder", "images"))?, sets)
     }
 }
 
 impl ImageDescriptor {
-    unsafe fn new(c: &Core, d: &Device, binding: u32, images: &Vec<ImageData>, sets: &Vec<vk::DescriptorSet>) -> ImageDescriptor {
+    unsafe fn new(c: &Core, d: &Device, binding: u32, images: &Vec<ImageData>, sets: &Vec<vk::DescriptorSet>) -> Result<ImageDescriptor, RendererError> {
         let mut write_sets = Vec::<vk::WriteDescriptorSet>::new();
 
         for i in 0..images.len() {
@@ -60,7 +61,7 @@ impl ImageDescriptor {
 
         d.device.update_descriptor_sets(&write_sets, &[]);
 
-        let layout_transition_buffer = Commands::new(d, d.get_queue(LayerExecution::Main).1, images.len(), false);
+        let layout_transition_buffer = Commands::new(d, d.get_queue(LayerExecution::Main).1, images.len(), false)?;
 
         layout_transition_buffer.record_all(d, |i, b| {
             let subresource_range = vk::ImageSubresourceRange::builder()
@@ -79,17 +80,19 @@ impl ImageDescriptor {
                 .build();
 
             d.device.cmd_pipeline_barrier(b, vk::PipelineStageFlags::TOP_OF_PIPE, vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(), &[], &[], &[barrier]);
-        });
+        })?;
 
         let submit_is = [vk::SubmitInfo::builder()
             .command_buffers(&layout_transition_buffer.buffers)
             .build()];
 
-        d.device.queue_submit(d.get_queue(LayerExecution::Main).0, &submit_is, vk::Fence::null()).unwrap();
-        d.device.queue_wait_idle(d.get_queue(LayerExecution::Main).0).unwrap();
+        d.device.queue_submit(d.get_queue(LayerExecution::Main).0, &submit_is, vk::Fence::null())?;
+        d.device.queue_wait_idle(d.get_queue(LayerExecution::Main).0)?;
 
-        ImageDescriptor {
+        layout_transition_buffer.destroy(d);
+
+        Ok(ImageDescriptor {
             data: images.clone(),
-        }
+        })
     }
 }
\ No newline at end of file
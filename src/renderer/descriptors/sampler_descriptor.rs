@@ -4,11 +4,12 @@ use crate::renderer::core::Core;
 use crate::renderer::device::Device;
 use crate::renderer::image::Image;
 use crate::renderer::sampler::Sampler;
+use crate::renderer::error::RendererError;
 
 #[derive(Copy, Clone)]
 pub struct ImageData {
-    image: vk::Image,
-    view: vk::ImageView,
+    pub image: vk::Image,
+    pub view: vk::ImageView,
 }
 
 pub struct SamplerDescriptor {
@@ -34,18 +35,16 @@ impl SamplerDescriptorBuilder {
         }
     }
 
-    pub unsafe fn build(&self, c: &Core, d: &Device, binding: u32, sets: &Vec<vk::DescriptorSet>) -> SamplerDescriptor {
-        if self.image_datas.is_none() {
-            panic!("Error: Sampler descriptor builder has no images");
-        }
+    pub unsafe fn build(&self, c: &Core, d: &Device, binding: u32, sets: &Vec<vk::DescriptorSet>) -> Result<SamplerDescriptor, RendererError> {
+        let image_datas = self.image_datas.as_ref().ok_or(RendererError::missing("SamplerDescriptorBuilder", "images"))?;
         
         let mut samplers = Vec::<Sampler>::new();
 
-        for image_data in self.image_datas.as_ref().unwrap() {
-            samplers.push(Sampler::new(c, d, image_data.view));
+        for image_data in image_datas {
+            samplers.push(Sampler::new(c, d, image_data.view)?);
         }
 
-        SamplerDescriptor::new(c, d, binding, self.image_datas.as_ref().unwrap(), &samplers, sets)
+        Ok(SamplerDescriptor::new(c, d, binding, image_datas, &samplers, sets))
     }
 }
 
@@ -57,7 +56,7 @@ impl SamplerDescriptor {
             let image_is = [vk::DescriptorImageInfo::builder()
                 .sampler(samplers[i as usize].sampler)
                 .image_view(samplers[i as usize].view)
-                .image_layout(vk::ImageLayout::GENERAL)
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
                 .build()];
 
             let write_set = vk::WriteDescriptorSet::builder()
@@ -2,10 +2,11 @@ use ash::vk;
 
 use crate::renderer::core::Core;
 use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
 use crate::renderer::buffer::Buffer;
 
-#[derive(Copy, Clone)]
-struct BufferData {
+#[derive(Copy, Clone, Debug)]
+pub struct BufferData {
     pub buffer: vk::Buffer,
     pub size: u64,
 }
@@ -15,7 +16,9 @@ pub struct StorageDescriptorBuilder {
 }
 
 #[derive(Debug)]
-pub struct StorageDescriptor {}
+pub struct StorageDescriptor {
+    pub data: Vec<BufferData>,
+}
 
 impl StorageDescriptorBuilder {
     pub fn new() -> StorageDescriptorBuilder {
@@ -31,8 +34,8 @@ impl StorageDescriptorBuilder {
         }
     }
 
-    pub unsafe fn build(&self, c: &Core, d: &Device, binding: u32, sets: &Vec<vk::DescriptorSet>) -> StorageDescriptor {
-        StorageDescriptor::new(d, binding, self.buffer_datas.as_ref().expect("Error: Storage descriptor builder has no buffers"), sets)
+    pub unsafe fn build(&self, c: &Core, d: &Device, binding: u32, sets: &Vec<vk::DescriptorSet>) -> Result<StorageDescriptor, RendererError> {
+        Ok(StorageDescriptor::new(d, binding, self.buffer_datas.as_ref().ok_or(RendererError::missing("StorageDescriptorBuilder", "buffers"))?, sets))
     }
 }
 
@@ -58,6 +61,8 @@ impl StorageDescriptor {
 
         d.device.update_descriptor_sets(&write_sets, &[]);
 
-        StorageDescriptor {}
+        StorageDescriptor {
+            data: buffers.clone(),
+        }
     }
 }
\ No newline at end of file
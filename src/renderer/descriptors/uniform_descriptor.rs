@@ -2,24 +2,31 @@ use ash::vk;
 
 use crate::renderer::core::Core;
 use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
 use crate::renderer::buffer::Buffer;
 
 #[derive(Copy, Clone)]
-struct BufferData {
+pub struct BufferData {
     pub buffer: vk::Buffer,
     pub size: u64,
 }
 
 pub struct UniformDescriptorBuilder {
     buffer_datas: Option<Vec<BufferData>>,
+    dynamic_range: Option<u64>,
 }
 
-pub struct UniformDescriptor {}
+pub struct UniformDescriptor {
+    pub data: Vec<BufferData>,
+    pub binding: u32,
+    pub dynamic_range: Option<u64>,
+}
 
 impl UniformDescriptorBuilder {
     pub fn new() -> UniformDescriptorBuilder {
         UniformDescriptorBuilder {
             buffer_datas: None,
+            dynamic_range: None,
         }
     }
 
@@ -27,26 +34,55 @@ impl UniformDescriptorBuilder {
         let buffer_datas = buffers.iter().map(|buffer| { BufferData { buffer: buffer.buffer, size: buffer.size} }).collect();
         UniformDescriptorBuilder {
             buffer_datas: Some(buffer_datas),
+            dynamic_range: self.dynamic_range,
         }
     }
 
-    pub unsafe fn build(&self, c: &Core, d: &Device, binding: u32, sets: &Vec<vk::DescriptorSet>) -> UniformDescriptor {
-        UniformDescriptor::new(d, binding, self.buffer_datas.as_ref().expect("Error: Uniform descriptor builder has no buffers"), sets)
+    // Only range bytes are visible to the shader, starting from an offset given when the descriptors are bound, so many objects can share one buffer
+    pub fn dynamic(&self, range: usize) -> UniformDescriptorBuilder {
+        UniformDescriptorBuilder {
+            buffer_datas: self.buffer_datas.clone(),
+            dynamic_range: Some(range as u64),
+        }
+    }
+
+    pub fn descriptor_type(&self) -> vk::DescriptorType {
+        match self.dynamic_range {
+            Some(_) => vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+            None => vk::DescriptorType::UNIFORM_BUFFER,
+        }
+    }
+
+    pub unsafe fn build(&self, c: &Core, d: &Device, binding: u32, sets: &Vec<vk::DescriptorSet>) -> Result<UniformDescriptor, RendererError> {
+        let buffers = self.buffer_datas.as_ref().ok_or(RendererError::missing("UniformDescriptorBuilder", "buffers"))?;
+
+        if let Some(range) = self.dynamic_range {
+            if buffers.iter().any(|b| b.size < range) {
+                return Err(RendererError::InvalidPassConfig("Dynamic uniform range is larger than its buffer"));
+            }
+        }
+
+        Ok(UniformDescriptor::new(d, binding, buffers, self.dynamic_range, sets))
     }
 }
 
 impl UniformDescriptor {
-    unsafe fn new(d: &Device, binding: u32, buffers: &Vec<BufferData>, sets: &Vec<vk::DescriptorSet>) -> UniformDescriptor {
+    unsafe fn new(d: &Device, binding: u32, buffers: &Vec<BufferData>, dynamic_range: Option<u64>, sets: &Vec<vk::DescriptorSet>) -> UniformDescriptor {
+        let descriptor_type = match dynamic_range {
+            Some(_) => vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+            None => vk::DescriptorType::UNIFORM_BUFFER,
+        };
+
         let mut write_sets = Vec::<vk::WriteDescriptorSet>::new();
 
         for i in 0..buffers.len() {
             let buffer_is = [vk::DescriptorBufferInfo::builder()
                 .buffer(buffers[i].buffer)
-                .range(buffers[i].size as u64)
+                .range(dynamic_range.unwrap_or(buffers[i].size))
                 .build()];
 
             let write_set = vk::WriteDescriptorSet::builder()
-                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_type(descriptor_type)
                 .dst_binding(binding)
                 .dst_set(sets[i])
                 .buffer_info(&buffer_is)
@@ -57,6 +93,10 @@ impl UniformDescriptor {
 
         d.device.update_descriptor_sets(&write_sets, &[]);
 
-        UniformDescriptor {}
+        UniformDescriptor {
+            data: buffers.clone(),
+            binding,
+            dynamic_range,
+        }
     }
 }
\ No newline at end of file
@@ -1,7 +1,9 @@
+use std::cell::RefCell;
+
 use ash::vk;
 use raw_window_handle::{RawWindowHandle, RawDisplayHandle};
 
-use crate::renderer::{core::Core, layer::LayerExecution};
+use crate::renderer::{core::Core, layer::LayerExecution, allocator::{Allocation, Allocator, AllocatorStats}, error::RendererError};
 
 pub struct Device {
     pub device: ash::Device,
@@ -19,45 +21,204 @@ pub struct Device {
     pub queue_present: (vk::Queue, u32),
     pub queue_main: (vk::Queue, u32),
     pub queue_async: (vk::Queue, u32),
+    pub queue_transfer: (vk::Queue, u32), // A transfer only family when there is one, otherwise the main queue
+    pub queue_families: Vec<vk::QueueFamilyProperties>,
+
+    pub allocator: RefCell<Allocator>,
+
+    pub features: vk::PhysicalDeviceFeatures, // Only what was enabled on the logical device
+    pub limits: vk::PhysicalDeviceLimits,
 }
 
 impl Device {
-    pub unsafe fn new(c: &Core, window: RawWindowHandle, display: RawDisplayHandle) -> Device {
+    pub unsafe fn new(c: &Core, window: RawWindowHandle, display: RawDisplayHandle, extent: vk::Extent2D) -> Result<Device, RendererError> {
+        let surface_init = ash::extensions::khr::Surface::new(&c.entry, &c.instance);
+        let surface = ash_window::create_surface(&c.entry, &c.instance, display, window, None)?;
+
+        // Failing to query support is treated the same as not supporting the surface
+        let (physical_device, queue_index_present, queue_index_main, queue_index_async, queue_index_transfer) = Device::pick_physical_device(c, |pd, i, _| {
+            surface_init.get_physical_device_surface_support(pd, i, surface).unwrap_or(false)
+        })?;
+
+        let extension_names = vec![ash::extensions::khr::Swapchain::name().as_ptr()];
+
+        let (device, features) = Device::create_logical_device(c, physical_device, vec![queue_index_present, queue_index_main, queue_index_async, queue_index_transfer], &extension_names)?;
+
+        let queue_present = (device.get_device_queue(queue_index_present, 0), queue_index_present);
+        let queue_main = (device.get_device_queue(queue_index_main, 0), queue_index_main);
+        let queue_async = (device.get_device_queue(queue_index_async, 0), queue_index_async);
+        let queue_transfer = (device.get_device_queue(queue_index_transfer, 0), queue_index_transfer);
+
+        let available_surface_formats = surface_init.get_physical_device_surface_formats(physical_device, surface)?;
+        let surface_format = available_surface_formats.iter().filter(|format| {
+            format.format == vk::Format::B8G8R8A8_SRGB && format.color_space == vk::ColorSpaceKHR::EXTENDED_SRGB_NONLINEAR_EXT
+        }).next().unwrap_or(&available_surface_formats[0]);
+
+        let mut device = Device {
+            device,
+
+            surface_init,
+            surface,
+            surface_format: *surface_format,
+            surface_capabilities: vk::SurfaceCapabilitiesKHR::default(),
+            surface_extent: extent,
+
+            extension_names,
+
+            physical_device,
+
+            queue_present,
+            queue_main,
+            queue_async,
+            queue_transfer,
+            queue_families: c.instance.get_physical_device_queue_family_properties(physical_device),
+
+            allocator: RefCell::new(Allocator::new(c, physical_device)),
+
+            features,
+            limits: c.instance.get_physical_device_properties(physical_device).limits,
+        };
+
+        device.refresh_surface(extent)?;
+
+        Ok(device)
+    }
+
+    /// Re-queries the surface after it has changed size, extent is only used when the surface doesn't dictate its own
+    ///
+    /// # Safety
+    /// Nothing may be using the swapchain, as its extent is recreated from the result.
+    pub unsafe fn refresh_surface(&mut self, extent: vk::Extent2D) -> Result<(), RendererError> {
+        self.surface_capabilities = self.surface_init.get_physical_device_surface_capabilities(self.physical_device, self.surface)?;
+
+        self.surface_extent = if self.surface_capabilities.current_extent.width == std::u32::MAX {
+            vk::Extent2D {
+                width: extent.width.clamp(self.surface_capabilities.min_image_extent.width, self.surface_capabilities.max_image_extent.width),
+                height: extent.height.clamp(self.surface_capabilities.min_image_extent.height, self.surface_capabilities.max_image_extent.height),
+            }
+        } else {
+            self.surface_capabilities.current_extent
+        };
+
+        Ok(())
+    }
+
+    /// Creates a device with no surface, the present queue is the main queue and the surface format/extent describe the offscreen targets
+    ///
+    /// # Safety
+    /// The device must be destroyed before `c`.
+    pub unsafe fn new_headless(c: &Core, extent: vk::Extent2D) -> Result<Device, RendererError> {
         let surface_init = ash::extensions::khr::Surface::new(&c.entry, &c.instance);
-        let surface = ash_window::create_surface(&c.entry, &c.instance, display, window, None).unwrap();
 
-        let available_physical_devices = c.instance.enumerate_physical_devices().unwrap();
+        let (physical_device, _, queue_index_main, queue_index_async, queue_index_transfer) = Device::pick_physical_device(c, |_, _, q| {
+            q.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+        })?;
+
+        let extension_names = Vec::<*const i8>::new();
+
+        let (device, features) = Device::create_logical_device(c, physical_device, vec![queue_index_main, queue_index_async, queue_index_transfer], &extension_names)?;
+
+        let queue_main = (device.get_device_queue(queue_index_main, 0), queue_index_main);
+        let queue_async = (device.get_device_queue(queue_index_async, 0), queue_index_async);
+        let queue_transfer = (device.get_device_queue(queue_index_transfer, 0), queue_index_transfer);
+
+        let surface_format = vk::SurfaceFormatKHR {
+            format: vk::Format::R8G8B8A8_UNORM,
+            color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+        };
+
+        Ok(Device {
+            device,
+
+            surface_init,
+            surface: vk::SurfaceKHR::null(),
+            surface_format,
+            surface_capabilities: vk::SurfaceCapabilitiesKHR::default(),
+            surface_extent: extent,
+
+            extension_names,
+
+            physical_device,
+
+            queue_present: queue_main,
+            queue_main,
+            queue_async,
+            queue_transfer,
+            queue_families: c.instance.get_physical_device_queue_family_properties(physical_device),
+
+            allocator: RefCell::new(Allocator::new(c, physical_device)),
+
+            features,
+            limits: c.instance.get_physical_device_properties(physical_device).limits,
+        })
+    }
+
+    unsafe fn pick_physical_device<F: Fn(vk::PhysicalDevice, u32, &vk::QueueFamilyProperties) -> bool>(c: &Core, present_support: F) -> Result<(vk::PhysicalDevice, u32, u32, u32, u32), RendererError> {
+        let available_physical_devices = c.instance.enumerate_physical_devices()?;
+
+        available_physical_devices.iter().filter_map(|&pd| {
+            if !Device::supports_timeline_semaphores(c, pd) {
+                return None;
+            }
 
-        let (physical_device, queue_index_present, queue_index_main, queue_index_async) = available_physical_devices.iter().filter_map(|&pd| {
             let queue_family_properties = c.instance.get_physical_device_queue_family_properties(pd);
 
-            let queue_index_properties_present = queue_family_properties.iter().enumerate().filter(|(i, ref q)| {
-                surface_init.get_physical_device_surface_support(pd, *i as u32, surface).unwrap()
+            let queue_index_properties_present = queue_family_properties.iter().enumerate().filter(|(i, q)| {
+                present_support(pd, *i as u32, q)
             }).next();
-            let queue_index_properties_main = queue_family_properties.iter().enumerate().filter(|(i, ref q)| {
+            let queue_index_properties_main = queue_family_properties.iter().enumerate().filter(|(_, q)| {
                 q.queue_flags.contains(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
             }).next();
-            let queue_index_properties_async = queue_family_properties.iter().enumerate().filter(|(i, ref q)| {
-                q.queue_flags.contains(vk::QueueFlags::COMPUTE)
-            }).next();
-
-            if queue_index_properties_present.is_some() && queue_index_properties_main.is_some() && queue_index_properties_async.is_some() {
-                Some((pd, queue_index_properties_present.unwrap().0 as u32, queue_index_properties_main.unwrap().0 as u32, queue_index_properties_async.unwrap().0 as u32))
+            // A family without graphics can run compute alongside the main queue, otherwise async layers share the main family
+            let queue_index_properties_async = queue_family_properties.iter().enumerate().filter(|(_, q)| {
+                q.queue_flags.contains(vk::QueueFlags::COMPUTE) && !q.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+            }).next().or(queue_index_properties_main);
+            // Families with only transfer are usually dedicated copy engines, which can upload without taking time from rendering
+            let queue_index_properties_transfer = queue_family_properties.iter().enumerate().filter(|(_, q)| {
+                q.queue_flags.contains(vk::QueueFlags::TRANSFER) && !q.queue_flags.intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+            }).next().or(queue_index_properties_main);
+
+            if queue_index_properties_present.is_some() && queue_index_properties_main.is_some() && queue_index_properties_async.is_some() && queue_index_properties_transfer.is_some() {
+                Some((pd, queue_index_properties_present.unwrap().0 as u32, queue_index_properties_main.unwrap().0 as u32, queue_index_properties_async.unwrap().0 as u32, queue_index_properties_transfer.unwrap().0 as u32))
             } else {
                 None
             }
-        }).next().expect("Suitable physical device not found");
+        }).next().ok_or(RendererError::NoSuitableDevice)
+    }
 
-        let extension_names = vec![ash::extensions::khr::Swapchain::name().as_ptr()];
+    // Layers synchronise with timeline semaphores, which are core in Vulkan 1.2
+    unsafe fn supports_timeline_semaphores(c: &Core, physical_device: vk::PhysicalDevice) -> bool {
+        if c.instance.get_physical_device_properties(physical_device).api_version < vk::API_VERSION_1_2 {
+            return false;
+        }
+
+        let mut vulkan_12_features = vk::PhysicalDeviceVulkan12Features::default();
+
+        {
+            let mut features = vk::PhysicalDeviceFeatures2::builder()
+                .push_next(&mut vulkan_12_features);
+
+            c.instance.get_physical_device_features2(physical_device, &mut features);
+        }
 
+        vulkan_12_features.timeline_semaphore == vk::TRUE
+    }
+
+    unsafe fn create_logical_device(c: &Core, physical_device: vk::PhysicalDevice, queue_indices: Vec<u32>, extension_names: &[*const i8]) -> Result<(ash::Device, vk::PhysicalDeviceFeatures), RendererError> {
+        let supported_features = c.instance.get_physical_device_features(physical_device);
+
+        // Optional features are turned on when the device has them, passes that need them check before using them
         let physical_device_features = vk::PhysicalDeviceFeatures {
             shader_clip_distance: 1,
+            fill_mode_non_solid: supported_features.fill_mode_non_solid,
+            pipeline_statistics_query: supported_features.pipeline_statistics_query,
+            multi_draw_indirect: supported_features.multi_draw_indirect,
+            draw_indirect_first_instance: supported_features.draw_indirect_first_instance,
             ..Default::default()
         };
 
         let priorities = [1.0];
 
-        let queue_indices = vec![queue_index_present, queue_index_main, queue_index_async];
         let mut unique_indices = Vec::<u32>::new();
         let mut queue_cis = Vec::<vk::DeviceQueueCreateInfo>::new();
 
@@ -76,68 +237,77 @@ impl Device {
             queue_cis.push(queue_ci);
         });
 
+        let mut vulkan_12_features = vk::PhysicalDeviceVulkan12Features::builder()
+            .timeline_semaphore(true);
+
         let device_ci = vk::DeviceCreateInfo::builder()
+            .push_next(&mut vulkan_12_features)
             .queue_create_infos(&queue_cis)
-            .enabled_extension_names(&extension_names)
+            .enabled_extension_names(extension_names)
             .enabled_features(&physical_device_features);
 
-        let device = c.instance.create_device(physical_device, &device_ci, None).unwrap();
-
-        let queue_present = (device.get_device_queue(queue_index_present, 0), queue_index_present);
-        let queue_main = (device.get_device_queue(queue_index_main, 0), queue_index_main);
-        let queue_async = (device.get_device_queue(queue_index_async, 0), queue_index_async);
+        Ok((c.instance.create_device(physical_device, &device_ci, None)?, physical_device_features))
+    }
 
-        let available_surface_formats = surface_init.get_physical_device_surface_formats(physical_device, surface).unwrap();
-        let surface_format = available_surface_formats.iter().filter(|format| {
-            format.format == vk::Format::B8G8R8A8_SRGB && format.color_space == vk::ColorSpaceKHR::EXTENDED_SRGB_NONLINEAR_EXT
-        }).next().unwrap_or(&available_surface_formats[0]);
+    /// Every resource allocated from the device has to have been destroyed first
+    ///
+    /// # Safety
+    /// Every object created from the device must be destroyed first, and the device must be idle.
+    pub unsafe fn destroy(&self) {
+        self.allocator.borrow_mut().destroy(&self.device);
+        self.device.destroy_device(None);
 
-        let surface_capabilities = surface_init.get_physical_device_surface_capabilities(physical_device, surface).unwrap();
+        if self.surface != vk::SurfaceKHR::null() {
+            self.surface_init.destroy_surface(self.surface, None);
+        }
+    }
 
-        let surface_extent = if surface_capabilities.current_extent.width == std::u32::MAX {
-            vk::Extent2D {
-                width: 1280,
-                height: 720,
-            }
-        } else {
-            surface_capabilities.current_extent
-        };
+    pub fn get_queue(&self, exec: LayerExecution) -> (vk::Queue, u32) {
+        match exec {
+            LayerExecution::Main => self.queue_main,
+            LayerExecution::Async => self.queue_async,
+        }
+    }
 
-        Device {
-            device,
+    // Timestamps can't be written on queues without valid bits
+    pub fn supports_timestamps(&self, exec: LayerExecution) -> bool {
+        self.queue_families[self.get_queue(exec).1 as usize].timestamp_valid_bits > 0
+    }
 
-            surface_init,
-            surface,
-            surface_format: *surface_format,
-            surface_capabilities,
-            surface_extent,
+    // Objects sharing a dynamic uniform buffer each take a multiple of minUniformBufferOffsetAlignment
+    pub fn uniform_stride(&self, size: usize) -> usize {
+        let alignment = (self.limits.min_uniform_buffer_offset_alignment as usize).max(1);
 
-            extension_names,
+        (size + alignment - 1) / alignment * alignment
+    }
 
-            physical_device,
+    // The highest sample count up to the requested one that framebuffers support, a single sample is always supported
+    pub fn sample_count(&self, requested: u32, with_depth_buffer: bool) -> vk::SampleCountFlags {
+        let mut supported = self.limits.framebuffer_color_sample_counts;
 
-            queue_present,
-            queue_main,
-            queue_async,
+        if with_depth_buffer {
+            supported &= self.limits.framebuffer_depth_sample_counts;
         }
+
+        [64, 32, 16, 8, 4, 2].iter()
+            .map(|&count| vk::SampleCountFlags::from_raw(count))
+            .find(|&count| count.as_raw() <= requested && supported.contains(count))
+            .unwrap_or(vk::SampleCountFlags::TYPE_1)
     }
 
-    pub fn get_queue(&self, exec: LayerExecution) -> (vk::Queue, u32) {
-        match exec {
-            LayerExecution::Main => self.queue_main,
-            LayerExecution::Async => self.queue_async,
-        }
+    /// # Safety
+    /// The memory must be freed through this device before it's destroyed.
+    pub unsafe fn allocate(&self, memory_requirements: vk::MemoryRequirements, properties: vk::MemoryPropertyFlags, tiling: vk::ImageTiling) -> Result<Allocation, RendererError> {
+        self.allocator.borrow_mut().allocate(&self.device, memory_requirements, properties, tiling)
     }
 
-    pub unsafe fn get_memory_type(&self, c: &Core, property_flags: vk::MemoryPropertyFlags, memory_requirements: vk::MemoryRequirements) -> usize {
-        let memory_type_index = c.instance.get_physical_device_memory_properties(self.physical_device).memory_types.iter().enumerate().find_map(|(i, m)| {
-            if (memory_requirements.memory_type_bits & (1 << i)) != 0 && (m.property_flags & property_flags == property_flags) {
-                Some(i)
-            } else {
-                None
-            }
-        }).unwrap();
+    /// # Safety
+    /// Nothing bound to the allocation can still be in use by the GPU.
+    pub unsafe fn free(&self, allocation: &Allocation) {
+        self.allocator.borrow_mut().free(&self.device, allocation);
+    }
 
-        memory_type_index
+    pub fn memory_stats(&self) -> AllocatorStats {
+        self.allocator.borrow().stats()
     }
 }
\ No newline at end of file
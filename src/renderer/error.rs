@@ -0,0 +1,104 @@
+use std::{fmt, io};
+
+use ash::vk;
+
+use crate::renderer::shader_compiler::ShaderDiagnostic;
+use crate::util::graph::GraphError;
+use crate::scene::SceneError;
+
+#[derive(Debug)]
+pub enum RendererError {
+    Vulkan(vk::Result),
+    MissingField { builder: &'static str, field: &'static str },
+    UnknownResource(String),
+    BufferOverflow { name: String, size: u64, required: u64 },
+    ShaderLoad { path: String, source: io::Error },
+    ShaderCompile { path: String, diagnostics: Vec<ShaderDiagnostic> },
+    ShaderCompiler(io::Error),
+    InvalidSpirv { path: String, message: String },
+    ShaderMismatch { path: String, message: String },
+    TextureLoad { path: String, source: ::image::ImageError },
+    InvalidTexture(String),
+    TraceExport { path: String, source: io::Error },
+    NoSuitableDevice,
+    UnsupportedFeature(&'static str),
+    InvalidPassConfig(&'static str),
+    FrameInProgress,
+    NoSuitableMemoryType(vk::MemoryPropertyFlags),
+    UnknownFormatSize(vk::Format),
+    Graph(GraphError),
+    Scene(SceneError),
+}
+
+impl RendererError {
+    pub fn missing(builder: &'static str, field: &'static str) -> RendererError {
+        RendererError::MissingField { builder, field }
+    }
+}
+
+impl fmt::Display for RendererError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RendererError::Vulkan(result) => write!(f, "Vulkan call failed: {}", result),
+            RendererError::MissingField { builder, field } => write!(f, "{} has no {}", builder, field),
+            RendererError::UnknownResource(name) => write!(f, "No resource named \"{}\"", name),
+            RendererError::BufferOverflow { name, size, required } => write!(f, "Buffer \"{}\" is {} bytes but {} are needed", name, size, required),
+            RendererError::ShaderLoad { path, source } => write!(f, "Failed to load shader \"{}\": {}", path, source),
+            RendererError::ShaderCompile { path, diagnostics } => {
+                write!(f, "Failed to compile shader \"{}\"", path)?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n{}", diagnostic)?;
+                }
+
+                Ok(())
+            },
+            RendererError::ShaderCompiler(e) => write!(f, "Failed to run glslc: {}", e),
+            RendererError::InvalidSpirv { path, message } => write!(f, "Failed to reflect shader \"{}\": {}", path, message),
+            RendererError::ShaderMismatch { path, message } => write!(f, "Shader \"{}\" doesn't match its pass: {}", path, message),
+            RendererError::TextureLoad { path, source } => write!(f, "Failed to load texture \"{}\": {}", path, source),
+            RendererError::InvalidTexture(message) => write!(f, "Invalid texture: {}", message),
+            RendererError::TraceExport { path, source } => write!(f, "Failed to export trace \"{}\": {}", path, source),
+            RendererError::NoSuitableDevice => write!(f, "No physical device supports the required queues and timeline semaphores"),
+            RendererError::UnsupportedFeature(feature) => write!(f, "The device doesn't support {}", feature),
+            RendererError::InvalidPassConfig(message) => write!(f, "Invalid pass configuration: {}", message),
+            RendererError::FrameInProgress => write!(f, "Images can't be read back between pre_draw and draw"),
+            RendererError::NoSuitableMemoryType(properties) => write!(f, "No memory type has the properties {:?}", properties),
+            RendererError::UnknownFormatSize(format) => write!(f, "Size of format {:?} is unknown", format),
+            RendererError::Graph(e) => write!(f, "{}", e),
+            RendererError::Scene(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RendererError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RendererError::Vulkan(e) => Some(e),
+            RendererError::ShaderLoad { source, .. } => Some(source),
+            RendererError::ShaderCompiler(e) => Some(e),
+            RendererError::TextureLoad { source, .. } => Some(source),
+            RendererError::TraceExport { source, .. } => Some(source),
+            RendererError::Graph(e) => Some(e),
+            RendererError::Scene(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<vk::Result> for RendererError {
+    fn from(result: vk::Result) -> RendererError {
+        RendererError::Vulkan(result)
+    }
+}
+
+impl From<GraphError> for RendererError {
+    fn from(e: GraphError) -> RendererError {
+        RendererError::Graph(e)
+    }
+}
+
+impl From<SceneError> for RendererError {
+    fn from(e: SceneError) -> RendererError {
+        RendererError::Scene(e)
+    }
+}
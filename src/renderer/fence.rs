@@ -1,6 +1,7 @@
 use ash::vk;
 
 use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
 
 #[derive(Copy, Clone)]
 pub struct Fence {
@@ -8,17 +9,23 @@ pub struct Fence {
 }
 
 impl Fence {
-    pub unsafe fn new(d: &Device, signaled: bool) -> Fence {
+    pub unsafe fn new(d: &Device, signaled: bool) -> Result<Fence, RendererError> {
         let mut fence_ci = vk::FenceCreateInfo::builder();
 
         if signaled {
             fence_ci = fence_ci.flags(vk::FenceCreateFlags::SIGNALED);
         }
 
-        let fence = d.device.create_fence(&fence_ci, None).unwrap();
+        let fence = d.device.create_fence(&fence_ci, None)?;
 
-        Fence {
+        Ok(Fence {
             fence
-        }
+        })
+    }
+
+    /// # Safety
+    /// The fence can't be pending on a queue submission.
+    pub unsafe fn destroy(&self, d: &Device) {
+        d.device.destroy_fence(self.fence, None);
     }
 }
\ No newline at end of file
@@ -1,22 +1,36 @@
 use crate::renderer::device::Device;
 use crate::renderer::semaphore::Semaphore;
 use crate::renderer::fence::Fence;
+use crate::renderer::error::RendererError;
 
 #[derive(Copy, Clone)]
 pub struct Frame {
     pub image_available_semaphore: Semaphore,
+    pub render_finished_semaphore: Semaphore, // Presentation can't wait on timeline semaphores
     pub in_flight_fence: Fence,
+    pub frame_number: u64, // Value the layers signaled when this frame was last submitted
 }
 
 impl Frame {
-    pub unsafe fn new(d: &Device) -> Frame {
-        let image_available_semaphore = Semaphore::new(d);
+    pub unsafe fn new(d: &Device) -> Result<Frame, RendererError> {
+        let image_available_semaphore = Semaphore::new(d)?;
+        let render_finished_semaphore = Semaphore::new(d)?;
 
-        let in_flight_fence = Fence::new(d, true);
+        let in_flight_fence = Fence::new(d, true)?;
 
-        Frame {
+        Ok(Frame {
             image_available_semaphore,
+            render_finished_semaphore,
             in_flight_fence,
-        }
+            frame_number: 0,
+        })
+    }
+
+    /// # Safety
+    /// The frame's fence must have been waited on.
+    pub unsafe fn destroy(&self, d: &Device) {
+        self.image_available_semaphore.destroy(d);
+        self.render_finished_semaphore.destroy(d);
+        self.in_flight_fence.destroy(d);
     }
 }
\ No newline at end of file
@@ -1,6 +1,7 @@
 use ash::vk;
 
 use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
 use crate::renderer::graphics_pipeline::GraphicsPipeline;
 use crate::renderer::image::Image;
 
@@ -9,13 +10,24 @@ pub struct Framebuffer {
 }
 
 impl Framebuffer {
-    pub unsafe fn new(d: &Device, g: &GraphicsPipeline, target: &Image, extent: Option<vk::Extent2D>) -> Framebuffer {
-        let mut views = vec![target.view];
+    // Takes one image for each color attachment
+    pub unsafe fn new(d: &Device, g: &GraphicsPipeline, targets: &[Image], extent: Option<vk::Extent2D>) -> Result<Framebuffer, RendererError> {
+        let target = &targets[0];
 
-        if let Some(depth_image) = g.depth_image {
+        // Same order as the render pass attachments, with MSAA the targets are the resolve attachments at the end
+        let mut views: Vec<vk::ImageView> = match g.color_images.is_empty() {
+            true => targets.iter().map(|t| t.view).collect(),
+            false => g.color_images.iter().map(|i| i.view).collect(),
+        };
+
+        if let Some(depth_image) = &g.depth_image {
             views.push(depth_image.view);
         }
 
+        if !g.color_images.is_empty() {
+            views.extend(targets.iter().map(|t| t.view));
+        }
+
         let (width, height) = match extent {
             Some(e) => (e.width, e.height),
             None => (target.width, target.height),
@@ -29,20 +41,27 @@ impl Framebuffer {
             .layers(1)
             .build();
 
-        let framebuffer = d.device.create_framebuffer(&framebuffer_ci, None).unwrap();
+        let framebuffer = d.device.create_framebuffer(&framebuffer_ci, None)?;
 
-        Framebuffer {
+        Ok(Framebuffer {
             framebuffer,
-        }
+        })
+    }
+
+    /// # Safety
+    /// The framebuffer mustn't be used by a frame still in flight.
+    pub unsafe fn destroy(&self, d: &Device) {
+        d.device.destroy_framebuffer(self.framebuffer, None);
     }
 
-    pub unsafe fn new_many(d: &Device, g: &GraphicsPipeline, targets: &Vec<Image>, extent: Option<vk::Extent2D>) -> Vec<Framebuffer> {
+    // Targets are given per attachment, a framebuffer is made from the images at each index
+    pub unsafe fn new_many(d: &Device, g: &GraphicsPipeline, targets: &[Vec<Image>], extent: Option<vk::Extent2D>) -> Result<Vec<Framebuffer>, RendererError> {
         let mut framebuffers = Vec::<Framebuffer>::new();
 
-        for target in targets {
-            framebuffers.push(Framebuffer::new(d, g, target, extent));
+        for i in 0..targets[0].len() {
+            framebuffers.push(Framebuffer::new(d, g, &targets.iter().map(|t| t[i].share()).collect::<Vec<Image>>(), extent)?);
         }
 
-        framebuffers
+        Ok(framebuffers)
     }
 }
\ No newline at end of file
@@ -0,0 +1,510 @@
+use std::fmt;
+
+use ash::vk;
+use gltf::image::Format;
+
+use crate::math::{vec::{Vec2, Vec3, Vec4}, mat::Mat4, quat::Quat};
+use crate::renderer::image::ImageBuilder;
+use crate::renderer::texture::TextureBuilder;
+use crate::renderer::vertex_buffer::{VertexAttribute, VertexAttributes};
+
+#[derive(Debug)]
+pub enum GltfError {
+    Import(gltf::Error),
+    MissingPositions { mesh: usize, primitive: usize },
+    UnsupportedTopology { mesh: usize, primitive: usize },
+    InvalidHierarchy { node: usize },
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct GltfVertex {
+    pub pos: Vec3,
+    pub normal: Vec3,
+    pub tex_coord: Vec2,
+    pub tangent: Vec4,
+}
+
+pub struct GltfPrimitive {
+    pub vertices: Vec<GltfVertex>,
+    pub indices: Vec<u32>,
+    pub material: Option<usize>,
+}
+
+pub struct GltfMesh {
+    pub name: String,
+    pub primitives: Vec<GltfPrimitive>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum GltfAlphaMode {
+    Opaque,
+    Mask(f32),
+    Blend,
+}
+
+pub struct GltfMaterial {
+    pub name: String,
+
+    pub base_colour: Vec4,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub emissive: Vec3,
+    pub normal_scale: f32,
+    pub occlusion_strength: f32,
+
+    // Indices into GltfScene::textures
+    pub base_colour_texture: Option<usize>,
+    pub metallic_roughness_texture: Option<usize>,
+    pub normal_texture: Option<usize>,
+    pub occlusion_texture: Option<usize>,
+    pub emissive_texture: Option<usize>,
+
+    pub alpha_mode: GltfAlphaMode,
+    pub double_sided: bool,
+}
+
+// Pixels are always expanded to 4 channels of 8 bits, colour textures are tagged as sRGB
+pub struct GltfTexture {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub format: vk::Format,
+    pub pixels: Vec<u8>,
+}
+
+pub struct GltfNode {
+    pub name: String,
+
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+
+    pub local: Mat4,
+    pub world: Mat4,
+
+    pub mesh: Option<usize>,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+}
+
+pub struct GltfScene {
+    pub nodes: Vec<GltfNode>,
+    pub roots: Vec<usize>, // Root nodes of the default scene, or the first scene if there is no default
+    pub meshes: Vec<GltfMesh>,
+    pub materials: Vec<GltfMaterial>,
+    pub textures: Vec<GltfTexture>,
+}
+
+impl VertexAttributes for GltfVertex {
+    fn get_attribute_data() -> Vec<VertexAttribute> {
+        vec![
+            VertexAttribute { format: vk::Format::R32G32B32_SFLOAT, offset: 0 },
+            VertexAttribute { format: vk::Format::R32G32B32_SFLOAT, offset: 12 },
+            VertexAttribute { format: vk::Format::R32G32_SFLOAT, offset: 24 },
+            VertexAttribute { format: vk::Format::R32G32B32A32_SFLOAT, offset: 32 },
+        ]
+    }
+}
+
+impl GltfTexture {
+    // The builder for a sampled image to upload this texture into
+    pub fn image_builder(&self) -> ImageBuilder {
+        ImageBuilder::new()
+            .width(self.width)
+            .height(self.height)
+            .format(self.format)
+            .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+    }
+
+    pub fn texture_builder(&self) -> TextureBuilder<'static> {
+        TextureBuilder::new()
+            .pixels(self.width, self.height, self.format, &self.pixels)
+            .mipmaps(true)
+    }
+}
+
+impl GltfScene {
+    // Handles .gltf with external or data URI buffers and .glb
+    pub fn load(path: &str) -> Result<GltfScene, GltfError> {
+        let (document, buffers, images) = gltf::import(path).map_err(GltfError::Import)?;
+
+        let mut scene = GltfScene {
+            nodes: Vec::with_capacity(document.nodes().len()),
+            roots: Vec::new(),
+            meshes: Vec::with_capacity(document.meshes().len()),
+            materials: Vec::with_capacity(document.materials().len()),
+            textures: Vec::with_capacity(document.textures().len()),
+        };
+
+        for mesh in document.meshes() {
+            let mut primitives = Vec::<GltfPrimitive>::new();
+
+            for primitive in mesh.primitives() {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    return Err(GltfError::UnsupportedTopology { mesh: mesh.index(), primitive: primitive.index() });
+                }
+
+                let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+                let positions: Vec<[f32; 3]> = reader.read_positions().ok_or(GltfError::MissingPositions { mesh: mesh.index(), primitive: primitive.index() })?.collect();
+
+                let mut vertices: Vec<GltfVertex> = positions.iter().map(|p| GltfVertex {
+                    pos: Vec3::new(p[0], p[1], p[2]),
+                    normal: Vec3::zero(),
+                    tex_coord: Vec2::zero(),
+                    tangent: Vec4::zero(),
+                }).collect();
+
+                if let Some(normals) = reader.read_normals() {
+                    for (v, n) in vertices.iter_mut().zip(normals) {
+                        v.normal = Vec3::new(n[0], n[1], n[2]);
+                    }
+                }
+
+                if let Some(tex_coords) = reader.read_tex_coords(0) {
+                    for (v, t) in vertices.iter_mut().zip(tex_coords.into_f32()) {
+                        v.tex_coord = Vec2::new(t[0], t[1]);
+                    }
+                }
+
+                if let Some(tangents) = reader.read_tangents() {
+                    for (v, t) in vertices.iter_mut().zip(tangents) {
+                        v.tangent = Vec4::new(t[0], t[1], t[2], t[3]);
+                    }
+                }
+
+                let indices: Vec<u32> = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..vertices.len() as u32).collect(),
+                };
+
+                // The spec says to use flat normals when none are given
+                if reader.read_normals().is_none() {
+                    flat_normals(&mut vertices, &indices);
+                }
+
+                primitives.push(GltfPrimitive {
+                    vertices,
+                    indices,
+                    material: primitive.material().index(),
+                });
+            }
+
+            scene.meshes.push(GltfMesh {
+                name: mesh.name().unwrap_or("").to_string(),
+                primitives,
+            });
+        }
+
+        let mut srgb_images = vec![false; images.len()];
+
+        for material in document.materials() {
+            let pbr = material.pbr_metallic_roughness();
+            let emissive = material.emissive_factor();
+
+            for info in [pbr.base_color_texture(), material.emissive_texture()].iter().flatten() {
+                srgb_images[info.texture().source().index()] = true;
+            }
+
+            scene.materials.push(GltfMaterial {
+                name: material.name().unwrap_or("").to_string(),
+
+                base_colour: Vec4::new(pbr.base_color_factor()[0], pbr.base_color_factor()[1], pbr.base_color_factor()[2], pbr.base_color_factor()[3]),
+                metallic: pbr.metallic_factor(),
+                roughness: pbr.roughness_factor(),
+                emissive: Vec3::new(emissive[0], emissive[1], emissive[2]),
+                normal_scale: material.normal_texture().map_or(1.0, |t| t.scale()),
+                occlusion_strength: material.occlusion_texture().map_or(1.0, |t| t.strength()),
+
+                base_colour_texture: pbr.base_color_texture().map(|t| t.texture().index()),
+                metallic_roughness_texture: pbr.metallic_roughness_texture().map(|t| t.texture().index()),
+                normal_texture: material.normal_texture().map(|t| t.texture().index()),
+                occlusion_texture: material.occlusion_texture().map(|t| t.texture().index()),
+                emissive_texture: material.emissive_texture().map(|t| t.texture().index()),
+
+                alpha_mode: match material.alpha_mode() {
+                    gltf::material::AlphaMode::Opaque => GltfAlphaMode::Opaque,
+                    gltf::material::AlphaMode::Mask => GltfAlphaMode::Mask(material.alpha_cutoff().unwrap_or(0.5)),
+                    gltf::material::AlphaMode::Blend => GltfAlphaMode::Blend,
+                },
+                double_sided: material.double_sided(),
+            });
+        }
+
+        for texture in document.textures() {
+            let source = texture.source().index();
+            let image = &images[source];
+
+            scene.textures.push(GltfTexture {
+                name: texture.name().or(texture.source().name()).unwrap_or("").to_string(),
+                width: image.width,
+                height: image.height,
+                format: if srgb_images[source] { vk::Format::R8G8B8A8_SRGB } else { vk::Format::R8G8B8A8_UNORM },
+                pixels: to_rgba8(image),
+            });
+        }
+
+        for node in document.nodes() {
+            let (translation, rotation, scale) = node.transform().decomposed();
+
+            scene.nodes.push(GltfNode {
+                name: node.name().unwrap_or("").to_string(),
+
+                translation: Vec3::new(translation[0], translation[1], translation[2]),
+                rotation: Quat { r: rotation[3], i: rotation[0], j: rotation[1], k: rotation[2] },
+                scale: Vec3::new(scale[0], scale[1], scale[2]),
+
+                local: column_major_to_mat4(node.transform().matrix()),
+                world: Mat4::identity(),
+
+                mesh: node.mesh().map(|m| m.index()),
+                parent: None,
+                children: node.children().map(|c| c.index()).collect(),
+            });
+        }
+
+        for i in 0..scene.nodes.len() {
+            for child in scene.nodes[i].children.clone() {
+                if scene.nodes[child].parent.is_some() {
+                    return Err(GltfError::InvalidHierarchy { node: child });
+                }
+
+                scene.nodes[child].parent = Some(i);
+            }
+        }
+
+        if let Some(default_scene) = document.default_scene().or(document.scenes().next()) {
+            scene.roots = default_scene.nodes().map(|n| n.index()).collect();
+        }
+
+        scene.update_world()?;
+
+        Ok(scene)
+    }
+
+    // Mat4 multiplication applies the left hand side first, so the local transform goes on the left
+    // Every node has at most one parent by now, so any node not reached from a parentless node is part of a cycle
+    fn update_world(&mut self) -> Result<(), GltfError> {
+        let mut visited = vec![false; self.nodes.len()];
+        let mut open_nodes: Vec<(usize, Mat4)> = (0..self.nodes.len()).filter(|i| self.nodes[*i].parent.is_none()).map(|i| (i, Mat4::identity())).collect();
+
+        while let Some((node, parent_world)) = open_nodes.pop() {
+            let world = self.nodes[node].local * parent_world;
+            self.nodes[node].world = world;
+            visited[node] = true;
+
+            open_nodes.extend(self.nodes[node].children.iter().map(|c| (*c, world)));
+        }
+
+        match visited.iter().position(|v| !v) {
+            Some(node) => Err(GltfError::InvalidHierarchy { node }),
+            None => Ok(()),
+        }
+    }
+
+    // Every mesh instance in the default scene with the world transform it is drawn with
+    pub fn mesh_instances(&self) -> Vec<(usize, Mat4)> {
+        let mut instances = Vec::<(usize, Mat4)>::new();
+        let mut open_nodes = self.roots.clone();
+
+        while let Some(node) = open_nodes.pop() {
+            if let Some(mesh) = self.nodes[node].mesh {
+                instances.push((mesh, self.nodes[node].world));
+            }
+
+            open_nodes.extend(self.nodes[node].children.iter());
+        }
+
+        instances
+    }
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GltfError::Import(e) => write!(f, "Failed to import glTF: {}", e),
+            GltfError::MissingPositions { mesh, primitive } => write!(f, "Primitive {} of mesh {} has no positions", primitive, mesh),
+            GltfError::UnsupportedTopology { mesh, primitive } => write!(f, "Primitive {} of mesh {} is not a triangle list", primitive, mesh),
+            GltfError::InvalidHierarchy { node } => write!(f, "Node {} has more than one parent or is part of a cycle", node),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GltfError::Import(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// glTF stores matrices column by column, Mat4 stores rows
+fn column_major_to_mat4(m: [[f32; 4]; 4]) -> Mat4 {
+    Mat4 {
+        x: Vec4::new(m[0][0], m[1][0], m[2][0], m[3][0]),
+        y: Vec4::new(m[0][1], m[1][1], m[2][1], m[3][1]),
+        z: Vec4::new(m[0][2], m[1][2], m[2][2], m[3][2]),
+        w: Vec4::new(m[0][3], m[1][3], m[2][3], m[3][3]),
+    }
+}
+
+fn flat_normals(vertices: &mut [GltfVertex], indices: &[u32]) {
+    for tri in indices.chunks_exact(3) {
+        let [v0, v1, v2] = [tri[0] as usize, tri[1] as usize, tri[2] as usize];
+        let normal = Vec3::cross(vertices[v1].pos - vertices[v0].pos, vertices[v2].pos - vertices[v0].pos);
+
+        if normal.len() > 0.0 {
+            for v in [v0, v1, v2] {
+                vertices[v].normal = normal.normalize();
+            }
+        }
+    }
+}
+
+// One channel is grey, two are grey and alpha, missing alpha is opaque. Wider channels are scaled down to 8 bits
+fn to_rgba8(image: &gltf::image::Data) -> Vec<u8> {
+    let channels = match image.format {
+        Format::R8 | Format::R16 => 1,
+        Format::R8G8 | Format::R16G16 => 2,
+        Format::R8G8B8 | Format::R16G16B16 | Format::R32G32B32FLOAT => 3,
+        Format::R8G8B8A8 | Format::R16G16B16A16 | Format::R32G32B32A32FLOAT => 4,
+    };
+
+    let values: Vec<u8> = match image.format {
+        Format::R8 | Format::R8G8 | Format::R8G8B8 | Format::R8G8B8A8 => image.pixels.clone(),
+        Format::R16 | Format::R16G16 | Format::R16G16B16 | Format::R16G16B16A16 => {
+            image.pixels.chunks_exact(2).map(|c| (u16::from_ne_bytes([c[0], c[1]]) >> 8) as u8).collect()
+        },
+        Format::R32G32B32FLOAT | Format::R32G32B32A32FLOAT => {
+            image.pixels.chunks_exact(4).map(|c| (f32::from_ne_bytes([c[0], c[1], c[2], c[3]]).clamp(0.0, 1.0) * 255.0).round() as u8).collect()
+        },
+    };
+
+    let mut pixels = Vec::<u8>::with_capacity((image.width * image.height * 4) as usize);
+    for pixel in values.chunks_exact(channels) {
+        let rgba = match *pixel {
+            [v] => [v, v, v, 255],
+            [v, a] => [v, v, v, a],
+            [r, g, b] => [r, g, b, 255],
+            _ => [pixel[0], pixel[1], pixel[2], pixel[3]],
+        };
+
+        pixels.extend_from_slice(&rgba);
+    }
+
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One triangle with positions and tex coords but no normals, under a translated parent node
+    const TRIANGLE: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [
+            { "name": "parent", "translation": [1, 2, 3], "children": [1] },
+            { "name": "child", "translation": [0, 0, 1], "scale": [2, 2, 2], "mesh": 0 }
+        ],
+        "meshes": [{ "name": "triangle", "primitives": [{ "attributes": { "POSITION": 0, "TEXCOORD_0": 1 }, "material": 0 }] }],
+        "materials": [{
+            "name": "red",
+            "pbrMetallicRoughness": { "baseColorFactor": [1, 0, 0, 1], "metallicFactor": 0.25, "roughnessFactor": 0.75 },
+            "alphaMode": "MASK",
+            "alphaCutoff": 0.3,
+            "doubleSided": true
+        }],
+        "buffers": [{ "byteLength": 60, "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAAAAAAAAAAAAIA/AAAAAAAAAAAAAIA/" }],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 24 }
+        ],
+        "accessors": [
+            { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] },
+            { "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC2" }
+        ]
+    }"#;
+
+    const CYCLE: &str = r#"{
+        "asset": { "version": "2.0" },
+        "nodes": [{ "children": [1] }, { "children": [0] }]
+    }"#;
+
+    // Each test gets its own file so they can run in parallel
+    fn load(test: &str, source: &str) -> Result<GltfScene, GltfError> {
+        let path = std::env::temp_dir().join(format!("gltf_test_{}_{}.gltf", std::process::id(), test));
+        std::fs::write(&path, source).unwrap();
+
+        GltfScene::load(path.to_str().unwrap())
+    }
+
+    fn image(format: Format, pixels: &[u8]) -> gltf::image::Data {
+        gltf::image::Data { pixels: pixels.to_vec(), format, width: 1, height: 1 }
+    }
+
+    #[test]
+    fn to_rgba8_expands_channels() {
+        assert_eq!(to_rgba8(&image(Format::R8, &[7])), [7, 7, 7, 255]);
+        assert_eq!(to_rgba8(&image(Format::R8G8, &[7, 9])), [7, 7, 7, 9]);
+        assert_eq!(to_rgba8(&image(Format::R8G8B8, &[1, 2, 3])), [1, 2, 3, 255]);
+        assert_eq!(to_rgba8(&image(Format::R8G8B8A8, &[1, 2, 3, 4])), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn to_rgba8_narrows_wide_channels() {
+        let r16: Vec<u8> = [0xabcdu16, 0x1234, 0xffff].iter().flat_map(|v| v.to_ne_bytes()).collect();
+        assert_eq!(to_rgba8(&image(Format::R16G16B16, &r16)), [0xab, 0x12, 0xff, 255]);
+
+        let r32: Vec<u8> = [0.0f32, 0.5, 2.0, -1.0].iter().flat_map(|v| v.to_ne_bytes()).collect();
+        assert_eq!(to_rgba8(&image(Format::R32G32B32A32FLOAT, &r32)), [0, 128, 255, 0]);
+    }
+
+    #[test]
+    fn loads_attributes_and_materials() {
+        let scene = load("attributes", TRIANGLE).unwrap();
+
+        assert_eq!(scene.meshes.len(), 1);
+        let primitive = &scene.meshes[0].primitives[0];
+        assert_eq!(primitive.indices, [0, 1, 2]);
+        assert_eq!(primitive.material, Some(0));
+        assert_eq!((primitive.vertices[1].pos.x, primitive.vertices[1].pos.y), (1.0, 0.0));
+        assert_eq!((primitive.vertices[2].tex_coord.x, primitive.vertices[2].tex_coord.y), (0.0, 1.0));
+
+        // No normals in the file, so the flat normal faces +z
+        let normal = primitive.vertices[0].normal;
+        assert!((normal.z - 1.0).abs() < 1e-6 && normal.x.abs() < 1e-6 && normal.y.abs() < 1e-6);
+
+        let material = &scene.materials[0];
+        assert_eq!(material.name, "red");
+        assert_eq!((material.base_colour.x, material.base_colour.y), (1.0, 0.0));
+        assert_eq!((material.metallic, material.roughness), (0.25, 0.75));
+        assert!(material.alpha_mode == GltfAlphaMode::Mask(0.3));
+        assert!(material.double_sided);
+        assert!(material.base_colour_texture.is_none());
+    }
+
+    #[test]
+    fn child_world_includes_parent_transform() {
+        let scene = load("transforms", TRIANGLE).unwrap();
+
+        assert_eq!(scene.roots, [0]);
+        assert_eq!(scene.nodes[1].parent, Some(0));
+
+        let world = scene.nodes[1].world;
+        assert_eq!((world.x.w, world.y.w, world.z.w), (1.0, 2.0, 4.0));
+        assert_eq!((world.x.x, world.y.y, world.z.z), (2.0, 2.0, 2.0));
+
+        let instances = scene.mesh_instances();
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].0, 0);
+    }
+
+    #[test]
+    fn cyclic_hierarchy_is_rejected() {
+        assert!(matches!(load("cycle", CYCLE), Err(GltfError::InvalidHierarchy { .. })));
+    }
+}
@@ -1,15 +1,19 @@
 use ash::vk;
 
 use crate::{math::vec::Vec4, renderer::layer::Pass};
-use crate::renderer::{core::Core, descriptors::CreationReference, renderer_data::RendererData};
+use crate::renderer::{core::Core, descriptors::CreationReference, renderer_data::{MeshHandle, RendererData, ResourceReference}};
 use crate::renderer::device::Device;
 use crate::renderer::descriptors::{Descriptors, DescriptorsBuilder};
-use crate::renderer::vertex_buffer::{VertexBuffer, VertexAttributes};
+use crate::renderer::vertex_buffer::{InstanceData, VertexBuffer, VertexAttributes, VertexLayout};
 use crate::renderer::push_constant::PushConstantBuilder;
-use crate::renderer::graphics_pipeline::GraphicsPipeline;
+use crate::renderer::graphics_pipeline::{BlendMode, DepthBias, GraphicsPipeline, PipelineState};
 use crate::renderer::framebuffer::Framebuffer;
 use crate::renderer::push_constant::PushConstant;
 use crate::renderer::image::Image;
+use crate::renderer::buffer::Buffer;
+use crate::renderer::resource_access::{AccessDeclaration, AccessIntent, AccessType, ResourceAccess};
+use crate::renderer::shader::{ShaderSource, ShaderType};
+use crate::renderer::error::RendererError;
 
 #[derive(Copy, Clone)]
 pub struct GraphicsPassDrawInfo {
@@ -17,25 +21,65 @@ pub struct GraphicsPassDrawInfo {
     pub index_count: u32,
     pub instance_count: u32,
     pub first_vertex: u32,
+    pub first_index: u32,
     pub first_instance: u32,
     pub vertex_offset: i32,
 }
 
+// Draw commands read from a renderer data buffer, so a compute pass can write them, e.g. after culling
+#[derive(Clone)]
+pub struct GraphicsPassIndirectInfo {
+    pub buffers: Vec<vk::Buffer>,
+    pub offset: u64,
+    pub draw_count: u32,
+    pub stride: u32,
+}
+
+// One mesh drawn by a mesh draw pass, the pass's push constants and dynamic uniform offsets can be replaced for just this draw
+#[derive(Clone)]
+pub struct DrawItem {
+    pub mesh: MeshHandle,
+    pub draw_info: GraphicsPassDrawInfo,
+    pub vertex_push_constant: Option<Vec<u8>>,
+    pub fragment_push_constant: Option<Vec<u8>>,
+    pub dynamic_offsets: Vec<(vk::ShaderStageFlags, u32, u32)>, // Stage, binding and offset
+}
+
+// A draw item that has been checked against the pass, with its dynamic offsets resolved to their index when binding
+pub struct PassDraw {
+    pub item: DrawItem,
+    pub vertex_offsets: Vec<(usize, u32)>,
+    pub fragment_offsets: Vec<(usize, u32)>,
+}
+
+// Swapchain targets have a framebuffer for each swapchain image, renderer data targets have one for each frame in flight
+#[derive(Copy, Clone, PartialEq)]
+pub enum TargetIndexing {
+    Present,
+    Frame,
+}
+
 pub struct GraphicsPassBuilder<'a, T: VertexAttributes> {
     draw_info: Option<GraphicsPassDrawInfo>,
-    targets: Option<Vec<Image>>,
+    targets: Option<(Vec<Vec<Image>>, TargetIndexing)>,
     extent: Option<vk::Extent2D>,
     offset: Option<vk::Offset2D>,
     vs: Option<&'a str>,
     fs: Option<&'a str>,
-    verts: Option<&'a Vec<T>>,
-    vertex_indices: Option<&'a Vec<u32>>,
+    defines: Vec<(String, String)>,
+    verts: Option<&'a [T]>,
+    vertex_indices: Option<&'a [u32]>,
+    instances: Option<InstanceData<'a>>,
+    indirect: Option<(String, Vec<Buffer>, u64, u32)>,
+    mesh_draws: bool,
     vertex_push_constant_builder: Option<PushConstantBuilder>,
     fragment_push_constant_builder: Option<PushConstantBuilder>,
     vertex_descriptors_builder: Option<DescriptorsBuilder>,
     fragment_descriptors_builder: Option<DescriptorsBuilder>,
     with_depth_buffer: bool,
+    pipeline_state: PipelineState,
     clear_col: Vec4,
+    access_declarations: Vec<AccessDeclaration>,
 }
 
 pub struct GraphicsPass {
@@ -49,10 +93,22 @@ pub struct GraphicsPass {
     pub pipeline: GraphicsPipeline,
     pub framebuffers: Vec<Framebuffer>,
     pub draw_info: GraphicsPassDrawInfo,
+    pub indirect: Option<GraphicsPassIndirectInfo>,
     pub indexed: bool,
 
+    pub mesh_layout: Option<VertexLayout>, // Only mesh draw passes have a layout without a vertex buffer
+    pub draws: Vec<PassDraw>,
+
     pub clear_values: Vec<vk::ClearValue>,
     pub target_rect: vk::Rect2D,
+
+    pub targets: Vec<Vec<Image>>, // The images of each color attachment, in the order of the fragment shader's outputs
+    pub target_indexing: TargetIndexing,
+    pub extent: Option<vk::Extent2D>,
+    pub offset: Option<vk::Offset2D>,
+
+    pub access_declarations: Vec<AccessDeclaration>,
+    pub accesses: Vec<ResourceAccess>,
 }
 
 impl Pass for GraphicsPass {}
@@ -64,6 +120,7 @@ impl GraphicsPassDrawInfo {
             index_count: 0,
             instance_count: 1,
             first_vertex: 0,
+            first_index: 0,
             first_instance: 0,
             vertex_offset: 0,
         }
@@ -75,10 +132,72 @@ impl GraphicsPassDrawInfo {
             index_count: index_count as u32,
             instance_count: 1,
             first_vertex: 0,
+            first_index: 0,
             first_instance: 0,
             vertex_offset: 0,
         }
     }
+
+    pub fn instanced(vertex_count: usize, instance_count: usize) -> GraphicsPassDrawInfo {
+        GraphicsPassDrawInfo {
+            instance_count: instance_count as u32,
+            ..GraphicsPassDrawInfo::simple_vertex(vertex_count)
+        }
+    }
+
+    pub fn instanced_indexed(vertex_count: usize, index_count: usize, instance_count: usize) -> GraphicsPassDrawInfo {
+        GraphicsPassDrawInfo {
+            instance_count: instance_count as u32,
+            ..GraphicsPassDrawInfo::simple_indexed(vertex_count, index_count)
+        }
+    }
+}
+
+impl DrawItem {
+    // Draws the whole mesh once
+    pub fn new(mesh: MeshHandle) -> DrawItem {
+        let draw_info = if mesh.index_count > 0 {
+            GraphicsPassDrawInfo::simple_indexed(mesh.vertex_count as usize, mesh.index_count as usize)
+        } else {
+            GraphicsPassDrawInfo::simple_vertex(mesh.vertex_count as usize)
+        };
+
+        DrawItem {
+            mesh,
+            draw_info,
+            vertex_push_constant: None,
+            fragment_push_constant: None,
+            dynamic_offsets: Vec::new(),
+        }
+    }
+
+    pub fn draw_info(mut self, draw_info: GraphicsPassDrawInfo) -> DrawItem {
+        self.draw_info = draw_info;
+
+        self
+    }
+
+    pub fn vertex_push_constant<T>(mut self, data: &T) -> DrawItem {
+        self.vertex_push_constant = Some(DrawItem::bytes(data));
+
+        self
+    }
+
+    pub fn fragment_push_constant<T>(mut self, data: &T) -> DrawItem {
+        self.fragment_push_constant = Some(DrawItem::bytes(data));
+
+        self
+    }
+
+    pub fn dynamic_offset(mut self, stage: vk::ShaderStageFlags, binding: u32, offset: u32) -> DrawItem {
+        self.dynamic_offsets.push((stage, binding, offset));
+
+        self
+    }
+
+    fn bytes<T>(data: &T) -> Vec<u8> {
+        unsafe { std::slice::from_raw_parts(data as *const T as *const u8, std::mem::size_of::<T>()) }.to_vec()
+    }
 }
 
 impl <'a, T: VertexAttributes> GraphicsPassBuilder<'a, T> {
@@ -90,15 +209,21 @@ impl <'a, T: VertexAttributes> GraphicsPassBuilder<'a, T> {
             offset: None,
             vs: None,
             fs: None,
+            defines: Vec::new(),
             verts: None,
             vertex_indices: None,
+            instances: None,
+            indirect: None,
+            mesh_draws: false,
             vertex_push_constant_builder: None,
             fragment_push_constant_builder: None,
 
             vertex_descriptors_builder: None,
             fragment_descriptors_builder: None,
             with_depth_buffer: false,
+            pipeline_state: PipelineState::default(),
             clear_col: Vec4::zero(),
+            access_declarations: Vec::new(),
         }
     }
 
@@ -109,11 +234,24 @@ impl <'a, T: VertexAttributes> GraphicsPassBuilder<'a, T> {
     }
 
     pub fn targets(mut self, targets: &Vec<Image>) -> GraphicsPassBuilder<'a, T> {
-        self.targets = Some(targets.clone());
+        self.targets = Some((vec![targets.iter().map(Image::share).collect()], TargetIndexing::Present));
 
         self
     }
 
+    // Renders to renderer data images, one color attachment per name, so later passes can sample what was drawn
+    pub fn render_targets(mut self, names: Vec<&str>, data: &RendererData) -> Result<GraphicsPassBuilder<'a, T>, RendererError> {
+        let mut targets = Vec::<Vec<Image>>::new();
+
+        for name in names {
+            targets.push(data.get_images(name)?.iter().map(Image::share).collect());
+        }
+
+        self.targets = Some((targets, TargetIndexing::Frame));
+
+        Ok(self)
+    }
+
     pub fn extent(mut self, extent: vk::Extent2D) -> GraphicsPassBuilder<'a, T> {
         self.extent = Some(extent);
 
@@ -138,18 +276,47 @@ impl <'a, T: VertexAttributes> GraphicsPassBuilder<'a, T> {
         self
     }
 
-    pub fn verts(mut self, verts: &'a Vec<T>) -> GraphicsPassBuilder<'a, T> {
+    // Defines apply to both the vertex and fragment shader
+    pub fn define(mut self, name: &str, value: &str) -> GraphicsPassBuilder<'a, T> {
+        self.defines.push((name.to_string(), value.to_string()));
+
+        self
+    }
+
+    pub fn verts(mut self, verts: &'a [T]) -> GraphicsPassBuilder<'a, T> {
         self.verts = Some(verts);
 
         self
     }
 
-    pub fn vertex_indices(mut self, vertex_indices: &'a Vec<u32>) -> GraphicsPassBuilder<'a, T> {
+    pub fn vertex_indices(mut self, vertex_indices: &'a [u32]) -> GraphicsPassBuilder<'a, T> {
         self.vertex_indices = Some(vertex_indices);
 
         self
     }
 
+    // Per-instance vertex attributes, the pass needs vertex data as well
+    pub fn instances<U: VertexAttributes>(mut self, instances: &'a [U]) -> GraphicsPassBuilder<'a, T> {
+        self.instances = Some(InstanceData::new(instances));
+
+        self
+    }
+
+    // Draws with the commands in a renderer data buffer instead of the draw info, the buffer needs the INDIRECT_BUFFER usage.
+    // The commands are VkDrawIndexedIndirectCommand when the pass has vertex indices, and VkDrawIndirectCommand otherwise
+    pub fn draw_indirect(mut self, name: &str, data: &RendererData, offset: u64, draw_count: u32) -> Result<GraphicsPassBuilder<'a, T>, RendererError> {
+        self.indirect = Some((name.to_string(), data.get_buffers(name)?.iter().map(Buffer::share).collect(), offset, draw_count));
+
+        Ok(self)
+    }
+
+    // Draws a list of meshes with vertex type T instead of its own vertices, the list is set with set_draws and can change every frame
+    pub fn mesh_draws(mut self) -> GraphicsPassBuilder<'a, T> {
+        self.mesh_draws = true;
+
+        self
+    }
+
     pub fn vertex_push_constant<U>(mut self) -> GraphicsPassBuilder<'a, T> {
         self.vertex_push_constant_builder = Some(PushConstantBuilder::new().stage(vk::ShaderStageFlags::VERTEX).size(std::mem::size_of::<U>()));
 
@@ -174,42 +341,42 @@ impl <'a, T: VertexAttributes> GraphicsPassBuilder<'a, T> {
         self
     }
 
-    pub fn vertex_descriptors(mut self, create_refs: Vec<CreationReference>, data: &RendererData) -> GraphicsPassBuilder<'a, T> {
+    pub fn vertex_descriptors(mut self, create_refs: Vec<CreationReference>, data: &RendererData) -> Result<GraphicsPassBuilder<'a, T>, RendererError> {
         let mut descriptors_builder = DescriptorsBuilder::new()
             .stage(vk::ShaderStageFlags::VERTEX)
             .count(data.count);
 
         for create_ref in create_refs {
             match create_ref {
-                CreationReference::Uniform(name) => { descriptors_builder = descriptors_builder.add_uniform_simple(data.get_buffers(&name)); },
-                CreationReference::Storage(name) => { descriptors_builder = descriptors_builder.add_storage_simple(data.get_buffers(&name)); },
-                CreationReference::Image(name) => { descriptors_builder = descriptors_builder.add_image_simple(data.get_images(&name)); },
-                CreationReference::Sampler(name) => { descriptors_builder = descriptors_builder.add_sampler_simple(data.get_images(&name)); },
+                CreationReference::Uniform(name) => { descriptors_builder = descriptors_builder.add_uniform_simple(data.get_buffers(&name)?); },
+                CreationReference::Storage(name) => { descriptors_builder = descriptors_builder.add_storage_simple(data.get_buffers(&name)?); },
+                CreationReference::Image(name) => { descriptors_builder = descriptors_builder.add_image_simple(data.get_images(&name)?); },
+                CreationReference::Sampler(name) => { descriptors_builder = descriptors_builder.add_sampler_simple(data.get_images(&name)?); },
             }
         }
 
         self.vertex_descriptors_builder = Some(descriptors_builder);
         
-        self
+        Ok(self)
     }
 
-    pub fn fragment_descriptors(mut self, create_refs: Vec<CreationReference>, data: &RendererData) -> GraphicsPassBuilder<'a, T> {
+    pub fn fragment_descriptors(mut self, create_refs: Vec<CreationReference>, data: &RendererData) -> Result<GraphicsPassBuilder<'a, T>, RendererError> {
         let mut descriptors_builder = DescriptorsBuilder::new()
             .stage(vk::ShaderStageFlags::FRAGMENT)
             .count(data.count);
 
         for create_ref in create_refs {
             match create_ref {
-                CreationReference::Uniform(name) => { descriptors_builder = descriptors_builder.add_uniform_simple(data.get_buffers(&name)); },
-                CreationReference::Storage(name) => { descriptors_builder = descriptors_builder.add_storage_simple(data.get_buffers(&name)); },
-                CreationReference::Image(name) => { descriptors_builder = descriptors_builder.add_image_simple(data.get_images(&name)); },
-                CreationReference::Sampler(name) => { descriptors_builder = descriptors_builder.add_sampler_simple(data.get_images(&name)); },
+                CreationReference::Uniform(name) => { descriptors_builder = descriptors_builder.add_uniform_simple(data.get_buffers(&name)?); },
+                CreationReference::Storage(name) => { descriptors_builder = descriptors_builder.add_storage_simple(data.get_buffers(&name)?); },
+                CreationReference::Image(name) => { descriptors_builder = descriptors_builder.add_image_simple(data.get_images(&name)?); },
+                CreationReference::Sampler(name) => { descriptors_builder = descriptors_builder.add_sampler_simple(data.get_images(&name)?); },
             }
         }
 
         self.fragment_descriptors_builder = Some(descriptors_builder);
         
-        self
+        Ok(self)
     }
 
     pub fn with_depth_buffer(mut self) -> GraphicsPassBuilder<'a, T> {
@@ -218,79 +385,222 @@ impl <'a, T: VertexAttributes> GraphicsPassBuilder<'a, T> {
         self
     }
 
+    pub fn topology(mut self, topology: vk::PrimitiveTopology) -> GraphicsPassBuilder<'a, T> {
+        self.pipeline_state.topology = topology;
+
+        self
+    }
+
+    pub fn cull_mode(mut self, cull_mode: vk::CullModeFlags, front_face: vk::FrontFace) -> GraphicsPassBuilder<'a, T> {
+        self.pipeline_state.cull_mode = cull_mode;
+        self.pipeline_state.front_face = front_face;
+
+        self
+    }
+
+    // Needs the fillModeNonSolid device feature
+    pub fn wireframe(mut self) -> GraphicsPassBuilder<'a, T> {
+        self.pipeline_state.polygon_mode = vk::PolygonMode::LINE;
+
+        self
+    }
+
+    pub fn blend_mode(mut self, blend_mode: BlendMode) -> GraphicsPassBuilder<'a, T> {
+        self.pipeline_state.blend_mode = blend_mode;
+
+        self
+    }
+
+    // Only used when the pass has a depth buffer
+    pub fn depth_test(mut self, test: bool, write: bool, compare_op: vk::CompareOp) -> GraphicsPassBuilder<'a, T> {
+        self.pipeline_state.depth_test = test;
+        self.pipeline_state.depth_write = write;
+        self.pipeline_state.depth_compare_op = compare_op;
+
+        self
+    }
+
+    pub fn depth_bias(mut self, constant_factor: f32, slope_factor: f32, clamp: f32) -> GraphicsPassBuilder<'a, T> {
+        self.pipeline_state.depth_bias = Some(DepthBias { constant_factor, slope_factor, clamp });
+
+        self
+    }
+
+    // Keeps what earlier passes drew to the targets instead of clearing them, the depth buffer belongs to the pass so it is always cleared
+    pub fn load_existing(mut self) -> GraphicsPassBuilder<'a, T> {
+        self.pipeline_state.load_existing = true;
+
+        self
+    }
+
+    // Rounded down to the highest count the device supports, falling back to no multisampling
+    pub fn msaa(mut self, samples: u32) -> GraphicsPassBuilder<'a, T> {
+        self.pipeline_state.samples = samples;
+
+        self
+    }
+
     pub fn clear_col(mut self, clear_col: Vec4) -> GraphicsPassBuilder<'a, T> {
         self.clear_col = clear_col;
 
         self
     }
 
-    pub unsafe fn build(self, c: &Core, d: &Device) -> GraphicsPass {
-        GraphicsPass::new(c, d, self.targets.expect("Error: Graphics pass builder has no targets"), self.extent, self.offset, self.verts, self.vertex_indices, self.vertex_descriptors_builder, self.fragment_descriptors_builder, self.vertex_push_constant_builder, self.fragment_push_constant_builder, self.vs.expect("Error: Graphics pass builder has no vertex shader"), self.fs.expect("Error: Graphics pass builder has no fragment shader"), self.with_depth_buffer, self.clear_col, self.draw_info.expect("Error: Graphics pass builder has no draw info"))
+    pub fn reads(mut self, create_ref: CreationReference) -> GraphicsPassBuilder<'a, T> {
+        AccessDeclaration::declare(&mut self.access_declarations, create_ref, AccessIntent::Read);
+
+        self
     }
-}
 
-impl GraphicsPass {
-    pub unsafe fn new<T: VertexAttributes>(c: &Core, d: &Device, targets: Vec<Image>, extent: Option<vk::Extent2D>, offset: Option<vk::Offset2D>, verts: Option<&Vec<T>>, indices: Option<&Vec<u32>>, vertex_descriptors_builder: Option<DescriptorsBuilder>, fragment_descriptors_builder: Option<DescriptorsBuilder>, vertex_push_constant_builder: Option<PushConstantBuilder>, fragment_push_constant_builder: Option<PushConstantBuilder>, vs: &str, fs: &str, with_depth_buffer: bool, clear_col: Vec4, draw_info: GraphicsPassDrawInfo) -> GraphicsPass {
-        let vertex_descriptors = match vertex_descriptors_builder {
-            Some(de_b) => Some(de_b.build(c, d)),
-            None => None
-        };
-        
-        let fragment_descriptors = match fragment_descriptors_builder {
-            Some(de_b) => Some(de_b.build(c, d)),
-            None => None
-        };
+    pub fn writes(mut self, create_ref: CreationReference) -> GraphicsPassBuilder<'a, T> {
+        AccessDeclaration::declare(&mut self.access_declarations, create_ref, AccessIntent::Write);
 
-        let vertex_descriptor_set_layout = match vertex_descriptors.as_ref() {
-            Some(de) => Some(de.set_layout),
-            None => None
+        self
+    }
+
+    pub unsafe fn build(self, c: &Core, d: &Device) -> Result<GraphicsPass, RendererError> {
+        let access_declarations = self.access_declarations.clone();
+
+        let (targets, target_indexing) = self.targets.ok_or(RendererError::missing("GraphicsPassBuilder", "targets"))?;
+        let vs = ShaderSource::new(self.vs.ok_or(RendererError::missing("GraphicsPassBuilder", "vertex shader"))?, &self.defines);
+        let fs = ShaderSource::new(self.fs.ok_or(RendererError::missing("GraphicsPassBuilder", "fragment shader"))?, &self.defines);
+
+        let indirect = match self.indirect {
+            Some((name, buffers, offset, draw_count)) => Some(GraphicsPassBuilder::<T>::indirect_info(d, name, buffers, offset, draw_count, self.vertex_indices.is_some())?),
+            None => None,
         };
 
-        let fragment_descriptor_set_layout = match fragment_descriptors.as_ref() {
-            Some(de) => Some(de.set_layout),
-            None => None
+        if self.mesh_draws && (self.verts.is_some() || self.vertex_indices.is_some() || indirect.is_some()) {
+            return Err(RendererError::InvalidPassConfig("Mesh draw passes can't have their own vertices, indices or indirect draws"));
+        }
+
+        if self.vertex_indices.is_some() && self.verts.is_none() {
+            return Err(RendererError::InvalidPassConfig("Vertex indices need vertex data"));
+        }
+
+        // Indirect passes take their draw parameters from the buffer, and mesh draw passes from each draw item
+        let draw_info = match (self.draw_info, indirect.is_some() || self.mesh_draws) {
+            (Some(draw_info), _) => draw_info,
+            (None, true) => GraphicsPassDrawInfo::simple_vertex(0),
+            (None, false) => return Err(RendererError::missing("GraphicsPassBuilder", "draw info")),
         };
 
+        if let Some(instances) = &self.instances {
+            if self.verts.is_none() {
+                return Err(RendererError::InvalidPassConfig("Instance data needs vertex data"));
+            }
+
+            if indirect.is_none() && draw_info.first_instance as usize + draw_info.instance_count as usize > instances.count {
+                return Err(RendererError::InvalidPassConfig("Draw info has more instances than the instance data"));
+            }
+        }
+
+        let mut pass = GraphicsPass::new(c, d, targets, target_indexing, self.extent, self.offset, self.verts, self.vertex_indices, self.instances.as_ref(), self.mesh_draws, self.vertex_descriptors_builder, self.fragment_descriptors_builder, self.vertex_push_constant_builder, self.fragment_push_constant_builder, &vs, &fs, self.with_depth_buffer, self.pipeline_state, self.clear_col, draw_info)?;
+        pass.access_declarations = access_declarations;
+        pass.indirect = indirect;
+
+        Ok(pass)
+    }
+
+    // Drawing more than once needs multiDrawIndirect, and the commands have to fit in every frame's copy of the buffer
+    fn indirect_info(d: &Device, name: String, buffers: Vec<Buffer>, offset: u64, draw_count: u32, indexed: bool) -> Result<GraphicsPassIndirectInfo, RendererError> {
+        if draw_count > 1 && d.features.multi_draw_indirect == 0 {
+            return Err(RendererError::UnsupportedFeature("multiDrawIndirect"));
+        }
+
+        if offset % 4 != 0 {
+            return Err(RendererError::InvalidPassConfig("Indirect draw offsets must be a multiple of 4"));
+        }
+
+        let stride = if indexed { std::mem::size_of::<vk::DrawIndexedIndirectCommand>() } else { std::mem::size_of::<vk::DrawIndirectCommand>() } as u32;
+        let required = offset + stride as u64 * draw_count as u64;
+
+        if let Some(buffer) = buffers.iter().find(|b| b.size < required) {
+            return Err(RendererError::BufferOverflow { name, size: buffer.size, required });
+        }
+
+        Ok(GraphicsPassIndirectInfo {
+            buffers: buffers.iter().map(|b| b.buffer).collect(),
+            offset,
+            draw_count,
+            stride,
+        })
+    }
+}
+
+impl GraphicsPass {
+    pub unsafe fn new<T: VertexAttributes>(c: &Core, d: &Device, targets: Vec<Vec<Image>>, target_indexing: TargetIndexing, extent: Option<vk::Extent2D>, offset: Option<vk::Offset2D>, verts: Option<&[T]>, indices: Option<&[u32]>, instances: Option<&InstanceData>, mesh_draws: bool, vertex_descriptors_builder: Option<DescriptorsBuilder>, fragment_descriptors_builder: Option<DescriptorsBuilder>, vertex_push_constant_builder: Option<PushConstantBuilder>, fragment_push_constant_builder: Option<PushConstantBuilder>, vs: &ShaderSource, fs: &ShaderSource, with_depth_buffer: bool, pipeline_state: PipelineState, clear_col: Vec4, draw_info: GraphicsPassDrawInfo) -> Result<GraphicsPass, RendererError> {
+        GraphicsPass::check_targets(&targets)?;
+
+        // Push constants don't create anything, so they're built first and nothing has to be cleaned up if they fail
         let vertex_push_constant = match vertex_push_constant_builder {
-            Some(builder) => Some(builder.build()),
+            Some(builder) => Some(builder.build()?),
             None => None
         };
 
         let fragment_push_constant = match fragment_push_constant_builder {
-            Some(builder) => Some(builder.build()),
+            Some(builder) => Some(builder.build()?),
             None => None
         };
 
-        let vertex_buffer = match verts {
-            Some(v) => Some(VertexBuffer::new(c, d, v, indices)),
+        let vertex_descriptors = match vertex_descriptors_builder {
+            Some(de_b) => Some(de_b.build(c, d)?),
             None => None
         };
-
-        let target_extent = match extent {
-            Some(e) => e,
-            None => vk::Extent2D { width: targets[0].width, height: targets[0].height },
+        
+        let fragment_descriptors = match fragment_descriptors_builder.map(|de_b| de_b.build(c, d)).transpose() {
+            Ok(fragment_descriptors) => fragment_descriptors,
+            Err(e) => {
+                GraphicsPass::destroy_partial(d, vertex_descriptors.as_ref(), None, None, None);
+                return Err(e);
+            },
         };
 
-        let offset = match offset {
-            Some(o) => o,
-            None => vk::Offset2D { x: 0, y: 0 },
+        let mut vertex_buffer = match verts.map(|v| VertexBuffer::new(c, d, v, indices)).transpose() {
+            Ok(vertex_buffer) => vertex_buffer,
+            Err(e) => {
+                GraphicsPass::destroy_partial(d, vertex_descriptors.as_ref(), fragment_descriptors.as_ref(), None, None);
+                return Err(e);
+            },
         };
 
-        let target_rect = vk::Rect2D { extent: target_extent, offset };
+        if let (Some(vertex_buffer), Some(instances)) = (&mut vertex_buffer, instances) {
+            if let Err(e) = vertex_buffer.add_instances(c, d, instances) {
+                GraphicsPass::destroy_partial(d, vertex_descriptors.as_ref(), fragment_descriptors.as_ref(), Some(vertex_buffer), None);
+                return Err(e);
+            }
+        }
+
+        let mesh_layout = if mesh_draws { Some(VertexLayout::new::<T>()) } else { None };
+        let vertex_layout = vertex_buffer.as_ref().map(|b| &b.layout).or(mesh_layout.as_ref());
+
+        let target_rect = GraphicsPass::get_target_rect(&targets, extent, offset);
         
-        let pipeline = GraphicsPipeline::new(c, d, target_rect, vertex_buffer.as_ref(), vertex_descriptor_set_layout, fragment_descriptor_set_layout, vertex_push_constant.as_ref(), fragment_push_constant.as_ref(), vs, fs, targets[0].layout, with_depth_buffer);
+        let pipeline = match GraphicsPipeline::new(c, d, target_rect, vertex_layout, vertex_descriptors.as_ref(), fragment_descriptors.as_ref(), vertex_push_constant.as_ref(), fragment_push_constant.as_ref(), vs, fs, &targets.iter().map(|t| t[0].share()).collect(), with_depth_buffer, pipeline_state) {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                GraphicsPass::destroy_partial(d, vertex_descriptors.as_ref(), fragment_descriptors.as_ref(), vertex_buffer.as_ref(), None);
+                return Err(e);
+            },
+        };
 
-        let framebuffers = Framebuffer::new_many(d, &pipeline, &targets, extent);
+        let framebuffers = match Framebuffer::new_many(d, &pipeline, &targets, extent) {
+            Ok(framebuffers) => framebuffers,
+            Err(e) => {
+                GraphicsPass::destroy_partial(d, vertex_descriptors.as_ref(), fragment_descriptors.as_ref(), vertex_buffer.as_ref(), Some(&pipeline));
+                return Err(e);
+            },
+        };
 
-        let indexed = indices.is_some();
+        let indexed = vertex_buffer.as_ref().is_some_and(|v| v.index_buffer.is_some());
 
-        let mut clear_values = vec![vk::ClearValue { color: vk::ClearColorValue { float32: [clear_col.x, clear_col.y, clear_col.z, clear_col.w] } }];
+        let mut clear_values = vec![vk::ClearValue { color: vk::ClearColorValue { float32: [clear_col.x, clear_col.y, clear_col.z, clear_col.w] } }; targets.len()];
 
         if with_depth_buffer {
             clear_values.push(vk::ClearValue { depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 } });
         }
 
-        GraphicsPass {
+        Ok(GraphicsPass {
             vertex_push_constant,
             fragment_push_constant,
             vertex_descriptors,
@@ -299,9 +609,254 @@ impl GraphicsPass {
             pipeline,
             framebuffers,
             draw_info,
+            indirect: None,
             indexed,
+            mesh_layout,
+            draws: Vec::new(),
             clear_values,
             target_rect,
+
+            targets,
+            target_indexing,
+            extent,
+            offset,
+
+            access_declarations: Vec::new(),
+            accesses: Vec::new(),
+        })
+    }
+
+    // Releases what new has built so far when a later step fails
+    unsafe fn destroy_partial(d: &Device, vertex_descriptors: Option<&Descriptors>, fragment_descriptors: Option<&Descriptors>, vertex_buffer: Option<&VertexBuffer>, pipeline: Option<&GraphicsPipeline>) {
+        if let Some(pipeline) = pipeline {
+            pipeline.destroy(d);
+        }
+
+        for descriptors in vertex_descriptors.iter().chain(fragment_descriptors.iter()) {
+            descriptors.destroy(d);
+        }
+
+        if let Some(vertex_buffer) = vertex_buffer {
+            vertex_buffer.destroy(d);
+        }
+    }
+
+    /// Targets are owned by the renderer data or swapchain they came from
+    ///
+    /// # Safety
+    /// The pass mustn't be used by a frame still in flight.
+    pub unsafe fn destroy(&self, d: &Device) {
+        for framebuffer in &self.framebuffers {
+            framebuffer.destroy(d);
+        }
+
+        self.pipeline.destroy(d);
+
+        for descriptors in self.vertex_descriptors.iter().chain(self.fragment_descriptors.iter()) {
+            descriptors.destroy(d);
+        }
+
+        if let Some(vertex_buffer) = &self.vertex_buffer {
+            vertex_buffer.destroy(d);
+        }
+    }
+
+    pub fn infer_accesses(&mut self, data: &RendererData) -> Result<(), RendererError> {
+        self.accesses = ResourceAccess::from_targets(&self.targets, data);
+
+        if let Some(descriptors) = &self.vertex_descriptors {
+            self.accesses.append(&mut ResourceAccess::from_descriptors(descriptors, ShaderType::Vertex, data));
+        }
+
+        if let Some(descriptors) = &self.fragment_descriptors {
+            self.accesses.append(&mut ResourceAccess::from_descriptors(descriptors, ShaderType::Fragment, data));
+        }
+
+        ResourceAccess::apply_declarations(&mut self.accesses, &self.access_declarations, ShaderType::Fragment, data)?;
+
+        // Reading the draw commands isn't a shader access, so declarations don't replace it
+        if let Some(indirect) = &self.indirect {
+            if let Some(index) = data.find_buffer_refs(indirect.buffers[0]) {
+                self.accesses.push(ResourceAccess::new(ResourceReference::Buffer(index), AccessType::IndirectRead, ShaderType::Vertex));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Replaces the draws recorded each frame. Items are checked against the pass here, only a mesh removed since can make them invalid
+    pub fn set_draws(&mut self, d: &Device, data: &RendererData, items: Vec<DrawItem>) -> Result<(), RendererError> {
+        let mesh_layout = self.mesh_layout.as_ref().ok_or(RendererError::InvalidPassConfig("Pass wasn't built for mesh draws"))?;
+
+        let mut draws = Vec::<PassDraw>::with_capacity(items.len());
+
+        for item in items {
+            if !data.get_mesh_buffer(item.mesh)?.layout.matches(mesh_layout) {
+                return Err(RendererError::InvalidPassConfig("Mesh vertex layout doesn't match the pass"));
+            }
+
+            GraphicsPass::check_draw_push_constant(self.vertex_push_constant.as_ref(), item.vertex_push_constant.as_deref())?;
+            GraphicsPass::check_draw_push_constant(self.fragment_push_constant.as_ref(), item.fragment_push_constant.as_deref())?;
+
+            let vertex_offsets = GraphicsPass::resolve_draw_offsets(d, self.vertex_descriptors.as_ref(), &item.dynamic_offsets, vk::ShaderStageFlags::VERTEX)?;
+            let fragment_offsets = GraphicsPass::resolve_draw_offsets(d, self.fragment_descriptors.as_ref(), &item.dynamic_offsets, vk::ShaderStageFlags::FRAGMENT)?;
+
+            draws.push(PassDraw { item, vertex_offsets, fragment_offsets });
+        }
+
+        self.draws = draws;
+
+        Ok(())
+    }
+
+    fn check_draw_push_constant(push_constant: Option<&PushConstant>, data: Option<&[u8]>) -> Result<(), RendererError> {
+        match (push_constant, data) {
+            (None, Some(_)) => Err(RendererError::InvalidPassConfig("Draw has push constant data for a stage the pass has no push constant for")),
+            (Some(push_constant), Some(data)) if data.len() > push_constant.size => Err(RendererError::InvalidPassConfig("Draw push constant data is larger than the pass's push constant")),
+            _ => Ok(()),
+        }
+    }
+
+    fn resolve_draw_offsets(d: &Device, descriptors: Option<&Descriptors>, dynamic_offsets: &[(vk::ShaderStageFlags, u32, u32)], stage: vk::ShaderStageFlags) -> Result<Vec<(usize, u32)>, RendererError> {
+        dynamic_offsets.iter().filter(|(s, _, _)| *s == stage).map(|(_, binding, offset)| {
+            let descriptors = descriptors.ok_or(RendererError::InvalidPassConfig("Pass has no descriptors for that stage"))?;
+
+            Ok((descriptors.dynamic_offset_index(d, *binding, *offset)?, *offset))
+        }).collect()
+    }
+
+    /// Recorded inside the render pass, after the pipeline and the pass's own descriptors have been bound. Draws of meshes that no longer exist are skipped
+    /// A draw without its own push constant data uses the pass's, so data from the draw before it isn't left in place
+    ///
+    /// # Safety
+    /// `b` must be inside this pass's render pass with its pipeline bound.
+    pub unsafe fn record_draws(&self, d: &Device, b: vk::CommandBuffer, resources: &RendererData, i: usize) {
+        let fragment_set = self.vertex_descriptors.is_some() as u32;
+        let mut overridden = false;
+
+        for draw in &self.draws {
+            let vertex_buffer = match resources.get_mesh_buffer(draw.item.mesh) {
+                Ok(vertex_buffer) => vertex_buffer,
+                Err(_) => continue,
+            };
+
+            if let Some(push_constant) = &self.vertex_push_constant {
+                d.device.cmd_push_constants(b, self.pipeline.pipeline_layout, push_constant.stage, 0, draw.item.vertex_push_constant.as_ref().unwrap_or(&push_constant.data));
+            }
+
+            if let Some(push_constant) = &self.fragment_push_constant {
+                d.device.cmd_push_constants(b, self.pipeline.pipeline_layout, push_constant.stage, 0, draw.item.fragment_push_constant.as_ref().unwrap_or(&push_constant.data));
+            }
+
+            // The pass's offsets are bound again after a draw that replaced them
+            let overrides = !draw.vertex_offsets.is_empty() || !draw.fragment_offsets.is_empty();
+
+            if overrides || overridden {
+                if let Some(descriptors) = &self.vertex_descriptors {
+                    descriptors.bind_with_offsets(d, &b, vk::PipelineBindPoint::GRAPHICS, &self.pipeline.pipeline_layout, 0, i, &GraphicsPass::apply_draw_offsets(descriptors, &draw.vertex_offsets));
+                }
+
+                if let Some(descriptors) = &self.fragment_descriptors {
+                    descriptors.bind_with_offsets(d, &b, vk::PipelineBindPoint::GRAPHICS, &self.pipeline.pipeline_layout, fragment_set, i, &GraphicsPass::apply_draw_offsets(descriptors, &draw.fragment_offsets));
+                }
+            }
+
+            overridden = overrides;
+
+            let info = &draw.item.draw_info;
+            d.device.cmd_bind_vertex_buffers(b, 0, &[vertex_buffer.buffer.buffer], &[0]);
+
+            match &vertex_buffer.index_buffer {
+                Some(index_buffer) => {
+                    d.device.cmd_bind_index_buffer(b, index_buffer.buffer, 0, vk::IndexType::UINT32);
+                    d.device.cmd_draw_indexed(b, info.index_count, info.instance_count, info.first_index, info.vertex_offset, info.first_instance);
+                },
+                None => d.device.cmd_draw(b, info.vertex_count, info.instance_count, info.first_vertex, info.first_instance),
+            }
+        }
+    }
+
+    fn apply_draw_offsets(descriptors: &Descriptors, draw_offsets: &[(usize, u32)]) -> Vec<u32> {
+        let mut dynamic_offsets = descriptors.dynamic_offsets.clone();
+
+        for (index, offset) in draw_offsets {
+            dynamic_offsets[*index] = *offset;
+        }
+
+        dynamic_offsets
+    }
+
+    // Every attachment needs an image for each framebuffer, and they all have to be the same size
+    fn check_targets(targets: &[Vec<Image>]) -> Result<(), RendererError> {
+        if targets.is_empty() || targets[0].is_empty() {
+            return Err(RendererError::InvalidPassConfig("Graphics passes need at least one target"));
         }
+
+        if targets.iter().any(|t| t.len() != targets[0].len()) {
+            return Err(RendererError::InvalidPassConfig("Render targets must have the same number of images"));
+        }
+
+        if targets.iter().flatten().any(|t| t.width != targets[0][0].width || t.height != targets[0][0].height) {
+            return Err(RendererError::InvalidPassConfig("Render targets must all be the same size"));
+        }
+
+        if targets.iter().flatten().any(|t| t.aspect() != vk::ImageAspectFlags::COLOR) {
+            return Err(RendererError::InvalidPassConfig("Render targets must be color images"));
+        }
+
+        Ok(())
+    }
+
+    fn get_target_rect(targets: &[Vec<Image>], extent: Option<vk::Extent2D>, offset: Option<vk::Offset2D>) -> vk::Rect2D {
+        let target_extent = match extent {
+            Some(e) => e,
+            None => vk::Extent2D { width: targets[0][0].width, height: targets[0][0].height },
+        };
+
+        let target_offset = match offset {
+            Some(o) => o,
+            None => vk::Offset2D { x: 0, y: 0 },
+        };
+
+        vk::Rect2D { extent: target_extent, offset: target_offset }
+    }
+
+    // Viewport and scissor are dynamic, so moving the pass within its targets doesn't need a new pipeline
+    pub fn set_target_rect(&mut self, extent: Option<vk::Extent2D>, offset: Option<vk::Offset2D>) {
+        self.extent = extent;
+        self.offset = offset;
+
+        self.target_rect = GraphicsPass::get_target_rect(&self.targets, self.extent, self.offset);
+        self.pipeline.set_target_rect(self.target_rect);
+    }
+
+    pub fn renders_to(&self, images: &[Image]) -> bool {
+        self.targets.iter().flatten().any(|target| images.iter().any(|image| image.image == target.image))
+    }
+
+    pub fn framebuffer(&self, frame_index: usize, present_index: usize) -> &Framebuffer {
+        match self.target_indexing {
+            TargetIndexing::Present => &self.framebuffers[present_index],
+            TargetIndexing::Frame => &self.framebuffers[frame_index],
+        }
+    }
+
+    /// Rebuilds everything sized from the targets, used when the swapchain is recreated. Only passes drawing to the swapchain are recreated, so there is one attachment
+    ///
+    /// # Safety
+    /// The device must be idle, the old framebuffers are destroyed straight away.
+    pub unsafe fn recreate_targets(&mut self, c: &Core, d: &Device, targets: &[Image]) -> Result<(), RendererError> {
+        for framebuffer in &self.framebuffers {
+            framebuffer.destroy(d);
+        }
+
+        self.targets = vec![targets.iter().map(Image::share).collect()];
+        self.set_target_rect(self.extent, self.offset);
+
+        self.pipeline.recreate_attachments(c, d, self.target_rect.extent)?;
+
+        self.framebuffers = Framebuffer::new_many(d, &self.pipeline, &self.targets, self.extent)?;
+
+        Ok(())
     }
 }
\ No newline at end of file
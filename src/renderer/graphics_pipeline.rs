@@ -1,13 +1,49 @@
 use std::ffi::CString;
+use std::path::PathBuf;
 
 use ash::vk::{self, RenderPass};
 
 use crate::renderer::{core::Core, image::ImageBuilder};
 use crate::renderer::device::Device;
-use crate::renderer::shader::Shader;
+use crate::renderer::descriptors::Descriptors;
+use crate::renderer::error::RendererError;
+use crate::renderer::shader::{Shader, ShaderSource};
+use crate::renderer::shader_reflection::StageInterface;
 use crate::renderer::image::Image;
 use crate::renderer::push_constant::PushConstant;
-use crate::renderer::vertex_buffer::VertexBuffer;
+use crate::renderer::vertex_buffer::VertexLayout;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum BlendMode {
+    Opaque,
+    Alpha, // Blends by the source alpha, for overlays
+    Additive,
+}
+
+#[derive(Copy, Clone)]
+pub struct DepthBias {
+    pub constant_factor: f32,
+    pub slope_factor: f32,
+    pub clamp: f32,
+}
+
+// Fixed function state set through GraphicsPassBuilder, the defaults are what every pass used before it was configurable
+#[derive(Copy, Clone)]
+pub struct PipelineState {
+    pub topology: vk::PrimitiveTopology,
+    pub polygon_mode: vk::PolygonMode,
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    pub blend_mode: BlendMode,
+
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub depth_compare_op: vk::CompareOp,
+    pub depth_bias: Option<DepthBias>,
+
+    pub load_existing: bool, // Draws over what's already in the targets instead of clearing them
+    pub samples: u32, // Requested sample count, the pipeline uses the closest one the device supports
+}
 
 pub struct GraphicsPipeline {
     pub pipeline: vk::Pipeline,
@@ -18,37 +54,113 @@ pub struct GraphicsPipeline {
     pub scissor: vk::Rect2D,
     
     pub depth_image: Option<Image>,
-}
+    pub color_images: Vec<Image>, // Multisampled attachments that are resolved into the targets, only used with MSAA
+    pub samples: vk::SampleCountFlags,
 
-impl GraphicsPipeline {
-    pub unsafe fn new(c: &Core, d: &Device, target_rect: vk::Rect2D, vertex_buffer: Option<&VertexBuffer>, vertex_descriptor_set_layout: Option<vk::DescriptorSetLayout>, fragment_descriptor_set_layout: Option<vk::DescriptorSetLayout>, vertex_push_constant: Option<&PushConstant>, fragment_push_constant: Option<&PushConstant>, vs: &str, fs: &str, target_layout: vk::ImageLayout, with_depth_buffer: bool) -> GraphicsPipeline {
-        let vert_shader = Shader::new(d, vs, vk::ShaderStageFlags::VERTEX);
-        let frag_shader = Shader::new(d, fs, vk::ShaderStageFlags::FRAGMENT);
+    pub vs: ShaderSource,
+    pub fs: ShaderSource,
+    pub dependencies: Vec<PathBuf>,
 
-        let shaders = vec![vert_shader, frag_shader];
+    color_attachment_count: usize,
 
-        let shader_entry_name = CString::new("main").unwrap();
+    vertex_attribute_descs: Vec<vk::VertexInputAttributeDescription>,
+    vertex_binding_descs: Vec<vk::VertexInputBindingDescription>,
 
-        let mut shader_stage_cis: Vec<vk::PipelineShaderStageCreateInfo> = Vec::new();
+    vertex_interface: StageInterface,
+    fragment_interface: StageInterface,
 
-        for s in shaders.iter() {
-            let shader_stage_ci = vk::PipelineShaderStageCreateInfo {
-                module: s.module,
-                p_name: shader_entry_name.as_ptr(),
-                stage: s.flags,
-                ..Default::default()
-            };
+    state: PipelineState,
+}
 
-            shader_stage_cis.push(shader_stage_ci);
+impl Default for PipelineState {
+    fn default() -> PipelineState {
+        PipelineState {
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            polygon_mode: vk::PolygonMode::FILL,
+            cull_mode: vk::CullModeFlags::NONE,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            blend_mode: BlendMode::Opaque,
+
+            depth_test: true,
+            depth_write: true,
+            depth_compare_op: vk::CompareOp::LESS,
+            depth_bias: None,
+
+            load_existing: false,
+            samples: 1,
         }
+    }
+}
 
-        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
-        let dynamic_state_ci = vk::PipelineDynamicStateCreateInfo::builder()
-            .dynamic_states(&dynamic_states);
+impl BlendMode {
+    fn attachment_state(&self) -> vk::PipelineColorBlendAttachmentState {
+        let builder = vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(
+                vk::ColorComponentFlags::R
+                | vk::ColorComponentFlags::G
+                | vk::ColorComponentFlags::B
+                | vk::ColorComponentFlags::A
+            );
+
+        match self {
+            BlendMode::Opaque => builder.blend_enable(false).build(),
+            BlendMode::Alpha => builder
+                .blend_enable(true)
+                .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+                .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+                .color_blend_op(vk::BlendOp::ADD)
+                .src_alpha_blend_factor(vk::BlendFactor::ONE)
+                .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+                .alpha_blend_op(vk::BlendOp::ADD)
+                .build(),
+            BlendMode::Additive => builder
+                .blend_enable(true)
+                .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+                .dst_color_blend_factor(vk::BlendFactor::ONE)
+                .color_blend_op(vk::BlendOp::ADD)
+                .src_alpha_blend_factor(vk::BlendFactor::ONE)
+                .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+                .alpha_blend_op(vk::BlendOp::ADD)
+                .build(),
+        }
+    }
+}
 
-        let input_assembly_state_ci = vk::PipelineInputAssemblyStateCreateInfo::builder()
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
-            .primitive_restart_enable(false);
+impl GraphicsPipeline {
+    pub unsafe fn new(c: &Core, d: &Device, target_rect: vk::Rect2D, vertex_layout: Option<&VertexLayout>, vertex_descriptors: Option<&Descriptors>, fragment_descriptors: Option<&Descriptors>, vertex_push_constant: Option<&PushConstant>, fragment_push_constant: Option<&PushConstant>, vs: &ShaderSource, fs: &ShaderSource, targets: &Vec<Image>, with_depth_buffer: bool, state: PipelineState) -> Result<GraphicsPipeline, RendererError> {
+        if state.polygon_mode != vk::PolygonMode::FILL && d.features.fill_mode_non_solid == 0 {
+            return Err(RendererError::UnsupportedFeature("fillModeNonSolid"));
+        }
+
+        let samples = d.sample_count(state.samples, with_depth_buffer);
+        let multisampled = samples != vk::SampleCountFlags::TYPE_1;
+
+        // The multisampled attachment starts empty every frame, so there's nothing to draw over
+        if multisampled && state.load_existing {
+            return Err(RendererError::InvalidPassConfig("MSAA passes can't load existing target contents"));
+        }
+
+        let (vertex_attribute_descs, vertex_binding_descs) = match vertex_layout {
+            Some(layout) => {
+                (layout.attrib_descs.clone(), layout.binding_descs.clone())
+            },
+            None => (vec![], vec![])
+        };
+
+        // Sets are numbered in the order they're added to the pipeline layout below
+        let vertex_interface = StageInterface {
+            descriptor_set: vertex_descriptors.map(|de| (0, de.descriptor_types())),
+            push_constant_size: vertex_push_constant.map(|pc| pc.size),
+            vertex_attributes: vertex_attribute_descs.clone(),
+        };
+
+        let fragment_interface = StageInterface {
+            descriptor_set: fragment_descriptors.map(|de| (vertex_descriptors.is_some() as u32, de.descriptor_types())),
+            push_constant_size: fragment_push_constant.map(|pc| pc.size),
+            vertex_attributes: Vec::new(),
+        };
+
+        let shaders = GraphicsPipeline::create_shaders(d, vs, fs, &vertex_interface, &fragment_interface)?;
 
         let viewport = vk::Viewport::builder()
             .x(target_rect.offset.x as f32)
@@ -61,43 +173,6 @@ impl GraphicsPipeline {
 
         let scissor = target_rect;
 
-        let viewport_state_ci = vk::PipelineViewportStateCreateInfo::builder()
-            .viewport_count(1)
-            .scissor_count(1)
-            .viewports(&[viewport])
-            .scissors(&[scissor])
-            .build();
-
-        let rasterization_state_ci = vk::PipelineRasterizationStateCreateInfo::builder()
-            .depth_clamp_enable(false)
-            .rasterizer_discard_enable(false)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .line_width(1.0)
-            .cull_mode(vk::CullModeFlags::NONE)
-            //.cull_mode(vk::CullModeFlags::BACK)
-            //.front_face(vk::FrontFace::CLOCKWISE)
-            .depth_bias_enable(false);
-
-        let multisample_state_ci = vk::PipelineMultisampleStateCreateInfo::builder()
-            .sample_shading_enable(false)
-            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
-
-        let color_blend_attachment_states = [vk::PipelineColorBlendAttachmentState::builder()
-            .color_write_mask(
-                vk::ColorComponentFlags::R
-                | vk::ColorComponentFlags::G
-                | vk::ColorComponentFlags::B
-                | vk::ColorComponentFlags::A
-            )
-            .blend_enable(false)
-            .build()
-        ];
-
-        let color_blend_state_ci = vk::PipelineColorBlendStateCreateInfo::builder()
-            .logic_op_enable(false)
-            .attachments(&color_blend_attachment_states)
-            .build();
-
         let mut push_constant_ranges = Vec::<vk::PushConstantRange>::new();
 
         if let Some(push_constant) = vertex_push_constant {
@@ -118,65 +193,67 @@ impl GraphicsPipeline {
 
         let mut descriptor_set_layouts = Vec::<vk::DescriptorSetLayout>::new();
 
-        if let Some(descriptor_set_layout) = vertex_descriptor_set_layout {
-            descriptor_set_layouts.push(descriptor_set_layout);
+        if let Some(descriptors) = vertex_descriptors {
+            descriptor_set_layouts.push(descriptors.set_layout);
         }
 
-        if let Some(descriptor_set_layout) = fragment_descriptor_set_layout {
-            descriptor_set_layouts.push(descriptor_set_layout);
+        if let Some(descriptors) = fragment_descriptors {
+            descriptor_set_layouts.push(descriptors.set_layout);
         }
 
-        let (vertex_attribute_descs, vertex_binding_descs) = match vertex_buffer {
-            Some(buffer) => {
-                (buffer.attrib_descs.clone(), vec![buffer.binding_desc])
-            },
-            None => (vec![], vec![])
-        };
-
-        let vertex_input_state_ci = vk::PipelineVertexInputStateCreateInfo::builder()
-            .vertex_attribute_descriptions(&vertex_attribute_descs)
-            .vertex_binding_descriptions(&vertex_binding_descs);
-
         let pipeline_layout_ci = vk::PipelineLayoutCreateInfo::builder()
             .set_layouts(&descriptor_set_layouts)
             .push_constant_ranges(&push_constant_ranges)
             .build();
         
-        let pipeline_layout = d.device.create_pipeline_layout(&pipeline_layout_ci, None).unwrap();
-
-        let mut attachment_descs = vec![vk::AttachmentDescription {
-            format: d.surface_format.format,
-            samples: vk::SampleCountFlags::TYPE_1,
-            load_op: vk::AttachmentLoadOp::CLEAR,
-            store_op: vk::AttachmentStoreOp::STORE,
-            initial_layout: vk::ImageLayout::UNDEFINED,
-            final_layout: target_layout,
-            ..Default::default()
-        }];
-
-        let color_attachment_refs = vec![vk::AttachmentReference {
-            attachment: 0,
-            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-        }];
+        let pipeline_layout = d.device.create_pipeline_layout(&pipeline_layout_ci, None)?;
 
-        let mut depth_stencil_state_ci_builder = vk::PipelineDepthStencilStateCreateInfo::builder();
+        // Loaded targets are expected to have been left in their usual layout by whatever drew to them first
+        let target_attachment_desc = |target: &Image| {
+            let (load_op, initial_layout) = match state.load_existing {
+                true => (vk::AttachmentLoadOp::LOAD, target.layout),
+                false => (vk::AttachmentLoadOp::CLEAR, vk::ImageLayout::UNDEFINED),
+            };
+
+            vk::AttachmentDescription {
+                format: target.format,
+                samples: vk::SampleCountFlags::TYPE_1,
+                load_op,
+                store_op: vk::AttachmentStoreOp::STORE,
+                initial_layout,
+                final_layout: target.layout,
+                ..Default::default()
+            }
+        };
+
+        // With MSAA the pass draws to its own multisampled images, which are only needed until they have been resolved into the targets
+        let mut attachment_descs: Vec<vk::AttachmentDescription> = match multisampled {
+            true => targets.iter().map(|target| vk::AttachmentDescription {
+                format: target.format,
+                samples,
+                load_op: vk::AttachmentLoadOp::CLEAR,
+                store_op: vk::AttachmentStoreOp::DONT_CARE,
+                initial_layout: vk::ImageLayout::UNDEFINED,
+                final_layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                ..Default::default()
+            }).collect(),
+            false => targets.iter().map(target_attachment_desc).collect(),
+        };
+
+        let color_attachment_refs: Vec<vk::AttachmentReference> = (0..targets.len()).map(|i| vk::AttachmentReference {
+            attachment: i as u32,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }).collect();
 
         let mut depth_attachment_ref = None;
         let mut depth_image = None;
 
         if with_depth_buffer {
-            let depth_format = vk::Format::D32_SFLOAT;
-
-            depth_image = Some(ImageBuilder::new()
-                .width(target_rect.extent.width)
-                .height(target_rect.extent.height)
-                .format(depth_format)
-                .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
-                .build(c, d));
+            depth_image = Some(GraphicsPipeline::create_depth_image(c, d, target_rect.extent, samples)?);
 
             attachment_descs.push(vk::AttachmentDescription {
-                format: depth_format,
-                samples: vk::SampleCountFlags::TYPE_1,
+                format: depth_image.as_ref().unwrap().format,
+                samples,
                 load_op: vk::AttachmentLoadOp::CLEAR,
                 store_op: vk::AttachmentStoreOp::DONT_CARE,
                 initial_layout: vk::ImageLayout::UNDEFINED,
@@ -185,24 +262,44 @@ impl GraphicsPipeline {
             });
 
             depth_attachment_ref = Some(vk::AttachmentReference {
-                attachment: 1,
+                attachment: targets.len() as u32,
                 layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
             });
-
-            depth_stencil_state_ci_builder = depth_stencil_state_ci_builder
-                .depth_test_enable(true)
-                .depth_write_enable(true)
-                .depth_bounds_test_enable(false)
-                .stencil_test_enable(false)
-                .depth_compare_op(vk::CompareOp::LESS)
         }
 
-        let depth_stencil_state_ci = depth_stencil_state_ci_builder
-            .build();
+        let mut resolve_attachment_refs = Vec::<vk::AttachmentReference>::new();
+        let mut color_images = Vec::<Image>::new();
+
+        if multisampled {
+            for target in targets {
+                match GraphicsPipeline::create_color_image(c, d, target_rect.extent, target.format, samples) {
+                    Ok(image) => color_images.push(image),
+                    Err(e) => {
+                        for image in depth_image.iter().chain(color_images.iter()) {
+                            image.destroy(d);
+                        }
+
+                        return Err(e);
+                    },
+                }
+
+                // The targets are only written by the resolve, so their previous contents are never needed
+                attachment_descs.push(vk::AttachmentDescription {
+                    load_op: vk::AttachmentLoadOp::DONT_CARE,
+                    ..target_attachment_desc(target)
+                });
+
+                resolve_attachment_refs.push(vk::AttachmentReference {
+                    attachment: attachment_descs.len() as u32 - 1,
+                    layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                });
+            }
+        }
 
         let subpass_description_builder = vk::SubpassDescription::builder()
             .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-            .color_attachments(&color_attachment_refs);
+            .color_attachments(&color_attachment_refs)
+            .resolve_attachments(&resolve_attachment_refs);
 
         let subpass_description = match depth_attachment_ref.as_ref() {
             Some(depth_ref) => subpass_description_builder.depth_stencil_attachment(depth_ref).build(),
@@ -231,7 +328,171 @@ impl GraphicsPipeline {
             .dependencies(&[subpass_dependency])
             .build();
 
-        let render_pass = d.device.create_render_pass(&render_pass_ci, None).unwrap();
+        let render_pass = d.device.create_render_pass(&render_pass_ci, None)?;
+
+        let mut graphics_pipeline = GraphicsPipeline {
+            pipeline: vk::Pipeline::null(),
+            pipeline_layout,
+            render_pass,
+
+            viewport,
+            scissor,
+
+            depth_image,
+            color_images,
+            samples,
+
+            color_attachment_count: targets.len(),
+
+            vs: vs.clone(),
+            fs: fs.clone(),
+            dependencies: shaders.iter().flat_map(|s| s.dependencies.iter().cloned()).collect(),
+
+            vertex_attribute_descs,
+            vertex_binding_descs,
+
+            vertex_interface,
+            fragment_interface,
+
+            state,
+        };
+
+        let pipeline = graphics_pipeline.create_pipeline(d, &shaders);
+
+        for shader in &shaders {
+            shader.destroy(d);
+        }
+
+        match pipeline {
+            Ok(pipeline) => graphics_pipeline.pipeline = pipeline,
+            Err(e) => {
+                graphics_pipeline.destroy(d);
+                return Err(e);
+            },
+        }
+
+        Ok(graphics_pipeline)
+    }
+
+    /// Recompiles both shaders into a new pipeline with the same layout and render pass, so framebuffers stay valid. The old pipeline is returned so it can be destroyed once no frame uses it
+    ///
+    /// # Safety
+    /// The returned pipeline must be kept alive until no frame in flight uses it.
+    pub unsafe fn reload(&mut self, d: &Device) -> Result<vk::Pipeline, RendererError> {
+        let shaders = GraphicsPipeline::create_shaders(d, &self.vs, &self.fs, &self.vertex_interface, &self.fragment_interface)?;
+
+        let pipeline = self.create_pipeline(d, &shaders);
+
+        for shader in &shaders {
+            shader.destroy(d);
+        }
+
+        let old_pipeline = std::mem::replace(&mut self.pipeline, pipeline?);
+        self.dependencies = shaders.iter().flat_map(|s| s.dependencies.iter().cloned()).collect();
+
+        Ok(old_pipeline)
+    }
+
+    unsafe fn create_shaders(d: &Device, vs: &ShaderSource, fs: &ShaderSource, vertex_interface: &StageInterface, fragment_interface: &StageInterface) -> Result<Vec<Shader>, RendererError> {
+        let vert_shader = Shader::new(d, vs, vk::ShaderStageFlags::VERTEX)?;
+
+        let frag_shader = match Shader::new(d, fs, vk::ShaderStageFlags::FRAGMENT) {
+            Ok(shader) => shader,
+            Err(e) => {
+                vert_shader.destroy(d);
+                return Err(e);
+            },
+        };
+
+        let shaders = vec![vert_shader, frag_shader];
+
+        if let Err(e) = shaders[0].check(vertex_interface).and_then(|_| shaders[1].check(fragment_interface)) {
+            for shader in &shaders {
+                shader.destroy(d);
+            }
+
+            return Err(e);
+        }
+
+        Ok(shaders)
+    }
+
+    unsafe fn create_pipeline(&self, d: &Device, shaders: &[Shader]) -> Result<vk::Pipeline, RendererError> {
+        let shader_entry_name = CString::new("main").unwrap();
+
+        let mut shader_stage_cis: Vec<vk::PipelineShaderStageCreateInfo> = Vec::new();
+
+        for s in shaders.iter() {
+            let shader_stage_ci = vk::PipelineShaderStageCreateInfo {
+                module: s.module,
+                p_name: shader_entry_name.as_ptr(),
+                stage: s.flags,
+                ..Default::default()
+            };
+
+            shader_stage_cis.push(shader_stage_ci);
+        }
+
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state_ci = vk::PipelineDynamicStateCreateInfo::builder()
+            .dynamic_states(&dynamic_states);
+
+        let input_assembly_state_ci = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(self.state.topology)
+            .primitive_restart_enable(false);
+
+        let viewport_state_ci = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1)
+            .viewports(&[self.viewport])
+            .scissors(&[self.scissor])
+            .build();
+
+        let mut rasterization_state_ci = vk::PipelineRasterizationStateCreateInfo::builder()
+            .depth_clamp_enable(false)
+            .rasterizer_discard_enable(false)
+            .polygon_mode(self.state.polygon_mode)
+            .line_width(1.0)
+            .cull_mode(self.state.cull_mode)
+            .front_face(self.state.front_face)
+            .depth_bias_enable(false);
+
+        if let Some(depth_bias) = self.state.depth_bias {
+            rasterization_state_ci = rasterization_state_ci
+                .depth_bias_enable(true)
+                .depth_bias_constant_factor(depth_bias.constant_factor)
+                .depth_bias_slope_factor(depth_bias.slope_factor)
+                .depth_bias_clamp(depth_bias.clamp);
+        }
+
+        let multisample_state_ci = vk::PipelineMultisampleStateCreateInfo::builder()
+            .sample_shading_enable(false)
+            .rasterization_samples(self.samples);
+
+        let color_blend_attachment_states = vec![self.state.blend_mode.attachment_state(); self.color_attachment_count];
+
+        let color_blend_state_ci = vk::PipelineColorBlendStateCreateInfo::builder()
+            .logic_op_enable(false)
+            .attachments(&color_blend_attachment_states)
+            .build();
+
+        let vertex_input_state_ci = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_attribute_descriptions(&self.vertex_attribute_descs)
+            .vertex_binding_descriptions(&self.vertex_binding_descs);
+
+        let mut depth_stencil_state_ci_builder = vk::PipelineDepthStencilStateCreateInfo::builder();
+
+        if self.depth_image.is_some() {
+            depth_stencil_state_ci_builder = depth_stencil_state_ci_builder
+                .depth_test_enable(self.state.depth_test)
+                .depth_write_enable(self.state.depth_write)
+                .depth_bounds_test_enable(false)
+                .stencil_test_enable(false)
+                .depth_compare_op(self.state.depth_compare_op)
+        }
+
+        let depth_stencil_state_ci = depth_stencil_state_ci_builder
+            .build();
 
         let mut pipeline_ci_builder = vk::GraphicsPipelineCreateInfo::builder()
             .stages(&shader_stage_cis)
@@ -242,11 +503,11 @@ impl GraphicsPipeline {
             .rasterization_state(&rasterization_state_ci)
             .multisample_state(&multisample_state_ci)
             .color_blend_state(&color_blend_state_ci)
-            .layout(pipeline_layout)
-            .render_pass(render_pass)
+            .layout(self.pipeline_layout)
+            .render_pass(self.render_pass)
             .subpass(0);
 
-        if with_depth_buffer {
+        if self.depth_image.is_some() {
             pipeline_ci_builder = pipeline_ci_builder
                 .depth_stencil_state(&depth_stencil_state_ci);
         }
@@ -254,17 +515,67 @@ impl GraphicsPipeline {
         let pipeline_ci = pipeline_ci_builder
             .build();
 
-        let pipeline = d.device.create_graphics_pipelines(vk::PipelineCache::null(), &[pipeline_ci], None).unwrap()[0];
+        Ok(d.device.create_graphics_pipelines(vk::PipelineCache::null(), &[pipeline_ci], None).map_err(|(_, e)| e)?[0])
+    }
 
-        GraphicsPipeline {
-            pipeline,
-            pipeline_layout,
-            render_pass,
+    unsafe fn create_depth_image(c: &Core, d: &Device, extent: vk::Extent2D, samples: vk::SampleCountFlags) -> Result<Image, RendererError> {
+        ImageBuilder::new()
+            .width(extent.width)
+            .height(extent.height)
+            .format(vk::Format::D32_SFLOAT)
+            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+            .samples(samples)
+            .build(c, d)
+    }
 
-            viewport,
-            scissor,
+    unsafe fn create_color_image(c: &Core, d: &Device, extent: vk::Extent2D, format: vk::Format, samples: vk::SampleCountFlags) -> Result<Image, RendererError> {
+        ImageBuilder::new()
+            .width(extent.width)
+            .height(extent.height)
+            .format(format)
+            .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT)
+            .samples(samples)
+            .build(c, d)
+    }
 
-            depth_image,
+    /// Recreates the attachments the pipeline owns, which are sized from the targets
+    ///
+    /// # Safety
+    /// The device must be idle, the old depth and multisampled images are destroyed straight away.
+    pub unsafe fn recreate_attachments(&mut self, c: &Core, d: &Device, extent: vk::Extent2D) -> Result<(), RendererError> {
+        if let Some(depth_image) = self.depth_image.take() {
+            depth_image.destroy(d);
+
+            self.depth_image = Some(GraphicsPipeline::create_depth_image(c, d, extent, self.samples)?);
         }
+
+        for color_image in std::mem::take(&mut self.color_images) {
+            color_image.destroy(d);
+
+            self.color_images.push(GraphicsPipeline::create_color_image(c, d, extent, color_image.format, self.samples)?);
+        }
+
+        Ok(())
+    }
+
+    /// # Safety
+    /// The pipeline mustn't be used by a frame still in flight.
+    pub unsafe fn destroy(&self, d: &Device) {
+        d.device.destroy_pipeline(self.pipeline, None);
+        d.device.destroy_pipeline_layout(self.pipeline_layout, None);
+        d.device.destroy_render_pass(self.render_pass, None);
+
+        for image in self.depth_image.iter().chain(self.color_images.iter()) {
+            image.destroy(d);
+        }
+    }
+
+    pub fn set_target_rect(&mut self, target_rect: vk::Rect2D) {
+        self.viewport.x = target_rect.offset.x as f32;
+        self.viewport.y = target_rect.offset.y as f32;
+        self.viewport.width = target_rect.extent.width as f32;
+        self.viewport.height = target_rect.extent.height as f32;
+
+        self.scissor = target_rect;
     }
 }
\ No newline at end of file
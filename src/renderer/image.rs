@@ -1,8 +1,11 @@
 use ash::vk;
 
+use crate::renderer::allocator::Allocation;
+use crate::renderer::buffer::BufferBuilder;
 use crate::renderer::commands::Commands;
 use crate::renderer::core::Core;
 use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
 use crate::renderer::layer::LayerExecution;
 use crate::renderer::sampler::Sampler;
 
@@ -14,19 +17,24 @@ pub struct ImageBuilder {
     pub usage: Option<vk::ImageUsageFlags>,
     pub format: Option<vk::Format>,
     pub layout: Option<vk::ImageLayout>,
+    pub samples: Option<vk::SampleCountFlags>,
     pub pre_allocated_images: Option<Vec<vk::Image>>,
 }
 
 
-#[derive(Copy, Clone)]
+// Not Clone, so only the value that created the handles destroys them. Everything else holds a share
 pub struct Image {
     pub image: vk::Image,
     pub view: vk::ImageView,
-    pub memory: Option<vk::DeviceMemory>,
+    pub allocation: Option<Allocation>,
     pub width: u32,
     pub height: u32,
     pub extent: vk::Extent3D,
+    pub format: vk::Format,
     pub layout: vk::ImageLayout,
+    pub mip_levels: u32,
+
+    pub(crate) shared: bool,
 }
 
 impl ImageBuilder {
@@ -38,6 +46,7 @@ impl ImageBuilder {
             usage: None,
             format: None,
             layout: None,
+            samples: None,
             pre_allocated_images: None,
         }
     }
@@ -78,37 +87,41 @@ impl ImageBuilder {
         self
     }
     
+    // Multisampled images can only be used as attachments, they're resolved into a single sampled image to be read
+    pub fn samples(mut self, samples: vk::SampleCountFlags) -> ImageBuilder {
+        self.samples = Some(samples);
+
+        self
+    }
+    
     pub fn pre_allocated_images(mut self, pre_allocated_images: Vec<vk::Image>) -> ImageBuilder {
         self.pre_allocated_images = Some(pre_allocated_images);
 
         self
     }
 
-    pub unsafe fn build(&self, c: &Core, d: &Device) -> Image {
+    pub unsafe fn build(&self, c: &Core, d: &Device) -> Result<Image, RendererError> {
         let mut pre_allocated_image: Option<vk::Image> = None;
         if let Some(is) = self.pre_allocated_images.as_ref() {
-            assert!(self.pre_allocated_images.as_ref().unwrap().len() == 1, "Error: Number of image handles given is not 1");
+            if is.len() != 1 {
+                return Err(RendererError::InvalidPassConfig("Number of image handles given is not 1"));
+            }
 
             pre_allocated_image = Some(is[0]);
         }
 
-        Image::new(
-            c, d,
-            self.width.expect("Error: Image builder has no specified width"),
-            self.height.expect("Error: Image builder has no specified height"),
-            self.depth,
-            self.usage.expect("Error: Image builder has no specified usage"),
-            self.format.expect("Error: Image builder has no specified format"),
-            self.layout,
-            pre_allocated_image,
-        )
+        let (width, height, usage, format) = self.fields()?;
+
+        Image::new(c, d, width, height, self.depth, usage, format, self.layout, self.samples.unwrap_or(vk::SampleCountFlags::TYPE_1), pre_allocated_image)
     }
 
-    pub unsafe fn build_many(&self, c: &Core, d: &Device, count: usize) -> Vec<Image> {
-        if self.pre_allocated_images.is_some() {
-            assert!(self.pre_allocated_images.as_ref().unwrap().len() == count, "Error: Number of image handles given is not equal to count");
+    pub unsafe fn build_many(&self, c: &Core, d: &Device, count: usize) -> Result<Vec<Image>, RendererError> {
+        if self.pre_allocated_images.as_ref().map_or(false, |is| is.len() != count) {
+            return Err(RendererError::InvalidPassConfig("Number of image handles given is not equal to count"));
         }
 
+        let (width, height, usage, format) = self.fields()?;
+
         let mut images = Vec::<Image>::new();
         for i in 0..count {
             let mut pre_allocated_image: Option<vk::Image> = None;
@@ -116,24 +129,24 @@ impl ImageBuilder {
                 pre_allocated_image = Some(is[i]);
             }
 
-            images.push(Image::new(
-                c, d,
-                self.width.expect("Error: Image builder has no specified width"),
-                self.height.expect("Error: Image builder has no specified height"),
-                self.depth,
-                self.usage.expect("Error: Image builder has no specified usage"),
-                self.format.expect("Error: Image builder has no specified format"),
-                self.layout,
-                pre_allocated_image,
-            ));
+            images.push(Image::new(c, d, width, height, self.depth, usage, format, self.layout, self.samples.unwrap_or(vk::SampleCountFlags::TYPE_1), pre_allocated_image)?);
         }
 
-        images
+        Ok(images)
+    }
+
+    fn fields(&self) -> Result<(u32, u32, vk::ImageUsageFlags, vk::Format), RendererError> {
+        Ok((
+            self.width.ok_or(RendererError::missing("ImageBuilder", "width"))?,
+            self.height.ok_or(RendererError::missing("ImageBuilder", "height"))?,
+            self.usage.ok_or(RendererError::missing("ImageBuilder", "usage"))?,
+            self.format.ok_or(RendererError::missing("ImageBuilder", "format"))?,
+        ))
     }
 }
 
 impl Image {
-    pub unsafe fn new(c: &Core, d: &Device, w: u32, h: u32, de: Option<u32>, u: vk::ImageUsageFlags, format: vk::Format, layout: Option<vk::ImageLayout>, pre_allocated_image: Option<vk::Image>) -> Image {
+    pub unsafe fn new(_c: &Core, d: &Device, w: u32, h: u32, de: Option<u32>, u: vk::ImageUsageFlags, format: vk::Format, layout: Option<vk::ImageLayout>, samples: vk::SampleCountFlags, pre_allocated_image: Option<vk::Image>) -> Result<Image, RendererError> {
         let (image_type, depth) = match de {
             Some(dep) => (vk::ImageType::TYPE_3D, dep),
             None => (vk::ImageType::TYPE_2D, 1),
@@ -146,7 +159,7 @@ impl Image {
             .build();
 
         let mut image: vk::Image;
-        let mut memory: Option<vk::DeviceMemory> = None;
+        let mut allocation: Option<Allocation> = None;
 
         if let Some(alloced_image) = pre_allocated_image {
             image = alloced_image;
@@ -160,19 +173,16 @@ impl Image {
                 .tiling(vk::ImageTiling::OPTIMAL)
                 .initial_layout(vk::ImageLayout::UNDEFINED)
                 .usage(u)
-                .samples(vk::SampleCountFlags::TYPE_1);
+                .samples(samples);
 
-            image = d.device.create_image(&image_ci, None).unwrap();
+            image = d.device.create_image(&image_ci, None)?;
 
             let memory_requirements = d.device.get_image_memory_requirements(image);
-            let memory_type_index = d.get_memory_type(c, vk::MemoryPropertyFlags::DEVICE_LOCAL, memory_requirements);
-    
-            let memory_alloc_i = vk::MemoryAllocateInfo::builder()
-                .allocation_size(memory_requirements.size)
-                .memory_type_index(memory_type_index as u32);
-    
-            memory = Some(d.device.allocate_memory(&memory_alloc_i, None).unwrap());
-            d.device.bind_image_memory(image, memory.unwrap(), 0).unwrap();
+
+            let image_allocation = d.allocate(memory_requirements, vk::MemoryPropertyFlags::DEVICE_LOCAL, vk::ImageTiling::OPTIMAL)?;
+            d.device.bind_image_memory(image, image_allocation.memory, image_allocation.offset)?;
+
+            allocation = Some(image_allocation);
         }
 
         let image_aspect = match u {
@@ -198,7 +208,7 @@ impl Image {
                 layer_count: 1,
             });
 
-        let view = d.device.create_image_view(&view_ci, None).unwrap();
+        let view = d.device.create_image_view(&view_ci, None)?;
 
         let mut image_layout = vk::ImageLayout::UNDEFINED;
 
@@ -206,7 +216,7 @@ impl Image {
             image_layout = layout.unwrap();
             
             if pre_allocated_image.is_none() {
-                let layout_transition_buffer = Commands::new(d, d.get_queue(LayerExecution::Main).1, 1, false);
+                let layout_transition_buffer = Commands::new(d, d.get_queue(LayerExecution::Main).1, 1, false)?;
 
                 layout_transition_buffer.record_all(d, |i, b| {
                     let subresource_range = vk::ImageSubresourceRange::builder()
@@ -225,34 +235,178 @@ impl Image {
                         .build();
     
                     d.device.cmd_pipeline_barrier(b, vk::PipelineStageFlags::TOP_OF_PIPE, vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(), &[], &[], &[barrier]);
-                });
+                })?;
     
                 let submit_is = [vk::SubmitInfo::builder()
                     .command_buffers(&layout_transition_buffer.buffers)
                     .build()];
     
-                d.device.queue_submit(d.get_queue(LayerExecution::Main).0, &submit_is, vk::Fence::null()).unwrap();
-                d.device.queue_wait_idle(d.get_queue(LayerExecution::Main).0).unwrap();
+                d.device.queue_submit(d.get_queue(LayerExecution::Main).0, &submit_is, vk::Fence::null())?;
+                d.device.queue_wait_idle(d.get_queue(LayerExecution::Main).0)?;
+
+                layout_transition_buffer.destroy(d);
             }
         }
 
-        Image {
+        Ok(Image {
             image,
             view,
-            memory,
+            allocation,
             width: w,
             height: h,
             extent,
+            format,
             layout: image_layout,
+            mip_levels: 1,
+            shared: false,
+        })
+    }
+
+    pub fn aspect(&self) -> vk::ImageAspectFlags {
+        match self.format {
+            vk::Format::D16_UNORM | vk::Format::D32_SFLOAT | vk::Format::X8_D24_UNORM_PACK32 => vk::ImageAspectFlags::DEPTH,
+            vk::Format::D16_UNORM_S8_UINT | vk::Format::D24_UNORM_S8_UINT | vk::Format::D32_SFLOAT_S8_UINT => vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL,
+            _ => vk::ImageAspectFlags::COLOR,
+        }
+    }
+
+    // A copy of the handles for passes and descriptors that use an image owned elsewhere, destroying a share does nothing
+    pub fn share(&self) -> Image {
+        Image {
+            image: self.image,
+            view: self.view,
+            allocation: self.allocation,
+            width: self.width,
+            height: self.height,
+            extent: self.extent,
+            format: self.format,
+            layout: self.layout,
+            mip_levels: self.mip_levels,
+            shared: true,
+        }
+    }
+
+    /// # Safety
+    /// The image mustn't be used by the GPU any more. Shares can be destroyed at any time, as they do nothing.
+    pub unsafe fn destroy(&self, d: &Device) {
+        if self.shared {
+            return;
+        }
+
+        d.device.destroy_image_view(self.view, None);
+
+        // Pre-allocated images such as the swapchain images are owned by whatever created them
+        if let Some(allocation) = &self.allocation {
+            d.device.destroy_image(self.image, None);
+            d.free(allocation);
         }
     }
 
-    pub unsafe fn generate_samplers(c: &Core, d: &Device, images: &Vec<Image>) -> Vec<Sampler> {
+    /// Copies the image into a host visible buffer and returns its texels tightly packed, row by row
+    ///
+    /// # Safety
+    /// Waits on the main queue, so the image mustn't be written by anything still in flight.
+    pub unsafe fn read_back(&self, c: &Core, d: &Device) -> Result<Vec<u8>, RendererError> {
+        let size = (self.extent.width * self.extent.height * self.extent.depth) as usize * format_size(self.format)?;
+
+        let staging_buffer = BufferBuilder::new()
+            .size(size)
+            .usage(vk::BufferUsageFlags::TRANSFER_DST)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .properties(vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT)
+            .build(c, d)?;
+
+        // Images without a layout are used as storage images, which the descriptors and layers keep in GENERAL
+        let layout = if self.layout == vk::ImageLayout::UNDEFINED { vk::ImageLayout::GENERAL } else { self.layout };
+
+        let read_back_commands = Commands::new(d, d.queue_main.1, 1, true)?;
+
+        read_back_commands.record_one(d, 0, |b| {
+            let subresource_range = vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .layer_count(1)
+                .level_count(1)
+                .build();
+
+            let to_transfer_barrier = vk::ImageMemoryBarrier::builder()
+                .image(self.image)
+                .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
+                .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .old_layout(layout)
+                .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .subresource_range(subresource_range)
+                .build();
+
+            d.device.cmd_pipeline_barrier(b, vk::PipelineStageFlags::ALL_COMMANDS, vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(), &[], &[], &[to_transfer_barrier]);
+
+            let image_copy = vk::BufferImageCopy::builder()
+                .image_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .image_extent(self.extent)
+                .build();
+
+            d.device.cmd_copy_image_to_buffer(b, self.image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, staging_buffer.buffer, &[image_copy]);
+
+            let from_transfer_barrier = vk::ImageMemoryBarrier::builder()
+                .image(self.image)
+                .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .dst_access_mask(vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE)
+                .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .new_layout(layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .subresource_range(subresource_range)
+                .build();
+
+            d.device.cmd_pipeline_barrier(b, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::ALL_COMMANDS, vk::DependencyFlags::empty(), &[], &[], &[from_transfer_barrier]);
+        })?;
+
+        let submit_is = [vk::SubmitInfo::builder()
+            .command_buffers(&read_back_commands.buffers)
+            .build()];
+
+        d.device.queue_submit(d.queue_main.0, &submit_is, vk::Fence::null())?;
+        d.device.queue_wait_idle(d.queue_main.0)?;
+
+        let mut data = vec![0u8; size];
+        std::ptr::copy(staging_buffer.p_dst.unwrap() as *const u8, data.as_mut_ptr(), size);
+
+        read_back_commands.destroy(d);
+        staging_buffer.destroy(d);
+
+        Ok(data)
+    }
+
+    pub unsafe fn generate_samplers(c: &Core, d: &Device, images: &Vec<Image>) -> Result<Vec<Sampler>, RendererError> {
         let mut samplers = Vec::<Sampler>::new();
         for image in images {
-            samplers.push(Sampler::new(c, d, image.view))
+            samplers.push(Sampler::new(c, d, image.view)?)
         }
 
-        samplers
+        Ok(samplers)
     }
+}
+// Bytes per texel, only for the uncompressed formats the renderer reads back or uploads
+pub fn format_size(format: vk::Format) -> Result<usize, RendererError> {
+    let size = match format {
+        vk::Format::R8_UNORM | vk::Format::R8_SNORM | vk::Format::R8_UINT | vk::Format::R8_SINT | vk::Format::R8_SRGB => 1,
+        vk::Format::R8G8_UNORM | vk::Format::R8G8_SNORM | vk::Format::R8G8_UINT | vk::Format::R8G8_SINT | vk::Format::R8G8_SRGB => 2,
+        vk::Format::R16_SFLOAT | vk::Format::R16_UNORM | vk::Format::R16_UINT | vk::Format::D16_UNORM => 2,
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SNORM | vk::Format::R8G8B8A8_UINT | vk::Format::R8G8B8A8_SINT | vk::Format::R8G8B8A8_SRGB => 4,
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SNORM | vk::Format::B8G8R8A8_UINT | vk::Format::B8G8R8A8_SINT | vk::Format::B8G8R8A8_SRGB => 4,
+        vk::Format::A2B10G10R10_UNORM_PACK32 | vk::Format::B10G11R11_UFLOAT_PACK32 => 4,
+        vk::Format::R16G16_SFLOAT | vk::Format::R32_SFLOAT | vk::Format::R32_UINT | vk::Format::R32_SINT | vk::Format::D32_SFLOAT => 4,
+        vk::Format::R16G16B16A16_SFLOAT | vk::Format::R16G16B16A16_UNORM | vk::Format::R32G32_SFLOAT => 8,
+        vk::Format::R32G32B32_SFLOAT => 12,
+        vk::Format::R32G32B32A32_SFLOAT | vk::Format::R32G32B32A32_UINT | vk::Format::R32G32B32A32_SINT => 16,
+        _ => return Err(RendererError::UnknownFormatSize(format)),
+    };
+
+    Ok(size)
 }
\ No newline at end of file
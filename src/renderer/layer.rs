@@ -1,9 +1,11 @@
+use std::path::PathBuf;
+
 use ash::vk;
 
-use crate::{renderer::{core::Core, semaphore::Semaphore, compute_pass::ComputePass, shader::ShaderType, renderer_data::{ResourceReference, RendererData}}, util::graph::Graph};
+use crate::{renderer::{core::Core, semaphore::Semaphore, profiler::LayerQueries, compute_pass::ComputePass, deletion_queue::{DeletionQueue, Retired}, error::RendererError, shader::ShaderType, renderer_data::{ResourceReference, RendererData}, resource_access::{BarrierBatch, QueueTransfer, ResourceAccess, ResourceState, ResourceTracker}}, util::graph::{Graph, GraphError}};
 use crate::renderer::device::Device;
 use crate::renderer::commands::Commands;
-use crate::renderer::graphics_pass::GraphicsPass;
+use crate::renderer::graphics_pass::{DrawItem, GraphicsPass};
 
 #[derive(Copy, Clone)]
 pub enum LayerExecution {
@@ -11,7 +13,7 @@ pub enum LayerExecution {
     Async,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum PassType {
     Compute,
     Graphics,
@@ -43,14 +45,16 @@ pub struct LayerDependencyInfo {
 
 pub trait Pass {}
 
+// Values are only used for timeline semaphores, binary semaphores in the same submission are given 0
 pub struct LayerSubmitInfo {
     pub wait_semaphores: Vec<vk::Semaphore>,
+    pub wait_values: Vec<u64>,
     pub wait_stages: Vec<vk::PipelineStageFlags>,
     pub signal_semaphores: Vec<vk::Semaphore>,
+    pub signal_values: Vec<u64>,
     pub command_buffers: Vec<vk::CommandBuffer>,
     pub queue: vk::Queue,
     pub fence: vk::Fence,
-    pub submit_i: vk::SubmitInfo,
 }
 
 pub struct Layer {
@@ -66,17 +70,20 @@ pub struct Layer {
 
     pub root_pass: String,
 
-    pub semaphore: Semaphore,
+    pub semaphore: Semaphore, // Timeline semaphore signaled with the renderer's frame number once the layer has finished
+    pub timeline_value: u64, // The last value the layer was submitted to signal
+
+    pub queries: Option<LayerQueries>, // Only while the renderer is profiling
 
     pub present: bool,
 }
 
 impl Layer {
-    pub unsafe fn new(c: &Core, d: &Device, count: usize, present: bool, exec: LayerExecution) -> Layer {
-        let commands = Commands::new(d, d.get_queue(exec).1, count, false);
-        let semaphore = Semaphore::new(d);
+    pub unsafe fn new(c: &Core, d: &Device, count: usize, present: bool, exec: LayerExecution) -> Result<Layer, RendererError> {
+        let commands = Commands::new(d, d.get_queue(exec).1, count, false)?;
+        let semaphore = Semaphore::new_timeline(d)?;
 
-        Layer {
+        Ok(Layer {
             count,
             commands,
             exec,
@@ -85,56 +92,284 @@ impl Layer {
             pass_graph: Graph::new(),
             root_pass: String::new(),
             semaphore,
+            timeline_value: 0,
+            queries: None,
             present,
-        }
+        })
     }
 
-    pub unsafe fn add_compute_pass(&mut self, name: &str, pass: ComputePass) {
+    // The name is checked before the pass is stored, so a duplicate leaves the layer untouched
+    pub unsafe fn add_compute_pass(&mut self, name: &str, pass: ComputePass) -> Result<(), RendererError> {
+        self.pass_graph.add_node(name, PassRef { pass_type: PassType::Compute, index: self.compute_passes.len() })?;
         self.compute_passes.push(pass);
-        self.pass_graph.add_node(name, PassRef { pass_type: PassType::Compute, index: self.compute_passes.len() - 1 });
+
+        Ok(())
     }
 
-    pub unsafe fn add_graphics_pass(&mut self, name: &str, pass: GraphicsPass) {
+    pub unsafe fn add_graphics_pass(&mut self, name: &str, pass: GraphicsPass) -> Result<(), RendererError> {
+        self.pass_graph.add_node(name, PassRef { pass_type: PassType::Graphics, index: self.graphics_passes.len() })?;
         self.graphics_passes.push(pass);
-        self.pass_graph.add_node(name, PassRef { pass_type: PassType::Graphics, index: self.graphics_passes.len() - 1 });
+
+        Ok(())
+    }
+
+    // The last pass of the same type is moved into the removed pass's slot, the removed pass is handed back to be destroyed once no frame uses it
+    pub fn remove_pass(&mut self, name: &str) -> Result<Retired, RendererError> {
+        let removed = self.pass_graph.remove_node(name)?;
+
+        let last_index = match removed.pass_type {
+            PassType::Compute => self.compute_passes.len() - 1,
+            PassType::Graphics => self.graphics_passes.len() - 1,
+        };
+
+        for node in self.pass_graph.nodes_mut() {
+            if node.data.pass_type == removed.pass_type && node.data.index == last_index {
+                node.data.index = removed.index;
+            }
+        }
+
+        Ok(match removed.pass_type {
+            PassType::Compute => Retired::ComputePass(Box::new(self.compute_passes.swap_remove(removed.index))),
+            PassType::Graphics => Retired::GraphicsPass(Box::new(self.graphics_passes.swap_remove(removed.index))),
+        })
+    }
+
+    /// # Safety
+    /// The layer's semaphore mustn't have pending waits or signals.
+    pub unsafe fn destroy(&self, d: &Device) {
+        for pass in &self.compute_passes {
+            pass.destroy(d);
+        }
+
+        for pass in &self.graphics_passes {
+            pass.destroy(d);
+        }
+
+        if let Some(queries) = &self.queries {
+            queries.destroy(d);
+        }
+
+        self.commands.destroy(d);
+        self.semaphore.destroy(d);
+    }
+
+    /// Layers on queues that can't write timestamps go unprofiled
+    ///
+    /// # Safety
+    /// The device must be idle.
+    pub unsafe fn enable_queries(&mut self, d: &Device, capacity: u32, pipeline_statistics: bool) -> Result<(), RendererError> {
+        self.disable_queries(d);
+
+        if d.supports_timestamps(self.exec) {
+            self.queries = Some(LayerQueries::new(d, self.count, capacity, self.exec, pipeline_statistics)?);
+        }
+
+        Ok(())
     }
 
-    pub fn add_pass_dependency(&mut self, src_name: &str, dst_name: &str, dep: Option<PassDependency>) {
-        self.pass_graph.add_edge(src_name, dst_name, dep);
+    /// # Safety
+    /// The device must be idle.
+    pub unsafe fn disable_queries(&mut self, d: &Device) {
+        if let Some(queries) = self.queries.take() {
+            queries.destroy(d);
+        }
+    }
+
+    pub fn add_pass_dependency(&mut self, src_name: &str, dst_name: &str, dep: Option<PassDependency>) -> Result<(), RendererError> {
+        Ok(self.pass_graph.add_edge(src_name, dst_name, dep)?)
+    }
+
+    /// Rebuilds the pipeline of every pass that uses one of the changed files, returning each pass's name with whether its new shaders compiled
+    ///
+    /// # Safety
+    /// Must be called between frames.
+    pub unsafe fn reload_shaders(&mut self, d: &Device, changed: &[PathBuf], deletion_queue: &mut DeletionQueue) -> Vec<(String, Result<(), RendererError>)> {
+        let mut reloads = Vec::<(String, Result<(), RendererError>)>::new();
+
+        for node in self.pass_graph.nodes() {
+            let result = match node.data.pass_type {
+                PassType::Compute => {
+                    let pipeline = &mut self.compute_passes[node.data.index].pipeline;
+                    if !pipeline.dependencies.iter().any(|p| changed.contains(p)) {
+                        continue;
+                    }
+
+                    pipeline.reload(d)
+                },
+                PassType::Graphics => {
+                    let pipeline = &mut self.graphics_passes[node.data.index].pipeline;
+                    if !pipeline.dependencies.iter().any(|p| changed.contains(p)) {
+                        continue;
+                    }
+
+                    pipeline.reload(d)
+                },
+            };
+
+            reloads.push((node.name.clone(), result.map(|old_pipeline| deletion_queue.retire(Retired::Pipeline(old_pipeline)))));
+        }
+
+        reloads
+    }
+
+    pub fn shader_dependencies(&self) -> Vec<PathBuf> {
+        let compute_dependencies = self.compute_passes.iter().flat_map(|p| p.pipeline.dependencies.iter());
+        let graphics_dependencies = self.graphics_passes.iter().flat_map(|p| p.pipeline.dependencies.iter());
+
+        compute_dependencies.chain(graphics_dependencies).cloned().collect()
     }
 
     pub fn set_root_path(&mut self, name: &str) {
         self.root_pass = name.to_string();
     }
 
-    pub fn get_compute_pass(&self, name: &str) -> &ComputePass {
-        &self.compute_passes[self.pass_graph.get_node(name).data.index]
+    // Index of the named pass in the list for its type, so a pass of the other type can't index the wrong list
+    fn find_pass(&self, name: &str, pass_type: PassType) -> Result<usize, RendererError> {
+        let pass_ref = self.pass_graph.find_node(name)?.data;
+
+        match (pass_ref.pass_type, pass_type) {
+            (PassType::Compute, PassType::Compute) | (PassType::Graphics, PassType::Graphics) => Ok(pass_ref.index),
+            (_, PassType::Compute) => Err(RendererError::InvalidPassConfig("Pass isn't a compute pass")),
+            (_, PassType::Graphics) => Err(RendererError::InvalidPassConfig("Pass isn't a graphics pass")),
+        }
+    }
+
+    pub fn get_compute_pass(&self, name: &str) -> Result<&ComputePass, RendererError> {
+        Ok(&self.compute_passes[self.find_pass(name, PassType::Compute)?])
+    }
+
+    pub fn get_graphics_pass(&self, name: &str) -> Result<&GraphicsPass, RendererError> {
+        Ok(&self.graphics_passes[self.find_pass(name, PassType::Graphics)?])
     }
 
-    pub fn get_graphics_pass(&self, name: &str) -> &GraphicsPass {
-        &self.graphics_passes[self.pass_graph.get_node(name).data.index]
+    pub unsafe fn fill_compute_push_constant<T>(&mut self, name: &str, data: &T) -> Result<(), RendererError> {
+        let index = self.find_pass(name, PassType::Compute)?;
+
+        self.compute_passes[index].push_constant.as_mut().ok_or(RendererError::InvalidPassConfig("Compute pass has no push constant to fill"))?.set_data(data)
     }
 
-    pub unsafe fn fill_compute_push_constant<T>(&mut self, name: &str, data: &T) {
-        self.compute_passes[self.pass_graph.get_node(name).data.index].push_constant.as_mut().expect("Error: Graphics pass has no vertex push constant to fill").set_data(data);
+    pub unsafe fn fill_vertex_push_constant<T>(&mut self, name: &str, data: &T) -> Result<(), RendererError> {
+        let index = self.find_pass(name, PassType::Graphics)?;
+
+        self.graphics_passes[index].vertex_push_constant.as_mut().ok_or(RendererError::InvalidPassConfig("Graphics pass has no vertex push constant to fill"))?.set_data(data)
     }
 
-    pub unsafe fn fill_vertex_push_constant<T>(&mut self, name: &str, data: &T) {
-        self.graphics_passes[self.pass_graph.get_node(name).data.index].vertex_push_constant.as_mut().expect("Error: Graphics pass has no vertex push constant to fill").set_data(data);
+    pub unsafe fn fill_fragment_push_constant<T>(&mut self, name: &str, data: &T) -> Result<(), RendererError> {
+        let index = self.find_pass(name, PassType::Graphics)?;
+
+        self.graphics_passes[index].fragment_push_constant.as_mut().ok_or(RendererError::InvalidPassConfig("Graphics pass has no fragment push constant to fill"))?.set_data(data)
     }
 
-    pub unsafe fn fill_fragment_push_constant<T>(&mut self, name: &str, data: &T) {
-        self.graphics_passes[self.pass_graph.get_node(name).data.index].fragment_push_constant.as_mut().expect("Error: Graphics pass has no fragment push constant to fill").set_data(data);
+    // Compute passes have one set of descriptors, graphics passes have one for each of their vertex and fragment stages
+    pub fn set_dynamic_offset(&mut self, d: &Device, name: &str, stage: vk::ShaderStageFlags, binding: u32, offset: u32) -> Result<(), RendererError> {
+        let pass_ref = self.pass_graph.find_node(name)?.data;
+
+        let descriptors = match (pass_ref.pass_type, stage) {
+            (PassType::Compute, _) => self.compute_passes[pass_ref.index].descriptors.as_mut(),
+            (PassType::Graphics, vk::ShaderStageFlags::VERTEX) => self.graphics_passes[pass_ref.index].vertex_descriptors.as_mut(),
+            (PassType::Graphics, vk::ShaderStageFlags::FRAGMENT) => self.graphics_passes[pass_ref.index].fragment_descriptors.as_mut(),
+            (PassType::Graphics, _) => None,
+        };
+
+        descriptors.ok_or(RendererError::InvalidPassConfig("Pass has no descriptors for that stage"))?.set_dynamic_offset(d, binding, offset)
     }
 
-    pub unsafe fn record_one(&self, d: &Device, resources: &RendererData, i: usize, present_index: usize) {
-        let mut dependencies = self.pass_graph.breadth_first_backwards(&self.root_pass);
-        dependencies.reverse();
+    pub fn set_draws(&mut self, d: &Device, data: &RendererData, name: &str, items: Vec<DrawItem>) -> Result<(), RendererError> {
+        let index = self.find_pass(name, PassType::Graphics)?;
+
+        self.graphics_passes[index].set_draws(d, data, items)
+    }
+
+    pub fn set_graphics_pass_rect(&mut self, name: &str, extent: Option<vk::Extent2D>, offset: Option<vk::Offset2D>) -> Result<(), RendererError> {
+        let index = self.find_pass(name, PassType::Graphics)?;
+
+        self.graphics_passes[index].set_target_rect(extent, offset);
+
+        Ok(())
+    }
+
+    fn get_pass_accesses(&self, pass_ref: PassRef) -> &Vec<ResourceAccess> {
+        match pass_ref.pass_type {
+            PassType::Compute => &self.compute_passes[pass_ref.index].accesses,
+            PassType::Graphics => &self.graphics_passes[pass_ref.index].accesses,
+        }
+    }
+
+    pub fn get_accessed_resources(&self) -> Vec<ResourceReference> {
+        let mut resources = Vec::<ResourceReference>::new();
+
+        let accesses = self.compute_passes.iter().flat_map(|p| p.accesses.iter()).chain(self.graphics_passes.iter().flat_map(|p| p.accesses.iter()));
+        for access in accesses {
+            if !resources.contains(&access.resource) {
+                resources.push(access.resource);
+            }
+        }
+
+        resources
+    }
+
+    // Barriers are worked out from the accesses each pass declares, a pass dependency on an incoming edge overrides the masks used for its resource
+    pub unsafe fn record_one(&mut self, d: &Device, resources: &RendererData, i: usize, present_index: usize, frame_number: u64, initial_stage: vk::PipelineStageFlags, acquires: &[QueueTransfer], releases: &[QueueTransfer]) -> Result<(), RendererError> {
+        let dependencies = self.pass_graph.topological_sort_to(&self.root_pass)?;
+        let pass_names: Vec<String> = dependencies.iter().map(|n| n.name.clone()).collect();
+        let pass_overrides = dependencies.iter().map(|n| Ok(self.pass_graph.get_prev_edges(&n.name)?.iter().filter_map(|e| e.info).collect())).collect::<Result<Vec<Vec<PassDependency>>, GraphError>>()?;
 
         self.commands.record_one(d, i, |b| {
-            for dependency in &dependencies {
+            let mut tracker = ResourceTracker::new(initial_stage);
+
+            if let Some(queries) = &self.queries {
+                queries.reset(d, b, i);
+            }
+
+            let mut acquire_barriers = BarrierBatch::new();
+
+            for transfer in acquires {
+                let first_access = dependencies.iter().flat_map(|n| self.get_pass_accesses(n.data).iter()).find(|a| a.resource == transfer.resource);
+
+                if let Some(access) = first_access {
+                    let src = tracker.home_state(transfer.resource, resources);
+                    let dst = ResourceState { stage: access.stage(), access: access.access(), layout: src.layout, written: false };
+
+                    acquire_barriers.add_transfer(transfer, resources, i, ResourceState { stage: vk::PipelineStageFlags::TOP_OF_PIPE, access: vk::AccessFlags::empty(), ..src }, dst);
+                    tracker.set_state(transfer.resource, ResourceState { written: true, ..dst });
+                }
+            }
+
+            acquire_barriers.record(d, b);
+
+            for (pass_index, dependency) in dependencies.iter().enumerate() {
                 let pass_ref = dependency.data;
 
+                let mut barriers = BarrierBatch::new();
+
+                let overrides = &pass_overrides[pass_index];
+
+                for access in self.get_pass_accesses(pass_ref) {
+                    if let Some((src, dst)) = tracker.access(access, resources) {
+                        match overrides.iter().find(|o| o.resource == access.resource) {
+                            Some(o) => {
+                                barriers.add(access.resource, resources, i, ResourceState { stage: o.src_stage, access: o.src_access, written: true, ..src }, ResourceState { stage: o.dst_stage, access: o.dst_access, ..dst });
+                            },
+                            None => {
+                                barriers.add(access.resource, resources, i, src, dst);
+                            },
+                        }
+                    }
+                }
+
+                // Dependencies on resources the pass never declared are still honoured, in whatever layout the resource is in
+                for o in overrides.iter().filter(|o| !self.get_pass_accesses(pass_ref).iter().any(|a| a.resource == o.resource)) {
+                    let state = tracker.get_state(o.resource, resources);
+
+                    barriers.add(o.resource, resources, i, ResourceState { stage: o.src_stage, access: o.src_access, layout: state.layout, written: true }, ResourceState { stage: o.dst_stage, access: o.dst_access, layout: state.layout, written: false });
+                }
+
+                barriers.record(d, b);
+
+                if let Some(queries) = &self.queries {
+                    queries.begin_pass(d, b, i, pass_index as u32);
+                }
+
                 match pass_ref.pass_type {
                     PassType::Compute => {
                         let pass = &self.compute_passes[pass_ref.index];
@@ -147,7 +382,7 @@ impl Layer {
         
                         if pass.descriptors.is_some() {
                             let descriptors = pass.descriptors.as_ref().unwrap();
-                            descriptors.bind(d, &b, vk::PipelineBindPoint::COMPUTE, &pass.pipeline.pipeline_layout, i);
+                            descriptors.bind(d, &b, vk::PipelineBindPoint::COMPUTE, &pass.pipeline.pipeline_layout, 0, i);
                         }
         
                         d.device.cmd_dispatch(b, pass.dispatch_info.x, pass.dispatch_info.y, pass.dispatch_info.z);
@@ -157,7 +392,7 @@ impl Layer {
 
                         let render_pass_bi = vk::RenderPassBeginInfo::builder()
                             .render_pass(pass.pipeline.render_pass)
-                            .framebuffer(pass.framebuffers[present_index].framebuffer)
+                            .framebuffer(pass.framebuffer(i, present_index).framebuffer)
                             .render_area(pass.target_rect)
                             .clear_values(&pass.clear_values);
 
@@ -170,11 +405,12 @@ impl Layer {
                         }
 
                         if let Some(descriptors) = &pass.vertex_descriptors {
-                            descriptors.bind(d, &b, vk::PipelineBindPoint::GRAPHICS, &pass.pipeline.pipeline_layout, i);
+                            descriptors.bind(d, &b, vk::PipelineBindPoint::GRAPHICS, &pass.pipeline.pipeline_layout, 0, i);
                         }
 
+                        // The fragment set comes after the vertex set in the pipeline layout when there are both
                         if let Some(descriptors) = &pass.fragment_descriptors {
-                            descriptors.bind(d, &b, vk::PipelineBindPoint::GRAPHICS, &pass.pipeline.pipeline_layout, i);
+                            descriptors.bind(d, &b, vk::PipelineBindPoint::GRAPHICS, &pass.pipeline.pipeline_layout, pass.vertex_descriptors.is_some() as u32, i);
                         }
                         
                         d.device.cmd_begin_render_pass(b, &render_pass_bi, vk::SubpassContents::INLINE);
@@ -184,64 +420,69 @@ impl Layer {
                         d.device.cmd_set_viewport(b, 0, &[pass.pipeline.viewport]);
                         d.device.cmd_set_scissor(b, 0, &[pass.pipeline.scissor]);
 
-                        if pass.vertex_buffer.is_some() {
-                            d.device.cmd_bind_vertex_buffers(b, 0, &[pass.vertex_buffer.as_ref().unwrap().buffer.buffer], &[0]);
+                        if let Some(vertex_buffer) = &pass.vertex_buffer {
+                            let buffers = vertex_buffer.buffers();
+                            d.device.cmd_bind_vertex_buffers(b, 0, &buffers, &vec![0; buffers.len()]);
                         }
                         
-                        if pass.indexed {
-                            d.device.cmd_bind_index_buffer(b, pass.vertex_buffer.as_ref().unwrap().index_buffer.unwrap().buffer, 0, vk::IndexType::UINT32);
-                            d.device.cmd_draw_indexed(b, pass.draw_info.index_count, pass.draw_info.instance_count, pass.draw_info.first_vertex, pass.draw_info.vertex_offset, pass.draw_info.instance_count);
+                        if let Some(index_buffer) = pass.vertex_buffer.as_ref().and_then(|v| v.index_buffer.as_ref()) {
+                            d.device.cmd_bind_index_buffer(b, index_buffer.buffer, 0, vk::IndexType::UINT32);
+                        }
+
+                        if pass.mesh_layout.is_some() {
+                            pass.record_draws(d, b, resources, i);
                         } else {
-                            d.device.cmd_draw(b, pass.draw_info.vertex_count, pass.draw_info.instance_count, pass.draw_info.first_vertex, pass.draw_info.instance_count);
+                            match (&pass.indirect, pass.indexed) {
+                                (Some(indirect), true) => d.device.cmd_draw_indexed_indirect(b, indirect.buffers[i], indirect.offset, indirect.draw_count, indirect.stride),
+                                (Some(indirect), false) => d.device.cmd_draw_indirect(b, indirect.buffers[i], indirect.offset, indirect.draw_count, indirect.stride),
+                                (None, true) => d.device.cmd_draw_indexed(b, pass.draw_info.index_count, pass.draw_info.instance_count, pass.draw_info.first_index, pass.draw_info.vertex_offset, pass.draw_info.first_instance),
+                                (None, false) => d.device.cmd_draw(b, pass.draw_info.vertex_count, pass.draw_info.instance_count, pass.draw_info.first_vertex, pass.draw_info.first_instance),
+                            }
                         }
 
                         d.device.cmd_end_render_pass(b);
                     }
                 }
 
-                let dependant_edges = self.pass_graph.get_next_edges(&dependency.name);
+                if let Some(queries) = &self.queries {
+                    queries.end_pass(d, b, i, pass_index as u32);
+                }
+            }
 
-                for dependant_edge in dependant_edges {
-                    if let Some(dependant_info) = dependant_edge.info {
-                        let mut memory_barriers = Vec::<vk::MemoryBarrier>::new();
-                        let mut buffer_memory_barriers = Vec::<vk::BufferMemoryBarrier>::new();
-                        let mut image_memory_barriers = Vec::<vk::ImageMemoryBarrier>::new();
+            // Resources are handed back in their home layout, then released to the queue family of the layer that uses them next.
+            // Releases don't change the layout, as the acquire on the other queue has to describe the same transition
+            let mut end_barriers = BarrierBatch::new();
+            let mut release_barriers = BarrierBatch::new();
 
-                        match dependant_info.resource {
-                            ResourceReference::Buffer(index) => {
-                                let memory_barrier = vk::MemoryBarrier::builder()
-                                    .src_access_mask(dependant_info.src_access)
-                                    .dst_access_mask(dependant_info.dst_access)
-                                    .build();
+            for (resource, state) in &tracker.states {
+                let home = ResourceState { stage: vk::PipelineStageFlags::BOTTOM_OF_PIPE, access: vk::AccessFlags::empty(), layout: ResourceTracker::home_layout(*resource, resources), written: false };
+                let release = releases.iter().find(|r| r.resource == *resource);
 
-                                memory_barriers.push(memory_barrier);
-                            },
-                            ResourceReference::Image(index) => {
-                                let subresource_range = vk::ImageSubresourceRange::builder()
-                                    .aspect_mask(vk::ImageAspectFlags::COLOR)
-                                    .layer_count(1)
-                                    .level_count(1)
-                                    .build();
-
-                                let image_memory_barrier = vk::ImageMemoryBarrier::builder()
-                                    .src_access_mask(dependant_info.src_access)
-                                    .dst_access_mask(dependant_info.dst_access)
-                                    .old_layout(vk::ImageLayout::GENERAL)
-                                    .new_layout(vk::ImageLayout::GENERAL)
-                                    .image(resources.get_images_from_ref(index)[i].image)
-                                    .subresource_range(subresource_range)
-                                    .src_queue_family_index(d.get_queue(self.exec).1)
-                                    .dst_queue_family_index(d.get_queue(self.exec).1)
-                                    .build();
-
-                                image_memory_barriers.push(image_memory_barrier);
-                            }
-                        }
+                let mut src = *state;
 
-                        d.device.cmd_pipeline_barrier(b, dependant_info.src_stage, dependant_info.dst_stage, vk::DependencyFlags::empty(), &memory_barriers, &buffer_memory_barriers, &image_memory_barriers);
-                    }
+                if state.layout != home.layout {
+                    let transitioned = match release {
+                        Some(_) => ResourceState { stage: vk::PipelineStageFlags::ALL_COMMANDS, ..home },
+                        None => home,
+                    };
+
+                    end_barriers.add(*resource, resources, i, *state, transitioned);
+                    src = transitioned;
+                }
+
+                if let Some(release) = release {
+                    release_barriers.add_transfer(release, resources, i, src, home);
                 }
             }
-        })
+
+            end_barriers.record(d, b);
+            release_barriers.record(d, b);
+        })?;
+
+        if let Some(queries) = &mut self.queries {
+            queries.set_recorded(i, frame_number, pass_names);
+        }
+
+        Ok(())
     }
 }
\ No newline at end of file
@@ -1,8 +1,11 @@
-use std::fs;
-use std::io::Read;
+use std::collections::HashMap;
+use std::{fmt, fs, io};
+use std::path::{Path, PathBuf};
 
-use crate::math::vec::Vec3;
-use crate::math::vec::Vec4;
+use ash::vk;
+
+use crate::math::vec::{Vec2, Vec3, Vec4};
+use crate::renderer::vertex_buffer::{VertexAttribute, VertexAttributes};
 
 pub trait FromObjTri {
     fn from_obj_tri(tri: Tri) -> Self;
@@ -14,10 +17,76 @@ pub struct Tri {
     pub normal: Vec4,
 }
 
+#[derive(Debug)]
+pub enum ObjError {
+    Io(PathBuf, io::Error),
+    Parse { file: PathBuf, line: usize, message: String },
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ObjVertex {
+    pub pos: Vec3,
+    pub normal: Vec3,
+    pub tex_coord: Vec2,
+}
+
+#[derive(Clone)]
+pub struct ObjMaterial {
+    pub name: String,
+
+    pub ambient: Vec3,
+    pub diffuse: Vec3,
+    pub specular: Vec3,
+    pub emissive: Vec3,
+    pub shininess: f32,
+    pub dissolve: f32,
+    pub optical_density: f32,
+    pub illum: u32,
+
+    // Texture paths are resolved relative to the MTL file
+    pub ambient_texture: Option<PathBuf>,
+    pub diffuse_texture: Option<PathBuf>,
+    pub specular_texture: Option<PathBuf>,
+    pub normal_texture: Option<PathBuf>,
+    pub dissolve_texture: Option<PathBuf>,
+}
+
+// A run of indices that share an object, group and material
+#[derive(Clone)]
+pub struct ObjGroup {
+    pub object: String,
+    pub name: String,
+    pub material: Option<usize>,
+
+    pub first_index: usize,
+    pub index_count: usize,
+}
+
+pub struct ObjMesh {
+    pub vertices: Vec<ObjVertex>,
+    pub indices: Vec<u32>,
+    pub groups: Vec<ObjGroup>,
+    pub materials: Vec<ObjMaterial>,
+    pub warnings: Vec<String>, // Problems the mesh was still loaded despite, prefixed with the file and line
+}
+
+// Where the parser has got to in a file, used for error messages
+struct ObjCursor<'a> {
+    file: &'a Path,
+    line: usize,
+}
+
 impl Tri {
     pub fn new(v0: Vec3, v1: Vec3, v2: Vec3) -> Tri {
-        let normal = Vec4::from_vec3(Vec3::cross(v1 - v0, v2 - v0).normalize());
-        
+        // Degenerate triangles get a zero normal rather than NaNs from normalizing a zero vector
+        let cross = Vec3::cross(v1 - v0, v2 - v0);
+        let normal = if cross.len() > 0.0 {
+            Vec4::from_vec3(cross.normalize())
+        } else {
+            Vec4::from_vec3(Vec3::zero())
+        };
+
         Tri {
             verts: [Vec4::from_vec3(v0), Vec4::from_vec3(v1), Vec4::from_vec3(v2)],
             normal,
@@ -25,82 +94,508 @@ impl Tri {
     }
 }
 
-enum ObjParserState {
-    Inactive,
-    Verts(usize, usize),
-    Faces(usize, usize),
+impl VertexAttributes for ObjVertex {
+    fn get_attribute_data() -> Vec<VertexAttribute> {
+        vec![
+            VertexAttribute { format: vk::Format::R32G32B32_SFLOAT, offset: 0 },
+            VertexAttribute { format: vk::Format::R32G32B32_SFLOAT, offset: 12 },
+            VertexAttribute { format: vk::Format::R32G32_SFLOAT, offset: 24 },
+        ]
+    }
+}
+
+impl ObjMaterial {
+    // Same defaults as the MTL spec uses for missing statements
+    pub fn new(name: &str) -> ObjMaterial {
+        ObjMaterial {
+            name: name.to_string(),
+
+            ambient: Vec3::new(0.2, 0.2, 0.2),
+            diffuse: Vec3::new(0.8, 0.8, 0.8),
+            specular: Vec3::new(1.0, 1.0, 1.0),
+            emissive: Vec3::zero(),
+            shininess: 0.0,
+            dissolve: 1.0,
+            optical_density: 1.0,
+            illum: 2,
+
+            ambient_texture: None,
+            diffuse_texture: None,
+            specular_texture: None,
+            normal_texture: None,
+            dissolve_texture: None,
+        }
+    }
 }
 
-pub fn parse_obj_as_tris<T: FromObjTri>(tris: &mut Vec<T>, name: &str) {
-    let mut file = fs::File::open(name).expect(&format!("Error: File \"{}\" not found", name));
-    let mut raw = String::new();
-    file.read_to_string(&mut raw).unwrap();
+impl<'a> ObjCursor<'a> {
+    fn error(&self, message: String) -> ObjError {
+        ObjError::Parse { file: self.file.to_path_buf(), line: self.line, message }
+    }
 
-    let mut state = ObjParserState::Inactive;
+    // For problems the mesh can still be loaded despite
+    fn warning(&self, message: String) -> String {
+        format!("{}:{}: {}", self.file.display(), self.line, message)
+    }
 
-    let mut vs: Vec<[f32; 3]> = vec![];
-    let mut fs: Vec<[[f32; 3]; 3]> = Vec::new();
+    fn parse_f32(&self, token: Option<&str>, statement: &str) -> Result<f32, ObjError> {
+        let token = token.ok_or_else(|| self.error(format!("\"{}\" is missing a value", statement)))?;
+        token.parse::<f32>().map_err(|_| self.error(format!("\"{}\" is not a number", token)))
+    }
 
-    let mut i: usize = 0;
-    let mut c_prev: char = 0 as char;
-    for c in raw.chars() {
-        match state {
-            ObjParserState::Inactive => {
-                if c == 'v' && c_prev == 10 as char {
-                    vs.push([0.0, 0.0, 0.0]);
+    fn parse_vec3(&self, tokens: &[&str], statement: &str) -> Result<Vec3, ObjError> {
+        Ok(Vec3::new(
+            self.parse_f32(tokens.first().copied(), statement)?,
+            self.parse_f32(tokens.get(1).copied(), statement)?,
+            self.parse_f32(tokens.get(2).copied(), statement)?,
+        ))
+    }
 
-                    state = ObjParserState::Verts(0, 0);
-                }
-                if c == 'f' && c_prev == 10 as char {
-                    fs.push([[0.0, 0.0, 0.0], [0.0, 0.0, 0.0], [0.0, 0.0, 0.0]]);
+    // OBJ indices start at 1, negative indices count back from the most recent element
+    fn resolve_index(&self, token: &str, len: usize, element: &str) -> Result<usize, ObjError> {
+        let index = token.parse::<i64>().map_err(|_| self.error(format!("\"{}\" is not a valid {} index", token, element)))?;
 
-                    state = ObjParserState::Faces(0, 0);
-                }
-            }
-            ObjParserState::Verts(count, start) => {
-                if char::is_whitespace(c) {
-                    if start != 0 {
-                        let v = vs.last_mut().unwrap();
+        let resolved = if index > 0 {
+            index - 1
+        } else {
+            len as i64 + index
+        };
 
-                        v[count] = raw[start..i].parse::<f32>().unwrap();
+        if index == 0 || resolved < 0 || resolved >= len as i64 {
+            return Err(self.error(format!("{} index {} is out of range, there are {}", element, index, len)));
+        }
 
-                        if count == 2 {
-                            state = ObjParserState::Inactive;
-                        } else {
-                            state = ObjParserState::Verts(count + 1, i + 1);
-                        }
-                    } else {
-                        state = ObjParserState::Verts(count, i + 1);
+        Ok(resolved as usize)
+    }
+}
+
+impl ObjMesh {
+    pub fn load(path: &str) -> Result<ObjMesh, ObjError> {
+        let path = Path::new(path);
+        let source = read_file(path)?;
+
+        ObjMesh::parse(&source, path)
+    }
+
+    // path is only used for error messages and to find mtllib files
+    pub fn parse(source: &str, path: &Path) -> Result<ObjMesh, ObjError> {
+        let mut positions = Vec::<Vec3>::new();
+        let mut normals = Vec::<Vec3>::new();
+        let mut tex_coords = Vec::<Vec2>::new();
+
+        let mut mesh = ObjMesh {
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            groups: Vec::new(),
+            materials: Vec::new(),
+            warnings: Vec::new(),
+        };
+
+        // Materials used before any library defined them, with the line of their first usemtl
+        let mut placeholders = HashMap::<usize, usize>::new();
+
+        // Each unique v/vt/vn triple becomes one vertex
+        let mut vertex_refs = HashMap::<(usize, Option<usize>, Option<usize>), u32>::new();
+        let mut missing_normals = Vec::<bool>::new();
+
+        let mut object = String::new();
+        let mut group = String::from("default");
+        let mut material: Option<usize> = None;
+
+        let mut cursor = ObjCursor { file: path, line: 0 };
+
+        for (line, statement) in logical_lines(source) {
+            cursor.line = line;
+
+            let tokens: Vec<&str> = statement.split_whitespace().collect();
+            let (keyword, args) = match tokens.split_first() {
+                Some((keyword, args)) => (*keyword, args),
+                None => continue,
+            };
+
+            match keyword {
+                "v" => positions.push(cursor.parse_vec3(args, keyword)?),
+                "vn" => normals.push(cursor.parse_vec3(args, keyword)?),
+                "vt" => {
+                    let u = cursor.parse_f32(args.first().copied(), keyword)?;
+                    let v = match args.get(1) {
+                        Some(v) => cursor.parse_f32(Some(v), keyword)?,
+                        None => 0.0,
+                    };
+
+                    tex_coords.push(Vec2::new(u, v));
+                },
+                "f" => {
+                    if args.len() < 3 {
+                        return Err(cursor.error(format!("Face has {} vertices, at least 3 are needed", args.len())));
                     }
-                }
-            }
-            ObjParserState::Faces(count, start) => {
-                if char::is_whitespace(c) {
-                    if start != 0 {
-                        let f = fs.last_mut().unwrap();
-                        let vi = raw[start..i].parse::<usize>().unwrap();
-
-                        f[count] = vs[vi - 1];
-
-                        if count == 2 {
-                            state = ObjParserState::Inactive;
-                        } else {
-                            state = ObjParserState::Faces(count + 1, i + 1);
+
+                    let mut face = Vec::<u32>::with_capacity(args.len());
+
+                    for arg in args {
+                        let mut parts = arg.split('/');
+
+                        let v = cursor.resolve_index(parts.next().unwrap(), positions.len(), "Vertex")?;
+                        let vt = match parts.next() {
+                            Some(vt) if !vt.is_empty() => Some(cursor.resolve_index(vt, tex_coords.len(), "Texture coordinate")?),
+                            _ => None,
+                        };
+                        let vn = match parts.next() {
+                            Some(vn) if !vn.is_empty() => Some(cursor.resolve_index(vn, normals.len(), "Normal")?),
+                            _ => None,
+                        };
+
+                        let index = *vertex_refs.entry((v, vt, vn)).or_insert_with(|| {
+                            mesh.vertices.push(ObjVertex {
+                                pos: positions[v],
+                                normal: vn.map_or(Vec3::zero(), |vn| normals[vn]),
+                                tex_coord: vt.map_or(Vec2::zero(), |vt| tex_coords[vt]),
+                            });
+                            missing_normals.push(vn.is_none());
+
+                            (mesh.vertices.len() - 1) as u32
+                        });
+
+                        face.push(index);
+                    }
+
+                    mesh.start_group(&object, &group, material);
+
+                    // Fan triangulation, fine for the convex polygons exporters write
+                    for i in 1..face.len() - 1 {
+                        mesh.indices.extend_from_slice(&[face[0], face[i], face[i + 1]]);
+                    }
+
+                    mesh.groups.last_mut().unwrap().index_count += 3 * (face.len() - 2);
+                },
+                "o" => object = args.join(" "),
+                "g" => group = if args.is_empty() { String::from("default") } else { args.join(" ") },
+                "usemtl" => {
+                    let name = args.join(" ");
+
+                    // Undefined materials get the MTL defaults under their name until a later mtllib defines them
+                    material = Some(match mesh.materials.iter().position(|m| m.name == name) {
+                        Some(i) => i,
+                        None => {
+                            mesh.materials.push(ObjMaterial::new(&name));
+                            placeholders.insert(mesh.materials.len() - 1, line);
+                            mesh.materials.len() - 1
+                        },
+                    });
+                },
+                "mtllib" => {
+                    for file in args {
+                        let mtl_path = path.parent().unwrap_or(Path::new("")).join(file);
+
+                        // A missing library only loses materials, usemtl falls back to the defaults for them
+                        let source = match read_file(&mtl_path) {
+                            Ok(source) => source,
+                            Err(e) => {
+                                mesh.warnings.push(cursor.warning(format!("{}, materials from it will use the defaults", e)));
+                                continue;
+                            },
+                        };
+
+                        for library_material in parse_mtl(&source, &mtl_path)? {
+                            match mesh.materials.iter().position(|m| m.name == library_material.name).filter(|i| placeholders.contains_key(i)) {
+                                Some(i) => {
+                                    placeholders.remove(&i);
+                                    mesh.materials[i] = library_material;
+                                },
+                                None => mesh.materials.push(library_material),
+                            }
                         }
-                    } else {
-                        state = ObjParserState::Faces(count, i + 1);
                     }
+                },
+                // Smoothing groups, lines, points and curves have no use here
+                _ => {},
+            }
+        }
+
+        let mut placeholders: Vec<(usize, usize)> = placeholders.into_iter().collect();
+        placeholders.sort_by_key(|p| p.1);
+
+        for (i, line) in placeholders {
+            cursor.line = line;
+            mesh.warnings.push(cursor.warning(format!("Material \"{}\" is not defined, using the default material", mesh.materials[i].name)));
+        }
+
+        mesh.smooth_missing_normals(&missing_normals);
+
+        Ok(mesh)
+    }
+
+    fn start_group(&mut self, object: &str, name: &str, material: Option<usize>) {
+        if let Some(last) = self.groups.last() {
+            if last.object == object && last.name == name && last.material == material {
+                return;
+            }
+        }
+
+        self.groups.retain(|g| g.index_count > 0);
+        self.groups.push(ObjGroup {
+            object: object.to_string(),
+            name: name.to_string(),
+            material,
+
+            first_index: self.indices.len(),
+            index_count: 0,
+        });
+    }
+
+    // Vertices without a vn get the average normal of the faces using them
+    fn smooth_missing_normals(&mut self, missing_normals: &[bool]) {
+        if !missing_normals.contains(&true) {
+            return;
+        }
+
+        for tri in self.indices.chunks_exact(3) {
+            let [v0, v1, v2] = [tri[0] as usize, tri[1] as usize, tri[2] as usize];
+            let normal = Vec3::cross(self.vertices[v1].pos - self.vertices[v0].pos, self.vertices[v2].pos - self.vertices[v0].pos);
+
+            for v in [v0, v1, v2] {
+                if missing_normals[v] {
+                    self.vertices[v].normal = self.vertices[v].normal + normal;
                 }
             }
         }
-        i += 1;
-        c_prev = c;
+
+        for (vertex, missing) in self.vertices.iter_mut().zip(missing_normals) {
+            if *missing && vertex.normal.len() > 0.0 {
+                vertex.normal = vertex.normal.normalize();
+            }
+        }
+    }
+
+    // Flat shaded triangles with face normals, for the raytracer
+    pub fn tris(&self) -> Vec<Tri> {
+        self.indices.chunks_exact(3).map(|tri| {
+            Tri::new(self.vertices[tri[0] as usize].pos, self.vertices[tri[1] as usize].pos, self.vertices[tri[2] as usize].pos)
+        }).collect()
+    }
+
+    pub fn tris_as<T: FromObjTri>(&self) -> Vec<T> {
+        self.tris().into_iter().map(T::from_obj_tri).collect()
+    }
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjError::Io(path, e) => write!(f, "Failed to read \"{}\": {}", path.display(), e),
+            ObjError::Parse { file, line, message } => write!(f, "{}:{}: {}", file.display(), line, message),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ObjError::Io(_, e) => Some(e),
+            ObjError::Parse { .. } => None,
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, ObjError> {
+    fs::read_to_string(path).map_err(|e| ObjError::Io(path.to_path_buf(), e))
+}
+
+// Strips comments and joins lines ending in a backslash, line numbers are where each statement starts
+fn logical_lines(source: &str) -> Vec<(usize, String)> {
+    let mut statements = Vec::<(usize, String)>::new();
+    let mut continued: Option<(usize, String)> = None;
+
+    for (i, line) in source.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+
+        let (start, mut statement) = continued.take().unwrap_or((i + 1, String::new()));
+        let line = line.trim_end();
+
+        match line.strip_suffix('\\') {
+            Some(rest) => {
+                statement.push_str(rest);
+                statement.push(' ');
+                continued = Some((start, statement));
+            },
+            None => {
+                statement.push_str(line);
+                statements.push((start, statement));
+            },
+        }
+    }
+
+    if let Some(statement) = continued {
+        statements.push(statement);
+    }
+
+    statements
+}
+
+fn parse_mtl(source: &str, path: &Path) -> Result<Vec<ObjMaterial>, ObjError> {
+    let mut materials = Vec::<ObjMaterial>::new();
+    let mut cursor = ObjCursor { file: path, line: 0 };
+
+    let texture_path = |args: &[&str]| args.last().map(|file| path.parent().unwrap_or(Path::new("")).join(file));
+
+    for (line, statement) in logical_lines(source) {
+        cursor.line = line;
+
+        let tokens: Vec<&str> = statement.split_whitespace().collect();
+        let (keyword, args) = match tokens.split_first() {
+            Some((keyword, args)) => (*keyword, args),
+            None => continue,
+        };
+
+        if keyword == "newmtl" {
+            materials.push(ObjMaterial::new(&args.join(" ")));
+            continue;
+        }
+
+        let material = match materials.last_mut() {
+            Some(material) => material,
+            None => return Err(cursor.error(format!("\"{}\" comes before any newmtl", keyword))),
+        };
+
+        // Texture options come before the file name, so only the last argument is used
+        match keyword {
+            "Ka" => material.ambient = cursor.parse_vec3(args, keyword)?,
+            "Kd" => material.diffuse = cursor.parse_vec3(args, keyword)?,
+            "Ks" => material.specular = cursor.parse_vec3(args, keyword)?,
+            "Ke" => material.emissive = cursor.parse_vec3(args, keyword)?,
+            "Ns" => material.shininess = cursor.parse_f32(args.first().copied(), keyword)?,
+            "Ni" => material.optical_density = cursor.parse_f32(args.first().copied(), keyword)?,
+            "d" => material.dissolve = cursor.parse_f32(args.first().copied(), keyword)?,
+            "Tr" => material.dissolve = 1.0 - cursor.parse_f32(args.first().copied(), keyword)?,
+            "illum" => material.illum = cursor.parse_f32(args.first().copied(), keyword)? as u32,
+            "map_Ka" => material.ambient_texture = texture_path(args),
+            "map_Kd" => material.diffuse_texture = texture_path(args),
+            "map_Ks" => material.specular_texture = texture_path(args),
+            "map_Bump" | "map_bump" | "bump" | "norm" => material.normal_texture = texture_path(args),
+            "map_d" => material.dissolve_texture = texture_path(args),
+            _ => {},
+        }
+    }
+
+    Ok(materials)
+}
+
+// Returns the mesh's warnings
+pub fn parse_obj_as_tris<T: FromObjTri>(tris: &mut Vec<T>, name: &str) -> Result<Vec<String>, ObjError> {
+    let mesh = ObjMesh::load(name)?;
+    tris.extend(mesh.tris_as::<T>());
+
+    Ok(mesh.warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> ObjMesh {
+        ObjMesh::parse(source, Path::new("test.obj")).unwrap()
+    }
+
+    fn assert_vec3(v: Vec3, x: f32, y: f32, z: f32) {
+        assert!((v.x - x).abs() < 1e-6 && (v.y - y).abs() < 1e-6 && (v.z - z).abs() < 1e-6, "({}) != ({}, {}, {})", v, x, y, z);
+    }
+
+    // Each test gets its own directory so they can run in parallel
+    fn write_mtl(test: &str, source: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("obj_test_{}_{}", std::process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("materials.mtl"), source).unwrap();
+
+        dir.join("test.obj")
+    }
+
+    #[test]
+    fn faces_with_tex_coords_and_normals() {
+        let mesh = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 0 1\nvn 0 0 1\nf 1/1/1 2/2/1 3/3/1\n");
+
+        assert_eq!(mesh.vertices.len(), 3);
+        assert_eq!(mesh.indices, [0, 1, 2]);
+        assert_vec3(mesh.vertices[1].pos, 1.0, 0.0, 0.0);
+        assert_eq!((mesh.vertices[2].tex_coord.x, mesh.vertices[2].tex_coord.y), (0.0, 1.0));
+        assert_vec3(mesh.vertices[0].normal, 0.0, 0.0, 1.0);
+    }
+
+    #[test]
+    fn negative_indices_count_back() {
+        let mesh = parse("v 5 5 5\nv 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\n");
+
+        assert_eq!(mesh.vertices.len(), 3);
+        assert_vec3(mesh.vertices[0].pos, 0.0, 0.0, 0.0);
+        assert_vec3(mesh.vertices[2].pos, 0.0, 1.0, 0.0);
+
+        assert!(ObjMesh::parse("v 0 0 0\nf -2 1 1\n", Path::new("test.obj")).is_err());
+        assert!(ObjMesh::parse("v 0 0 0\nf 0 1 1\n", Path::new("test.obj")).is_err());
+    }
+
+    #[test]
+    fn quads_and_ngons_are_fanned() {
+        let mesh = parse("v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv -1 1 0\nf 1 2 3 4\nf 1 2 3 4 5\n");
+
+        assert_eq!(mesh.indices, [0, 1, 2, 0, 2, 3, 0, 1, 2, 0, 2, 3, 0, 3, 4]);
+        assert_eq!(mesh.groups.len(), 1);
+        assert_eq!(mesh.groups[0].index_count, 15);
+    }
+
+    #[test]
+    fn crlf_comments_and_continuations() {
+        let mesh = parse("# header\r\nv 0 0 0 # origin\r\nv 1 \\\r\n 0 0\r\nv 0 1 0\r\n\r\nf 1 2 3\r\n");
+
+        assert_eq!(mesh.vertices.len(), 3);
+        assert_vec3(mesh.vertices[1].pos, 1.0, 0.0, 0.0);
+        assert_eq!(mesh.indices, [0, 1, 2]);
+    }
+
+    #[test]
+    fn missing_normals_are_smoothed_from_faces() {
+        let mesh = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\nvn 1 0 0\nf 1 2 3\nf 1//1 3//1 4//1\n");
+
+        // Only the vertices without a vn get face normals
+        assert_vec3(mesh.vertices[0].normal, 0.0, 0.0, 1.0);
+        assert_vec3(mesh.vertices[3].normal, 1.0, 0.0, 0.0);
     }
 
-    for i in &fs {
-        let tri = Tri::new(Vec3::new(i[0][0], i[0][1], i[0][2]), Vec3::new(i[1][0], i[1][1], i[1][2]), Vec3::new(i[2][0], i[2][1], i[2][2]));
-        let formatted_tri = T::from_obj_tri(tri.clone());
+    #[test]
+    fn undefined_materials_fall_back_to_defaults() {
+        let mesh = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl missing\nf 1 2 3\n");
 
-        tris.push(formatted_tri);
+        assert_eq!(mesh.materials.len(), 1);
+        assert_eq!(mesh.materials[0].name, "missing");
+        assert_vec3(mesh.materials[0].diffuse, 0.8, 0.8, 0.8);
+        assert_eq!(mesh.groups[0].material, Some(0));
+        assert_eq!(mesh.warnings, ["test.obj:4: Material \"missing\" is not defined, using the default material"]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn missing_mtllib_is_a_warning() {
+        let mesh = ObjMesh::parse("mtllib nowhere.mtl\n", &std::env::temp_dir().join("obj_test_missing").join("test.obj")).unwrap();
+
+        assert_eq!(mesh.warnings.len(), 1);
+        assert!(mesh.warnings[0].contains("nowhere.mtl"));
+    }
+
+    #[test]
+    fn later_mtllib_replaces_placeholder_materials() {
+        let path = write_mtl("placeholder", "newmtl red\nKd 1 0 0\nnewmtl blue\nKd 0 0 1\n");
+        let mesh = ObjMesh::parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nf 1 2 3\nmtllib materials.mtl\nusemtl blue\nf 1 2 3\n", &path).unwrap();
+
+        assert_eq!(mesh.materials.len(), 2);
+        assert_vec3(mesh.materials[0].diffuse, 1.0, 0.0, 0.0);
+        assert_eq!(mesh.groups.iter().map(|g| g.material).collect::<Vec<_>>(), [Some(0), Some(1)]);
+        assert!(mesh.warnings.is_empty());
+    }
+
+    #[test]
+    fn degenerate_tris_have_zero_normals() {
+        let tri = Tri::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 1.0), Vec3::new(2.0, 2.0, 2.0));
+
+        assert_vec3(tri.normal.to_vec3(), 0.0, 0.0, 0.0);
+    }
+}
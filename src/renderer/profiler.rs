@@ -0,0 +1,572 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use ash::vk;
+
+use crate::renderer::{device::Device, error::RendererError, layer::LayerExecution};
+use crate::util::frametime::Frametime;
+
+// Results come back in the order of these bits, compute queues without graphics can only count compute invocations
+const STATISTICS: [vk::QueryPipelineStatisticFlags; 5] = [
+    vk::QueryPipelineStatisticFlags::INPUT_ASSEMBLY_VERTICES,
+    vk::QueryPipelineStatisticFlags::VERTEX_SHADER_INVOCATIONS,
+    vk::QueryPipelineStatisticFlags::CLIPPING_PRIMITIVES,
+    vk::QueryPipelineStatisticFlags::FRAGMENT_SHADER_INVOCATIONS,
+    vk::QueryPipelineStatisticFlags::COMPUTE_SHADER_INVOCATIONS,
+];
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PipelineStatistics {
+    pub input_assembly_vertices: u64,
+    pub vertex_shader_invocations: u64,
+    pub clipping_primitives: u64,
+    pub fragment_shader_invocations: u64,
+    pub compute_shader_invocations: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct PassTiming {
+    pub layer: String,
+    pub pass: String,
+    pub start: Duration, // From the first timestamp written in the frame
+    pub duration: Duration,
+    pub statistics: Option<PipelineStatistics>,
+}
+
+// GPU timings of one frame, read back once the frame has finished, alongside the CPU timings given for the same frame
+#[derive(Debug, Clone)]
+pub struct FrameProfile {
+    pub frame_number: u64,
+
+    pub gpu_start: Duration, // From the first profiled frame's first timestamp
+    pub passes: Vec<PassTiming>,
+
+    pub cpu_start: Option<Duration>, // From when profiling was enabled
+    pub cpu: Vec<(String, Duration)>,
+}
+
+// Timestamps are written around every pass a layer records, with a pool per frame in flight so a frame's results can be read while the next is recorded
+pub struct LayerQueries {
+    pub capacity: u32, // Passes past this many in a layer aren't profiled
+    pub statistic_flags: vk::QueryPipelineStatisticFlags,
+    timestamp_mask: u64, // Bits past the queue's timestamp_valid_bits are undefined
+
+    timestamp_pools: Vec<vk::QueryPool>,
+    statistics_pools: Vec<vk::QueryPool>, // Empty when pipeline statistics aren't queried
+    recorded: Vec<Option<(u64, Vec<String>)>>, // The frame number and passes last recorded into each frame's pools
+}
+
+pub struct ProfilerBuilder {
+    pipeline_statistics: Option<bool>,
+    max_passes: Option<u32>,
+    history: Option<usize>,
+}
+
+pub struct Profiler {
+    pub pipeline_statistics: bool,
+    pub max_passes: u32,
+    pub history_len: usize,
+
+    pub history: VecDeque<FrameProfile>, // Oldest first
+    pub timestamp_period: f32, // Nanoseconds per tick
+
+    origin: Instant,
+    gpu_origin: Option<Duration>,
+    cpu_timings: HashMap<u64, (Duration, Vec<(String, Duration)>)>,
+}
+
+impl PipelineStatistics {
+    fn from_results(flags: vk::QueryPipelineStatisticFlags, results: &[u64; 5]) -> PipelineStatistics {
+        let mut values = [0; 5];
+        let mut result = 0;
+
+        for (i, statistic) in STATISTICS.iter().enumerate() {
+            if flags.contains(*statistic) {
+                values[i] = results[result];
+                result += 1;
+            }
+        }
+
+        PipelineStatistics {
+            input_assembly_vertices: values[0],
+            vertex_shader_invocations: values[1],
+            clipping_primitives: values[2],
+            fragment_shader_invocations: values[3],
+            compute_shader_invocations: values[4],
+        }
+    }
+}
+
+impl FrameProfile {
+    // From the start of the first pass to the end of the last, on any queue
+    pub fn gpu_total(&self) -> Duration {
+        self.passes.iter().map(|p| p.start + p.duration).max().unwrap_or(Duration::ZERO)
+    }
+
+    pub fn cpu_total(&self) -> Duration {
+        self.cpu.iter().map(|(_, time)| *time).sum()
+    }
+}
+
+impl fmt::Display for FrameProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Frame {} (GPU = {:.3}ms, CPU = {:.3}ms):", self.frame_number, self.gpu_total().as_secs_f64() * 1000.0, self.cpu_total().as_secs_f64() * 1000.0)?;
+
+        for pass in &self.passes {
+            write!(f, "\n\t{}/{}: {:.3}ms", pass.layer, pass.pass, pass.duration.as_secs_f64() * 1000.0)?;
+        }
+
+        for (segment, time) in &self.cpu {
+            write!(f, "\n\tCPU {}: {:.3}ms", segment, time.as_secs_f64() * 1000.0)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl LayerQueries {
+    /// # Safety
+    /// The pools must be destroyed before `d`.
+    pub unsafe fn new(d: &Device, count: usize, capacity: u32, exec: LayerExecution, pipeline_statistics: bool) -> Result<LayerQueries, RendererError> {
+        let statistic_flags = match (pipeline_statistics, exec) {
+            (false, _) => vk::QueryPipelineStatisticFlags::empty(),
+            (true, LayerExecution::Main) => STATISTICS.iter().fold(vk::QueryPipelineStatisticFlags::empty(), |flags, s| flags | *s),
+            (true, LayerExecution::Async) => vk::QueryPipelineStatisticFlags::COMPUTE_SHADER_INVOCATIONS,
+        };
+
+        let valid_bits = d.queue_families[d.get_queue(exec).1 as usize].timestamp_valid_bits;
+        if valid_bits == 0 {
+            return Err(RendererError::UnsupportedFeature("timestamp queries on this queue"));
+        }
+
+        let mut queries = LayerQueries {
+            capacity,
+            statistic_flags,
+            timestamp_mask: if valid_bits >= 64 { u64::MAX } else { (1 << valid_bits) - 1 },
+
+            timestamp_pools: Vec::new(),
+            statistics_pools: Vec::new(),
+            recorded: vec![None; count],
+        };
+
+        for _ in 0..count {
+            let timestamp_pool_ci = vk::QueryPoolCreateInfo::builder()
+                .query_type(vk::QueryType::TIMESTAMP)
+                .query_count(capacity * 2);
+
+            match d.device.create_query_pool(&timestamp_pool_ci, None) {
+                Ok(pool) => queries.timestamp_pools.push(pool),
+                Err(e) => {
+                    queries.destroy(d);
+                    return Err(e.into());
+                },
+            }
+
+            if pipeline_statistics {
+                let statistics_pool_ci = vk::QueryPoolCreateInfo::builder()
+                    .query_type(vk::QueryType::PIPELINE_STATISTICS)
+                    .query_count(capacity)
+                    .pipeline_statistics(statistic_flags);
+
+                match d.device.create_query_pool(&statistics_pool_ci, None) {
+                    Ok(pool) => queries.statistics_pools.push(pool),
+                    Err(e) => {
+                        queries.destroy(d);
+                        return Err(e.into());
+                    },
+                }
+            }
+        }
+
+        Ok(queries)
+    }
+
+    /// Queries have to be reset before they're written again, which can't happen inside a render pass
+    ///
+    /// # Safety
+    /// `b` must be recording outside of a render pass.
+    pub unsafe fn reset(&self, d: &Device, b: vk::CommandBuffer, i: usize) {
+        d.device.cmd_reset_query_pool(b, self.timestamp_pools[i], 0, self.capacity * 2);
+
+        if let Some(pool) = self.statistics_pools.get(i) {
+            d.device.cmd_reset_query_pool(b, *pool, 0, self.capacity);
+        }
+    }
+
+    /// # Safety
+    /// `b` must be recording and `index` must be below the pool's capacity.
+    pub unsafe fn begin_pass(&self, d: &Device, b: vk::CommandBuffer, i: usize, index: u32) {
+        if index >= self.capacity {
+            return;
+        }
+
+        d.device.cmd_write_timestamp(b, vk::PipelineStageFlags::TOP_OF_PIPE, self.timestamp_pools[i], index * 2);
+
+        if let Some(pool) = self.statistics_pools.get(i) {
+            d.device.cmd_begin_query(b, *pool, index, vk::QueryControlFlags::empty());
+        }
+    }
+
+    /// # Safety
+    /// `b` must be recording and `begin_pass` must have been recorded for the same `index`.
+    pub unsafe fn end_pass(&self, d: &Device, b: vk::CommandBuffer, i: usize, index: u32) {
+        if index >= self.capacity {
+            return;
+        }
+
+        if let Some(pool) = self.statistics_pools.get(i) {
+            d.device.cmd_end_query(b, *pool, index);
+        }
+
+        d.device.cmd_write_timestamp(b, vk::PipelineStageFlags::BOTTOM_OF_PIPE, self.timestamp_pools[i], index * 2 + 1);
+    }
+
+    pub fn set_recorded(&mut self, i: usize, frame_number: u64, passes: Vec<String>) {
+        self.recorded[i] = Some((frame_number, passes));
+    }
+
+    /// Starts are from the device's timestamp epoch, None if nothing has been recorded into the frame's pools or the results aren't ready
+    ///
+    /// # Safety
+    /// Frame `i`'s fence must have been waited on.
+    pub unsafe fn read(&self, d: &Device, i: usize, layer: &str, timestamp_period: f32) -> Result<Option<(u64, Vec<PassTiming>)>, RendererError> {
+        let (frame_number, passes) = match &self.recorded[i] {
+            Some(recorded) => recorded,
+            None => return Ok(None),
+        };
+
+        let count = (passes.len() as u32).min(self.capacity);
+        if count == 0 {
+            return Ok(None);
+        }
+
+        let mut timestamps = vec![0u64; count as usize * 2];
+
+        match d.device.get_query_pool_results(self.timestamp_pools[i], 0, count * 2, &mut timestamps, vk::QueryResultFlags::TYPE_64) {
+            Ok(()) => {},
+            Err(vk::Result::NOT_READY) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut statistics = Vec::<[u64; 5]>::new();
+
+        if let Some(pool) = self.statistics_pools.get(i) {
+            statistics = vec![[0; 5]; count as usize];
+
+            match d.device.get_query_pool_results(*pool, 0, count, &mut statistics, vk::QueryResultFlags::TYPE_64) {
+                Ok(()) => {},
+                Err(vk::Result::NOT_READY) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let to_duration = |ticks: u64| Duration::from_nanos((ticks as f64 * timestamp_period as f64) as u64);
+
+        let timings = passes.iter().take(count as usize).enumerate().map(|(j, pass)| {
+            let (begin, end) = (timestamps[j * 2] & self.timestamp_mask, timestamps[j * 2 + 1] & self.timestamp_mask);
+
+            PassTiming {
+                layer: layer.to_string(),
+                pass: pass.clone(),
+                start: to_duration(begin),
+                // The counter can wrap around between the two timestamps
+                duration: to_duration(end.wrapping_sub(begin) & self.timestamp_mask),
+                statistics: statistics.get(j).map(|s| PipelineStatistics::from_results(self.statistic_flags, s)),
+            }
+        }).collect();
+
+        Ok(Some((*frame_number, timings)))
+    }
+
+    /// # Safety
+    /// No frame in flight can still be writing to the pools.
+    pub unsafe fn destroy(&self, d: &Device) {
+        for pool in self.timestamp_pools.iter().chain(self.statistics_pools.iter()) {
+            d.device.destroy_query_pool(*pool, None);
+        }
+    }
+}
+
+impl Default for ProfilerBuilder {
+    fn default() -> ProfilerBuilder {
+        ProfilerBuilder::new()
+    }
+}
+
+impl ProfilerBuilder {
+    pub fn new() -> ProfilerBuilder {
+        ProfilerBuilder {
+            pipeline_statistics: None,
+            max_passes: None,
+            history: None,
+        }
+    }
+
+    pub fn pipeline_statistics(mut self, pipeline_statistics: bool) -> Self {
+        self.pipeline_statistics = Some(pipeline_statistics);
+        self
+    }
+
+    pub fn max_passes(mut self, max_passes: u32) -> Self {
+        self.max_passes = Some(max_passes);
+        self
+    }
+
+    // How many frames of results are kept for exporting
+    pub fn history(mut self, history: usize) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    pub fn build(self, d: &Device) -> Result<Profiler, RendererError> {
+        let pipeline_statistics = self.pipeline_statistics.unwrap_or(false);
+
+        if pipeline_statistics && d.features.pipeline_statistics_query == vk::FALSE {
+            return Err(RendererError::UnsupportedFeature("pipeline statistics queries"));
+        }
+
+        Ok(Profiler {
+            pipeline_statistics,
+            max_passes: self.max_passes.unwrap_or(32),
+            history_len: self.history.unwrap_or(300),
+
+            history: VecDeque::new(),
+
+            timestamp_period: d.limits.timestamp_period,
+            origin: Instant::now(),
+            gpu_origin: None,
+            cpu_timings: HashMap::new(),
+        })
+    }
+}
+
+impl Profiler {
+    pub fn latest(&self) -> Option<&FrameProfile> {
+        self.history.back()
+    }
+
+    // CPU timings are held until the GPU results of the same frame are read back
+    pub fn add_frametime(&mut self, frame_number: u64, frametime: &Frametime) {
+        let start = frametime.start_time.saturating_duration_since(self.origin);
+        self.cpu_timings.insert(frame_number, (start, frametime.deltas.clone()));
+    }
+
+    // A frame's pools are read each time they come round again, which happens more than once if frames are skipped in between
+    pub fn add_frame(&mut self, frame_number: u64, mut passes: Vec<PassTiming>) {
+        if self.history.back().is_some_and(|f| f.frame_number >= frame_number) {
+            return;
+        }
+
+        let frame_start = passes.iter().map(|p| p.start).min().unwrap_or(Duration::ZERO);
+        let gpu_origin = *self.gpu_origin.get_or_insert(frame_start);
+
+        for pass in &mut passes {
+            pass.start -= frame_start;
+        }
+
+        let (cpu_start, cpu) = match self.cpu_timings.remove(&frame_number) {
+            Some((start, cpu)) => (Some(start), cpu),
+            None => (None, Vec::new()),
+        };
+        self.cpu_timings.retain(|&n, _| n > frame_number);
+
+        self.history.push_back(FrameProfile {
+            frame_number,
+
+            gpu_start: frame_start.saturating_sub(gpu_origin),
+            passes,
+
+            cpu_start,
+            cpu,
+        });
+
+        while self.history.len() > self.history_len {
+            self.history.pop_front();
+        }
+    }
+
+    // Chrome's about://tracing and Perfetto both load this format. The CPU and GPU clocks aren't calibrated against each other,
+    // so each layer gets its own row on the GPU's clock and the CPU row only lines up with itself
+    pub fn chrome_trace(&self) -> String {
+        let mut layers = Vec::<&str>::new();
+        let mut events = Vec::<String>::new();
+
+        for frame in &self.history {
+            if let Some(cpu_start) = frame.cpu_start {
+                let mut start = cpu_start;
+
+                for (segment, time) in &frame.cpu {
+                    events.push(format!(
+                        "{{\"name\":\"{}\",\"cat\":\"cpu\",\"ph\":\"X\",\"pid\":0,\"tid\":0,\"ts\":{:.3},\"dur\":{:.3},\"args\":{{\"frame\":{}}}}}",
+                        escape(segment), micros(start), micros(*time), frame.frame_number,
+                    ));
+                    start += *time;
+                }
+            }
+
+            for pass in &frame.passes {
+                let tid = match layers.iter().position(|l| *l == pass.layer) {
+                    Some(i) => i + 1,
+                    None => {
+                        layers.push(&pass.layer);
+                        layers.len()
+                    },
+                };
+
+                let mut args = format!("\"frame\":{}", frame.frame_number);
+                if let Some(s) = &pass.statistics {
+                    args.push_str(&format!(
+                        ",\"input_assembly_vertices\":{},\"vertex_shader_invocations\":{},\"clipping_primitives\":{},\"fragment_shader_invocations\":{},\"compute_shader_invocations\":{}",
+                        s.input_assembly_vertices, s.vertex_shader_invocations, s.clipping_primitives, s.fragment_shader_invocations, s.compute_shader_invocations,
+                    ));
+                }
+
+                events.push(format!(
+                    "{{\"name\":\"{}\",\"cat\":\"gpu\",\"ph\":\"X\",\"pid\":0,\"tid\":{},\"ts\":{:.3},\"dur\":{:.3},\"args\":{{{}}}}}",
+                    escape(&pass.pass), tid, micros(frame.gpu_start + pass.start), micros(pass.duration), args,
+                ));
+            }
+        }
+
+        let mut names = vec![String::from("{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":0,\"args\":{\"name\":\"CPU\"}}")];
+        for (i, layer) in layers.iter().enumerate() {
+            names.push(format!("{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":{},\"args\":{{\"name\":\"GPU {}\"}}}}", i + 1, escape(layer)));
+        }
+
+        format!("{{\"traceEvents\":[\n{}\n]}}\n", names.into_iter().chain(events).collect::<Vec<String>>().join(",\n"))
+    }
+
+    pub fn export_chrome_trace(&self, path: &str) -> Result<(), RendererError> {
+        fs::write(path, self.chrome_trace()).map_err(|source| RendererError::TraceExport { path: path.to_string(), source })
+    }
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1_000_000.0
+}
+
+// JSON strings can't hold raw control characters
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profiler(history_len: usize) -> Profiler {
+        Profiler {
+            pipeline_statistics: false,
+            max_passes: 32,
+            history_len,
+
+            history: VecDeque::new(),
+            timestamp_period: 1.0,
+
+            origin: Instant::now(),
+            gpu_origin: None,
+            cpu_timings: HashMap::new(),
+        }
+    }
+
+    fn pass(layer: &str, pass: &str, start_us: u64, duration_us: u64) -> PassTiming {
+        PassTiming {
+            layer: layer.to_string(),
+            pass: pass.to_string(),
+            start: Duration::from_micros(start_us),
+            duration: Duration::from_micros(duration_us),
+            statistics: None,
+        }
+    }
+
+    #[test]
+    fn add_frame_makes_starts_relative() {
+        let mut profiler = profiler(10);
+
+        profiler.add_frame(1, vec![pass("main", "a", 1000, 50), pass("main", "b", 1100, 20)]);
+        profiler.add_frame(2, vec![pass("main", "a", 3000, 50)]);
+
+        let first = &profiler.history[0];
+        assert_eq!(first.gpu_start, Duration::ZERO);
+        assert_eq!(first.passes[1].start, Duration::from_micros(100));
+        assert_eq!(first.gpu_total(), Duration::from_micros(120));
+
+        let second = &profiler.history[1];
+        assert_eq!(second.gpu_start, Duration::from_micros(2000));
+        assert_eq!(second.passes[0].start, Duration::ZERO);
+    }
+
+    #[test]
+    fn add_frame_skips_old_frames_and_trims_history() {
+        let mut profiler = profiler(2);
+
+        for frame_number in 1..=3 {
+            profiler.add_frame(frame_number, vec![pass("main", "a", frame_number * 1000, 10)]);
+        }
+        profiler.add_frame(3, vec![pass("main", "a", 9000, 10)]);
+        profiler.add_frame(2, vec![pass("main", "a", 9000, 10)]);
+
+        let frames: Vec<u64> = profiler.history.iter().map(|f| f.frame_number).collect();
+        assert_eq!(frames, [2, 3]);
+        assert_eq!(profiler.latest().unwrap().gpu_start, Duration::from_micros(2000));
+    }
+
+    #[test]
+    fn add_frame_takes_cpu_timings_of_the_same_frame() {
+        let mut profiler = profiler(10);
+        let frametime = Frametime {
+            deltas: vec![(String::from("update"), Duration::from_micros(300))],
+            start_time: profiler.origin + Duration::from_millis(5),
+            last_time: profiler.origin,
+        };
+
+        profiler.add_frametime(1, &frametime);
+        profiler.add_frametime(2, &frametime);
+        profiler.add_frame(2, vec![pass("main", "a", 0, 10)]);
+
+        let frame = profiler.latest().unwrap();
+        assert_eq!(frame.cpu_start, Some(Duration::from_millis(5)));
+        assert_eq!(frame.cpu_total(), Duration::from_micros(300));
+
+        // Timings of frames that were never read back are dropped
+        assert!(profiler.cpu_timings.is_empty());
+    }
+
+    #[test]
+    fn chrome_trace_gives_each_layer_a_row() {
+        let mut profiler = profiler(10);
+        profiler.cpu_timings.insert(1, (Duration::from_micros(10), vec![(String::from("update"), Duration::from_micros(5))]));
+        profiler.add_frame(1, vec![pass("main", "gbuffer", 1000, 50), pass("async", "cull", 1010, 20)]);
+
+        let trace = profiler.chrome_trace();
+
+        assert!(trace.starts_with("{\"traceEvents\":["));
+        assert!(trace.contains("\"tid\":1,\"args\":{\"name\":\"GPU main\"}"));
+        assert!(trace.contains("\"tid\":2,\"args\":{\"name\":\"GPU async\"}"));
+        assert!(trace.contains("{\"name\":\"update\",\"cat\":\"cpu\",\"ph\":\"X\",\"pid\":0,\"tid\":0,\"ts\":10.000,\"dur\":5.000,\"args\":{\"frame\":1}}"));
+        assert!(trace.contains("{\"name\":\"cull\",\"cat\":\"gpu\",\"ph\":\"X\",\"pid\":0,\"tid\":2,\"ts\":10.000,\"dur\":20.000,\"args\":{\"frame\":1}}"));
+    }
+
+    #[test]
+    fn escape_handles_quotes_and_control_characters() {
+        assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+        assert_eq!(escape("line\nnext\ttab\r"), "line\\nnext\\ttab\\r");
+        assert_eq!(escape("bell\u{7}"), "bell\\u0007");
+    }
+}
@@ -2,6 +2,8 @@ use std::{ptr, mem};
 
 use ash::vk;
 
+use crate::renderer::error::RendererError;
+
 pub struct PushConstantBuilder {
     size: usize,
     stage: Option<vk::ShaderStageFlags>,
@@ -31,8 +33,15 @@ impl PushConstantBuilder {
         self
     }
 
-    pub fn build(&self) -> PushConstant {
-        PushConstant::new(self.size, self.stage.unwrap())
+    pub fn build(&self) -> Result<PushConstant, RendererError> {
+        let stage = self.stage.ok_or(RendererError::missing("PushConstantBuilder", "stage"))?;
+
+        // 128 bytes is the smallest maxPushConstantsSize any device reports
+        if self.size > 128 {
+            return Err(RendererError::InvalidPassConfig("Push constants can't be larger than 128 bytes"));
+        }
+
+        Ok(PushConstant::new(self.size, stage))
     }
 }
 
@@ -48,13 +57,15 @@ impl PushConstant {
         }
     }
 
-    pub unsafe fn set_data<T>(&mut self, data: &T) {
-        assert!(mem::size_of::<T>() <= 128, "Error: Push constant data type is larger than 128 bytes");
+    pub unsafe fn set_data<T>(&mut self, data: &T) -> Result<(), RendererError> {
+        if mem::size_of::<T>() > self.size {
+            return Err(RendererError::InvalidPassConfig("Push constant data is larger than the push constant"));
+        }
 
-        self.data.resize(mem::size_of::<T>(), 0);
-        
         let data_ptr = self.data.as_mut_ptr();
         
-        ptr::copy(data as *const T as *mut u8, data_ptr, self.size);
+        ptr::copy(data as *const T as *const u8, data_ptr, mem::size_of::<T>());
+
+        Ok(())
     }
 }
\ No newline at end of file
@@ -1,21 +1,33 @@
 use std::collections::HashMap;
 
-use crate::renderer::{buffer::{Buffer, BufferBuilder}, image::{Image, ImageBuilder}, core::Core, device::Device};
+use ash::vk;
 
-#[derive(Copy, Clone)]
+use crate::renderer::{buffer::{Buffer, BufferBuilder}, image::{Image, ImageBuilder}, texture::TextureBuilder, vertex_buffer::{VertexAttributes, VertexBuffer}, core::Core, device::Device, deletion_queue::{DeletionQueue, Retired}, error::RendererError};
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub enum ResourceReference {
     Buffer(usize),
     Image(usize),
 }
 
+// Carries the counts a draw needs, the buffers are looked up when the draw is recorded so a removed mesh is caught
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MeshHandle {
+    pub index: usize,
+    pub vertex_count: u32,
+    pub index_count: u32, // 0 when the mesh has no indices
+}
+
 pub struct RendererData {
     pub count: usize,
 
     pub buffers: Vec<Vec<Buffer>>,
     pub images: Vec<Vec<Image>>,
+    pub meshes: Vec<Option<VertexBuffer>>, // Meshes never change so every frame shares them
 
     pub buffer_refs: HashMap<String, usize>,
     pub image_refs: HashMap<String, usize>,
+    pub mesh_refs: HashMap<String, MeshHandle>,
 }
 
 impl RendererData {
@@ -24,35 +36,152 @@ impl RendererData {
             count,
             buffers: Vec::new(),
             images: Vec::new(),
+            meshes: Vec::new(),
             buffer_refs: HashMap::new(),
             image_refs: HashMap::new(),
+            mesh_refs: HashMap::new(),
         }
     }
 
-    pub unsafe fn add_buffers(&mut self, c: &Core, d: &Device, name: &str, builder: BufferBuilder) {
-        self.buffers.push(builder.build_many(c, d, self.count));
+    pub unsafe fn add_buffers(&mut self, c: &Core, d: &Device, name: &str, builder: BufferBuilder) -> Result<(), RendererError> {
+        self.buffers.push(builder.build_many(c, d, self.count)?);
         self.buffer_refs.insert(name.to_string(), self.buffers.len() - 1);
+
+        Ok(())
     }
 
-    pub unsafe fn add_images(&mut self, c: &Core, d: &Device, name: &str, builder: ImageBuilder) {
-        self.images.push(builder.build_many(c, d, self.count));
+    pub unsafe fn add_images(&mut self, c: &Core, d: &Device, name: &str, builder: ImageBuilder) -> Result<(), RendererError> {
+        // Layers expect every image to be in a known layout at the start of each frame
+        let builder = match builder.layout {
+            Some(_) => builder,
+            None => builder.layout(vk::ImageLayout::GENERAL),
+        };
+
+        self.images.push(builder.build_many(c, d, self.count)?);
+        self.image_refs.insert(name.to_string(), self.images.len() - 1);
+
+        Ok(())
+    }
+
+    /// Textures never change so every frame shares the same image
+    ///
+    /// # Safety
+    /// Submits the upload and waits for it, so it can't be called while a frame is being recorded.
+    pub unsafe fn add_texture(&mut self, c: &Core, d: &Device, name: &str, builder: TextureBuilder) -> Result<(), RendererError> {
+        let image = builder.build(c, d)?;
+        self.insert_texture(name, image);
+
+        Ok(())
+    }
+
+    pub fn insert_texture(&mut self, name: &str, image: Image) {
+        let shares: Vec<Image> = (1..self.count).map(|_| image.share()).collect();
+
+        self.images.push(std::iter::once(image).chain(shares).collect());
         self.image_refs.insert(name.to_string(), self.images.len() - 1);
     }
 
-    pub fn get_buffers(&self, name: &str) -> &Vec<Buffer> {
-        &self.buffers[*self.buffer_refs.get(name).unwrap()]
+    /// # Safety
+    /// Must not be called while a frame is being recorded.
+    pub unsafe fn add_mesh<T: VertexAttributes>(&mut self, c: &Core, d: &Device, name: &str, verts: &[T], indices: Option<&[u32]>) -> Result<MeshHandle, RendererError> {
+        let vertex_buffer = VertexBuffer::new(c, d, verts, indices)?;
+
+        let handle = MeshHandle {
+            index: self.meshes.len(),
+            vertex_count: verts.len() as u32,
+            index_count: indices.map_or(0, |is| is.len() as u32),
+        };
+
+        self.meshes.push(Some(vertex_buffer));
+        self.mesh_refs.insert(name.to_string(), handle);
+
+        Ok(handle)
+    }
+
+    // The slot is left empty rather than removed so the references held by passes to other resources stay valid
+    pub fn remove_buffers(&mut self, name: &str, deletion_queue: &mut DeletionQueue) -> Result<(), RendererError> {
+        let buffer_ref = self.buffer_refs.remove(name).ok_or(RendererError::UnknownResource(name.to_string()))?;
+
+        for buffer in std::mem::take(&mut self.buffers[buffer_ref]) {
+            deletion_queue.retire(Retired::Buffer(buffer));
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_images(&mut self, name: &str, deletion_queue: &mut DeletionQueue) -> Result<(), RendererError> {
+        let image_ref = self.image_refs.remove(name).ok_or(RendererError::UnknownResource(name.to_string()))?;
+
+        for image in std::mem::take(&mut self.images[image_ref]) {
+            deletion_queue.retire(Retired::Image(image));
+        }
+
+        Ok(())
+    }
+
+    // Slots aren't reused, so draws still holding the handle fail instead of drawing a different mesh
+    pub fn remove_mesh(&mut self, name: &str, deletion_queue: &mut DeletionQueue) -> Result<(), RendererError> {
+        let handle = self.mesh_refs.remove(name).ok_or(RendererError::UnknownResource(name.to_string()))?;
+
+        if let Some(vertex_buffer) = self.meshes[handle.index].take() {
+            deletion_queue.retire(Retired::Mesh(Box::new(vertex_buffer)));
+        }
+
+        Ok(())
+    }
+
+    /// The device has to be idle before calling this
+    ///
+    /// # Safety
+    /// The device must be idle.
+    pub unsafe fn destroy(&mut self, d: &Device) {
+        for buffer in self.buffers.drain(..).flatten() {
+            buffer.destroy(d);
+        }
+
+        for image in self.images.drain(..).flatten() {
+            image.destroy(d);
+        }
+
+        for vertex_buffer in self.meshes.drain(..).flatten() {
+            vertex_buffer.destroy(d);
+        }
+
+        self.buffer_refs.clear();
+        self.image_refs.clear();
+        self.mesh_refs.clear();
+    }
+
+    pub fn get_buffers(&self, name: &str) -> Result<&Vec<Buffer>, RendererError> {
+        Ok(&self.buffers[self.get_buffer_refs(name)?])
+    }
+
+    pub fn get_images(&self, name: &str) -> Result<&Vec<Image>, RendererError> {
+        Ok(&self.images[self.get_image_refs(name)?])
+    }
+
+    pub fn get_mesh(&self, name: &str) -> Result<MeshHandle, RendererError> {
+        self.mesh_refs.get(name).copied().ok_or(RendererError::UnknownResource(name.to_string()))
+    }
+
+    pub fn get_mesh_buffer(&self, handle: MeshHandle) -> Result<&VertexBuffer, RendererError> {
+        self.meshes.get(handle.index).and_then(|m| m.as_ref()).ok_or(RendererError::UnknownResource(format!("mesh {}", handle.index)))
+    }
+
+    pub fn get_buffer_refs(&self, name: &str) -> Result<usize, RendererError> {
+        self.buffer_refs.get(name).copied().ok_or(RendererError::UnknownResource(name.to_string()))
     }
 
-    pub fn get_images(&self, name: &str) -> &Vec<Image> {
-        &self.images[*self.image_refs.get(name).unwrap()]
+    pub fn get_image_refs(&self, name: &str) -> Result<usize, RendererError> {
+        self.image_refs.get(name).copied().ok_or(RendererError::UnknownResource(name.to_string()))
     }
 
-    pub fn get_buffer_refs(&self, name: &str) -> usize {
-        *self.buffer_refs.get(name).unwrap()
+    pub fn find_buffer_refs(&self, buffer: vk::Buffer) -> Option<usize> {
+        self.buffers.iter().position(|buffers| buffers.iter().any(|b| b.buffer == buffer))
     }
 
-    pub fn get_image_refs(&self, name: &str) -> usize {
-        *self.image_refs.get(name).unwrap()
+    pub fn find_image_refs(&self, image: vk::Image) -> Option<usize> {
+        self.images.iter().position(|images| images.iter().any(|i| i.image == image))
     }
 
     pub fn get_buffers_from_ref(&self, index: usize) -> &Vec<Buffer> {
@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+
+use ash::vk;
+
+use crate::renderer::{descriptors::{Descriptors, CreationReference}, device::Device, image::Image, renderer_data::{ResourceReference, RendererData}, shader::ShaderType, error::RendererError};
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum AccessType {
+    Uniform,
+    StorageRead,
+    StorageWrite,
+    StorageReadWrite,
+    Sampled,
+    ColorAttachment,
+    DepthAttachment,
+    TransferRead,
+    TransferWrite,
+    IndirectRead,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum AccessIntent {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+// Lets a pass narrow down how it uses a resource, since descriptors alone can't tell reads from writes
+#[derive(Clone)]
+pub struct AccessDeclaration {
+    pub create_ref: CreationReference,
+    pub intent: AccessIntent,
+}
+
+#[derive(Copy, Clone)]
+pub struct ResourceAccess {
+    pub resource: ResourceReference,
+    pub access_type: AccessType,
+    pub shader: ShaderType,
+}
+
+// Where a resource was last used within a layer, reads since the last write are merged together
+#[derive(Copy, Clone)]
+pub struct ResourceState {
+    pub stage: vk::PipelineStageFlags,
+    pub access: vk::AccessFlags,
+    pub layout: vk::ImageLayout,
+    pub written: bool,
+}
+
+#[derive(Copy, Clone)]
+pub struct QueueTransfer {
+    pub resource: ResourceReference,
+    pub src_queue_family: u32,
+    pub dst_queue_family: u32,
+}
+
+pub struct BarrierBatch {
+    pub src_stage: vk::PipelineStageFlags,
+    pub dst_stage: vk::PipelineStageFlags,
+    pub buffer_barriers: Vec<vk::BufferMemoryBarrier>,
+    pub image_barriers: Vec<vk::ImageMemoryBarrier>,
+}
+
+pub struct ResourceTracker {
+    pub states: HashMap<ResourceReference, ResourceState>,
+    pub initial_stage: vk::PipelineStageFlags,
+}
+
+impl AccessType {
+    pub fn from_creation_reference(create_ref: &CreationReference, intent: AccessIntent) -> AccessType {
+        match create_ref {
+            CreationReference::Uniform(_) => AccessType::Uniform,
+            CreationReference::Sampler(_) => AccessType::Sampled,
+            CreationReference::Storage(_) | CreationReference::Image(_) => match intent {
+                AccessIntent::Read => AccessType::StorageRead,
+                AccessIntent::Write => AccessType::StorageWrite,
+                AccessIntent::ReadWrite => AccessType::StorageReadWrite,
+            },
+        }
+    }
+}
+
+impl AccessDeclaration {
+    // Declaring both a read and a write of the same resource makes it read-write
+    pub fn declare(declarations: &mut Vec<AccessDeclaration>, create_ref: CreationReference, intent: AccessIntent) {
+        match declarations.iter_mut().find(|d| d.create_ref == create_ref) {
+            Some(declaration) => {
+                if declaration.intent != intent {
+                    declaration.intent = AccessIntent::ReadWrite;
+                }
+            },
+            None => declarations.push(AccessDeclaration { create_ref, intent }),
+        }
+    }
+}
+
+impl ResourceAccess {
+    pub fn new(resource: ResourceReference, access_type: AccessType, shader: ShaderType) -> ResourceAccess {
+        ResourceAccess { resource, access_type, shader }
+    }
+
+    // Conservatively assumes storage buffers and images are both read and written, declarations can narrow this down
+    pub fn from_descriptors(descriptors: &Descriptors, shader: ShaderType, data: &RendererData) -> Vec<ResourceAccess> {
+        let mut accesses = Vec::<ResourceAccess>::new();
+
+        for uniform in &descriptors.uniforms {
+            if let Some(index) = data.find_buffer_refs(uniform.data[0].buffer) {
+                accesses.push(ResourceAccess::new(ResourceReference::Buffer(index), AccessType::Uniform, shader));
+            }
+        }
+
+        for ssbo in &descriptors.ssbos {
+            if let Some(index) = data.find_buffer_refs(ssbo.data[0].buffer) {
+                accesses.push(ResourceAccess::new(ResourceReference::Buffer(index), AccessType::StorageReadWrite, shader));
+            }
+        }
+
+        for image in &descriptors.images {
+            if let Some(index) = data.find_image_refs(image.data[0].image) {
+                accesses.push(ResourceAccess::new(ResourceReference::Image(index), AccessType::StorageReadWrite, shader));
+            }
+        }
+
+        for sampler in &descriptors.samplers {
+            if let Some(index) = data.find_image_refs(sampler.data[0].image) {
+                accesses.push(ResourceAccess::new(ResourceReference::Image(index), AccessType::Sampled, shader));
+            }
+        }
+
+        accesses
+    }
+
+    // Targets that aren't renderer data, like the swapchain images, don't need tracking
+    pub fn from_targets(targets: &[Vec<Image>], data: &RendererData) -> Vec<ResourceAccess> {
+        targets.iter().filter_map(|target| {
+            let index = data.find_image_refs(target[0].image)?;
+            let access_type = if target[0].aspect().contains(vk::ImageAspectFlags::DEPTH) { AccessType::DepthAttachment } else { AccessType::ColorAttachment };
+
+            Some(ResourceAccess::new(ResourceReference::Image(index), access_type, ShaderType::Fragment))
+        }).collect()
+    }
+
+    // Declarations replace the inferred access to the same resource, or are added if the resource wasn't inferred
+    pub fn apply_declarations(accesses: &mut Vec<ResourceAccess>, declarations: &[AccessDeclaration], shader: ShaderType, data: &RendererData) -> Result<(), RendererError> {
+        for declaration in declarations {
+            let resource = match &declaration.create_ref {
+                CreationReference::Uniform(name) | CreationReference::Storage(name) => ResourceReference::Buffer(data.get_buffer_refs(name)?),
+                CreationReference::Image(name) | CreationReference::Sampler(name) => ResourceReference::Image(data.get_image_refs(name)?),
+            };
+
+            let access_type = AccessType::from_creation_reference(&declaration.create_ref, declaration.intent);
+
+            let mut declared = false;
+            for access in accesses.iter_mut().filter(|a| a.resource == resource) {
+                access.access_type = access_type;
+                declared = true;
+            }
+
+            if !declared {
+                accesses.push(ResourceAccess::new(resource, access_type, shader));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn stage(&self) -> vk::PipelineStageFlags {
+        match self.access_type {
+            AccessType::ColorAttachment => vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            AccessType::DepthAttachment => vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            AccessType::TransferRead | AccessType::TransferWrite => vk::PipelineStageFlags::TRANSFER,
+            AccessType::IndirectRead => vk::PipelineStageFlags::DRAW_INDIRECT,
+            _ => match self.shader {
+                ShaderType::Compute => vk::PipelineStageFlags::COMPUTE_SHADER,
+                ShaderType::Vertex => vk::PipelineStageFlags::VERTEX_SHADER,
+                ShaderType::Fragment => vk::PipelineStageFlags::FRAGMENT_SHADER,
+            },
+        }
+    }
+
+    pub fn access(&self) -> vk::AccessFlags {
+        match self.access_type {
+            AccessType::Uniform => vk::AccessFlags::UNIFORM_READ,
+            AccessType::StorageRead | AccessType::Sampled => vk::AccessFlags::SHADER_READ,
+            AccessType::StorageWrite => vk::AccessFlags::SHADER_WRITE,
+            AccessType::StorageReadWrite => vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            AccessType::ColorAttachment => vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            AccessType::DepthAttachment => vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            AccessType::TransferRead => vk::AccessFlags::TRANSFER_READ,
+            AccessType::TransferWrite => vk::AccessFlags::TRANSFER_WRITE,
+            AccessType::IndirectRead => vk::AccessFlags::INDIRECT_COMMAND_READ,
+        }
+    }
+
+    // Attachments are left in the image's own layout, as that's the final layout of every render pass
+    pub fn layout(&self, home_layout: vk::ImageLayout) -> vk::ImageLayout {
+        match self.access_type {
+            AccessType::StorageRead | AccessType::StorageWrite | AccessType::StorageReadWrite => vk::ImageLayout::GENERAL,
+            AccessType::Sampled => vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            AccessType::TransferRead => vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            AccessType::TransferWrite => vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            AccessType::Uniform | AccessType::ColorAttachment | AccessType::DepthAttachment | AccessType::IndirectRead => home_layout,
+        }
+    }
+
+    pub fn writes(&self) -> bool {
+        matches!(self.access_type, AccessType::StorageWrite | AccessType::StorageReadWrite | AccessType::ColorAttachment | AccessType::DepthAttachment | AccessType::TransferWrite)
+    }
+}
+
+impl Default for BarrierBatch {
+    fn default() -> BarrierBatch {
+        BarrierBatch::new()
+    }
+}
+
+impl BarrierBatch {
+    pub fn new() -> BarrierBatch {
+        BarrierBatch {
+            src_stage: vk::PipelineStageFlags::empty(),
+            dst_stage: vk::PipelineStageFlags::empty(),
+            buffer_barriers: Vec::new(),
+            image_barriers: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, resource: ResourceReference, resources: &RendererData, i: usize, src: ResourceState, dst: ResourceState) {
+        self.push(resource, resources, i, src, dst, (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED));
+    }
+
+    // Releases or acquires the resource between the transfer's queue families as part of the barrier
+    pub fn add_transfer(&mut self, transfer: &QueueTransfer, resources: &RendererData, i: usize, src: ResourceState, dst: ResourceState) {
+        self.push(transfer.resource, resources, i, src, dst, (transfer.src_queue_family, transfer.dst_queue_family));
+    }
+
+    fn push(&mut self, resource: ResourceReference, resources: &RendererData, i: usize, src: ResourceState, dst: ResourceState, (src_queue_family, dst_queue_family): (u32, u32)) {
+        self.src_stage |= src.stage;
+        self.dst_stage |= dst.stage;
+
+        // Only writes need to be made available, ordering after reads just needs the execution dependency
+        let src_access = if src.written { src.access } else { vk::AccessFlags::empty() };
+
+        match resource {
+            ResourceReference::Buffer(index) => {
+                let buffer = &resources.get_buffers_from_ref(index)[i];
+
+                let (src_queue_family, dst_queue_family) = if buffer.sharing_mode == vk::SharingMode::CONCURRENT { (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED) } else { (src_queue_family, dst_queue_family) };
+
+                self.buffer_barriers.push(vk::BufferMemoryBarrier::builder()
+                    .src_access_mask(src_access)
+                    .dst_access_mask(dst.access)
+                    .buffer(buffer.buffer)
+                    .offset(0)
+                    .size(vk::WHOLE_SIZE)
+                    .src_queue_family_index(src_queue_family)
+                    .dst_queue_family_index(dst_queue_family)
+                    .build());
+            },
+            ResourceReference::Image(index) => {
+                let image = &resources.get_images_from_ref(index)[i];
+
+                let subresource_range = vk::ImageSubresourceRange::builder()
+                    .aspect_mask(image.aspect())
+                    .base_mip_level(0)
+                    .level_count(vk::REMAINING_MIP_LEVELS)
+                    .base_array_layer(0)
+                    .layer_count(vk::REMAINING_ARRAY_LAYERS)
+                    .build();
+
+                self.image_barriers.push(vk::ImageMemoryBarrier::builder()
+                    .src_access_mask(src_access)
+                    .dst_access_mask(dst.access)
+                    .old_layout(src.layout)
+                    .new_layout(dst.layout)
+                    .image(image.image)
+                    .subresource_range(subresource_range)
+                    .src_queue_family_index(src_queue_family)
+                    .dst_queue_family_index(dst_queue_family)
+                    .build());
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer_barriers.is_empty() && self.image_barriers.is_empty()
+    }
+
+    /// # Safety
+    /// `b` must be recording outside of a render pass.
+    pub unsafe fn record(&self, d: &Device, b: vk::CommandBuffer) {
+        if self.is_empty() {
+            return;
+        }
+
+        let src_stage = if self.src_stage.is_empty() { vk::PipelineStageFlags::TOP_OF_PIPE } else { self.src_stage };
+        let dst_stage = if self.dst_stage.is_empty() { vk::PipelineStageFlags::BOTTOM_OF_PIPE } else { self.dst_stage };
+
+        d.device.cmd_pipeline_barrier(b, src_stage, dst_stage, vk::DependencyFlags::empty(), &[], &self.buffer_barriers, &self.image_barriers);
+    }
+}
+
+impl ResourceTracker {
+    // The initial stage is whatever the layer's semaphores wait on, so the first barriers chain onto those waits
+    pub fn new(initial_stage: vk::PipelineStageFlags) -> ResourceTracker {
+        ResourceTracker {
+            states: HashMap::new(),
+            initial_stage,
+        }
+    }
+
+    // Every layer starts and ends with its resources in their home layout, so layers can be recorded independently
+    pub fn home_layout(resource: ResourceReference, resources: &RendererData) -> vk::ImageLayout {
+        match resource {
+            ResourceReference::Buffer(_) => vk::ImageLayout::UNDEFINED,
+            ResourceReference::Image(index) => {
+                let layout = resources.get_images_from_ref(index)[0].layout;
+
+                if layout == vk::ImageLayout::UNDEFINED { vk::ImageLayout::GENERAL } else { layout }
+            },
+        }
+    }
+
+    pub fn home_state(&self, resource: ResourceReference, resources: &RendererData) -> ResourceState {
+        ResourceState {
+            stage: self.initial_stage,
+            access: vk::AccessFlags::empty(),
+            layout: ResourceTracker::home_layout(resource, resources),
+            written: false,
+        }
+    }
+
+    pub fn set_state(&mut self, resource: ResourceReference, state: ResourceState) {
+        self.states.insert(resource, state);
+    }
+
+    pub fn get_state(&self, resource: ResourceReference, resources: &RendererData) -> ResourceState {
+        match self.states.get(&resource) {
+            Some(state) => *state,
+            None => self.home_state(resource, resources),
+        }
+    }
+
+    // Returns the state the resource has to be moved from if a barrier is needed before the access
+    pub fn access(&mut self, access: &ResourceAccess, resources: &RendererData) -> Option<(ResourceState, ResourceState)> {
+        let prev = self.get_state(access.resource, resources);
+
+        // Buffers have no layout, they only ever need execution and memory dependencies
+        let layout = match access.resource {
+            ResourceReference::Buffer(_) => vk::ImageLayout::UNDEFINED,
+            ResourceReference::Image(_) => access.layout(ResourceTracker::home_layout(access.resource, resources)),
+        };
+
+        let next = ResourceState {
+            stage: access.stage(),
+            access: access.access(),
+            layout,
+            written: access.writes(),
+        };
+
+        let hazard = prev.written || (access.writes() && !prev.stage.is_empty());
+
+        if hazard || prev.layout != next.layout {
+            self.states.insert(access.resource, next);
+
+            Some((prev, next))
+        } else if next.written {
+            // A write with nothing before it in the layer has nothing to wait on, but what comes after has to wait on it
+            self.states.insert(access.resource, next);
+
+            None
+        } else {
+            // Consecutive reads in the same layout don't need to wait on each other
+            self.states.insert(access.resource, ResourceState {
+                stage: prev.stage | next.stage,
+                access: prev.access | next.access,
+                layout: prev.layout,
+                written: false,
+            });
+
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUFFER: ResourceReference = ResourceReference::Buffer(0);
+
+    fn access(access_type: AccessType, shader: ShaderType) -> ResourceAccess {
+        ResourceAccess::new(BUFFER, access_type, shader)
+    }
+
+    #[test]
+    fn read_after_write_needs_a_barrier() {
+        let data = RendererData::new(1);
+        let mut tracker = ResourceTracker::new(vk::PipelineStageFlags::empty());
+
+        assert!(tracker.access(&access(AccessType::StorageWrite, ShaderType::Compute), &data).is_none());
+
+        let (src, dst) = tracker.access(&access(AccessType::StorageRead, ShaderType::Fragment), &data).unwrap();
+        assert!(src.written);
+        assert_eq!((src.stage, src.access), (vk::PipelineStageFlags::COMPUTE_SHADER, vk::AccessFlags::SHADER_WRITE));
+        assert_eq!((dst.stage, dst.access), (vk::PipelineStageFlags::FRAGMENT_SHADER, vk::AccessFlags::SHADER_READ));
+    }
+
+    #[test]
+    fn write_after_read_waits_on_every_read() {
+        let data = RendererData::new(1);
+        let mut tracker = ResourceTracker::new(vk::PipelineStageFlags::empty());
+
+        tracker.access(&access(AccessType::Uniform, ShaderType::Vertex), &data);
+        tracker.access(&access(AccessType::StorageRead, ShaderType::Fragment), &data);
+
+        let (src, dst) = tracker.access(&access(AccessType::StorageWrite, ShaderType::Compute), &data).unwrap();
+        assert!(!src.written);
+        assert_eq!(src.stage, vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER);
+        assert!(dst.written);
+    }
+
+    #[test]
+    fn consecutive_reads_are_merged() {
+        let data = RendererData::new(1);
+        let mut tracker = ResourceTracker::new(vk::PipelineStageFlags::TOP_OF_PIPE);
+
+        assert!(tracker.access(&access(AccessType::Uniform, ShaderType::Vertex), &data).is_none());
+        assert!(tracker.access(&access(AccessType::StorageRead, ShaderType::Compute), &data).is_none());
+
+        let state = tracker.get_state(BUFFER, &data);
+        assert_eq!(state.stage, vk::PipelineStageFlags::TOP_OF_PIPE | vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER);
+        assert_eq!(state.access, vk::AccessFlags::UNIFORM_READ | vk::AccessFlags::SHADER_READ);
+        assert!(!state.written);
+    }
+
+    #[test]
+    fn first_write_waits_on_the_layer_semaphores() {
+        let data = RendererData::new(1);
+        let mut tracker = ResourceTracker::new(vk::PipelineStageFlags::TRANSFER);
+
+        let (src, _) = tracker.access(&access(AccessType::StorageWrite, ShaderType::Compute), &data).unwrap();
+        assert_eq!(src.stage, vk::PipelineStageFlags::TRANSFER);
+    }
+}
@@ -2,6 +2,7 @@ use ash::vk;
 
 use crate::renderer::core::Core;
 use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
 
 #[derive(Copy, Clone)]
 pub struct Sampler {
@@ -10,19 +11,28 @@ pub struct Sampler {
 }
 
 impl Sampler {
-    pub unsafe fn new(c: &Core, d: &Device, v: vk::ImageView) -> Sampler {
+    pub unsafe fn new(c: &Core, d: &Device, v: vk::ImageView) -> Result<Sampler, RendererError> {
         let sampler_ci = vk::SamplerCreateInfo::builder()
             .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_BORDER)
             .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_BORDER)
             .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_BORDER)
             .mag_filter(vk::Filter::NEAREST)
-            .min_filter(vk::Filter::NEAREST);
+            .min_filter(vk::Filter::NEAREST)
+            .max_lod(vk::LOD_CLAMP_NONE);
         
-        let sampler = d.device.create_sampler(&sampler_ci, None).unwrap();
+        let sampler = d.device.create_sampler(&sampler_ci, None)?;
 
-        Sampler {
+        Ok(Sampler {
             sampler,
             view: v,
-        }
+        })
+    }
+
+    /// The view belongs to the image being sampled
+    ///
+    /// # Safety
+    /// The sampler mustn't be used by a frame still in flight.
+    pub unsafe fn destroy(&self, d: &Device) {
+        d.device.destroy_sampler(self.sampler, None);
     }
 }
\ No newline at end of file
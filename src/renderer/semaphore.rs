@@ -1,6 +1,7 @@
 use ash::vk;
 
 use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
 
 #[derive(Copy, Clone)]
 pub struct Semaphore {
@@ -8,13 +9,38 @@ pub struct Semaphore {
 }
 
 impl Semaphore {
-    pub unsafe fn new(d: &Device) -> Semaphore {
+    pub unsafe fn new(d: &Device) -> Result<Semaphore, RendererError> {
         let semaphore_ci = vk::SemaphoreCreateInfo::builder();
 
-        let semaphore = d.device.create_semaphore(&semaphore_ci, None).unwrap();
+        let semaphore = d.device.create_semaphore(&semaphore_ci, None)?;
 
-        Semaphore {
+        Ok(Semaphore {
             semaphore
-        }
+        })
+    }
+
+    /// Timeline semaphores count up instead of being signaled and waited on once, so they can be waited on from any frame without being reset
+    ///
+    /// # Safety
+    /// The semaphore must be destroyed before `d`.
+    pub unsafe fn new_timeline(d: &Device) -> Result<Semaphore, RendererError> {
+        let mut semaphore_type_ci = vk::SemaphoreTypeCreateInfo::builder()
+            .semaphore_type(vk::SemaphoreType::TIMELINE)
+            .initial_value(0);
+
+        let semaphore_ci = vk::SemaphoreCreateInfo::builder()
+            .push_next(&mut semaphore_type_ci);
+
+        let semaphore = d.device.create_semaphore(&semaphore_ci, None)?;
+
+        Ok(Semaphore {
+            semaphore
+        })
+    }
+
+    /// # Safety
+    /// No submission can still be waiting on or signalling the semaphore.
+    pub unsafe fn destroy(&self, d: &Device) {
+        d.device.destroy_semaphore(self.semaphore, None);
     }
 }
\ No newline at end of file
@@ -1,9 +1,11 @@
-use std::fs::File;
+use std::path::PathBuf;
 
-use ash::util::read_spv;
 use ash::vk;
 
 use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
+use crate::renderer::shader_compiler;
+use crate::renderer::shader_reflection::{ShaderReflection, StageInterface};
 
 #[derive(Copy, Clone)]
 pub enum ShaderType {
@@ -12,32 +14,59 @@ pub enum ShaderType {
     Fragment,
 }
 
+// A file in res/shaders/src and the preprocessor defines it's compiled with
+#[derive(Clone)]
+pub struct ShaderSource {
+    pub path: String,
+    pub defines: Vec<(String, String)>,
+}
+
 pub struct Shader {
+    pub path: String,
     pub module: vk::ShaderModule,
     pub flags: vk::ShaderStageFlags,
     pub bytecode: Vec<u32>,
+    pub dependencies: Vec<PathBuf>,
+    pub reflection: ShaderReflection,
 }
 
-impl Shader {
-    pub unsafe fn new(d: &Device, path: &str, flags: vk::ShaderStageFlags) -> Shader {
-        let shader_base_path = "./res/shaders/bin/";
-        let shader_ext = ".spv";
-
-        let mut shader_path = String::from(shader_base_path);
-        shader_path.push_str(path);
-        shader_path.push_str(shader_ext);
+impl ShaderSource {
+    pub fn new(path: &str, defines: &[(String, String)]) -> ShaderSource {
+        ShaderSource {
+            path: path.to_string(),
+            defines: defines.to_vec(),
+        }
+    }
+}
 
-        let shader_path = shader_path.as_str();
+impl Shader {
+    pub unsafe fn new(d: &Device, source: &ShaderSource, flags: vk::ShaderStageFlags) -> Result<Shader, RendererError> {
+        let compiled = shader_compiler::compile(source, flags)?;
+        let reflection = ShaderReflection::new(&compiled.bytecode).map_err(|message| RendererError::InvalidSpirv { path: source.path.clone(), message })?;
 
-        let mut shader_file = File::open(shader_path).expect(format!("Error: Shader file at {shader_path} doesn't exist").as_str());
-        let bytecode = read_spv(&mut shader_file).expect("Error reading shader");
-        let shader_ci = vk::ShaderModuleCreateInfo::builder().code(&bytecode);
-        let module = d.device.create_shader_module(&shader_ci, None).expect("Error creating shader module");
+        let shader_ci = vk::ShaderModuleCreateInfo::builder().code(&compiled.bytecode);
+        let module = d.device.create_shader_module(&shader_ci, None)?;
 
-        Shader {
+        Ok(Shader {
+            path: source.path.clone(),
             module,
             flags,
-            bytecode,
-        }
+            bytecode: compiled.bytecode,
+            dependencies: compiled.dependencies,
+            reflection,
+        })
+    }
+
+    // Checks the descriptors, push constant and vertex attributes a pass gives this stage against what the shader declares
+    pub fn check(&self, interface: &StageInterface) -> Result<(), RendererError> {
+        self.reflection.check(interface).map_err(|message| RendererError::ShaderMismatch { path: self.path.clone(), message })
     }
-}
\ No newline at end of file
+
+    /// Modules are only needed while creating pipelines
+    ///
+    /// # Safety
+    /// No pipeline can still be being created from the module.
+    pub unsafe fn destroy(&self, d: &Device) {
+        d.device.destroy_shader_module(self.module, None);
+    }
+}
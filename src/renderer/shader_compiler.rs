@@ -0,0 +1,296 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Cursor, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use ash::util::read_spv;
+use ash::vk;
+
+use crate::renderer::error::RendererError;
+use crate::renderer::shader::ShaderSource;
+
+const SHADER_SOURCE_PATH: &str = "./res/shaders/src/";
+const SHADER_BINARY_PATH: &str = "./res/shaders/bin/";
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DiagnosticSeverity {
+    Error,
+    Warning,
+}
+
+// One message from the compiler, line is missing for messages about the whole file or the compiler itself
+#[derive(Debug, Clone)]
+pub struct ShaderDiagnostic {
+    pub path: String,
+    pub line: Option<u32>,
+    pub severity: DiagnosticSeverity,
+    pub message: String,
+}
+
+pub struct CompiledShader {
+    pub bytecode: Vec<u32>,
+    pub dependencies: Vec<PathBuf>, // The source file followed by everything it includes, for knowing when to recompile
+}
+
+impl fmt::Display for ShaderDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            DiagnosticSeverity::Error => "error",
+            DiagnosticSeverity::Warning => "warning",
+        };
+
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}: {}", self.path, line, severity, self.message),
+            None => write!(f, "{}: {}: {}", self.path, severity, self.message),
+        }
+    }
+}
+
+// Compiles GLSL from res/shaders/src with glslc, which comes with the Vulkan SDK. Includes are resolved relative to the including file, then the source directory
+// Without glslc the .spv built by compile_shaders.bat is loaded from res/shaders/bin instead, which can't take defines
+pub fn compile(source: &ShaderSource, flags: vk::ShaderStageFlags) -> Result<CompiledShader, RendererError> {
+    let path = Path::new(SHADER_SOURCE_PATH).join(&source.path);
+    let display_path = path.to_string_lossy().to_string();
+
+    let mut command = Command::new(glslc_path());
+    command.arg(&path)
+        .arg("-I").arg(SHADER_SOURCE_PATH)
+        .arg("-o").arg("-");
+
+    if let Some(stage) = stage_name(flags) {
+        command.arg(format!("-fshader-stage={}", stage));
+    }
+
+    for (name, value) in &source.defines {
+        match value.is_empty() {
+            true => command.arg(format!("-D{}", name)),
+            false => command.arg(format!("-D{}={}", name, value)),
+        };
+    }
+
+    let output = match command.output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == ErrorKind::NotFound && source.defines.is_empty() => return load_precompiled(source),
+        Err(e) => return Err(RendererError::ShaderCompiler(e)),
+    };
+
+    if !output.status.success() {
+        let mut diagnostics = parse_diagnostics(&String::from_utf8_lossy(&output.stderr));
+
+        // Anything glslc says that isn't in the usual format is still worth seeing
+        if !diagnostics.iter().any(|d| d.severity == DiagnosticSeverity::Error) {
+            diagnostics.push(ShaderDiagnostic {
+                path: display_path.clone(),
+                line: None,
+                severity: DiagnosticSeverity::Error,
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+
+        return Err(RendererError::ShaderCompile { path: display_path, diagnostics });
+    }
+
+    let bytecode = read_spv(&mut Cursor::new(&output.stdout)).map_err(|source| RendererError::ShaderLoad { path: display_path, source })?;
+
+    let mut dependencies = Vec::<PathBuf>::new();
+    find_dependencies(&path, &mut dependencies)?;
+
+    Ok(CompiledShader {
+        bytecode,
+        dependencies,
+    })
+}
+
+// Precompiled shaders have no dependencies, so nothing is watched and hot reload only happens with glslc
+fn load_precompiled(source: &ShaderSource) -> Result<CompiledShader, RendererError> {
+    let path = format!("{}{}.spv", SHADER_BINARY_PATH, source.path);
+
+    let bytecode = File::open(&path)
+        .and_then(|mut file| read_spv(&mut file))
+        .map_err(|source| RendererError::ShaderLoad { path, source })?;
+
+    Ok(CompiledShader {
+        bytecode,
+        dependencies: Vec::new(),
+    })
+}
+
+fn glslc_path() -> PathBuf {
+    match std::env::var_os("VULKAN_SDK") {
+        Some(sdk) => Path::new(&sdk).join("bin").join("glslc"),
+        None => PathBuf::from("glslc"),
+    }
+}
+
+fn stage_name(flags: vk::ShaderStageFlags) -> Option<&'static str> {
+    match flags {
+        vk::ShaderStageFlags::COMPUTE => Some("compute"),
+        vk::ShaderStageFlags::VERTEX => Some("vertex"),
+        vk::ShaderStageFlags::FRAGMENT => Some("fragment"),
+        _ => None,
+    }
+}
+
+fn find_dependencies(path: &Path, dependencies: &mut Vec<PathBuf>) -> Result<(), RendererError> {
+    if dependencies.iter().any(|p| p == path) {
+        return Ok(());
+    }
+
+    let source = fs::read_to_string(path).map_err(|source| RendererError::ShaderLoad { path: path.to_string_lossy().to_string(), source })?;
+    dependencies.push(path.to_path_buf());
+
+    for line in strip_comments(&source).lines() {
+        let line = line.trim();
+
+        if let Some(include) = line.strip_prefix("#include") {
+            let name = include.trim().trim_matches(|c| c == '"' || c == '<' || c == '>');
+
+            let relative = normalise(&path.parent().unwrap_or(Path::new(SHADER_SOURCE_PATH)).join(name));
+            let include_path = if relative.exists() { relative } else { normalise(&Path::new(SHADER_SOURCE_PATH).join(name)) };
+
+            // Missing includes are left for glslc to report with the line they're on
+            if include_path.exists() {
+                find_dependencies(&include_path, dependencies)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Removes // and /* */ comments so includes that are commented out aren't tracked
+fn strip_comments(source: &str) -> String {
+    let mut stripped = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    let mut in_block = false;
+
+    while let Some(c) = chars.next() {
+        if in_block {
+            if c == '*' && chars.peek() == Some(&'/') {
+                chars.next();
+                in_block = false;
+            } else if c == '\n' {
+                stripped.push(c);
+            }
+        } else if c == '/' && chars.peek() == Some(&'*') {
+            chars.next();
+            in_block = true;
+            stripped.push(' ');
+        } else if c == '/' && chars.peek() == Some(&'/') {
+            while chars.peek().is_some_and(|c| *c != '\n') {
+                chars.next();
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+
+    stripped
+}
+
+// Removes ".." so the same file reached through different includes is only tracked once
+fn normalise(path: &Path) -> PathBuf {
+    let mut normalised = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::ParentDir if matches!(normalised.components().next_back(), Some(Component::Normal(_))) => { normalised.pop(); },
+            Component::CurDir if normalised.components().next().is_some() => {},
+            _ => normalised.push(component),
+        }
+    }
+
+    normalised
+}
+
+// glslc reports problems as "file:line: error: message", or "glslc: error: message" for ones that aren't in a file
+fn parse_diagnostics(stderr: &str) -> Vec<ShaderDiagnostic> {
+    stderr.lines().filter_map(|line| {
+        let (severity, index, marker_len) = [(DiagnosticSeverity::Error, ": error: "), (DiagnosticSeverity::Warning, ": warning: ")]
+            .iter()
+            .find_map(|(severity, marker)| line.find(marker).map(|i| (*severity, i, marker.len())))?;
+
+        let location = &line[..index];
+        let message = line[index + marker_len..].trim().to_string();
+
+        let (path, line) = match location.rsplit_once(':') {
+            Some((path, number)) if number.parse::<u32>().is_ok() => (path, number.parse::<u32>().ok()),
+            _ => (location, None),
+        };
+
+        Some(ShaderDiagnostic {
+            path: path.to_string(),
+            line,
+            severity,
+            message,
+        })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each test gets its own directory so they can run in parallel
+    fn write_sources(test: &str, sources: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("shader_test_{}_{}", std::process::id(), test));
+
+        for (name, source) in sources {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+
+        dir
+    }
+
+    #[test]
+    fn parse_diagnostics_reads_file_and_compiler_messages() {
+        let stderr = "res/shaders/src/a.frag:12: error: 'x' : undeclared identifier\n\
+                      res/shaders/src/a.frag:3: warning: unused variable\n\
+                      glslc: error: cannot open input file: 'b.vert'\n\
+                      1 error generated.\n";
+
+        let diagnostics = parse_diagnostics(stderr);
+        assert_eq!(diagnostics.len(), 3);
+
+        assert_eq!(diagnostics[0].path, "res/shaders/src/a.frag");
+        assert_eq!(diagnostics[0].line, Some(12));
+        assert_eq!(diagnostics[0].severity, DiagnosticSeverity::Error);
+        assert_eq!(diagnostics[0].message, "'x' : undeclared identifier");
+
+        assert_eq!(diagnostics[1].severity, DiagnosticSeverity::Warning);
+
+        assert_eq!(diagnostics[2].path, "glslc");
+        assert_eq!(diagnostics[2].line, None);
+        assert_eq!(diagnostics[2].message, "cannot open input file: 'b.vert'");
+    }
+
+    #[test]
+    fn normalise_removes_parent_dirs() {
+        assert_eq!(normalise(Path::new("a/b/../c")), PathBuf::from("a/c"));
+        assert_eq!(normalise(Path::new("./a/./b")), PathBuf::from("./a/b"));
+        assert_eq!(normalise(Path::new("a/../../b")), PathBuf::from("../b"));
+    }
+
+    #[test]
+    fn find_dependencies_follows_includes_once() {
+        let dir = write_sources("includes", &[
+            ("main.vert", "#include \"lib/common.glsl\"\n// #include \"unused.glsl\"\n/*\n#include \"unused.glsl\"\n*/\nvoid main() {}\n"),
+            ("lib/common.glsl", "#include \"../shared.glsl\"\n#include \"missing.glsl\"\n"),
+            ("shared.glsl", "#include \"lib/common.glsl\"\n"),
+            ("unused.glsl", ""),
+        ]);
+
+        let mut dependencies = Vec::new();
+        find_dependencies(&dir.join("main.vert"), &mut dependencies).unwrap();
+
+        assert_eq!(dependencies, [dir.join("main.vert"), dir.join("lib/common.glsl"), dir.join("shared.glsl")]);
+    }
+
+    #[test]
+    fn strip_comments_keeps_lines() {
+        assert_eq!(strip_comments("a // b\nc /* d\ne */ f\n"), "a \nc  \n f\n");
+    }
+}
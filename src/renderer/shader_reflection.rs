@@ -0,0 +1,489 @@
+use std::collections::HashMap;
+
+use ash::vk;
+
+const SPIRV_MAGIC: u32 = 0x07230203;
+
+// Opcodes
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+// Decorations
+const DECORATION_BLOCK: u32 = 2;
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+// Storage classes
+const STORAGE_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_INPUT: u32 = 1;
+const STORAGE_UNIFORM: u32 = 2;
+const STORAGE_PUSH_CONSTANT: u32 = 9;
+const STORAGE_STORAGE_BUFFER: u32 = 12;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NumericType {
+    Float,
+    SInt,
+    UInt,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ReflectedBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ReflectedVertexInput {
+    pub location: u32,
+    pub numeric_type: NumericType,
+    pub components: u32,
+}
+
+// Everything a shader expects to be given, read from its SPIR-V
+#[derive(Debug, Clone)]
+pub struct ShaderReflection {
+    pub stage: vk::ShaderStageFlags,
+    pub bindings: Vec<ReflectedBinding>,
+    pub push_constant_size: Option<u32>,
+    pub vertex_inputs: Vec<ReflectedVertexInput>,
+}
+
+// What a pass gives one of its shader stages, descriptor types are indexed by binding
+#[derive(Clone)]
+pub struct StageInterface {
+    pub descriptor_set: Option<(u32, Vec<vk::DescriptorType>)>,
+    pub push_constant_size: Option<usize>,
+    pub vertex_attributes: Vec<vk::VertexInputAttributeDescription>,
+}
+
+enum SpirvType {
+    Scalar { numeric_type: NumericType, width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray,
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+    Other,
+}
+
+#[derive(Default)]
+struct Decorations {
+    set: Option<u32>,
+    binding: Option<u32>,
+    location: Option<u32>,
+    array_stride: Option<u32>,
+    built_in: bool,
+    block: bool,
+    buffer_block: bool,
+    member_offsets: HashMap<u32, u32>,
+    member_matrix_strides: HashMap<u32, u32>,
+}
+
+struct Module {
+    types: HashMap<u32, SpirvType>,
+    constants: HashMap<u32, u32>,
+    decorations: HashMap<u32, Decorations>,
+}
+
+impl ShaderReflection {
+    pub fn new(bytecode: &[u32]) -> Result<ShaderReflection, String> {
+        if bytecode.len() < 5 || bytecode[0] != SPIRV_MAGIC {
+            return Err("not a SPIR-V module".to_string());
+        }
+
+        let mut module = Module {
+            types: HashMap::new(),
+            constants: HashMap::new(),
+            decorations: HashMap::new(),
+        };
+
+        let mut stage = vk::ShaderStageFlags::empty();
+        let mut variables = Vec::<(u32, u32, u32)>::new(); // Result id, pointer type id, storage class
+
+        let mut i = 5;
+        while i < bytecode.len() {
+            let word_count = (bytecode[i] >> 16) as usize;
+            let opcode = bytecode[i] & 0xffff;
+
+            if word_count == 0 || i + word_count > bytecode.len() {
+                return Err(format!("malformed instruction at word {}", i));
+            }
+
+            let operands = &bytecode[i + 1..i + word_count];
+
+            if operands.len() < operand_count(opcode) {
+                return Err(format!("instruction with opcode {} at word {} has {} operands but needs {}", opcode, i, operands.len(), operand_count(opcode)));
+            }
+
+            match opcode {
+                OP_ENTRY_POINT => {
+                    stage = match operands[0] {
+                        0 => vk::ShaderStageFlags::VERTEX,
+                        4 => vk::ShaderStageFlags::FRAGMENT,
+                        5 => vk::ShaderStageFlags::COMPUTE,
+                        _ => vk::ShaderStageFlags::empty(),
+                    };
+                },
+                OP_DECORATE => {
+                    let decorations = module.decorations.entry(operands[0]).or_default();
+                    let literal = operands.get(2).copied();
+
+                    match operands[1] {
+                        DECORATION_BLOCK => decorations.block = true,
+                        DECORATION_BUFFER_BLOCK => decorations.buffer_block = true,
+                        DECORATION_ARRAY_STRIDE => decorations.array_stride = literal,
+                        DECORATION_BUILT_IN => decorations.built_in = true,
+                        DECORATION_LOCATION => decorations.location = literal,
+                        DECORATION_BINDING => decorations.binding = literal,
+                        DECORATION_DESCRIPTOR_SET => decorations.set = literal,
+                        _ => {},
+                    }
+                },
+                OP_MEMBER_DECORATE => {
+                    let decorations = module.decorations.entry(operands[0]).or_default();
+
+                    match (operands[2], operands.get(3)) {
+                        (DECORATION_OFFSET, Some(offset)) => { decorations.member_offsets.insert(operands[1], *offset); },
+                        (DECORATION_MATRIX_STRIDE, Some(stride)) => { decorations.member_matrix_strides.insert(operands[1], *stride); },
+                        _ => {},
+                    }
+                },
+                OP_TYPE_BOOL => { module.types.insert(operands[0], SpirvType::Other); },
+                OP_TYPE_INT => {
+                    let numeric_type = if operands[2] == 1 { NumericType::SInt } else { NumericType::UInt };
+                    module.types.insert(operands[0], SpirvType::Scalar { numeric_type, width: operands[1] });
+                },
+                OP_TYPE_FLOAT => { module.types.insert(operands[0], SpirvType::Scalar { numeric_type: NumericType::Float, width: operands[1] }); },
+                OP_TYPE_VECTOR => { module.types.insert(operands[0], SpirvType::Vector { component: operands[1], count: operands[2] }); },
+                OP_TYPE_MATRIX => { module.types.insert(operands[0], SpirvType::Matrix { column: operands[1], count: operands[2] }); },
+                OP_TYPE_IMAGE => { module.types.insert(operands[0], SpirvType::Image { sampled: operands[6] }); },
+                OP_TYPE_SAMPLER => { module.types.insert(operands[0], SpirvType::Sampler); },
+                OP_TYPE_SAMPLED_IMAGE => { module.types.insert(operands[0], SpirvType::SampledImage); },
+                OP_TYPE_ARRAY => { module.types.insert(operands[0], SpirvType::Array { element: operands[1], length: operands[2] }); },
+                OP_TYPE_RUNTIME_ARRAY => { module.types.insert(operands[0], SpirvType::RuntimeArray); },
+                OP_TYPE_STRUCT => { module.types.insert(operands[0], SpirvType::Struct { members: operands[1..].to_vec() }); },
+                OP_TYPE_POINTER => { module.types.insert(operands[0], SpirvType::Pointer { pointee: operands[2] }); },
+                // Only the low word is needed, array lengths are all that constants are read for
+                OP_CONSTANT => { module.constants.insert(operands[1], operands[2]); },
+                OP_VARIABLE => variables.push((operands[1], operands[0], operands[2])),
+                _ => {},
+            }
+
+            i += word_count;
+        }
+
+        let mut reflection = ShaderReflection {
+            stage,
+            bindings: Vec::new(),
+            push_constant_size: None,
+            vertex_inputs: Vec::new(),
+        };
+
+        for (id, pointer_type, storage_class) in variables {
+            let pointee = match module.types.get(&pointer_type) {
+                Some(SpirvType::Pointer { pointee }) => *pointee,
+                _ => continue,
+            };
+
+            let no_decorations = Decorations::default();
+            let decorations = module.decorations.get(&id).unwrap_or(&no_decorations);
+
+            match storage_class {
+                STORAGE_UNIFORM_CONSTANT | STORAGE_UNIFORM | STORAGE_STORAGE_BUFFER => {
+                    let (element, count) = module.array_element(pointee);
+
+                    if let Some(descriptor_type) = module.descriptor_type(element, storage_class) {
+                        reflection.bindings.push(ReflectedBinding {
+                            set: decorations.set.unwrap_or(0),
+                            binding: decorations.binding.unwrap_or(0),
+                            descriptor_type,
+                            count,
+                        });
+                    }
+                },
+                STORAGE_PUSH_CONSTANT => {
+                    reflection.push_constant_size = Some(module.size_of(pointee));
+                },
+                STORAGE_INPUT if stage == vk::ShaderStageFlags::VERTEX && !decorations.built_in => {
+                    if let (Some(location), Some((numeric_type, components))) = (decorations.location, module.vector_shape(pointee)) {
+                        reflection.vertex_inputs.push(ReflectedVertexInput { location, numeric_type, components });
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        reflection.bindings.sort_by_key(|b| (b.set, b.binding));
+        reflection.vertex_inputs.sort_by_key(|v| v.location);
+
+        Ok(reflection)
+    }
+
+    // Bindings the pass doesn't give a descriptor for, or gives the wrong type of descriptor for, are reported. Extra descriptors are fine since compilers drop unused bindings
+    pub fn check(&self, interface: &StageInterface) -> Result<(), String> {
+        for binding in &self.bindings {
+            let descriptor_types = match &interface.descriptor_set {
+                Some((set, descriptor_types)) if *set == binding.set => descriptor_types,
+                Some((set, _)) => return Err(format!("binding {} is in set {} but the pass gives this stage set {}", binding.binding, binding.set, set)),
+                None => return Err(format!("binding {} in set {} is used but the pass gives this stage no descriptors", binding.binding, binding.set)),
+            };
+
+            match descriptor_types.get(binding.binding as usize) {
+                Some(descriptor_type) if *descriptor_type != binding.descriptor_type => {
+                    return Err(format!("binding {} is a {:?} in the shader but a {:?} in the pass", binding.binding, binding.descriptor_type, descriptor_type));
+                },
+                None => return Err(format!("binding {} is a {:?} in the shader but the pass only has {} descriptors", binding.binding, binding.descriptor_type, descriptor_types.len())),
+                _ => {},
+            }
+
+            if binding.count != 1 {
+                return Err(format!("binding {} is an array of {} but the pass gives it one descriptor", binding.binding, binding.count));
+            }
+        }
+
+        match (self.push_constant_size, interface.push_constant_size) {
+            (Some(size), None) => return Err(format!("the shader has a {} byte push constant block but the pass has no push constant for this stage", size)),
+            (Some(size), Some(pass_size)) if size as usize > pass_size => return Err(format!("the shader has a {} byte push constant block but the pass's push constant is only {} bytes", size, pass_size)),
+            _ => {},
+        }
+
+        for input in &self.vertex_inputs {
+            match interface.vertex_attributes.iter().find(|a| a.location == input.location) {
+                Some(attribute) => {
+                    if let Some(numeric_type) = format_numeric_type(attribute.format) {
+                        if numeric_type != input.numeric_type {
+                            return Err(format!("vertex input {} is {:?} in the shader but the attribute format is {:?}", input.location, input.numeric_type, attribute.format));
+                        }
+                    }
+                },
+                None => return Err(format!("vertex input {} isn't given by the pass's vertex attributes", input.location)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Module {
+    // Descriptor arrays are flattened to their element type and length, runtime arrays count as one
+    fn array_element(&self, type_id: u32) -> (u32, u32) {
+        match self.types.get(&type_id) {
+            Some(SpirvType::Array { element, length }) => (*element, self.constants.get(length).copied().unwrap_or(1)),
+            _ => (type_id, 1),
+        }
+    }
+
+    fn descriptor_type(&self, type_id: u32, storage_class: u32) -> Option<vk::DescriptorType> {
+        let no_decorations = Decorations::default();
+        let decorations = self.decorations.get(&type_id).unwrap_or(&no_decorations);
+
+        match (self.types.get(&type_id)?, storage_class) {
+            (SpirvType::SampledImage, _) => Some(vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+            (SpirvType::Image { sampled: 2 }, _) => Some(vk::DescriptorType::STORAGE_IMAGE),
+            (SpirvType::Image { .. }, _) => Some(vk::DescriptorType::SAMPLED_IMAGE),
+            (SpirvType::Sampler, _) => Some(vk::DescriptorType::SAMPLER),
+            (SpirvType::Struct { .. }, STORAGE_STORAGE_BUFFER) => Some(vk::DescriptorType::STORAGE_BUFFER),
+            (SpirvType::Struct { .. }, STORAGE_UNIFORM) if decorations.buffer_block => Some(vk::DescriptorType::STORAGE_BUFFER),
+            (SpirvType::Struct { .. }, STORAGE_UNIFORM) if decorations.block => Some(vk::DescriptorType::UNIFORM_BUFFER),
+            _ => None,
+        }
+    }
+
+    fn vector_shape(&self, type_id: u32) -> Option<(NumericType, u32)> {
+        match self.types.get(&type_id)? {
+            SpirvType::Scalar { numeric_type, .. } => Some((*numeric_type, 1)),
+            SpirvType::Vector { component, count } => self.vector_shape(*component).map(|(numeric_type, _)| (numeric_type, *count)),
+            _ => None,
+        }
+    }
+
+    // Sizes follow the offsets and strides the compiler decorated the block with
+    fn size_of(&self, type_id: u32) -> u32 {
+        match self.types.get(&type_id) {
+            Some(SpirvType::Scalar { width, .. }) => width / 8,
+            Some(SpirvType::Vector { component, count }) => self.size_of(*component) * count,
+            Some(SpirvType::Matrix { column, count }) => self.size_of(*column) * count,
+            Some(SpirvType::Array { element, length }) => {
+                let length = self.constants.get(length).copied().unwrap_or(1);
+                let stride = self.decorations.get(&type_id).and_then(|d| d.array_stride).unwrap_or_else(|| self.size_of(*element));
+
+                stride * length
+            },
+            Some(SpirvType::Struct { members }) => {
+                let decorations = self.decorations.get(&type_id);
+
+                members.iter().enumerate().map(|(i, member)| {
+                    let offset = decorations.and_then(|d| d.member_offsets.get(&(i as u32))).copied().unwrap_or(0);
+
+                    let size = match (self.types.get(member), decorations.and_then(|d| d.member_matrix_strides.get(&(i as u32)))) {
+                        (Some(SpirvType::Matrix { count, .. }), Some(stride)) => stride * count,
+                        _ => self.size_of(*member),
+                    };
+
+                    offset + size
+                }).max().unwrap_or(0)
+            },
+            Some(SpirvType::RuntimeArray) | Some(SpirvType::Image { .. }) | Some(SpirvType::Sampler) | Some(SpirvType::SampledImage) | Some(SpirvType::Pointer { .. }) | Some(SpirvType::Other) | None => 0,
+        }
+    }
+}
+
+// Fewest operands each instruction that's read needs, everything else is skipped over
+fn operand_count(opcode: u32) -> usize {
+    match opcode {
+        OP_ENTRY_POINT | OP_TYPE_BOOL | OP_TYPE_SAMPLER | OP_TYPE_STRUCT => 1,
+        OP_DECORATE | OP_TYPE_FLOAT | OP_TYPE_SAMPLED_IMAGE | OP_TYPE_RUNTIME_ARRAY => 2,
+        OP_MEMBER_DECORATE | OP_TYPE_INT | OP_TYPE_VECTOR | OP_TYPE_MATRIX | OP_TYPE_ARRAY | OP_TYPE_POINTER | OP_CONSTANT | OP_VARIABLE => 3,
+        OP_TYPE_IMAGE => 7,
+        _ => 0,
+    }
+}
+
+// Formats that aren't usable as vertex attributes return None and aren't checked
+fn format_numeric_type(format: vk::Format) -> Option<NumericType> {
+    match format {
+        vk::Format::R8_UNORM | vk::Format::R8_SNORM | vk::Format::R8_USCALED | vk::Format::R8_SSCALED
+        | vk::Format::R8G8_UNORM | vk::Format::R8G8_SNORM | vk::Format::R8G8_USCALED | vk::Format::R8G8_SSCALED
+        | vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SNORM | vk::Format::R8G8B8A8_USCALED | vk::Format::R8G8B8A8_SSCALED
+        | vk::Format::B8G8R8A8_UNORM | vk::Format::A2B10G10R10_UNORM_PACK32 | vk::Format::A2B10G10R10_SNORM_PACK32
+        | vk::Format::R16_UNORM | vk::Format::R16_SNORM | vk::Format::R16_SFLOAT
+        | vk::Format::R16G16_UNORM | vk::Format::R16G16_SNORM | vk::Format::R16G16_SFLOAT
+        | vk::Format::R16G16B16A16_UNORM | vk::Format::R16G16B16A16_SNORM | vk::Format::R16G16B16A16_SFLOAT
+        | vk::Format::R32_SFLOAT | vk::Format::R32G32_SFLOAT | vk::Format::R32G32B32_SFLOAT | vk::Format::R32G32B32A32_SFLOAT
+        | vk::Format::R64_SFLOAT | vk::Format::R64G64_SFLOAT | vk::Format::R64G64B64_SFLOAT | vk::Format::R64G64B64A64_SFLOAT => Some(NumericType::Float),
+        vk::Format::R8_SINT | vk::Format::R8G8_SINT | vk::Format::R8G8B8A8_SINT
+        | vk::Format::R16_SINT | vk::Format::R16G16_SINT | vk::Format::R16G16B16A16_SINT
+        | vk::Format::R32_SINT | vk::Format::R32G32_SINT | vk::Format::R32G32B32_SINT | vk::Format::R32G32B32A32_SINT => Some(NumericType::SInt),
+        vk::Format::R8_UINT | vk::Format::R8G8_UINT | vk::Format::R8G8B8A8_UINT
+        | vk::Format::R16_UINT | vk::Format::R16G16_UINT | vk::Format::R16G16B16A16_UINT
+        | vk::Format::R32_UINT | vk::Format::R32G32_UINT | vk::Format::R32G32B32_UINT | vk::Format::R32G32B32A32_UINT => Some(NumericType::UInt),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use ash::util::read_spv;
+    use ash::vk;
+
+    use super::*;
+
+    // Assembled to match res/shaders/test/reflection.vert, so the tests don't need glslc
+    fn reflect_test_shader() -> ShaderReflection {
+        let bytecode = read_spv(&mut Cursor::new(&include_bytes!("../../res/shaders/test/reflection.vert.spv")[..])).unwrap();
+        ShaderReflection::new(&bytecode).unwrap()
+    }
+
+    fn attribute(location: u32, format: vk::Format) -> vk::VertexInputAttributeDescription {
+        vk::VertexInputAttributeDescription { location, binding: 0, format, offset: 0 }
+    }
+
+    fn matching_interface() -> StageInterface {
+        StageInterface {
+            descriptor_set: Some((0, vec![vk::DescriptorType::UNIFORM_BUFFER, vk::DescriptorType::COMBINED_IMAGE_SAMPLER])),
+            push_constant_size: Some(20),
+            vertex_attributes: vec![attribute(0, vk::Format::R32G32B32_SFLOAT), attribute(1, vk::Format::R32G32_UINT)],
+        }
+    }
+
+    #[test]
+    fn reflects_test_shader() {
+        let reflection = reflect_test_shader();
+
+        assert_eq!(reflection.stage, vk::ShaderStageFlags::VERTEX);
+        assert_eq!(reflection.bindings, vec![
+            ReflectedBinding { set: 0, binding: 0, descriptor_type: vk::DescriptorType::UNIFORM_BUFFER, count: 1 },
+            ReflectedBinding { set: 0, binding: 1, descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER, count: 1 },
+        ]);
+        assert_eq!(reflection.push_constant_size, Some(20));
+        assert_eq!(reflection.vertex_inputs, vec![
+            ReflectedVertexInput { location: 0, numeric_type: NumericType::Float, components: 3 },
+            ReflectedVertexInput { location: 1, numeric_type: NumericType::UInt, components: 2 },
+        ]);
+    }
+
+    #[test]
+    fn check_accepts_matching_interface() {
+        assert_eq!(reflect_test_shader().check(&matching_interface()), Ok(()));
+    }
+
+    #[test]
+    fn check_rejects_missing_descriptors() {
+        let interface = StageInterface { descriptor_set: None, ..matching_interface() };
+        assert!(reflect_test_shader().check(&interface).is_err());
+    }
+
+    #[test]
+    fn check_rejects_wrong_descriptor_type() {
+        let interface = StageInterface { descriptor_set: Some((0, vec![vk::DescriptorType::STORAGE_BUFFER, vk::DescriptorType::COMBINED_IMAGE_SAMPLER])), ..matching_interface() };
+        assert!(reflect_test_shader().check(&interface).is_err());
+    }
+
+    #[test]
+    fn check_rejects_small_push_constant() {
+        let interface = StageInterface { push_constant_size: Some(16), ..matching_interface() };
+        assert!(reflect_test_shader().check(&interface).is_err());
+    }
+
+    #[test]
+    fn check_rejects_missing_vertex_attribute() {
+        let interface = StageInterface { vertex_attributes: vec![attribute(0, vk::Format::R32G32B32_SFLOAT)], ..matching_interface() };
+        assert!(reflect_test_shader().check(&interface).is_err());
+    }
+
+    #[test]
+    fn check_rejects_vertex_attribute_type() {
+        let interface = StageInterface { vertex_attributes: vec![attribute(0, vk::Format::R32G32B32_SFLOAT), attribute(1, vk::Format::R32G32_SFLOAT)], ..matching_interface() };
+        assert!(reflect_test_shader().check(&interface).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_instruction() {
+        // An OpTypeVector with only its result id
+        let bytecode = [SPIRV_MAGIC, 0x00010000, 0, 2, 0, (2 << 16) | OP_TYPE_VECTOR, 1];
+        assert!(ShaderReflection::new(&bytecode).is_err());
+    }
+
+    #[test]
+    fn rejects_non_spirv() {
+        assert!(ShaderReflection::new(&[0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn format_numeric_types() {
+        assert_eq!(format_numeric_type(vk::Format::R8G8B8A8_UNORM), Some(NumericType::Float));
+        assert_eq!(format_numeric_type(vk::Format::R32G32_SINT), Some(NumericType::SInt));
+        assert_eq!(format_numeric_type(vk::Format::R16_UINT), Some(NumericType::UInt));
+        assert_eq!(format_numeric_type(vk::Format::D32_SFLOAT), None);
+    }
+}
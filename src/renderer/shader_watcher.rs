@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::renderer::error::RendererError;
+
+// Which pass a reload was attempted for, a failed reload leaves the pass drawing with its old pipeline
+pub struct ShaderReload {
+    pub layer: String,
+    pub pass: String,
+    pub result: Result<(), RendererError>,
+}
+
+// Polls the modification times of shader sources, checking at most once per interval so it can be called every frame
+pub struct ShaderWatcher {
+    pub interval: Duration,
+
+    files: HashMap<PathBuf, Option<SystemTime>>,
+    last_poll: Instant,
+}
+
+impl ShaderWatcher {
+    pub fn new(interval: Duration) -> ShaderWatcher {
+        ShaderWatcher {
+            interval,
+
+            files: HashMap::new(),
+            last_poll: Instant::now(),
+        }
+    }
+
+    pub fn watch(&mut self, paths: &[PathBuf]) {
+        for path in paths {
+            if !self.files.contains_key(path) {
+                self.files.insert(path.clone(), ShaderWatcher::modified(path));
+            }
+        }
+    }
+
+    // Returns every watched file that has been written to since the last poll
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        if self.last_poll.elapsed() < self.interval {
+            return Vec::new();
+        }
+
+        self.last_poll = Instant::now();
+
+        let mut changed = Vec::<PathBuf>::new();
+
+        for (path, last_modified) in &mut self.files {
+            let modified = ShaderWatcher::modified(path);
+
+            // Editors can briefly remove a file while saving it, so a missing file isn't a change until it comes back
+            if modified.is_some() && modified != *last_modified {
+                *last_modified = modified;
+                changed.push(path.clone());
+            }
+        }
+
+        changed
+    }
+
+    fn modified(path: &PathBuf) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+}
@@ -3,6 +3,7 @@ use ash::vk;
 use crate::renderer::{core::Core, image::ImageBuilder};
 use crate::renderer::device::Device;
 use crate::renderer::image::Image;
+use crate::renderer::error::RendererError;
 
 pub struct Swapchain {
     pub swapchain_init: ash::extensions::khr::Swapchain,
@@ -13,8 +14,57 @@ pub struct Swapchain {
 }
 
 impl Swapchain {
-    pub unsafe fn new(c: &Core, d: &Device) -> Swapchain {
-        assert!(d.surface_capabilities.max_image_count >= 2, "Swapchain doesn't support 2 images");
+    pub unsafe fn new(c: &Core, d: &Device) -> Result<Swapchain, RendererError> {
+        let swapchain_init = ash::extensions::khr::Swapchain::new(&c.instance, &d.device);
+
+        let (swapchain, image_count, images) = Swapchain::create(c, d, &swapchain_init, vk::SwapchainKHR::null())?;
+
+        Ok(Swapchain {
+            swapchain_init,
+            swapchain,
+
+            image_count,
+            images,
+        })
+    }
+
+    /// The device has to be idle and its surface refreshed before calling this, the old image views and swapchain are destroyed
+    ///
+    /// # Safety
+    /// The device must be idle, the old swapchain images are destroyed straight away.
+    pub unsafe fn recreate(&mut self, c: &Core, d: &Device) -> Result<(), RendererError> {
+        let (swapchain, image_count, images) = Swapchain::create(c, d, &self.swapchain_init, self.swapchain)?;
+
+        for image in &self.images {
+            image.destroy(d);
+        }
+
+        self.swapchain_init.destroy_swapchain(self.swapchain, None);
+
+        self.swapchain = swapchain;
+        self.image_count = image_count;
+        self.images = images;
+
+        Ok(())
+    }
+
+    /// # Safety
+    /// The images mustn't be used by a frame still in flight.
+    pub unsafe fn destroy(&self, d: &Device) {
+        for image in &self.images {
+            image.destroy(d);
+        }
+
+        if self.swapchain != vk::SwapchainKHR::null() {
+            self.swapchain_init.destroy_swapchain(self.swapchain, None);
+        }
+    }
+
+    unsafe fn create(c: &Core, d: &Device, swapchain_init: &ash::extensions::khr::Swapchain, old_swapchain: vk::SwapchainKHR) -> Result<(vk::SwapchainKHR, u32, Vec<Image>), RendererError> {
+        // A max of 0 means there is no limit
+        if d.surface_capabilities.max_image_count != 0 && d.surface_capabilities.max_image_count < 2 {
+            return Err(RendererError::UnsupportedFeature("Swapchain doesn't support 2 images"));
+        }
 
         let image_count = if d.surface_capabilities.max_image_count > 0 && d.surface_capabilities.min_image_count + 1 > d.surface_capabilities.max_image_count {
             d.surface_capabilities.max_image_count
@@ -30,11 +80,9 @@ impl Swapchain {
             (vec![d.queue_present.1, d.queue_main.1], vk::SharingMode::CONCURRENT)
         };
 
-        let present_mode = d.surface_init.get_physical_device_surface_present_modes(d.physical_device, d.surface).unwrap().iter().cloned().find(|&pm| pm == vk::PresentModeKHR::MAILBOX).unwrap_or(vk::PresentModeKHR::FIFO);
+        let present_mode = d.surface_init.get_physical_device_surface_present_modes(d.physical_device, d.surface)?.iter().cloned().find(|&pm| pm == vk::PresentModeKHR::MAILBOX).unwrap_or(vk::PresentModeKHR::FIFO);
         //let present_mode = vk::PresentModeKHR::IMMEDIATE;
 
-        let swapchain_init = ash::extensions::khr::Swapchain::new(&c.instance, &d.device);
-
         let swapchain_ci = vk::SwapchainCreateInfoKHR::builder()
             .surface(d.surface)
             .min_image_count(image_count)
@@ -48,11 +96,12 @@ impl Swapchain {
             .pre_transform(d.surface_capabilities.current_transform)
             .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
             .present_mode(present_mode)
-            .clipped(true);
+            .clipped(true)
+            .old_swapchain(old_swapchain);
 
-        let swapchain = swapchain_init.create_swapchain(&swapchain_ci, None).unwrap();
+        let swapchain = swapchain_init.create_swapchain(&swapchain_ci, None)?;
 
-        let image_handles = swapchain_init.get_swapchain_images(swapchain).unwrap();
+        let image_handles = swapchain_init.get_swapchain_images(swapchain)?;
 
         let images = ImageBuilder::new()
             .width(d.surface_extent.width)
@@ -61,14 +110,32 @@ impl Swapchain {
             .usage(vk::ImageUsageFlags::empty())
             .layout(vk::ImageLayout::PRESENT_SRC_KHR)
             .pre_allocated_images(image_handles)
-            .build_many(c, d, image_count as usize);
+            .build_many(c, d, image_count as usize)?;
+
+        Ok((swapchain, image_count, images))
+    }
 
-        Swapchain {
+    /// Offscreen stand-in for the swapchain, the images are left in TRANSFER_SRC_OPTIMAL so they can be read back after each frame
+    ///
+    /// # Safety
+    /// The images must be destroyed before `d`.
+    pub unsafe fn new_headless(c: &Core, d: &Device, image_count: u32) -> Result<Swapchain, RendererError> {
+        let swapchain_init = ash::extensions::khr::Swapchain::new(&c.instance, &d.device);
+
+        let images = ImageBuilder::new()
+            .width(d.surface_extent.width)
+            .height(d.surface_extent.height)
+            .format(d.surface_format.format)
+            .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC)
+            .layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .build_many(c, d, image_count as usize)?;
+
+        Ok(Swapchain {
             swapchain_init,
-            swapchain,
+            swapchain: vk::SwapchainKHR::null(),
 
             image_count,
             images,
-        }
+        })
     }
 }
\ No newline at end of file
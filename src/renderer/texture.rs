@@ -0,0 +1,327 @@
+use std::ffi::c_void;
+
+use ash::vk;
+
+use crate::renderer::buffer::BufferBuilder;
+use crate::renderer::commands::Commands;
+use crate::renderer::core::Core;
+use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
+use crate::renderer::image::{format_size, Image};
+
+// Decoded pixels ready to be uploaded, rows are tightly packed
+#[derive(Clone)]
+pub struct TextureData {
+    pub width: u32,
+    pub height: u32,
+    pub format: vk::Format,
+    pub pixels: Vec<u8>,
+}
+
+pub struct TextureBuilder<'a> {
+    pub path: Option<&'a str>,
+    pub data: Option<TextureData>,
+    pub srgb: bool,
+    pub mipmaps: bool,
+}
+
+impl TextureData {
+    // HDR files keep their full range as 32 bit floats, everything else is 8 bits per channel
+    pub fn load(path: &str, srgb: bool) -> Result<TextureData, ::image::ImageError> {
+        let image = ::image::open(path)?;
+
+        let (width, height) = (image.width(), image.height());
+
+        match image.color() {
+            ::image::ColorType::Rgb32F | ::image::ColorType::Rgba32F => Ok(TextureData {
+                width,
+                height,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                pixels: image.into_rgba32f().into_raw().iter().flat_map(|c| c.to_ne_bytes()).collect(),
+            }),
+            _ => Ok(TextureData {
+                width,
+                height,
+                format: if srgb { vk::Format::R8G8B8A8_SRGB } else { vk::Format::R8G8B8A8_UNORM },
+                pixels: image.into_rgba8().into_raw(),
+            }),
+        }
+    }
+}
+
+impl<'a> TextureBuilder<'a> {
+    pub fn new() -> TextureBuilder<'a> {
+        TextureBuilder {
+            path: None,
+            data: None,
+            srgb: true,
+            mipmaps: false,
+        }
+    }
+
+    pub fn path(mut self, path: &'a str) -> TextureBuilder<'a> {
+        self.path = Some(path);
+
+        self
+    }
+
+    pub fn pixels(mut self, width: u32, height: u32, format: vk::Format, pixels: &[u8]) -> TextureBuilder<'a> {
+        self.data = Some(TextureData { width, height, format, pixels: pixels.to_vec() });
+
+        self
+    }
+
+    // Only affects 8 bit images loaded from a path, raw pixels keep the format they were given with
+    pub fn srgb(mut self, srgb: bool) -> TextureBuilder<'a> {
+        self.srgb = srgb;
+
+        self
+    }
+
+    pub fn mipmaps(mut self, mipmaps: bool) -> TextureBuilder<'a> {
+        self.mipmaps = mipmaps;
+
+        self
+    }
+
+    // Loads the file if the texture has a path, for uploading somewhere other than build
+    pub fn load(&self) -> Result<TextureData, RendererError> {
+        match (&self.path, &self.data) {
+            (Some(path), None) => TextureData::load(path, self.srgb).map_err(|source| RendererError::TextureLoad { path: path.to_string(), source }),
+            (None, Some(data)) => Ok(data.clone()),
+            (Some(_), Some(_)) => Err(RendererError::InvalidTexture("Texture builder has both a path and pixels".to_string())),
+            (None, None) => Err(RendererError::missing("TextureBuilder", "path or pixels")),
+        }
+    }
+
+    /// # Safety
+    /// Submits the upload and waits for it, so it can't be called while a frame is being recorded.
+    pub unsafe fn build(&self, c: &Core, d: &Device) -> Result<Image, RendererError> {
+        match (&self.path, &self.data) {
+            (Some(path), None) => {
+                let data = TextureData::load(path, self.srgb).map_err(|source| RendererError::TextureLoad { path: path.to_string(), source })?;
+                Image::from_pixels(c, d, &data, self.mipmaps)
+            },
+            (None, Some(data)) => Image::from_pixels(c, d, data, self.mipmaps),
+            (Some(_), Some(_)) => Err(RendererError::InvalidTexture("Texture builder has both a path and pixels".to_string())),
+            (None, None) => Err(RendererError::missing("TextureBuilder", "path or pixels")),
+        }
+    }
+}
+
+impl Image {
+    /// Uploads through a staging buffer and leaves every mip level in SHADER_READ_ONLY_OPTIMAL
+    ///
+    /// # Safety
+    /// Waits for the main queue to be idle.
+    pub unsafe fn from_pixels(c: &Core, d: &Device, data: &TextureData, mipmaps: bool) -> Result<Image, RendererError> {
+        let image = Image::new_texture(c, d, data, mipmaps)?;
+
+        let staging_buffer = BufferBuilder::new()
+            .size(data.pixels.len())
+            .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .properties(vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT)
+            .build_with_data(c, d, data.pixels.as_ptr() as *const c_void);
+
+        let staging_buffer = match staging_buffer {
+            Ok(staging_buffer) => staging_buffer,
+            Err(e) => {
+                image.destroy(d);
+
+                return Err(e);
+            },
+        };
+
+        let uploaded = Commands::new(d, d.queue_main.1, 1, true).and_then(|upload_commands| {
+            let submitted = upload_commands.record_one(d, 0, |b| {
+                image.record_upload(d, b, staging_buffer.buffer);
+                image.record_mipmaps(d, b);
+            }).and_then(|_| {
+                let submit_is = [vk::SubmitInfo::builder()
+                    .command_buffers(&upload_commands.buffers)
+                    .build()];
+
+                d.device.queue_submit(d.queue_main.0, &submit_is, vk::Fence::null())?;
+                d.device.queue_wait_idle(d.queue_main.0)?;
+
+                Ok(())
+            });
+
+            upload_commands.destroy(d);
+
+            submitted
+        });
+
+        staging_buffer.destroy(d);
+
+        if let Err(e) = uploaded {
+            image.destroy(d);
+
+            return Err(e);
+        }
+
+        Ok(image)
+    }
+
+    /// The image's layout is where it will be once uploaded, its contents are undefined until then
+    ///
+    /// # Safety
+    /// The image's contents are undefined until an upload has been recorded and has finished.
+    pub unsafe fn new_texture(c: &Core, d: &Device, data: &TextureData, mipmaps: bool) -> Result<Image, RendererError> {
+        let size = (data.width * data.height) as usize * format_size(data.format)?;
+        if data.pixels.len() != size {
+            return Err(RendererError::InvalidTexture(format!("Texture has {} bytes of pixels but {} are needed", data.pixels.len(), size)));
+        }
+
+        // Mipmaps are made by linear blits, which not every format supports
+        let format_properties = c.instance.get_physical_device_format_properties(d.physical_device, data.format);
+        let can_blit = format_properties.optimal_tiling_features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR | vk::FormatFeatureFlags::BLIT_SRC | vk::FormatFeatureFlags::BLIT_DST);
+
+        let mip_levels = if mipmaps && can_blit { 32 - data.width.max(data.height).leading_zeros() } else { 1 };
+
+        let extent = vk::Extent3D { width: data.width, height: data.height, depth: 1 };
+
+        let image_ci = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .extent(extent)
+            .mip_levels(mip_levels)
+            .array_layers(1)
+            .format(data.format)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::TRANSFER_SRC)
+            .samples(vk::SampleCountFlags::TYPE_1);
+
+        let image = d.device.create_image(&image_ci, None)?;
+
+        let memory_requirements = d.device.get_image_memory_requirements(image);
+
+        let allocation = match d.allocate(memory_requirements, vk::MemoryPropertyFlags::DEVICE_LOCAL, vk::ImageTiling::OPTIMAL) {
+            Ok(allocation) => allocation,
+            Err(e) => {
+                d.device.destroy_image(image, None);
+
+                return Err(e);
+            },
+        };
+
+        if let Err(e) = d.device.bind_image_memory(image, allocation.memory, allocation.offset) {
+            d.device.destroy_image(image, None);
+            d.free(&allocation);
+
+            return Err(e.into());
+        }
+
+        let view_ci = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(data.format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: mip_levels,
+                base_array_layer: 0,
+                layer_count: 1,
+            });
+
+        let view = match d.device.create_image_view(&view_ci, None) {
+            Ok(view) => view,
+            Err(e) => {
+                d.device.destroy_image(image, None);
+                d.free(&allocation);
+
+                return Err(e.into());
+            },
+        };
+
+        Ok(Image {
+            image,
+            view,
+            allocation: Some(allocation),
+            width: data.width,
+            height: data.height,
+            extent,
+            format: data.format,
+            layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            mip_levels,
+            shared: false,
+        })
+    }
+
+    pub fn level_barrier(&self, level: u32, old_layout: vk::ImageLayout, new_layout: vk::ImageLayout, src_access: vk::AccessFlags, dst_access: vk::AccessFlags) -> vk::ImageMemoryBarrier {
+        vk::ImageMemoryBarrier::builder()
+            .image(self.image)
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access)
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: level,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .build()
+    }
+
+    /// Leaves every level in TRANSFER_DST_OPTIMAL with the first one filled, only needs a transfer queue
+    ///
+    /// # Safety
+    /// `b` must be recording and `staging_buffer` must hold the texture's pixels until the commands have finished.
+    pub unsafe fn record_upload(&self, d: &Device, b: vk::CommandBuffer, staging_buffer: vk::Buffer) {
+        let to_transfer_barriers: Vec<vk::ImageMemoryBarrier> = (0..self.mip_levels).map(|level| {
+            self.level_barrier(level, vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::AccessFlags::empty(), vk::AccessFlags::TRANSFER_WRITE)
+        }).collect();
+
+        d.device.cmd_pipeline_barrier(b, vk::PipelineStageFlags::TOP_OF_PIPE, vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(), &[], &[], &to_transfer_barriers);
+
+        let image_copy = vk::BufferImageCopy::builder()
+            .image_subresource(vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image_extent(self.extent)
+            .build();
+
+        d.device.cmd_copy_buffer_to_image(b, staging_buffer, self.image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &[image_copy]);
+    }
+
+    /// Fills the rest of the levels after record_upload and makes them all readable, blits need a graphics queue
+    ///
+    /// # Safety
+    /// `b` must be recording, after `record_upload` for this image.
+    pub unsafe fn record_mipmaps(&self, d: &Device, b: vk::CommandBuffer) {
+        // Each level is blitted from the one above it, which is then finished with and can be made readable
+        for level in 1..self.mip_levels {
+            let src_barrier = self.level_barrier(level - 1, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::TRANSFER_READ);
+            d.device.cmd_pipeline_barrier(b, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(), &[], &[], &[src_barrier]);
+
+            let mip_offset = |level: u32| vk::Offset3D {
+                x: (self.width >> level).max(1) as i32,
+                y: (self.height >> level).max(1) as i32,
+                z: 1,
+            };
+
+            let blit = vk::ImageBlit::builder()
+                .src_subresource(vk::ImageSubresourceLayers { aspect_mask: vk::ImageAspectFlags::COLOR, mip_level: level - 1, base_array_layer: 0, layer_count: 1 })
+                .src_offsets([vk::Offset3D { x: 0, y: 0, z: 0 }, mip_offset(level - 1)])
+                .dst_subresource(vk::ImageSubresourceLayers { aspect_mask: vk::ImageAspectFlags::COLOR, mip_level: level, base_array_layer: 0, layer_count: 1 })
+                .dst_offsets([vk::Offset3D { x: 0, y: 0, z: 0 }, mip_offset(level)])
+                .build();
+
+            d.device.cmd_blit_image(b, self.image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, self.image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &[blit], vk::Filter::LINEAR);
+
+            let read_barrier = self.level_barrier(level - 1, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::AccessFlags::TRANSFER_READ, vk::AccessFlags::SHADER_READ);
+            d.device.cmd_pipeline_barrier(b, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::ALL_COMMANDS, vk::DependencyFlags::empty(), &[], &[], &[read_barrier]);
+        }
+
+        let last_barrier = self.level_barrier(self.mip_levels - 1, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::SHADER_READ);
+        d.device.cmd_pipeline_barrier(b, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::ALL_COMMANDS, vk::DependencyFlags::empty(), &[], &[], &[last_barrier]);
+    }
+}
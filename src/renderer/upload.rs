@@ -0,0 +1,409 @@
+use std::ffi::c_void;
+
+use ash::vk;
+
+use crate::renderer::{core::Core, buffer::{Buffer, BufferBuilder}, commands::Commands, image::Image, semaphore::Semaphore, texture::TextureData};
+use crate::renderer::device::Device;
+use crate::renderer::error::RendererError;
+
+// The timeline value an upload's batch signals once it's resident
+pub type UploadId = u64;
+
+// A host visible buffer for each frame in flight that writes to device local buffers go through.
+// Copies are recorded into their own command buffer, submitted on the main queue before any layer, and a frame's part of the ring is reused once the frame has finished
+pub struct StagingRing {
+    pub size: u64,
+
+    buffers: Vec<Buffer>,
+    cursors: Vec<u64>,
+    copies: Vec<Vec<(vk::Buffer, vk::BufferCopy)>>,
+
+    pub commands: Commands,
+    pub semaphore: Semaphore, // Timeline semaphore signaled with the frame number once a frame's copies have finished
+}
+
+enum PendingUpload {
+    Buffer { staging: Buffer, dst: vk::Buffer, offset: u64, size: u64 },
+    Image { staging: Buffer, image: Image },
+}
+
+struct UploadBatch {
+    id: UploadId,
+    transfer_commands: Commands,
+    main_commands: Commands,
+    staging_buffers: Vec<Buffer>,
+}
+
+// Copies data into device local resources on the transfer queue without waiting for it, so assets can be streamed in while frames are drawn.
+// Everything queued between flushes goes in one submission, then ownership is handed to the main queue family where mipmaps are generated
+pub struct Uploader {
+    pub semaphore: Semaphore, // Timeline semaphore signaled with a batch's id once everything in it is resident
+    transfer_semaphore: Semaphore, // Signaled with the id once the transfer queue's copies have finished
+
+    pending: Vec<PendingUpload>,
+    in_flight: Vec<UploadBatch>,
+    next_id: UploadId,
+}
+
+impl StagingRing {
+    /// # Safety
+    /// The ring must be destroyed before `d`.
+    pub unsafe fn new(c: &Core, d: &Device, count: usize, size: u64) -> Result<StagingRing, RendererError> {
+        let buffers = BufferBuilder::new()
+            .size(size as usize)
+            .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .properties(vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT)
+            .build_many(c, d, count)?;
+
+        let commands = Commands::new(d, d.queue_main.1, count, true)?;
+        let semaphore = Semaphore::new_timeline(d)?;
+
+        Ok(StagingRing {
+            size,
+
+            buffers,
+            cursors: vec![0; count],
+            copies: vec![Vec::new(); count],
+
+            commands,
+            semaphore,
+        })
+    }
+
+    /// # Safety
+    /// No frame in flight can still be copying from the ring.
+    pub unsafe fn destroy(&self, d: &Device) {
+        for buffer in &self.buffers {
+            buffer.destroy(d);
+        }
+
+        self.commands.destroy(d);
+        self.semaphore.destroy(d);
+    }
+
+    // Called once frame i has finished, nothing is still copying out of its part of the ring
+    pub fn reset(&mut self, i: usize) {
+        self.cursors[i] = 0;
+        self.copies[i].clear();
+    }
+
+    /// # Safety
+    /// `data` must point to `size` readable bytes and frame `i`'s fence must have been waited on.
+    pub unsafe fn stage(&mut self, i: usize, dst: &Buffer, offset: u64, data: *const c_void, size: u64) -> Result<(), RendererError> {
+        if self.cursors[i] + size > self.size {
+            return Err(RendererError::BufferOverflow { name: String::from("staging ring"), size: self.size, required: self.cursors[i] + size });
+        }
+
+        self.buffers[i].fill_at(self.cursors[i] as usize, data, size as usize);
+
+        let buffer_copy = vk::BufferCopy::builder()
+            .src_offset(self.cursors[i])
+            .dst_offset(offset)
+            .size(size)
+            .build();
+
+        self.copies[i].push((dst.buffer, buffer_copy));
+        self.cursors[i] += size;
+
+        Ok(())
+    }
+
+    /// Records the copies staged for frame i, None if there weren't any. Later submissions on the main queue are ordered after the copies by the barrier,
+    /// layers on other queues have to wait on the semaphore
+    ///
+    /// # Safety
+    /// Frame `i`'s fence must have been waited on.
+    pub unsafe fn record(&self, d: &Device, i: usize) -> Result<Option<vk::CommandBuffer>, RendererError> {
+        if self.copies[i].is_empty() {
+            return Ok(None);
+        }
+
+        self.commands.record_one(d, i, |b| {
+            for (dst, buffer_copy) in &self.copies[i] {
+                d.device.cmd_copy_buffer(b, self.buffers[i].buffer, *dst, &[*buffer_copy]);
+            }
+
+            let memory_barrier = vk::MemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE)
+                .build();
+
+            d.device.cmd_pipeline_barrier(b, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::ALL_COMMANDS, vk::DependencyFlags::empty(), &[memory_barrier], &[], &[]);
+        })?;
+
+        Ok(Some(self.commands.buffers[i]))
+    }
+}
+
+impl Uploader {
+    /// # Safety
+    /// The uploader must be destroyed before `d`.
+    pub unsafe fn new(d: &Device) -> Result<Uploader, RendererError> {
+        Ok(Uploader {
+            semaphore: Semaphore::new_timeline(d)?,
+            transfer_semaphore: Semaphore::new_timeline(d)?,
+
+            pending: Vec::new(),
+            in_flight: Vec::new(),
+            next_id: 1,
+        })
+    }
+
+    /// Waits for every batch, nothing can be destroyed while the device may still be copying it
+    ///
+    /// # Safety
+    /// Nothing else can be submitting uploads.
+    pub unsafe fn destroy(&mut self, d: &Device) {
+        self.wait(d, self.next_id - 1).ok();
+
+        for upload in self.pending.drain(..) {
+            match upload {
+                PendingUpload::Buffer { staging, .. } => staging.destroy(d),
+                PendingUpload::Image { staging, .. } => staging.destroy(d),
+            }
+        }
+
+        for batch in self.in_flight.drain(..) {
+            batch.destroy(d);
+        }
+
+        self.semaphore.destroy(d);
+        self.transfer_semaphore.destroy(d);
+    }
+
+    /// The destination isn't written until the next flush, the returned id is resident once that batch has finished
+    ///
+    /// # Safety
+    /// `data` must point to `size` readable bytes and `dst` must stay alive until the upload is resident.
+    pub unsafe fn upload_buffer(&mut self, c: &Core, d: &Device, dst: &Buffer, offset: u64, data: *const c_void, size: u64) -> Result<UploadId, RendererError> {
+        if offset + size > dst.size {
+            return Err(RendererError::BufferOverflow { name: String::from("upload destination"), size: dst.size, required: offset + size });
+        }
+
+        let staging = Uploader::staging_buffer(c, d, data, size as usize)?;
+        self.pending.push(PendingUpload::Buffer { staging, dst: dst.buffer, offset, size });
+
+        Ok(self.next_id)
+    }
+
+    /// The image can be handed out straight away, but mustn't be read until its id is resident
+    ///
+    /// # Safety
+    /// The image mustn't be sampled before the returned id is resident.
+    pub unsafe fn upload_texture(&mut self, c: &Core, d: &Device, data: &TextureData, mipmaps: bool) -> Result<(Image, UploadId), RendererError> {
+        let image = Image::new_texture(c, d, data, mipmaps)?;
+
+        let staging = match Uploader::staging_buffer(c, d, data.pixels.as_ptr() as *const c_void, data.pixels.len()) {
+            Ok(staging) => staging,
+            Err(e) => {
+                image.destroy(d);
+                return Err(e);
+            },
+        };
+
+        self.pending.push(PendingUpload::Image { staging, image: image.share() });
+
+        Ok((image, self.next_id))
+    }
+
+    /// Submits everything queued since the last flush as one batch, None if nothing was queued
+    ///
+    /// # Safety
+    /// Must not be called while a frame is being recorded on the main queue.
+    pub unsafe fn flush(&mut self, d: &Device) -> Result<Option<UploadId>, RendererError> {
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+
+        let id = self.next_id;
+        let (transfer_family, main_family) = (d.queue_transfer.1, d.queue_main.1);
+
+        // Families only need to be named when ownership actually moves
+        let (src_family, dst_family) = match transfer_family == main_family {
+            true => (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED),
+            false => (transfer_family, main_family),
+        };
+
+        let transfer_commands = Commands::new(d, transfer_family, 1, true)?;
+        let main_commands = match Commands::new(d, main_family, 1, true) {
+            Ok(commands) => commands,
+            Err(e) => {
+                transfer_commands.destroy(d);
+                return Err(e);
+            },
+        };
+
+        let mut buffer_barriers = Vec::<vk::BufferMemoryBarrier>::new();
+        let mut image_barriers = Vec::<vk::ImageMemoryBarrier>::new();
+
+        for upload in &self.pending {
+            match upload {
+                PendingUpload::Buffer { dst, offset, size, .. } => {
+                    buffer_barriers.push(vk::BufferMemoryBarrier::builder()
+                        .buffer(*dst)
+                        .offset(*offset)
+                        .size(*size)
+                        .src_queue_family_index(src_family)
+                        .dst_queue_family_index(dst_family)
+                        .build());
+                },
+                PendingUpload::Image { image, .. } => {
+                    for level in 0..image.mip_levels {
+                        let mut barrier = image.level_barrier(level, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::AccessFlags::empty(), vk::AccessFlags::empty());
+                        barrier.src_queue_family_index = src_family;
+                        barrier.dst_queue_family_index = dst_family;
+
+                        image_barriers.push(barrier);
+                    }
+                },
+            }
+        }
+
+        let recorded = transfer_commands.record_one(d, 0, |b| {
+            for upload in &self.pending {
+                match upload {
+                    PendingUpload::Buffer { staging, dst, offset, size } => {
+                        let buffer_copy = vk::BufferCopy::builder()
+                            .dst_offset(*offset)
+                            .size(*size)
+                            .build();
+
+                        d.device.cmd_copy_buffer(b, staging.buffer, *dst, &[buffer_copy]);
+                    },
+                    PendingUpload::Image { staging, image } => image.record_upload(d, b, staging.buffer),
+                }
+            }
+
+            // Releases only make the copies available, the acquire on the main queue makes them visible
+            let release_buffer_barriers: Vec<vk::BufferMemoryBarrier> = buffer_barriers.iter().map(|barrier| vk::BufferMemoryBarrier { src_access_mask: vk::AccessFlags::TRANSFER_WRITE, ..*barrier }).collect();
+            let release_image_barriers: Vec<vk::ImageMemoryBarrier> = image_barriers.iter().map(|barrier| vk::ImageMemoryBarrier { src_access_mask: vk::AccessFlags::TRANSFER_WRITE, ..*barrier }).collect();
+
+            d.device.cmd_pipeline_barrier(b, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::BOTTOM_OF_PIPE, vk::DependencyFlags::empty(), &[], &release_buffer_barriers, &release_image_barriers);
+        }).and_then(|_| main_commands.record_one(d, 0, |b| {
+            let acquire_buffer_barriers: Vec<vk::BufferMemoryBarrier> = buffer_barriers.iter().map(|barrier| vk::BufferMemoryBarrier { dst_access_mask: vk::AccessFlags::MEMORY_READ, ..*barrier }).collect();
+            let acquire_image_barriers: Vec<vk::ImageMemoryBarrier> = image_barriers.iter().map(|barrier| vk::ImageMemoryBarrier { dst_access_mask: vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::TRANSFER_WRITE, ..*barrier }).collect();
+
+            d.device.cmd_pipeline_barrier(b, vk::PipelineStageFlags::TOP_OF_PIPE, vk::PipelineStageFlags::ALL_COMMANDS, vk::DependencyFlags::empty(), &[], &acquire_buffer_barriers, &acquire_image_barriers);
+
+            for upload in &self.pending {
+                if let PendingUpload::Image { image, .. } = upload {
+                    image.record_mipmaps(d, b);
+                }
+            }
+        }));
+
+        if let Err(e) = recorded {
+            transfer_commands.destroy(d);
+            main_commands.destroy(d);
+            return Err(e);
+        }
+
+        let transfer_values = [id];
+        let transfer_semaphores = [self.transfer_semaphore.semaphore];
+        let transfer_stages = [vk::PipelineStageFlags::ALL_COMMANDS];
+        let signal_semaphores = [self.semaphore.semaphore];
+
+        let mut transfer_timeline_submit_i = vk::TimelineSemaphoreSubmitInfo::builder()
+            .signal_semaphore_values(&transfer_values);
+
+        let transfer_submit_i = vk::SubmitInfo::builder()
+            .push_next(&mut transfer_timeline_submit_i)
+            .command_buffers(&transfer_commands.buffers)
+            .signal_semaphores(&transfer_semaphores)
+            .build();
+
+        let mut main_timeline_submit_i = vk::TimelineSemaphoreSubmitInfo::builder()
+            .wait_semaphore_values(&transfer_values)
+            .signal_semaphore_values(&transfer_values);
+
+        let main_submit_i = vk::SubmitInfo::builder()
+            .push_next(&mut main_timeline_submit_i)
+            .wait_semaphores(&transfer_semaphores)
+            .wait_dst_stage_mask(&transfer_stages)
+            .command_buffers(&main_commands.buffers)
+            .signal_semaphores(&signal_semaphores)
+            .build();
+
+        let submitted = d.device.queue_submit(d.queue_transfer.0, &[transfer_submit_i], vk::Fence::null())
+            .and_then(|_| d.device.queue_submit(d.queue_main.0, &[main_submit_i], vk::Fence::null()));
+
+        let staging_buffers = self.pending.drain(..).map(|upload| match upload {
+            PendingUpload::Buffer { staging, .. } => staging,
+            PendingUpload::Image { staging, .. } => staging,
+        }).collect();
+
+        let batch = UploadBatch { id, transfer_commands, main_commands, staging_buffers };
+
+        if let Err(e) = submitted {
+            d.device.device_wait_idle().ok();
+            batch.destroy(d);
+            return Err(e.into());
+        }
+
+        self.in_flight.push(batch);
+        self.next_id += 1;
+
+        Ok(Some(id))
+    }
+
+    /// # Safety
+    /// The id must come from this uploader.
+    pub unsafe fn is_resident(&self, d: &Device, id: UploadId) -> Result<bool, RendererError> {
+        Ok(d.device.get_semaphore_counter_value(self.semaphore.semaphore)? >= id)
+    }
+
+    /// Only waits for batches that have been flushed
+    ///
+    /// # Safety
+    /// The id must come from this uploader.
+    pub unsafe fn wait(&self, d: &Device, id: UploadId) -> Result<(), RendererError> {
+        let semaphores = [self.semaphore.semaphore];
+        let values = [id.min(self.next_id - 1)];
+
+        let wait_i = vk::SemaphoreWaitInfo::builder()
+            .semaphores(&semaphores)
+            .values(&values);
+
+        d.device.wait_semaphores(&wait_i, u64::MAX)?;
+
+        Ok(())
+    }
+
+    /// Frees the staging buffers and commands of batches that have finished
+    ///
+    /// # Safety
+    /// Must be called from the thread that flushes.
+    pub unsafe fn collect(&mut self, d: &Device) -> Result<(), RendererError> {
+        let resident = d.device.get_semaphore_counter_value(self.semaphore.semaphore)?;
+
+        let (finished, in_flight): (Vec<UploadBatch>, Vec<UploadBatch>) = self.in_flight.drain(..).partition(|batch| batch.id <= resident);
+        self.in_flight = in_flight;
+
+        for batch in finished {
+            batch.destroy(d);
+        }
+
+        Ok(())
+    }
+
+    unsafe fn staging_buffer(c: &Core, d: &Device, data: *const c_void, size: usize) -> Result<Buffer, RendererError> {
+        BufferBuilder::new()
+            .size(size)
+            .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .properties(vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT)
+            .build_with_data(c, d, data)
+    }
+}
+
+impl UploadBatch {
+    unsafe fn destroy(self, d: &Device) {
+        for buffer in &self.staging_buffers {
+            buffer.destroy(d);
+        }
+
+        self.transfer_commands.destroy(d);
+        self.main_commands.destroy(d);
+    }
+}
@@ -5,6 +5,7 @@ use ash::vk;
 use crate::{renderer::core::Core, math::vec::Vec4};
 use crate::renderer::device::Device;
 use crate::renderer::buffer::{Buffer, BufferBuilder};
+use crate::renderer::error::RendererError;
 
 pub struct VertexAttribute {
     pub format: vk::Format,
@@ -31,15 +32,44 @@ impl VertexAttributes for Vec4 {
     }
 }
 
-pub struct VertexBuffer {
-    pub binding_desc: vk::VertexInputBindingDescription,
+// Per-instance data with its type erased, so passes don't need a second vertex type parameter
+pub struct InstanceData<'a> {
+    pub bytes: &'a [u8],
+    pub stride: usize,
+    pub count: usize,
+    pub attributes: Vec<VertexAttribute>,
+}
+
+// What a pipeline needs to know about the vertex buffers it draws with
+#[derive(Clone)]
+pub struct VertexLayout {
+    pub binding_descs: Vec<vk::VertexInputBindingDescription>,
     pub attrib_descs: Vec<vk::VertexInputAttributeDescription>,
+}
+
+pub struct VertexBuffer {
+    pub layout: VertexLayout,
     pub buffer: Buffer,
+    pub instance_buffer: Option<Buffer>,
     pub index_buffer: Option<Buffer>,
 }
 
-impl VertexBuffer {
-    pub unsafe fn new<T: VertexAttributes>(c: &Core, d: &Device, verts: &Vec<T>, indices: Option<&Vec<u32>>) -> VertexBuffer {
+impl <'a> InstanceData<'a> {
+    pub fn new<U: VertexAttributes>(instances: &'a [U]) -> InstanceData<'a> {
+        let stride = mem::size_of::<U>();
+        let bytes = unsafe { std::slice::from_raw_parts(instances.as_ptr() as *const u8, stride * instances.len()) };
+
+        InstanceData {
+            bytes,
+            stride,
+            count: instances.len(),
+            attributes: U::get_attribute_data(),
+        }
+    }
+}
+
+impl VertexLayout {
+    pub fn new<T: VertexAttributes>() -> VertexLayout {
         let binding_desc = vk::VertexInputBindingDescription::builder()
             .binding(0)
             .stride(std::mem::size_of::<T>() as u32)
@@ -58,30 +88,145 @@ impl VertexBuffer {
                 .build());
         }
 
+        VertexLayout {
+            binding_descs: vec![binding_desc],
+            attrib_descs,
+        }
+    }
+
+    // Instance attributes go in binding 1, at the locations following the vertex attributes
+    // Any instance attributes already in the layout are replaced
+    pub fn add_instances(&mut self, instances: &InstanceData) {
+        self.binding_descs.retain(|b| b.binding == 0);
+        self.attrib_descs.retain(|a| a.binding == 0);
+
+        self.binding_descs.push(vk::VertexInputBindingDescription::builder()
+            .binding(1)
+            .stride(instances.stride as u32)
+            .input_rate(vk::VertexInputRate::INSTANCE)
+            .build());
+
+        let first_location = self.attrib_descs.len();
+
+        for (i, a) in instances.attributes.iter().enumerate() {
+            self.attrib_descs.push(vk::VertexInputAttributeDescription::builder()
+                .binding(1)
+                .location((first_location + i) as u32)
+                .format(a.format)
+                .offset(a.offset as u32)
+                .build());
+        }
+    }
+
+    // Meshes can be drawn by any pass whose vertex type has the same layout
+    pub fn matches(&self, other: &VertexLayout) -> bool {
+        let bindings_match = self.binding_descs.len() == other.binding_descs.len() && self.binding_descs.iter().zip(other.binding_descs.iter())
+            .all(|(a, b)| a.binding == b.binding && a.stride == b.stride && a.input_rate == b.input_rate);
+
+        let attribs_match = self.attrib_descs.len() == other.attrib_descs.len() && self.attrib_descs.iter().zip(other.attrib_descs.iter())
+            .all(|(a, b)| a.location == b.location && a.binding == b.binding && a.format == b.format && a.offset == b.offset);
+
+        bindings_match && attribs_match
+    }
+}
+
+impl VertexBuffer {
+    pub unsafe fn new<T: VertexAttributes>(c: &Core, d: &Device, verts: &[T], indices: Option<&[u32]>) -> Result<VertexBuffer, RendererError> {
         let buffer = BufferBuilder::new()
             .size(mem::size_of::<T>() * verts.len())
             .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
             .sharing_mode(vk::SharingMode::EXCLUSIVE)
             .properties(vk::MemoryPropertyFlags::DEVICE_LOCAL)
-            .build_with_data(c, d, verts.as_ptr() as *const c_void);
+            .build_with_data(c, d, verts.as_ptr() as *const c_void)?;
 
         let index_buffer = match indices {
             Some(is) => {
-                Some(BufferBuilder::new()
+                let index_buffer = BufferBuilder::new()
                     .size(mem::size_of::<u32>() * is.len())
                     .usage(vk::BufferUsageFlags::INDEX_BUFFER)
                     .sharing_mode(vk::SharingMode::EXCLUSIVE)
                     .properties(vk::MemoryPropertyFlags::DEVICE_LOCAL)
-                    .build_with_data(c, d, is.as_ptr() as *const c_void))
+                    .build_with_data(c, d, is.as_ptr() as *const c_void);
+
+                match index_buffer {
+                    Ok(index_buffer) => Some(index_buffer),
+                    Err(e) => {
+                        buffer.destroy(d);
+
+                        return Err(e);
+                    },
+                }
             },
             None => None,
         };
 
-        VertexBuffer {
-            binding_desc,
-            attrib_descs,
+        Ok(VertexBuffer {
+            layout: VertexLayout::new::<T>(),
             buffer,
+            instance_buffer: None,
             index_buffer,
+        })
+    }
+
+    /// # Safety
+    /// The vertex buffer mustn't be used by a frame still in flight, its instance buffer is replaced.
+    pub unsafe fn add_instances(&mut self, c: &Core, d: &Device, instances: &InstanceData) -> Result<(), RendererError> {
+        let instance_buffer = BufferBuilder::new()
+            .size(instances.bytes.len())
+            .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .properties(vk::MemoryPropertyFlags::DEVICE_LOCAL)
+            .build_with_data(c, d, instances.bytes.as_ptr() as *const c_void)?;
+
+        if let Some(old) = self.instance_buffer.replace(instance_buffer) {
+            old.destroy(d);
+        }
+
+        self.layout.add_instances(instances);
+
+        Ok(())
+    }
+
+    // In binding order, ready to be bound starting at binding 0
+    pub fn buffers(&self) -> Vec<vk::Buffer> {
+        let mut buffers = vec![self.buffer.buffer];
+
+        if let Some(instance_buffer) = &self.instance_buffer {
+            buffers.push(instance_buffer.buffer);
+        }
+
+        buffers
+    }
+
+    /// # Safety
+    /// The buffers mustn't be used by a frame still in flight.
+    pub unsafe fn destroy(&self, d: &Device) {
+        self.buffer.destroy(d);
+
+        if let Some(instance_buffer) = &self.instance_buffer {
+            instance_buffer.destroy(d);
+        }
+
+        if let Some(index_buffer) = &self.index_buffer {
+            index_buffer.destroy(d);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adding_instances_twice_replaces_binding_1() {
+        let offsets = [Vec4::zero(); 2];
+        let mut layout = VertexLayout::new::<Vec4>();
+
+        layout.add_instances(&InstanceData::new(&offsets));
+        layout.add_instances(&InstanceData::new(&offsets));
+
+        assert_eq!(layout.binding_descs.len(), 2);
+        assert_eq!(layout.attrib_descs.len(), 2);
+        assert_eq!((layout.attrib_descs[1].binding, layout.attrib_descs[1].location), (1, 1));
+    }
+}
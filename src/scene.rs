@@ -0,0 +1,412 @@
+pub mod transform;
+pub mod camera;
+
+use std::fmt;
+
+use crate::math::{mat::Mat4, vec::{Vec3, Vec4}};
+use crate::renderer::{deferred::Light, graphics_pass::DrawItem, renderer_data::MeshHandle};
+use crate::scene::{camera::{Camera, CameraView}, transform::Transform};
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct NodeId(pub usize);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SceneError {
+    UnknownNode(NodeId),
+    ParentCycle(NodeId), // The node would become its own ancestor
+}
+
+// Lights shine from their node's position, along its local +z
+#[derive(Copy, Clone)]
+pub enum LightComponent {
+    Point { col: Vec3, intensity: f32, range: f32 },
+    Directional { col: Vec3, intensity: f32 },
+    Spot { col: Vec3, intensity: f32, range: f32, inner_angle: f32, outer_angle: f32 },
+}
+
+pub struct Node {
+    pub name: String,
+
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+
+    transform: Transform,
+    world: Mat4, // Only up to date after Scene::update
+    dirty: bool,
+
+    pub visible: bool, // Hides the node and everything under it
+    pub mesh: Option<MeshHandle>,
+    pub camera: Option<Camera>,
+    pub light: Option<LightComponent>,
+}
+
+pub struct SceneDraw {
+    pub node: NodeId,
+    pub mesh: MeshHandle,
+    pub model: Mat4,
+}
+
+// Everything the renderer needs from the scene for one frame
+pub struct SceneExtract {
+    pub camera: Option<CameraView>,
+    pub draws: Vec<SceneDraw>,
+    pub lights: Vec<Light>,
+}
+
+// Nodes are kept in slots that aren't reused, so a removed node's id can't refer to a different node later
+pub struct Scene {
+    nodes: Vec<Option<Node>>,
+    roots: Vec<NodeId>,
+    dirty: bool,
+
+    pub active_camera: Option<NodeId>,
+}
+
+impl LightComponent {
+    pub fn light(&self, world: Mat4) -> Light {
+        let pos = Vec3::new(world.x.w, world.y.w, world.z.w);
+        let dir = (world * Vec4::new(0.0, 0.0, 1.0, 0.0)).to_vec3().normalize();
+
+        match *self {
+            LightComponent::Point { col, intensity, range } => Light::point(pos, col, intensity, range),
+            LightComponent::Directional { col, intensity } => Light::directional(dir, col, intensity),
+            LightComponent::Spot { col, intensity, range, inner_angle, outer_angle } => Light::spot(pos, dir, col, intensity, range, inner_angle, outer_angle),
+        }
+    }
+}
+
+impl Node {
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &Vec<NodeId> {
+        &self.children
+    }
+
+    pub fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    pub fn world(&self) -> Mat4 {
+        self.world
+    }
+}
+
+impl SceneExtract {
+    // Builds the renderer's draw items, the push constant of each draw is made from the draw and the camera
+    pub fn draw_items<P, F: Fn(&SceneDraw, Option<&CameraView>) -> P>(&self, push_constant: F) -> Vec<DrawItem> {
+        self.draws.iter().map(|draw| DrawItem::new(draw.mesh).vertex_push_constant(&push_constant(draw, self.camera.as_ref()))).collect()
+    }
+}
+
+impl Default for Scene {
+    fn default() -> Scene {
+        Scene::new()
+    }
+}
+
+impl Scene {
+    pub fn new() -> Scene {
+        Scene {
+            nodes: Vec::new(),
+            roots: Vec::new(),
+            dirty: false,
+
+            active_camera: None,
+        }
+    }
+
+    pub fn add_node(&mut self, name: &str, parent: Option<NodeId>, transform: Transform) -> Result<NodeId, SceneError> {
+        if let Some(parent) = parent {
+            self.get(parent)?;
+        }
+
+        let id = NodeId(self.nodes.len());
+
+        self.nodes.push(Some(Node {
+            name: name.to_string(),
+            parent,
+            children: Vec::new(),
+            transform,
+            world: Mat4::identity(),
+            dirty: true,
+            visible: true,
+            mesh: None,
+            camera: None,
+            light: None,
+        }));
+
+        match parent {
+            Some(parent) => self.get_mut(parent)?.children.push(id),
+            None => self.roots.push(id),
+        }
+
+        self.dirty = true;
+
+        Ok(id)
+    }
+
+    // Removes the node and everything under it
+    pub fn remove_node(&mut self, id: NodeId) -> Result<(), SceneError> {
+        let parent = self.get(id)?.parent;
+        self.detach(id, parent);
+
+        let mut removed = vec![id];
+
+        while let Some(id) = removed.pop() {
+            if let Some(node) = self.nodes[id.0].take() {
+                removed.extend(node.children);
+            }
+
+            if self.active_camera == Some(id) {
+                self.active_camera = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The node keeps its local transform, so it moves with its new parent
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) -> Result<(), SceneError> {
+        let old_parent = self.get(id)?.parent;
+
+        let mut ancestor = parent;
+        while let Some(a) = ancestor {
+            if a == id {
+                return Err(SceneError::ParentCycle(id));
+            }
+
+            ancestor = self.get(a)?.parent;
+        }
+
+        self.detach(id, old_parent);
+
+        match parent {
+            Some(parent) => self.get_mut(parent)?.children.push(id),
+            None => self.roots.push(id),
+        }
+
+        let node = self.get_mut(id)?;
+        node.parent = parent;
+        node.dirty = true;
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    fn detach(&mut self, id: NodeId, parent: Option<NodeId>) {
+        let siblings = match parent.and_then(|p| self.nodes[p.0].as_mut()) {
+            Some(parent) => &mut parent.children,
+            None => &mut self.roots,
+        };
+
+        siblings.retain(|&sibling| sibling != id);
+    }
+
+    pub fn get(&self, id: NodeId) -> Result<&Node, SceneError> {
+        self.nodes.get(id.0).and_then(|n| n.as_ref()).ok_or(SceneError::UnknownNode(id))
+    }
+
+    // Components can be changed through the node, transforms go through transform_mut or set_transform so the node is marked as changed
+    pub fn get_mut(&mut self, id: NodeId) -> Result<&mut Node, SceneError> {
+        self.nodes.get_mut(id.0).and_then(|n| n.as_mut()).ok_or(SceneError::UnknownNode(id))
+    }
+
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.nodes.iter().position(|n| n.as_ref().is_some_and(|n| n.name == name)).map(NodeId)
+    }
+
+    pub fn transform_mut(&mut self, id: NodeId) -> Result<&mut Transform, SceneError> {
+        self.dirty = true;
+
+        let node = self.get_mut(id)?;
+        node.dirty = true;
+
+        Ok(&mut node.transform)
+    }
+
+    pub fn set_transform(&mut self, id: NodeId, transform: Transform) -> Result<(), SceneError> {
+        *self.transform_mut(id)? = transform;
+
+        Ok(())
+    }
+
+    // Recomputes world matrices, only for nodes whose transform or an ancestor's changed since the last update
+    pub fn update(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        let mut stack: Vec<(NodeId, Mat4, bool)> = self.roots.iter().rev().map(|&root| (root, Mat4::identity(), false)).collect();
+
+        while let Some((id, parent_world, parent_changed)) = stack.pop() {
+            // Children lists only hold live nodes, a removed slot is skipped rather than trusted
+            let node = match self.nodes[id.0].as_mut() {
+                Some(node) => node,
+                None => continue,
+            };
+            let changed = parent_changed || node.dirty;
+
+            if changed {
+                node.world = node.transform.matrix() * parent_world;
+                node.dirty = false;
+            }
+
+            for &child in node.children.iter().rev() {
+                stack.push((child, node.world, changed));
+            }
+        }
+
+        self.dirty = false;
+    }
+
+    // Ratio is the width over height of the target the camera renders to. Draws of the same mesh are kept next to each other
+    pub fn extract(&mut self, ratio: f32) -> SceneExtract {
+        self.update();
+
+        let camera = self.active_camera
+            .and_then(|id| self.get(id).ok())
+            .and_then(|node| Some(CameraView::new(node.camera.as_ref()?, node.world, ratio)));
+
+        let mut draws = Vec::<SceneDraw>::new();
+        let mut lights = Vec::<Light>::new();
+
+        let mut stack: Vec<NodeId> = self.roots.iter().rev().copied().collect();
+
+        while let Some(id) = stack.pop() {
+            let node = match self.nodes[id.0].as_ref() {
+                Some(node) if node.visible => node,
+                _ => continue,
+            };
+
+            if let Some(mesh) = node.mesh {
+                draws.push(SceneDraw { node: id, mesh, model: node.world });
+            }
+
+            if let Some(light) = &node.light {
+                lights.push(light.light(node.world));
+            }
+
+            stack.extend(node.children.iter().rev());
+        }
+
+        draws.sort_by_key(|draw| draw.mesh.index);
+
+        SceneExtract {
+            camera,
+            draws,
+            lights,
+        }
+    }
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::UnknownNode(id) => write!(f, "Node {} does not exist", id.0),
+            SceneError::ParentCycle(id) => write!(f, "Node {} can't be parented under itself", id.0),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh(index: usize) -> MeshHandle {
+        MeshHandle { index, vertex_count: 3, index_count: 0 }
+    }
+
+    fn pos(scene: &Scene, id: NodeId) -> Vec3 {
+        let world = scene.get(id).unwrap().world();
+        Vec3::new(world.x.w, world.y.w, world.z.w)
+    }
+
+    #[test]
+    fn update_propagates_dirty_transforms_to_children() {
+        let mut scene = Scene::new();
+        let parent = scene.add_node("parent", None, Transform::from_pos(Vec3::new(1.0, 0.0, 0.0))).unwrap();
+        let child = scene.add_node("child", Some(parent), Transform::from_pos(Vec3::new(0.0, 2.0, 0.0))).unwrap();
+
+        scene.update();
+        assert_eq!(pos(&scene, child).y, 2.0);
+        assert_eq!(pos(&scene, child).x, 1.0);
+
+        // Only the parent is marked, the child has to pick the change up from it
+        scene.transform_mut(parent).unwrap().pos = Vec3::new(5.0, 0.0, 0.0);
+        assert!(!scene.get(child).unwrap().dirty);
+
+        scene.update();
+        assert_eq!(pos(&scene, child).x, 5.0);
+        assert_eq!(pos(&scene, child).y, 2.0);
+    }
+
+    #[test]
+    fn set_parent_rejects_cycles() {
+        let mut scene = Scene::new();
+        let a = scene.add_node("a", None, Transform::identity()).unwrap();
+        let b = scene.add_node("b", Some(a), Transform::identity()).unwrap();
+        let c = scene.add_node("c", Some(b), Transform::identity()).unwrap();
+
+        assert_eq!(scene.set_parent(a, Some(c)), Err(SceneError::ParentCycle(a)));
+        assert_eq!(scene.set_parent(a, Some(a)), Err(SceneError::ParentCycle(a)));
+
+        // Nothing changed
+        assert_eq!(scene.get(a).unwrap().parent(), None);
+        assert_eq!(scene.get(b).unwrap().children(), &vec![c]);
+
+        scene.set_parent(c, None).unwrap();
+        scene.set_parent(a, Some(c)).unwrap();
+        assert_eq!(scene.get(a).unwrap().parent(), Some(c));
+    }
+
+    #[test]
+    fn remove_node_removes_descendants() {
+        let mut scene = Scene::new();
+        let a = scene.add_node("a", None, Transform::identity()).unwrap();
+        let b = scene.add_node("b", Some(a), Transform::identity()).unwrap();
+        let c = scene.add_node("c", Some(b), Transform::identity()).unwrap();
+        let d = scene.add_node("d", None, Transform::identity()).unwrap();
+
+        scene.get_mut(c).unwrap().camera = Some(Camera::perspective(1.0, 0.1, 100.0));
+        scene.active_camera = Some(c);
+
+        scene.remove_node(b).unwrap();
+
+        assert!(scene.get(b).is_err());
+        assert!(scene.get(c).is_err());
+        assert!(scene.get(a).unwrap().children().is_empty());
+        assert!(scene.get(d).is_ok());
+        assert_eq!(scene.active_camera, None);
+
+        // Ids aren't reused
+        let e = scene.add_node("e", None, Transform::identity()).unwrap();
+        assert_ne!(e, b);
+        assert_ne!(e, c);
+    }
+
+    #[test]
+    fn extract_orders_draws_by_mesh_and_skips_hidden_nodes() {
+        let mut scene = Scene::new();
+        let a = scene.add_node("a", None, Transform::identity()).unwrap();
+        let b = scene.add_node("b", Some(a), Transform::identity()).unwrap();
+        let c = scene.add_node("c", None, Transform::identity()).unwrap();
+        let hidden = scene.add_node("hidden", None, Transform::identity()).unwrap();
+        let under_hidden = scene.add_node("under_hidden", Some(hidden), Transform::identity()).unwrap();
+
+        scene.get_mut(a).unwrap().mesh = Some(mesh(1));
+        scene.get_mut(b).unwrap().mesh = Some(mesh(0));
+        scene.get_mut(c).unwrap().mesh = Some(mesh(1));
+        scene.get_mut(under_hidden).unwrap().mesh = Some(mesh(0));
+        scene.get_mut(hidden).unwrap().visible = false;
+
+        let extract = scene.extract(1.0);
+        let draws: Vec<(usize, NodeId)> = extract.draws.iter().map(|d| (d.mesh.index, d.node)).collect();
+
+        // Depth first order is kept between draws of the same mesh
+        assert_eq!(draws, [(0, b), (1, a), (1, c)]);
+    }
+}
@@ -0,0 +1,59 @@
+use crate::math::{mat::Mat4, vec::{Vec3, Vec4}};
+
+#[derive(Copy, Clone)]
+pub enum Projection {
+    Perspective { fov: f32, near: f32, far: f32 }, // fov is vertical, in radians
+    Orthographic { zoom: f32, near: f32, far: f32 },
+}
+
+#[derive(Copy, Clone)]
+pub struct Camera {
+    pub projection: Projection,
+}
+
+// What the active camera sees in a frame. Matrices are in the math module's layout, shaders need them transposed
+#[derive(Copy, Clone)]
+pub struct CameraView {
+    pub pos: Vec3,
+    pub dir: Vec3,
+    pub view: Mat4,
+    pub proj: Mat4,
+    pub view_proj: Mat4,
+}
+
+impl Camera {
+    pub fn perspective(fov: f32, near: f32, far: f32) -> Camera {
+        Camera { projection: Projection::Perspective { fov, near, far } }
+    }
+
+    pub fn orthographic(zoom: f32, near: f32, far: f32) -> Camera {
+        Camera { projection: Projection::Orthographic { zoom, near, far } }
+    }
+
+    // Ratio is width over height
+    pub fn proj(&self, ratio: f32) -> Mat4 {
+        match self.projection {
+            Projection::Perspective { fov, near, far } => Mat4::perspective(ratio, fov, near, far),
+            Projection::Orthographic { zoom, near, far } => Mat4::orthogonal(ratio, zoom, near, far),
+        }
+    }
+}
+
+impl CameraView {
+    // Cameras look along their node's local +z, roll is ignored as Mat4::view keeps the world's up
+    pub fn new(camera: &Camera, world: Mat4, ratio: f32) -> CameraView {
+        let pos = Vec3::new(world.x.w, world.y.w, world.z.w);
+        let dir = (world * Vec4::new(0.0, 0.0, 1.0, 0.0)).to_vec3().normalize();
+
+        let view = Mat4::view(dir, pos);
+        let proj = camera.proj(ratio);
+
+        CameraView {
+            pos,
+            dir,
+            view,
+            proj,
+            view_proj: view * proj,
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use crate::math::{mat::Mat4, quat::Quat, vec::Vec3};
+
+// Applied as scale, then rotation, then translation
+#[derive(Copy, Clone)]
+pub struct Transform {
+    pub pos: Vec3,
+    pub rot: Quat,
+    pub scale: Vec3,
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            pos: Vec3::zero(),
+            rot: Quat::identity(),
+            scale: Vec3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn new(pos: Vec3, rot: Quat, scale: Vec3) -> Transform {
+        Transform {
+            pos,
+            rot,
+            scale,
+        }
+    }
+
+    pub fn from_pos(pos: Vec3) -> Transform {
+        Transform {
+            pos,
+            ..Transform::identity()
+        }
+    }
+
+    // Mat4 multiplication applies the left hand side first
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::scale(self.scale) * Mat4::rot(self.rot) * Mat4::translation(self.pos)
+    }
+}
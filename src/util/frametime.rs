@@ -1,8 +1,7 @@
-use std::collections::HashMap;
 use std::time::{Instant, Duration};
 
 pub struct Frametime {
-    pub deltas: HashMap<String, Duration>,
+    pub deltas: Vec<(String, Duration)>, // In the order they were set, so they can be laid out one after another
 
     pub start_time: Instant,
     pub last_time: Instant,
@@ -11,7 +10,7 @@ pub struct Frametime {
 impl Frametime {
     pub fn new() -> Frametime {
         Frametime {
-            deltas: HashMap::new(),
+            deltas: Vec::new(),
         
             start_time: Instant::now(),
             last_time: Instant::now(),
@@ -26,8 +25,13 @@ impl Frametime {
 
     pub fn set(&mut self, s: &str) {
         let time_cur = Instant::now();
-        let entry = self.deltas.entry(s.to_string());
-        *entry.or_default() = time_cur - self.last_time;
+        let delta = time_cur - self.last_time;
+
+        match self.deltas.iter_mut().find(|(segment, _)| segment == s) {
+            Some(entry) => entry.1 = delta,
+            None => self.deltas.push((s.to_string(), delta)),
+        }
+
         self.last_time = time_cur;
     }
 
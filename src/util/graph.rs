@@ -1,4 +1,4 @@
-use std::collections::{HashMap, VecDeque};
+use std::{collections::{HashMap, HashSet, VecDeque}, fmt};
 
 pub struct Node<T> {
     pub name: String,
@@ -12,6 +12,14 @@ pub struct Edge<U: Copy> {
     pub info: U,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum GraphError {
+    DuplicateNode(String),
+    UnknownNode(String),
+    UnknownEdge(String, String),
+    Cycle(Vec<String>),
+}
+
 pub struct Graph<T, U: Copy> {
     nodes: Vec<Node<T>>,
     edges: Vec<Edge<U>>,
@@ -33,31 +41,108 @@ impl <T, U: Copy> Graph<T, U> {
         }
     }
 
-    pub fn add_node(&mut self, name: &str, data: T) {
+    pub fn add_node(&mut self, name: &str, data: T) -> Result<(), GraphError> {
+        if self.node_refs.contains_key(name) {
+            return Err(GraphError::DuplicateNode(name.to_string()));
+        }
+
         self.nodes.push(Node { name: name.to_string(), data });
         self.node_refs.insert(name.to_string(), self.nodes.len() - 1);
 
         self.src_edge_refs.insert(name.to_string(), Vec::new());
         self.dst_edge_refs.insert(name.to_string(), Vec::new());
+
+        Ok(())
     }
 
-    pub fn add_edge(&mut self, src: &str, dst: &str, info: U) {
+    pub fn add_edge(&mut self, src: &str, dst: &str, info: U) -> Result<(), GraphError> {
+        let src_ref = self.find_node_ref(src)?;
+        let dst_ref = self.find_node_ref(dst)?;
+
         self.edges.push(Edge { 
-            src: *self.node_refs.get(src).unwrap(),
-            dst: *self.node_refs.get(dst).unwrap(),
+            src: src_ref,
+            dst: dst_ref,
 
             info,
         });
 
-        let src_edge_ref = self.get_node_ref(dst);
-        let dst_edge_ref = self.get_node_ref(src);
+        self.src_edge_refs.get_mut(src).unwrap().push((dst_ref, self.edges.len() - 1));
+        self.dst_edge_refs.get_mut(dst).unwrap().push((src_ref, self.edges.len() - 1));
+
+        Ok(())
+    }
+
+    // Removes the node along with every edge going into or out of it
+    pub fn remove_node(&mut self, name: &str) -> Result<T, GraphError> {
+        let node_ref = self.find_node_ref(name)?;
+
+        self.edges.retain(|e| e.src != node_ref && e.dst != node_ref);
+        for edge in &mut self.edges {
+            if edge.src > node_ref { edge.src -= 1; }
+            if edge.dst > node_ref { edge.dst -= 1; }
+        }
+
+        let node = self.nodes.remove(node_ref);
+        self.rebuild_refs();
 
-        self.src_edge_refs.get_mut(src).unwrap().push((src_edge_ref, self.edges.len() - 1));
-        self.dst_edge_refs.get_mut(dst).unwrap().push((dst_edge_ref, self.edges.len() - 1));
+        Ok(node.data)
     }
 
-    pub fn get_node(&self, name: &str) -> &Node<T> {
-        &self.nodes[*self.node_refs.get(name).unwrap()]
+    // Removes every edge from src to dst
+    pub fn remove_edge(&mut self, src: &str, dst: &str) -> Result<(), GraphError> {
+        let src_ref = self.find_node_ref(src)?;
+        let dst_ref = self.find_node_ref(dst)?;
+
+        let edge_count = self.edges.len();
+        self.edges.retain(|e| e.src != src_ref || e.dst != dst_ref);
+
+        if self.edges.len() == edge_count {
+            return Err(GraphError::UnknownEdge(src.to_string(), dst.to_string()));
+        }
+
+        self.rebuild_refs();
+
+        Ok(())
+    }
+
+    fn rebuild_refs(&mut self) {
+        self.node_refs.clear();
+        self.src_edge_refs.clear();
+        self.dst_edge_refs.clear();
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            self.node_refs.insert(node.name.clone(), i);
+            self.src_edge_refs.insert(node.name.clone(), Vec::new());
+            self.dst_edge_refs.insert(node.name.clone(), Vec::new());
+        }
+
+        for (i, edge) in self.edges.iter().enumerate() {
+            self.src_edge_refs.get_mut(&self.nodes[edge.src].name).unwrap().push((edge.dst, i));
+            self.dst_edge_refs.get_mut(&self.nodes[edge.dst].name).unwrap().push((edge.src, i));
+        }
+    }
+
+    pub fn contains_node(&self, name: &str) -> bool {
+        self.node_refs.contains_key(name)
+    }
+
+    pub fn find_node(&self, name: &str) -> Result<&Node<T>, GraphError> {
+        self.node_refs.get(name).map(|i| &self.nodes[*i]).ok_or(GraphError::UnknownNode(name.to_string()))
+    }
+
+    pub fn find_node_mut(&mut self, name: &str) -> Result<&mut Node<T>, GraphError> {
+        match self.node_refs.get(name) {
+            Some(i) => Ok(&mut self.nodes[*i]),
+            None => Err(GraphError::UnknownNode(name.to_string())),
+        }
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Node<T>> {
+        self.nodes.iter()
+    }
+
+    pub fn nodes_mut(&mut self) -> impl Iterator<Item = &mut Node<T>> {
+        self.nodes.iter_mut()
     }
 
     pub fn get_src_node(&self, edge: &Edge<U>) -> &Node<T> {
@@ -68,130 +153,257 @@ impl <T, U: Copy> Graph<T, U> {
         &self.nodes[edge.dst]
     }
 
-    fn get_node_ref(&self, name: &str) -> usize {
-        *self.node_refs.get(name).unwrap()
+    fn find_node_ref(&self, name: &str) -> Result<usize, GraphError> {
+        self.node_refs.get(name).copied().ok_or_else(|| GraphError::UnknownNode(name.to_string()))
     }
 
-    pub fn get_prev_nodes(&self, dst: &str) -> Vec<&Node<T>> {
-        let prev_node_refs = self.dst_edge_refs.get(dst).unwrap();
+    pub fn get_prev_nodes(&self, dst: &str) -> Result<Vec<&Node<T>>, GraphError> {
+        let prev_node_refs = self.dst_edge_refs.get(dst).ok_or_else(|| GraphError::UnknownNode(dst.to_string()))?;
         let mut prev_nodes = Vec::<&Node<T>>::with_capacity(prev_node_refs.len());        
 
         for node_ref in prev_node_refs {
             prev_nodes.push(&self.nodes[node_ref.0]);
         }
 
-        prev_nodes
+        Ok(prev_nodes)
     }
 
-    pub fn get_prev_edges(&self, dst: &str) -> Vec<&Edge<U>> {
-        let prev_edge_refs = self.dst_edge_refs.get(dst).unwrap();
+    pub fn get_prev_edges(&self, dst: &str) -> Result<Vec<&Edge<U>>, GraphError> {
+        let prev_edge_refs = self.dst_edge_refs.get(dst).ok_or_else(|| GraphError::UnknownNode(dst.to_string()))?;
         let mut prev_edges = Vec::<&Edge<U>>::with_capacity(prev_edge_refs.len());        
 
         for edge_ref in prev_edge_refs {
             prev_edges.push(&self.edges[edge_ref.1]);
         }
 
-        prev_edges
+        Ok(prev_edges)
     }
 
-    pub fn get_next_nodes(&self, src: &str) -> Vec<&Node<T>> {
-        let next_node_refs = self.src_edge_refs.get(src).unwrap();
+    pub fn get_next_nodes(&self, src: &str) -> Result<Vec<&Node<T>>, GraphError> {
+        let next_node_refs = self.src_edge_refs.get(src).ok_or_else(|| GraphError::UnknownNode(src.to_string()))?;
         let mut next_nodes = Vec::<&Node<T>>::with_capacity(next_node_refs.len());        
 
         for node_ref in next_node_refs {
             next_nodes.push(&self.nodes[node_ref.0]);
         }
 
-        next_nodes
+        Ok(next_nodes)
     }
 
-    pub fn get_next_edges(&self, src: &str) -> Vec<&Edge<U>> {
-        let next_edge_refs = self.src_edge_refs.get(src).unwrap();
+    pub fn get_next_edges(&self, src: &str) -> Result<Vec<&Edge<U>>, GraphError> {
+        let next_edge_refs = self.src_edge_refs.get(src).ok_or_else(|| GraphError::UnknownNode(src.to_string()))?;
         let mut next_edges = Vec::<&Edge<U>>::with_capacity(next_edge_refs.len());        
 
         for edge_ref in next_edge_refs {
             next_edges.push(&self.edges[edge_ref.1]);
         }
 
-        next_edges
+        Ok(next_edges)
     }
 
+    // Only called with names of nodes in the graph, which always have an entry
     fn get_prev_node_refs(&self, dst: &str) -> Vec<usize> {
-        self.dst_edge_refs.get(dst).unwrap().to_vec().iter().map(|e| {
-            e.0
-        }).collect()
+        self.dst_edge_refs.get(dst).map_or_else(Vec::new, |refs| refs.iter().map(|e| e.0).collect())
     }
 
     fn get_next_node_refs(&self, src: &str) -> Vec<usize> {
-        self.src_edge_refs.get(src).unwrap().to_vec().iter().map(|e| {
-            e.0
-        }).collect()
+        self.src_edge_refs.get(src).map_or_else(Vec::new, |refs| refs.iter().map(|e| e.0).collect())
     }
 
-    pub fn breadth_first_forwards(&self, root: &str) -> Vec<&Node<T>> {
-        const MAX_ITERATIONS: u32 = 1000;
+    pub fn breadth_first_forwards(&self, root: &str) -> Result<Vec<&Node<T>>, GraphError> {
+        self.breadth_first(root, |name| self.get_next_node_refs(name))
+    }
 
-        let mut tree = vec![self.get_node(root)];
-        let mut tree_refs = vec![self.get_node_ref(root)];
+    pub fn breadth_first_backwards(&self, root: &str) -> Result<Vec<&Node<T>>, GraphError> {
+        self.breadth_first(root, |name| self.get_prev_node_refs(name))
+    }
 
-        let open_node_refs = self.get_next_node_refs(root);
-        let mut open_node_refs_queue = VecDeque::from(open_node_refs);
+    fn breadth_first<F: Fn(&str) -> Vec<usize>>(&self, root: &str, neighbours: F) -> Result<Vec<&Node<T>>, GraphError> {
+        let root_ref = self.find_node_ref(root)?;
 
-        let mut iterations: u32 = 0;
-        while !open_node_refs_queue.is_empty() && iterations < MAX_ITERATIONS {
-            iterations += 1;
+        let mut tree = vec![&self.nodes[root_ref]];
+        let mut visited = HashSet::from([root_ref]);
 
-            let new_ref = open_node_refs_queue.pop_front().unwrap();
-            let current_node = &self.nodes[new_ref];
+        let mut open_node_refs_queue = VecDeque::from(neighbours(root));
 
-            if !tree_refs.contains(&new_ref) {
-                tree_refs.push(new_ref);
-                tree.push(&self.nodes[new_ref]);
+        while let Some(new_ref) = open_node_refs_queue.pop_front() {
+            if !visited.insert(new_ref) {
+                continue;
             }
 
-            let next_open_node_refs = self.get_next_node_refs(&current_node.name);
-            for node_ref in next_open_node_refs {
-                if !open_node_refs_queue.contains(&node_ref) {
-                    open_node_refs_queue.push_back(node_ref);
-                }
-            }
+            tree.push(&self.nodes[new_ref]);
+
+            open_node_refs_queue.extend(neighbours(&self.nodes[new_ref].name));
         }
 
-        tree
+        Ok(tree)
     }
 
-    pub fn breadth_first_backwards(&self, root: &str) -> Vec<&Node<T>> {
-        const MAX_ITERATIONS: u32 = 1000;
+    // Orders every node after all of the nodes it depends on, ties are broken by insertion order
+    pub fn topological_sort(&self) -> Result<Vec<&Node<T>>, GraphError> {
+        let node_refs: Vec<usize> = (0..self.nodes.len()).collect();
+        self.topological_sort_refs(&node_refs)
+    }
 
-        let mut tree = vec![self.get_node(root)];
-        let mut tree_refs = vec![self.get_node_ref(root)];
+    // Same as topological_sort, but only for root and the nodes it depends on
+    pub fn topological_sort_to(&self, root: &str) -> Result<Vec<&Node<T>>, GraphError> {
+        let mut node_refs = self.breadth_first_backwards(root)?.iter().map(|n| self.find_node_ref(&n.name)).collect::<Result<Vec<usize>, GraphError>>()?;
+        node_refs.sort();
 
-        let open_node_refs = self.get_prev_node_refs(root);
-        let mut open_node_refs_queue = VecDeque::from(open_node_refs);
+        self.topological_sort_refs(&node_refs)
+    }
 
-        let mut iterations: u32 = 0;
-        while !open_node_refs_queue.is_empty() && iterations < MAX_ITERATIONS {
-            iterations += 1;
+    // Kahn's algorithm over a subset of the nodes, edges leaving the subset are ignored
+    fn topological_sort_refs(&self, node_refs: &[usize]) -> Result<Vec<&Node<T>>, GraphError> {
+        let included: HashSet<usize> = node_refs.iter().copied().collect();
 
-            let new_ref = open_node_refs_queue.pop_front().unwrap();
-            let current_node = &self.nodes[new_ref];
+        let mut in_degrees = HashMap::<usize, usize>::new();
+        for node_ref in node_refs {
+            let in_degree = self.get_prev_node_refs(&self.nodes[*node_ref].name).iter().filter(|r| included.contains(r)).count();
+            in_degrees.insert(*node_ref, in_degree);
+        }
 
-            if !tree_refs.contains(&new_ref) {
-                tree_refs.push(new_ref);
-                tree.push(&self.nodes[new_ref]);
-            }
+        let mut ready: VecDeque<usize> = node_refs.iter().copied().filter(|r| in_degrees[r] == 0).collect();
+        let mut sorted = Vec::<&Node<T>>::with_capacity(node_refs.len());
+
+        while let Some(node_ref) = ready.pop_front() {
+            sorted.push(&self.nodes[node_ref]);
+
+            for next_ref in self.get_next_node_refs(&self.nodes[node_ref].name) {
+                if let Some(in_degree) = in_degrees.get_mut(&next_ref) {
+                    *in_degree -= 1;
 
-            let prev_open_node_refs = self.get_prev_node_refs(&current_node.name);
-            for node_ref in prev_open_node_refs {
-                if !open_node_refs_queue.contains(&node_ref) {
-                    open_node_refs_queue.push_back(node_ref);
+                    if *in_degree == 0 {
+                        ready.push_back(next_ref);
+                    }
                 }
             }
         }
 
-        tree
+        if sorted.len() != node_refs.len() {
+            let remaining: Vec<usize> = node_refs.iter().copied().filter(|r| in_degrees[r] > 0).collect();
+            return Err(GraphError::Cycle(self.find_cycle_in(&remaining)));
+        }
+
+        Ok(sorted)
+    }
+
+    // Returns the names of the nodes making up a cycle, in edge order, if the graph has one
+    pub fn find_cycle(&self) -> Option<Vec<String>> {
+        match self.topological_sort() {
+            Err(GraphError::Cycle(names)) => Some(names),
+            _ => None,
+        }
+    }
+
+    // Every node left over by Kahn's algorithm has a leftover node before it, so walking backwards from any of them has to revisit a node
+    fn find_cycle_in(&self, node_refs: &[usize]) -> Vec<String> {
+        let included: HashSet<usize> = node_refs.iter().copied().collect();
+
+        let mut path = vec![node_refs[0]];
+        loop {
+            let current = *path.last().unwrap();
+            let prev_ref = self.get_prev_node_refs(&self.nodes[current].name).into_iter().find(|r| included.contains(r)).unwrap();
+
+            if let Some(position) = path.iter().position(|r| *r == prev_ref) {
+                return path[position..].iter().rev().map(|r| self.nodes[*r].name.clone()).collect();
+            }
+
+            path.push(prev_ref);
+        }
     }
 
     pub fn node_count(&self) -> usize { self.nodes.len() }
     pub fn edge_count(&self) -> usize { self.edges.len() }
-}
\ No newline at end of file
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::DuplicateNode(name) => write!(f, "Node \"{}\" already exists", name),
+            GraphError::UnknownNode(name) => write!(f, "Node \"{}\" does not exist", name),
+            GraphError::UnknownEdge(src, dst) => write!(f, "No edge from \"{}\" to \"{}\"", src, dst),
+            GraphError::Cycle(names) => write!(f, "Cycle between nodes {}", names.join(" -> ")),
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(nodes: &[&str], edges: &[(&str, &str)]) -> Graph<(), ()> {
+        let mut graph = Graph::new();
+
+        for node in nodes {
+            graph.add_node(node, ()).unwrap();
+        }
+        for (src, dst) in edges {
+            graph.add_edge(src, dst, ()).unwrap();
+        }
+
+        graph
+    }
+
+    fn names(nodes: Vec<&Node<()>>) -> Vec<&str> {
+        nodes.iter().map(|n| n.name.as_str()).collect()
+    }
+
+    #[test]
+    fn topological_sort_breaks_ties_by_insertion_order() {
+        let graph = graph(&["d", "c", "b", "a"], &[("a", "b"), ("c", "b"), ("b", "d")]);
+
+        assert_eq!(names(graph.topological_sort().unwrap()), ["c", "a", "b", "d"]);
+        assert_eq!(names(graph.topological_sort_to("b").unwrap()), ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn find_cycle_returns_nodes_in_edge_order() {
+        let graph = graph(&["a", "b", "c", "d"], &[("d", "a"), ("a", "b"), ("b", "c"), ("c", "a")]);
+
+        let cycle = graph.find_cycle().unwrap();
+        assert_eq!(cycle.len(), 3);
+
+        for (i, name) in cycle.iter().enumerate() {
+            let next = &cycle[(i + 1) % cycle.len()];
+            assert!(names(graph.get_next_nodes(name).unwrap()).contains(&next.as_str()), "No edge from {} to {}", name, next);
+        }
+
+        assert!(matches!(graph.topological_sort(), Err(GraphError::Cycle(_))));
+    }
+
+    #[test]
+    fn find_cycle_is_none_for_a_dag() {
+        let graph = graph(&["a", "b", "c"], &[("a", "b"), ("a", "c"), ("b", "c")]);
+
+        assert_eq!(graph.find_cycle(), None);
+    }
+
+    #[test]
+    fn remove_node_removes_its_edges() {
+        let mut graph = graph(&["a", "b", "c"], &[("a", "b"), ("b", "c"), ("a", "c")]);
+
+        graph.remove_node("b").unwrap();
+
+        assert_eq!(graph.node_count(), 2);
+        assert_eq!(graph.edge_count(), 1);
+        assert!(!graph.contains_node("b"));
+        assert_eq!(names(graph.get_next_nodes("a").unwrap()), ["c"]);
+        assert_eq!(names(graph.get_prev_nodes("c").unwrap()), ["a"]);
+
+        assert_eq!(graph.remove_node("b").err(), Some(GraphError::UnknownNode(String::from("b"))));
+    }
+
+    #[test]
+    fn remove_edge_breaks_a_cycle() {
+        let mut graph = graph(&["a", "b"], &[("a", "b"), ("b", "a")]);
+
+        graph.remove_edge("b", "a").unwrap();
+
+        assert_eq!(graph.edge_count(), 1);
+        assert_eq!(names(graph.topological_sort().unwrap()), ["a", "b"]);
+        assert_eq!(graph.remove_edge("b", "a"), Err(GraphError::UnknownEdge(String::from("b"), String::from("a"))));
+    }
+}